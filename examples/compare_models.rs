@@ -0,0 +1,51 @@
+// Compare the arithmetic coder's adaptive models on some files:
+//     cargo run --release --example compare_models -- file...
+use squash::squash_algorithm::*;
+use std::env;
+use std::fs;
+use std::time::Instant;
+
+fn main() {
+    let files: Vec<String> = env::args().skip(1).collect();
+    if files.is_empty() {
+        eprintln!("usage: compare_models file...");
+        return;
+    }
+    println!(
+        "{:<30} {:>8} {:>12} {:>8} {:>10} {:>10}",
+        "file", "model", "size", "ratio", "enc ms", "dec ms"
+    );
+    for file in &files {
+        let input = match fs::read(file) {
+            Ok(x) => x,
+            Err(x) => {
+                eprintln!("{}: {}", file, x);
+                continue;
+            }
+        };
+        for (name, model) in &[("window", Model::Window), ("halving", Model::Halving)] {
+            let options = SquashOptions {
+                model: *model,
+                ..SquashOptions::default_options()
+            };
+            let start = Instant::now();
+            let mut squashed = vec![];
+            squash_with_options(&mut &input[..], &mut squashed, &options).unwrap();
+            let encode_time = start.elapsed();
+            let start = Instant::now();
+            let mut unsquashed = vec![];
+            unsquash(&mut &squashed[..], &mut unsquashed).unwrap();
+            let decode_time = start.elapsed();
+            assert!(unsquashed == input, "round trip failed for {}", file);
+            println!(
+                "{:<30} {:>8} {:>12} {:>8.3} {:>10} {:>10}",
+                file,
+                name,
+                squashed.len(),
+                squashed.len() as f64 / input.len().max(1) as f64,
+                encode_time.as_millis(),
+                decode_time.as_millis()
+            );
+        }
+    }
+}
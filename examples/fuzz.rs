@@ -0,0 +1,34 @@
+// Run the fuzz entry points on files, to replay what a fuzzer found, or on standard
+// input, for fuzzers like AFL that run a program once per input:
+//     cargo run --release --features fuzzing --example fuzz -- crash-file...
+use squash::fuzz;
+use std::env;
+use std::fs;
+use std::io::{self, Read};
+
+fn run(data: &[u8]) {
+    fuzz::unsquash(data);
+    fuzz::inspect(data);
+    fuzz::recover(data);
+    fuzz::repair(data);
+    fuzz::round_trip(data);
+}
+
+fn main() {
+    let files: Vec<String> = env::args().skip(1).collect();
+    if files.is_empty() {
+        let mut data = vec![];
+        io::stdin().read_to_end(&mut data).unwrap();
+        run(&data);
+        return;
+    }
+    for file in &files {
+        match fs::read(file) {
+            Ok(data) => {
+                run(&data);
+                println!("{}: ok", file);
+            }
+            Err(x) => eprintln!("{}: {}", file, x),
+        }
+    }
+}
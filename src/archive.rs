@@ -0,0 +1,773 @@
+use crate::squash_algorithm::{squash_with_options, unsquash, Metadata, SquashOptions};
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+
+// A container for many files and directories, built on squash streams.
+//
+// The archive starts with a short header, followed by the file data and then a
+// central directory listing every entry, so that listing an archive only has to
+// read the end of it. In per-file mode every file is its own squash stream and can
+// be extracted without touching the rest; in solid mode the contents of all the
+// files are squashed together as one stream, which compresses many small, similar
+// files much better.
+//
+//   header:    u32 magic, u8 version, u8 flags
+//   data:      one squash stream per file, or a single stream in solid mode
+//   directory: u32 entry count, then for each entry
+//              u8 kind, u16 path length, path, u64 offset, u64 length, metadata
+//   footer:    u64 offset of the directory, u32 end magic
+
+const ARCHIVE_MAGIC: u32 = 0xca55_a4c1;
+const END_MAGIC: u32 = 0xca55_a4c0;
+const ARCHIVE_VERSION: u8 = 1;
+const HEADER_LENGTH: u64 = 6;
+const FOOTER_LENGTH: u64 = 12;
+
+// archive flags
+const SOLID: u8 = 1;
+const KNOWN_FLAGS: u8 = SOLID;
+
+pub const SUFFIX: &str = ".sqa";
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum EntryKind {
+    File,
+    Directory,
+}
+
+impl EntryKind {
+    fn to_byte(self) -> u8 {
+        match self {
+            EntryKind::File => 0,
+            EntryKind::Directory => 1,
+        }
+    }
+
+    fn from_byte(byte: u8) -> io::Result<Self> {
+        match byte {
+            0 => Ok(EntryKind::File),
+            1 => Ok(EntryKind::Directory),
+            _ => Err(io::Error::other("unknown archive entry kind")),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Entry {
+    pub kind: EntryKind,
+    // relative to wherever the archive is extracted, separated with `/`
+    pub path: String,
+    // in per-file archives, where the file's stream starts in the archive and its
+    // length. In solid archives, where the file starts in the decompressed data, and 0
+    pub offset: u64,
+    pub length: u64,
+    // size, mtime and mode. The name is left out, as the path covers it
+    pub metadata: Metadata,
+}
+
+impl Entry {
+    pub fn size(&self) -> u64 {
+        self.metadata.size.unwrap_or(0)
+    }
+
+    fn write(&self, writer: &mut dyn Write) -> io::Result<()> {
+        let path_len = u16::try_from(self.path.len())
+            .map_err(|_| io::Error::other(format!("{}: path is too long", self.path)))?;
+        writer.write_all(&[self.kind.to_byte()])?;
+        writer.write_all(&path_len.to_le_bytes())?;
+        writer.write_all(self.path.as_bytes())?;
+        writer.write_all(&self.offset.to_le_bytes())?;
+        writer.write_all(&self.length.to_le_bytes())?;
+        self.metadata.write(writer)
+    }
+
+    fn read(reader: &mut dyn Read) -> io::Result<Self> {
+        let mut one_byte = [0; 1];
+        let mut two_bytes = [0; 2];
+        let mut eight_bytes = [0; 8];
+        reader.read_exact(&mut one_byte)?;
+        let kind = EntryKind::from_byte(one_byte[0])?;
+        reader.read_exact(&mut two_bytes)?;
+        let mut path = vec![0; usize::from(u16::from_le_bytes(two_bytes))];
+        reader.read_exact(&mut path)?;
+        let path = String::from_utf8(path)
+            .map_err(|_| io::Error::other("archive entry path isn't UTF-8"))?;
+        reader.read_exact(&mut eight_bytes)?;
+        let offset = u64::from_le_bytes(eight_bytes);
+        reader.read_exact(&mut eight_bytes)?;
+        let length = u64::from_le_bytes(eight_bytes);
+        let metadata = Metadata::read(reader)?;
+        Ok(Entry {
+            kind,
+            path,
+            offset,
+            length,
+            metadata,
+        })
+    }
+}
+
+pub struct ArchiveOptions {
+    // squash every file's contents together as one stream
+    pub solid: bool,
+    // how to squash the file data. Any metadata given here is ignored
+    pub squash: SquashOptions,
+}
+
+impl ArchiveOptions {
+    pub fn default_options() -> Self {
+        ArchiveOptions {
+            solid: false,
+            squash: SquashOptions::default_options(),
+        }
+    }
+}
+
+pub struct ExtractOptions {
+    // replace files that already exist
+    pub overwrite: bool,
+    // only extract these paths, and anything below them. Empty means everything
+    pub only: Vec<String>,
+}
+
+impl ExtractOptions {
+    pub fn default_options() -> Self {
+        ExtractOptions {
+            overwrite: false,
+            only: vec![],
+        }
+    }
+}
+
+// what went into a new archive
+pub struct Created {
+    pub entries: Vec<Entry>,
+    // symbolic links and other special files, which can't be archived
+    pub skipped: Vec<PathBuf>,
+}
+
+// archive the given files and directories, and everything below the directories.
+// Each is stored under the path it was given by, less any root, `.` or `..` parts
+pub fn create(
+    inputs: &[PathBuf],
+    writer: &mut dyn Write,
+    options: &ArchiveOptions,
+) -> io::Result<Created> {
+    let mut sources = vec![];
+    let mut skipped = vec![];
+    for input in inputs {
+        collect(input, &mut sources, &mut skipped)?;
+    }
+
+    let mut writer = CountingWriter::new(writer);
+    writer.write_all(&ARCHIVE_MAGIC.to_le_bytes())?;
+    writer.write_all(&[ARCHIVE_VERSION, if options.solid { SOLID } else { 0 }])?;
+
+    let squash_options = SquashOptions {
+        metadata: None,
+        ..options.squash.clone()
+    };
+    if options.solid {
+        let mut offset = 0;
+        for (_, entry) in sources.iter_mut() {
+            if entry.kind == EntryKind::File {
+                entry.offset = offset;
+                offset += entry.size();
+            }
+        }
+        let files: Vec<(&Path, u64)> = sources
+            .iter()
+            .filter(|(_, entry)| entry.kind == EntryKind::File)
+            .map(|(source, entry)| (source.as_path(), entry.size()))
+            .collect();
+        squash_with_options(&mut Contents::new(&files), &mut writer, &squash_options)?;
+    } else {
+        for (source, entry) in sources.iter_mut() {
+            if entry.kind == EntryKind::File {
+                entry.offset = writer.count;
+                squash_with_options(
+                    &mut Contents::new(&[(source.as_path(), entry.size())]),
+                    &mut writer,
+                    &squash_options,
+                )?;
+                entry.length = writer.count - entry.offset;
+            }
+        }
+    }
+
+    let directory_offset = writer.count;
+    let count = u32::try_from(sources.len())
+        .map_err(|_| io::Error::other("too many files for one archive"))?;
+    writer.write_all(&count.to_le_bytes())?;
+    for (_, entry) in &sources {
+        entry.write(&mut writer)?;
+    }
+    writer.write_all(&directory_offset.to_le_bytes())?;
+    writer.write_all(&END_MAGIC.to_le_bytes())?;
+    Ok(Created {
+        entries: sources.into_iter().map(|(_, entry)| entry).collect(),
+        skipped,
+    })
+}
+
+// read the central directory of an archive
+pub fn list<R: Read + Seek>(archive: &mut R) -> io::Result<Vec<Entry>> {
+    read_directory(archive).map(|(_, entries)| entries)
+}
+
+// extract an archive below `destination`, returning the entries extracted. Entries
+// with paths that would escape the destination are refused
+pub fn extract<R: Read + Seek>(
+    archive: &mut R,
+    destination: &Path,
+    options: &ExtractOptions,
+) -> io::Result<Vec<Entry>> {
+    let (flags, entries) = read_directory(archive)?;
+    let selected: Vec<&Entry> = entries
+        .iter()
+        .filter(|entry| {
+            options.only.is_empty()
+                || options.only.iter().any(|only| {
+                    let only = only.trim_end_matches('/');
+                    entry.path == only || entry.path.starts_with(&format!("{}/", only))
+                })
+        })
+        .collect();
+    let targets = selected
+        .iter()
+        .map(|entry| Ok(destination.join(safe_path(&entry.path)?)))
+        .collect::<io::Result<Vec<PathBuf>>>()?;
+
+    for (entry, target) in selected.iter().zip(&targets) {
+        match entry.kind {
+            EntryKind::Directory => fs::create_dir_all(target)?,
+            EntryKind::File => {
+                if let Some(parent) = target.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+            }
+        }
+    }
+
+    if flags & SOLID != 0 {
+        // the files can only be reached by decompressing everything before them
+        let mut outputs = VecDeque::new();
+        for entry in entries.iter().filter(|e| e.kind == EntryKind::File) {
+            let target = selected
+                .iter()
+                .position(|s| std::ptr::eq(*s, entry))
+                .map(|i| (targets[i].as_path(), entry));
+            outputs.push_back(Output {
+                remaining: entry.size(),
+                target,
+            });
+        }
+        let data_length = directory_offset(archive)? - HEADER_LENGTH;
+        archive.seek(SeekFrom::Start(HEADER_LENGTH))?;
+        let mut splitter = Splitter {
+            outputs,
+            overwrite: options.overwrite,
+            file: None,
+        };
+        unsquash(&mut archive.take(data_length), &mut splitter)?;
+        splitter.finish()?;
+    } else {
+        for (entry, target) in selected.iter().zip(&targets) {
+            if entry.kind != EntryKind::File {
+                continue;
+            }
+            let mut file = io::BufWriter::new(create_file(target, options.overwrite)?);
+            archive.seek(SeekFrom::Start(entry.offset))?;
+            let mut counter = CountingWriter::new(&mut file);
+            unsquash(&mut archive.take(entry.length), &mut counter)?;
+            if counter.count != entry.size() {
+                return Err(io::Error::other(format!(
+                    "{}: size doesn't match the directory",
+                    entry.path
+                )));
+            }
+            let file = file.into_inner().map_err(|x| x.into_error())?;
+            entry.metadata.restore(&file)?;
+        }
+    }
+
+    restore_directories(
+        selected
+            .iter()
+            .zip(&targets)
+            .filter(|(entry, _)| entry.kind == EntryKind::Directory)
+            .map(|(entry, target)| (target.as_path(), &entry.metadata)),
+    );
+    Ok(selected.into_iter().cloned().collect())
+}
+
+// set the metadata of extracted directories, given in the order they were created.
+// This comes last, and deepest first, since filling a directory in changes its mtime
+// and setting its mode may make it read only. Not every platform can open a directory
+// to set them, so failures are ignored
+pub(crate) fn restore_directories<'a>(
+    directories: impl DoubleEndedIterator<Item = (&'a Path, &'a Metadata)>,
+) {
+    for (path, metadata) in directories.rev() {
+        if let Ok(directory) = fs::File::open(path) {
+            let _ = metadata.restore(&directory);
+        }
+    }
+}
+
+// find the files and directories below an input, along with their entries
+pub(crate) fn collect(
+    input: &Path,
+    sources: &mut Vec<(PathBuf, Entry)>,
+    skipped: &mut Vec<PathBuf>,
+) -> io::Result<()> {
+    let with_path = |x: io::Error| io::Error::new(x.kind(), format!("{}: {}", input.display(), x));
+    let fs_metadata = fs::symlink_metadata(input).map_err(with_path)?;
+    let kind = if fs_metadata.is_dir() {
+        EntryKind::Directory
+    } else if fs_metadata.is_file() {
+        EntryKind::File
+    } else {
+        skipped.push(input.to_path_buf());
+        return Ok(());
+    };
+    let path = archive_path(input)?;
+    let mut metadata = Metadata::from_file(input).map_err(with_path)?;
+    metadata.name = None;
+    if kind == EntryKind::Directory {
+        metadata.size = None;
+    }
+    // the root of a relative input like `.` has nothing to be called, and needs no entry
+    if !path.is_empty() {
+        sources.push((
+            input.to_path_buf(),
+            Entry {
+                kind,
+                path,
+                offset: 0,
+                length: 0,
+                metadata,
+            },
+        ));
+    }
+    if kind == EntryKind::Directory {
+        let mut children: Vec<PathBuf> = fs::read_dir(input)
+            .and_then(|children| children.map(|c| c.map(|c| c.path())).collect())
+            .map_err(with_path)?;
+        children.sort();
+        for child in children {
+            collect(&child, sources, skipped)?;
+        }
+    }
+    Ok(())
+}
+
+// the path an input is stored under, less any root and `.`. A `..` is refused rather
+// than dropped, since without it the path would name some other file
+fn archive_path(input: &Path) -> io::Result<String> {
+    let mut parts = vec![];
+    for component in input.components() {
+        match component {
+            Component::Normal(part) => parts.push(part.to_str().ok_or_else(|| {
+                io::Error::other(format!("{}: path isn't UTF-8", input.display()))
+            })?),
+            Component::ParentDir => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{}: paths with .. can't be stored", input.display()),
+                ))
+            }
+            _ => (),
+        }
+    }
+    Ok(parts.join("/"))
+}
+
+// turn a stored path into a relative one, refusing anything that could reach outside
+// the destination
+pub(crate) fn safe_path(path: &str) -> io::Result<PathBuf> {
+    let unsafe_path = || io::Error::other(format!("{}: unsafe path in archive", path));
+    let mut safe = PathBuf::new();
+    for part in path.split('/') {
+        let part = Path::new(part);
+        match part.components().next() {
+            Some(Component::Normal(_)) if part.components().count() == 1 => safe.push(part),
+            _ => return Err(unsafe_path()),
+        }
+    }
+    Ok(safe)
+}
+
+fn read_directory<R: Read + Seek>(archive: &mut R) -> io::Result<(u8, Vec<Entry>)> {
+    let mut four_bytes = [0; 4];
+    let mut one_byte = [0; 1];
+    archive.seek(SeekFrom::Start(0))?;
+    archive.read_exact(&mut four_bytes)?;
+    if u32::from_le_bytes(four_bytes) != ARCHIVE_MAGIC {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not a squash archive",
+        ));
+    }
+    archive.read_exact(&mut one_byte)?;
+    if one_byte[0] != ARCHIVE_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "unsupported archive version",
+        ));
+    }
+    archive.read_exact(&mut one_byte)?;
+    let flags = one_byte[0];
+    if flags & !KNOWN_FLAGS != 0 {
+        return Err(io::Error::other("unsupported archive flags"));
+    }
+
+    let directory_offset = directory_offset(archive)?;
+    let directory_end = archive.seek(SeekFrom::End(-(FOOTER_LENGTH as i64)))?;
+    archive.seek(SeekFrom::Start(directory_offset))?;
+    let mut directory = archive.take(directory_end - directory_offset);
+    directory.read_exact(&mut four_bytes)?;
+    let count = u32::from_le_bytes(four_bytes);
+    let mut entries = vec![];
+    let mut solid_offset = 0;
+    for _ in 0..count {
+        let entry = Entry::read(&mut directory)?;
+        let in_bounds = if entry.kind == EntryKind::Directory {
+            entry.offset == 0 && entry.length == 0
+        } else if flags & SOLID != 0 {
+            let contiguous = entry.offset == solid_offset && entry.length == 0;
+            solid_offset = entry.offset.saturating_add(entry.size());
+            contiguous
+        } else {
+            entry.offset >= HEADER_LENGTH
+                && entry
+                    .offset
+                    .checked_add(entry.length)
+                    .is_some_and(|end| end <= directory_offset)
+        };
+        if !in_bounds {
+            return Err(io::Error::other(format!(
+                "{}: entry is out of place in the archive",
+                entry.path
+            )));
+        }
+        entries.push(entry);
+    }
+    Ok((flags, entries))
+}
+
+// read the footer, checking it points somewhere sensible
+fn directory_offset<R: Read + Seek>(archive: &mut R) -> io::Result<u64> {
+    let mut four_bytes = [0; 4];
+    let mut eight_bytes = [0; 8];
+    let length = archive.seek(SeekFrom::End(0))?;
+    if length < HEADER_LENGTH + FOOTER_LENGTH {
+        return Err(io::Error::other("archive is truncated"));
+    }
+    archive.seek(SeekFrom::Start(length - FOOTER_LENGTH))?;
+    archive.read_exact(&mut eight_bytes)?;
+    archive.read_exact(&mut four_bytes)?;
+    let offset = u64::from_le_bytes(eight_bytes);
+    if u32::from_le_bytes(four_bytes) != END_MAGIC
+        || offset < HEADER_LENGTH
+        || offset > length - FOOTER_LENGTH
+    {
+        return Err(io::Error::other("archive is truncated"));
+    }
+    Ok(offset)
+}
+
+pub(crate) fn create_file(path: &Path, overwrite: bool) -> io::Result<fs::File> {
+    fs::OpenOptions::new()
+        .write(true)
+        .create(overwrite)
+        .truncate(overwrite)
+        .create_new(!overwrite)
+        .open(path)
+        .map_err(|x| match x.kind() {
+            io::ErrorKind::AlreadyExists => {
+                io::Error::new(x.kind(), format!("{}: already exists", path.display()))
+            }
+            _ => io::Error::new(x.kind(), format!("{}: {}", path.display(), x)),
+        })
+}
+
+// reads the contents of a list of files one after another, checking each is still the
+// size it was when it was listed. A file that has grown is cut short
+struct Contents<'a> {
+    files: std::slice::Iter<'a, (&'a Path, u64)>,
+    current: Option<(&'a Path, io::Take<fs::File>)>,
+}
+
+impl<'a> Contents<'a> {
+    fn new(files: &'a [(&'a Path, u64)]) -> Self {
+        Contents {
+            files: files.iter(),
+            current: None,
+        }
+    }
+}
+
+impl Read for Contents<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let (path, file) = match &mut self.current {
+                Some(current) => current,
+                None => match self.files.next() {
+                    Some((path, size)) => {
+                        let file = fs::File::open(path)?.take(*size);
+                        self.current.insert((path, file))
+                    }
+                    None => return Ok(0),
+                },
+            };
+            let read = file.read(buf)?;
+            if read > 0 || buf.is_empty() {
+                return Ok(read);
+            }
+            if file.limit() > 0 {
+                return Err(io::Error::other(format!(
+                    "{}: file shrank while it was being archived",
+                    path.display()
+                )));
+            }
+            self.current = None;
+        }
+    }
+}
+
+// divides the decompressed data of a solid archive between its files, opening each
+// one as its data begins. Files that weren't selected have no target, and their
+// data is dropped
+struct Splitter<'a> {
+    // the files still to come, the first being the one currently written
+    outputs: VecDeque<Output<'a>>,
+    overwrite: bool,
+    // the first output's file, once it's open
+    file: Option<io::BufWriter<fs::File>>,
+}
+
+struct Output<'a> {
+    remaining: u64,
+    target: Option<(&'a Path, &'a Entry)>,
+}
+
+impl Splitter<'_> {
+    // close any files that are complete, and open the next one that needs data
+    fn advance(&mut self) -> io::Result<()> {
+        while let Some(output) = self.outputs.front() {
+            if let (None, Some((target, _))) = (&self.file, output.target) {
+                self.file = Some(io::BufWriter::new(create_file(target, self.overwrite)?));
+            }
+            if output.remaining > 0 {
+                break;
+            }
+            if let (Some(file), Some((_, entry))) = (self.file.take(), output.target) {
+                let file = file.into_inner().map_err(|x| x.into_error())?;
+                entry.metadata.restore(&file)?;
+            }
+            self.outputs.pop_front();
+        }
+        Ok(())
+    }
+
+    // check every file got all of its data
+    fn finish(mut self) -> io::Result<()> {
+        self.advance()?;
+        if !self.outputs.is_empty() {
+            return Err(io::Error::other(
+                "archive data is shorter than the directory says",
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Write for Splitter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        self.advance()?;
+        let output = self
+            .outputs
+            .front_mut()
+            .ok_or_else(|| io::Error::other("archive data is longer than the directory says"))?;
+        let length = buf
+            .len()
+            .min(usize::try_from(output.remaining).unwrap_or(usize::MAX));
+        if let Some(file) = &mut self.file {
+            file.write_all(&buf[..length])?;
+        }
+        output.remaining -= length as u64;
+        Ok(length)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// passes writes through, counting the bytes. Public for the command line tool only
+#[doc(hidden)]
+pub struct CountingWriter<W: Write> {
+    pub inner: W,
+    pub count: u64,
+}
+
+impl<W: Write> CountingWriter<W> {
+    pub fn new(inner: W) -> Self {
+        CountingWriter { inner, count: 0 }
+    }
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.count += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::common::scratch_dir;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn build_tree(root: &Path) {
+        fs::create_dir_all(root.join("tree/nested/empty")).unwrap();
+        fs::write(root.join("tree/a.txt"), "the quick brown fox ".repeat(200)).unwrap();
+        fs::write(root.join("tree/nested/b.txt"), "jumps over the lazy dog").unwrap();
+        fs::write(root.join("tree/nested/nothing"), "").unwrap();
+        let file = fs::File::open(root.join("tree/a.txt")).unwrap();
+        file.set_modified(UNIX_EPOCH + Duration::from_secs(1_000_000_000))
+            .unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            file.set_permissions(fs::Permissions::from_mode(0o4755))
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn archive_round_trip() {
+        for solid in &[false, true] {
+            let dir = scratch_dir(if *solid {
+                "archive-solid"
+            } else {
+                "archive-per-file"
+            });
+            build_tree(&dir);
+            let options = ArchiveOptions {
+                solid: *solid,
+                ..ArchiveOptions::default_options()
+            };
+            let mut archived = vec![];
+            let created = create(&[dir.join("tree")], &mut archived, &options).unwrap();
+            assert!(created.skipped.is_empty());
+
+            let mut archive = io::Cursor::new(archived);
+            let entries = list(&mut archive).unwrap();
+            assert_eq!(entries, created.entries);
+            let paths: Vec<&str> = entries
+                .iter()
+                .map(|e| e.path.strip_prefix(&archive_path(&dir).unwrap()).unwrap())
+                .collect();
+            assert_eq!(
+                paths,
+                [
+                    "/tree",
+                    "/tree/a.txt",
+                    "/tree/nested",
+                    "/tree/nested/b.txt",
+                    "/tree/nested/empty",
+                    "/tree/nested/nothing"
+                ]
+            );
+
+            let out = dir.join("out");
+            extract(&mut archive, &out, &ExtractOptions::default_options()).unwrap();
+            let extracted = out.join(safe_path(&entries[0].path).unwrap());
+            assert_eq!(
+                fs::read(extracted.join("a.txt")).unwrap(),
+                fs::read(dir.join("tree/a.txt")).unwrap()
+            );
+            assert_eq!(
+                fs::metadata(extracted.join("a.txt"))
+                    .unwrap()
+                    .modified()
+                    .unwrap(),
+                UNIX_EPOCH + Duration::from_secs(1_000_000_000)
+            );
+            assert_eq!(
+                fs::read(extracted.join("nested/b.txt")).unwrap(),
+                b"jumps over the lazy dog"
+            );
+            assert!(extracted.join("nested/empty").is_dir());
+            assert_eq!(fs::read(extracted.join("nested/nothing")).unwrap(), b"");
+            // a setuid bit is archived, but not extracted
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o7777;
+                assert_eq!(entries[1].metadata.mode, Some(0o4755));
+                assert_eq!(mode(&extracted.join("a.txt")), 0o755);
+            }
+
+            // existing files are only replaced when asked
+            assert!(extract(&mut archive, &out, &ExtractOptions::default_options()).is_err());
+
+            // just the one file
+            let only = dir.join("only");
+            let options = ExtractOptions {
+                only: vec![entries[3].path.clone()],
+                ..ExtractOptions::default_options()
+            };
+            assert_eq!(
+                extract(&mut archive, &only, &options).unwrap(),
+                [entries[3].clone()]
+            );
+            assert!(only.join(safe_path(&entries[3].path).unwrap()).is_file());
+            assert!(!only.join(safe_path(&entries[1].path).unwrap()).exists());
+            fs::remove_dir_all(&dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn unsafe_paths() {
+        assert_eq!(safe_path("a/b").unwrap(), Path::new("a").join("b"));
+        for path in &["", "/etc/passwd", "a/../../b", "./a", "a//b", ".."] {
+            assert!(safe_path(path).is_err(), "{}", path);
+        }
+        assert_eq!(archive_path(Path::new("/a/./b/c")).unwrap(), "a/b/c");
+        for path in &["/a/./b/../c", "../a", ".."] {
+            assert!(archive_path(Path::new(path)).is_err(), "{}", path);
+        }
+    }
+
+    #[test]
+    fn damaged_archives() {
+        let dir = scratch_dir("archive-damaged");
+        build_tree(&dir);
+        let mut archived = vec![];
+        create(
+            &[dir.join("tree")],
+            &mut archived,
+            &ArchiveOptions::default_options(),
+        )
+        .unwrap();
+        assert!(list(&mut io::Cursor::new(&archived[..archived.len() - 1])).is_err());
+        assert!(list(&mut io::Cursor::new(&archived[1..])).is_err());
+        assert!(list(&mut io::Cursor::new(b"")).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,238 @@
+use super::info::json_string;
+use super::USAGE;
+use squash::squash_algorithm::*;
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::time::Duration;
+
+// the stages in pipeline order; text only appears for blocks that look like text
+const STAGES: [&str; 6] = ["input", "text", "bwt", "mtf", "rle", "arithmetic"];
+
+// `squash analyze [--json] [--no-text] [--model=MODEL] <file...>`
+pub fn run(args: &[String]) -> Result<(), String> {
+    let mut json = false;
+    let mut options = SquashOptions::default_options();
+    let mut files = vec![];
+    for arg in args {
+        match arg.as_str() {
+            "--json" => json = true,
+            "--no-text" => options.text_preprocessing = false,
+            "--model=window" => options.model = Model::Window,
+            "--model=halving" => options.model = Model::Halving,
+            "-" => files.push(arg),
+            _ if arg.starts_with('-') => return Err(format!("unknown option {}\n{}", arg, USAGE)),
+            _ => files.push(arg),
+        }
+    }
+    if files.is_empty() {
+        return Err(format!("analyze takes the files to analyze\n{}", USAGE));
+    }
+
+    let mut failed = false;
+    for file in files {
+        let blocks = if file == "-" {
+            analyze(&mut io::stdin().lock(), &options)
+        } else {
+            fs::File::open(file)
+                .map(io::BufReader::new)
+                .and_then(|mut reader| analyze(&mut reader, &options))
+        };
+        match blocks {
+            Ok(blocks) if json => println!("{}", to_json(file, &blocks)),
+            Ok(blocks) => print!("{}", to_text(file, &blocks)),
+            Err(x) => {
+                eprintln!("squash: {}: {}", file, x);
+                failed = true;
+            }
+        }
+    }
+    if failed {
+        Err(String::new())
+    } else {
+        Ok(())
+    }
+}
+
+// the same figures as a block's, over the whole file
+struct Totals {
+    stages: Vec<Stage>,
+    mtf_zeros: f64,
+    rle_shrink: f64,
+    bits_per_symbol: f64,
+    entropy_bound: f64,
+}
+
+fn totals(blocks: &[BlockAnalysis]) -> Totals {
+    let stages: Vec<Stage> = STAGES
+        .iter()
+        .filter_map(|name| {
+            let stages: Vec<&Stage> = blocks
+                .iter()
+                .flat_map(|b| &b.stages)
+                .filter(|s| s.name == *name)
+                .collect();
+            if stages.is_empty() {
+                return None;
+            }
+            Some(Stage {
+                name,
+                symbols: stages.iter().map(|s| s.symbols).sum(),
+                entropy: weighted(stages.iter().map(|s| (s.entropy, s.symbols))),
+                time: stages.iter().map(|s| s.time).sum(),
+            })
+        })
+        .collect();
+    let symbols = |block: &BlockAnalysis, name| {
+        block
+            .stages
+            .iter()
+            .find(|s| s.name == name)
+            .map_or(0, |s| s.symbols)
+    };
+    let mtf: usize = blocks.iter().map(|b| symbols(b, "mtf")).sum();
+    let rle: usize = blocks.iter().map(|b| symbols(b, "rle")).sum();
+    Totals {
+        mtf_zeros: weighted(blocks.iter().map(|b| (b.mtf_zeros, symbols(b, "mtf")))),
+        rle_shrink: if rle == 0 {
+            0.0
+        } else {
+            mtf as f64 / rle as f64
+        },
+        bits_per_symbol: weighted(
+            blocks
+                .iter()
+                .map(|b| (b.bits_per_symbol, symbols(b, "rle"))),
+        ),
+        entropy_bound: weighted(blocks.iter().map(|b| (b.entropy_bound, symbols(b, "rle")))),
+        stages,
+    }
+}
+
+// the average of some per-symbol figures, weighted by their symbol counts
+fn weighted(values: impl Iterator<Item = (f64, usize)>) -> f64 {
+    let (sum, count) = values.fold((0.0, 0), |(sum, count), (value, symbols)| {
+        (sum + value * symbols as f64, count + symbols)
+    });
+    if count == 0 {
+        0.0
+    } else {
+        sum / count as f64
+    }
+}
+
+fn milliseconds(time: Duration) -> f64 {
+    time.as_secs_f64() * 1000.0
+}
+
+// how far the coder is from the entropy bound
+fn overhead(bits_per_symbol: f64, entropy_bound: f64) -> String {
+    if entropy_bound == 0.0 {
+        String::from("-")
+    } else {
+        format!("{:+.1}%", 100.0 * (bits_per_symbol / entropy_bound - 1.0))
+    }
+}
+
+fn to_text(file: &str, blocks: &[BlockAnalysis]) -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "{}:", file);
+    let _ = writeln!(out, "  blocks            {}", blocks.len());
+    if blocks.is_empty() {
+        return out;
+    }
+    let totals = totals(blocks);
+    let _ = writeln!(
+        out,
+        "  {:<10}  {:>10}  {:>12}  {:>10}",
+        "stage", "symbols", "entropy", "time"
+    );
+    for stage in &totals.stages {
+        let _ = writeln!(
+            out,
+            "  {:<10}  {:>10}  {:>7.3} bits  {:>7.1} ms",
+            stage.name,
+            stage.symbols,
+            stage.entropy,
+            milliseconds(stage.time)
+        );
+    }
+    let _ = writeln!(out, "  mtf zeros         {:.1}%", 100.0 * totals.mtf_zeros);
+    let _ = writeln!(out, "  rle shrink        {:.2}x", totals.rle_shrink);
+    let _ = writeln!(
+        out,
+        "  coded             {:.3} bits/symbol, entropy bound {:.3} ({})",
+        totals.bits_per_symbol,
+        totals.entropy_bound,
+        overhead(totals.bits_per_symbol, totals.entropy_bound)
+    );
+    if blocks.len() == 1 {
+        return out;
+    }
+
+    let _ = writeln!(
+        out,
+        "  {:>5}  {:>8}  {:<4}  {:>9}  {:>10}  {:>8}  {:>8}  {:>9}",
+        "block", "length", "text", "mtf zeros", "rle shrink", "bits/sym", "bound", "overhead"
+    );
+    for (i, block) in blocks.iter().enumerate() {
+        let _ = writeln!(
+            out,
+            "  {:>5}  {:>8}  {:<4}  {:>8.1}%  {:>9.2}x  {:>8.3}  {:>8.3}  {:>9}",
+            i,
+            block.length,
+            if block.text { "yes" } else { "no" },
+            100.0 * block.mtf_zeros,
+            block.rle_shrink,
+            block.bits_per_symbol,
+            block.entropy_bound,
+            overhead(block.bits_per_symbol, block.entropy_bound)
+        );
+    }
+    out
+}
+
+fn stages_json(stages: &[Stage]) -> String {
+    let stages: Vec<String> = stages
+        .iter()
+        .map(|stage| {
+            format!(
+                "{{\"name\":\"{}\",\"symbols\":{},\"entropy\":{:.4},\"seconds\":{:.6}}}",
+                stage.name,
+                stage.symbols,
+                stage.entropy,
+                stage.time.as_secs_f64()
+            )
+        })
+        .collect();
+    format!("[{}]", stages.join(","))
+}
+
+fn to_json(file: &str, blocks: &[BlockAnalysis]) -> String {
+    let totals = totals(blocks);
+    let block_json: Vec<String> = blocks
+        .iter()
+        .map(|block| {
+            format!(
+                "{{\"length\":{},\"text\":{},\"stages\":{},\"mtf_zeros\":{:.4},\"rle_shrink\":{:.4},\"bits_per_symbol\":{:.4},\"entropy_bound\":{:.4}}}",
+                block.length,
+                block.text,
+                stages_json(&block.stages),
+                block.mtf_zeros,
+                block.rle_shrink,
+                block.bits_per_symbol,
+                block.entropy_bound
+            )
+        })
+        .collect();
+    format!(
+        "{{\"file\":{},\"stages\":{},\"mtf_zeros\":{:.4},\"rle_shrink\":{:.4},\"bits_per_symbol\":{:.4},\"entropy_bound\":{:.4},\"blocks\":[{}]}}",
+        json_string(file),
+        stages_json(&totals.stages),
+        totals.mtf_zeros,
+        totals.rle_shrink,
+        totals.bits_per_symbol,
+        totals.entropy_bound,
+        block_json.join(",")
+    )
+}
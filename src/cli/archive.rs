@@ -0,0 +1,137 @@
+use super::compress::write_output;
+use super::{parse_args, USAGE};
+use squash::archive::{self, ArchiveOptions, EntryKind, ExtractOptions};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// `squash create [options] [--solid] <archive> <path...>`, taking the same
+// compression options as squashing a file
+pub fn create(args: &[String]) -> Result<(), String> {
+    // --solid only means something here, so it's picked out before the rest are parsed
+    let end = args.iter().position(|a| a == "--").unwrap_or(args.len());
+    let solid = args[..end].iter().any(|a| a == "--solid");
+    let args: Vec<String> = args
+        .iter()
+        .enumerate()
+        .filter(|(i, a)| *i >= end || *a != "--solid")
+        .map(|(_, a)| a.clone())
+        .collect();
+    let (options, files) = parse_args(&args)?;
+    let (archive, inputs) = match files.split_first() {
+        Some((archive, inputs)) if archive != "-" && !inputs.is_empty() => (archive, inputs),
+        _ if args.iter().any(|a| a == "-h" || a == "--help") => return Ok(()),
+        _ => {
+            return Err(format!(
+                "create takes an archive and the paths to put in it\n{}",
+                USAGE
+            ))
+        }
+    };
+    let inputs: Vec<PathBuf> = inputs.iter().map(PathBuf::from).collect();
+    let archive_options = ArchiveOptions {
+        solid,
+        squash: options.squash_options(),
+    };
+    let mut created = None;
+    write_output(Path::new(archive), options.force, |writer| {
+        created = Some(archive::create(&inputs, writer, &archive_options)?);
+        Ok(())
+    })?;
+    if let Some(created) = created {
+        for skipped in &created.skipped {
+            eprintln!(
+                "squash: {}: not a regular file or directory -- skipped",
+                skipped.display()
+            );
+        }
+        if options.verbose {
+            for entry in &created.entries {
+                eprintln!("{}", entry.path);
+            }
+        }
+    }
+    Ok(())
+}
+
+// `squash list <archive>`
+pub fn list(args: &[String]) -> Result<(), String> {
+    let archive = match args {
+        [archive] => archive,
+        _ => return Err(format!("list takes an archive\n{}", USAGE)),
+    };
+    let mut file = fs::File::open(archive).map_err(|x| format!("{}: {}", archive, x))?;
+    let entries = archive::list(&mut file).map_err(|x| format!("{}: {}", archive, x))?;
+    for entry in entries {
+        let (kind, suffix) = match entry.kind {
+            EntryKind::File => ('-', ""),
+            EntryKind::Directory => ('d', "/"),
+        };
+        println!(
+            "{}{} {:>12} {}{}",
+            kind,
+            permissions(entry.metadata.mode.unwrap_or(0)),
+            entry.size(),
+            entry.path,
+            suffix
+        );
+    }
+    Ok(())
+}
+
+// `squash extract [-f] [-v] [-C <dir>] <archive> [path...]`
+pub fn extract(args: &[String]) -> Result<(), String> {
+    let mut options = ExtractOptions::default_options();
+    let mut verbose = false;
+    let mut destination = PathBuf::from(".");
+    let mut files = vec![];
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--" => {
+                files.extend(args.cloned());
+                break;
+            }
+            "-f" | "--force" => options.overwrite = true,
+            "-v" | "--verbose" => verbose = true,
+            "-C" => {
+                destination = PathBuf::from(
+                    args.next()
+                        .ok_or_else(|| format!("-C needs a directory\n{}", USAGE))?,
+                )
+            }
+            _ if arg.len() > 1 && arg.starts_with('-') => {
+                return Err(format!("unknown option {}\n{}", arg, USAGE))
+            }
+            _ => files.push(arg.clone()),
+        }
+    }
+    let archive = match files.split_first() {
+        Some((archive, only)) => {
+            options.only = only.to_vec();
+            archive
+        }
+        None => return Err(format!("extract takes an archive\n{}", USAGE)),
+    };
+    let mut file = fs::File::open(archive).map_err(|x| format!("{}: {}", archive, x))?;
+    let extracted = archive::extract(&mut file, &destination, &options)
+        .map_err(|x| format!("{}: {}", archive, x))?;
+    if verbose {
+        for entry in &extracted {
+            eprintln!("{}", entry.path);
+        }
+    }
+    if extracted.is_empty() && !options.only.is_empty() {
+        return Err(format!("{}: nothing matched", archive));
+    }
+    Ok(())
+}
+
+// e.g. rw-r--r--
+fn permissions(mode: u32) -> String {
+    (0..9)
+        .map(|i| match mode & (0o400 >> i) {
+            0 => '-',
+            _ => ['r', 'w', 'x'][i % 3],
+        })
+        .collect()
+}
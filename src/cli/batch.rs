@@ -0,0 +1,212 @@
+use super::compress::{self, Report, SUFFIX};
+use super::Options;
+use std::fs;
+use std::io::{self, IsTerminal};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+
+// Works out the full list of inputs, expanding directories with -r, then
+// compresses or decompresses them on a bounded pool of threads. A failure
+// doesn't stop the batch; the run fails at the end if any file failed.
+//
+// Symbolic links are never followed or compressed, whether they are named on
+// the command line or found while recursing. They are skipped with a warning,
+// which doesn't count as a failure.
+
+enum Input {
+    Stdin,
+    File(PathBuf),
+}
+
+pub fn run(options: &Options, files: &[String]) -> Result<(), String> {
+    let mut inputs = vec![];
+    let mut failed = 0;
+    for file in files {
+        if file == "-" {
+            inputs.push(Input::Stdin);
+        } else if let Err(x) = expand(Path::new(file), options, true, &mut inputs) {
+            eprintln!("squash: {}", x);
+            failed += 1;
+        }
+    }
+
+    // summarise each file whenever there's more than one of them
+    let summarise = !options.quiet && (options.verbose || inputs.len() + failed > 1);
+    let mut total = Report {
+        input_size: 0,
+        output_size: 0,
+        output: None,
+    };
+    let mut done = 0;
+    for_each_result(options, &inputs, |input, result| match result {
+        Ok(Some(report)) => {
+            if let (true, Input::File(file)) = (summarise, input) {
+                eprintln!("{}", summary(file, &report, options));
+            }
+            total.input_size += report.input_size;
+            total.output_size += report.output_size;
+            done += 1;
+        }
+        Ok(None) => (),
+        Err(x) => {
+            eprintln!("squash: {}", x);
+            failed += 1;
+        }
+    });
+    if summarise && done > 1 {
+        eprintln!("{} files: {}", done, ratio(&total, options.decompress));
+    }
+    match failed {
+        0 => Ok(()),
+        1 => Err(String::from("1 file failed")),
+        n => Err(format!("{} files failed", n)),
+    }
+}
+
+// add a path to the inputs, walking into it if it's a directory and -r was given.
+// Anything named on the command line is passed on as is, so that it's reported if it
+// can't be processed; files found in directories are only taken if they have (or,
+// when compressing, don't have) the suffix
+fn expand(
+    path: &Path,
+    options: &Options,
+    named: bool,
+    inputs: &mut Vec<Input>,
+) -> Result<(), String> {
+    let metadata = fs::symlink_metadata(path).map_err(|x| format!("{}: {}", path.display(), x))?;
+    let file_type = metadata.file_type();
+    if file_type.is_symlink() {
+        eprintln!("squash: {}: symbolic link -- skipped", path.display());
+    } else if file_type.is_dir() {
+        if !options.recursive {
+            return Err(format!("{}: is a directory -- ignored", path.display()));
+        }
+        let mut entries: Vec<PathBuf> = fs::read_dir(path)
+            .and_then(|entries| entries.map(|e| e.map(|e| e.path())).collect())
+            .map_err(|x| format!("{}: {}", path.display(), x))?;
+        entries.sort();
+        let mut failure = None;
+        for entry in entries {
+            if let Err(x) = expand(&entry, options, false, inputs) {
+                eprintln!("squash: {}", x);
+                failure = Some(format!("{}: not everything could be read", path.display()));
+            }
+        }
+        if let Some(x) = failure {
+            return Err(x);
+        }
+    } else if named {
+        inputs.push(Input::File(path.to_path_buf()));
+    } else if !file_type.is_file() {
+        eprintln!("squash: {}: not a regular file -- skipped", path.display());
+    } else if path.to_string_lossy().ends_with(SUFFIX) == options.decompress {
+        inputs.push(Input::File(path.to_path_buf()));
+    }
+    Ok(())
+}
+
+// process every input, handing the results back in input order. Standard input and
+// output can't be shared, so anything using them runs on the calling thread
+fn for_each_result(
+    options: &Options,
+    inputs: &[Input],
+    mut handle: impl FnMut(&Input, Result<Option<Report>, String>),
+) {
+    let uses_stdio = options.stdout || inputs.iter().any(|i| matches!(i, Input::Stdin));
+    let jobs = if uses_stdio {
+        1
+    } else {
+        options
+            .jobs
+            .unwrap_or_else(|| thread::available_parallelism().map_or(1, |n| n.get()))
+            .min(inputs.len())
+    };
+    // one file at a time can have a progress bar to itself
+    if jobs <= 1 {
+        let progress = !options.quiet && io::stderr().is_terminal();
+        for input in inputs {
+            handle(input, process(input, options, progress));
+        }
+        return;
+    }
+
+    let next = AtomicUsize::new(0);
+    let (sender, receiver) = mpsc::channel();
+    thread::scope(|scope| {
+        for _ in 0..jobs {
+            let sender = sender.clone();
+            let next = &next;
+            scope.spawn(move || loop {
+                let index = next.fetch_add(1, Ordering::Relaxed);
+                match inputs.get(index) {
+                    Some(input) => {
+                        if sender
+                            .send((index, process(input, options, false)))
+                            .is_err()
+                        {
+                            return;
+                        }
+                    }
+                    None => return,
+                }
+            });
+        }
+        drop(sender);
+
+        // results arrive in any order, so hold on to them until it's their turn
+        let mut pending: Vec<Option<Result<Option<Report>, String>>> =
+            inputs.iter().map(|_| None).collect();
+        let mut reported = 0;
+        for (index, result) in receiver {
+            pending[index] = Some(result);
+            while let Some(result) = pending.get_mut(reported).and_then(Option::take) {
+                handle(&inputs[reported], result);
+                reported += 1;
+            }
+        }
+    });
+}
+
+fn process(input: &Input, options: &Options, progress: bool) -> Result<Option<Report>, String> {
+    match (input, options.decompress) {
+        (Input::Stdin, false) => compress::compress_stdin(options, progress).map(|()| None),
+        (Input::Stdin, true) => compress::decompress_stdin(options, progress).map(|()| None),
+        (Input::File(file), false) => compress::compress_file(file, options, progress).map(Some),
+        (Input::File(file), true) => compress::decompress_file(file, options, progress).map(Some),
+    }
+}
+
+// e.g. "notes.txt: 4096 -> 1207 bytes (70.5% saved), replaced with notes.txt.sq"
+fn summary(file: &Path, report: &Report, options: &Options) -> String {
+    let outcome = match (&report.output, options.keep) {
+        (None, _) => String::from("written to stdout"),
+        (Some(output), false) => format!("replaced with {}", output.display()),
+        (Some(output), true) => format!("created {}", output.display()),
+    };
+    format!(
+        "{}: {}, {}",
+        file.display(),
+        ratio(report, options.decompress),
+        outcome
+    )
+}
+
+// sizes before and after, with the space saved as a fraction of the uncompressed size
+fn ratio(report: &Report, decompress: bool) -> String {
+    let (compressed, uncompressed) = if decompress {
+        (report.input_size, report.output_size)
+    } else {
+        (report.output_size, report.input_size)
+    };
+    let saved = if uncompressed == 0 {
+        0.0
+    } else {
+        100.0 * (1.0 - compressed as f64 / uncompressed as f64)
+    };
+    format!(
+        "{} -> {} bytes ({:.1}% saved)",
+        report.input_size, report.output_size, saved
+    )
+}
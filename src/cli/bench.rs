@@ -0,0 +1,311 @@
+use super::info::{json_string, optional};
+use super::progress::human_size;
+use super::{corpus, parse_size, USAGE};
+use squash::squash_algorithm::*;
+use std::fs;
+use std::time::{Duration, Instant};
+
+const OPTION_SETS: [&str; 4] = ["default", "no-text", "dedup", "halving"];
+
+// counting allocations costs every command, not just bench, so the allocator is only
+// installed with the `bench` feature, and peak memory is only reported with it
+#[cfg(feature = "bench")]
+pub mod allocator {
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // heap bytes currently allocated, and the most allocated at once since the last
+    // reset
+    static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+    static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+    // the system allocator, keeping count of how much is allocated
+    pub struct CountingAllocator;
+
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            let ptr = System.alloc(layout);
+            if !ptr.is_null() {
+                grown(layout.size());
+            }
+            ptr
+        }
+
+        unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+            let ptr = System.alloc_zeroed(layout);
+            if !ptr.is_null() {
+                grown(layout.size());
+            }
+            ptr
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout);
+            ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        }
+
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            let new_ptr = System.realloc(ptr, layout, new_size);
+            if !new_ptr.is_null() {
+                if new_size > layout.size() {
+                    grown(new_size - layout.size());
+                } else {
+                    ALLOCATED.fetch_sub(layout.size() - new_size, Ordering::Relaxed);
+                }
+            }
+            new_ptr
+        }
+    }
+
+    fn grown(size: usize) {
+        let allocated = ALLOCATED.fetch_add(size, Ordering::Relaxed) + size;
+        PEAK.fetch_max(allocated, Ordering::Relaxed);
+    }
+
+    // start counting the peak afresh, returning what's allocated already
+    pub fn reset_peak() -> usize {
+        let base = ALLOCATED.load(Ordering::Relaxed);
+        PEAK.store(base, Ordering::Relaxed);
+        base
+    }
+
+    // the most allocated at once on top of `base` since the reset
+    pub fn peak_since(base: usize) -> usize {
+        PEAK.load(Ordering::Relaxed).saturating_sub(base)
+    }
+}
+
+// run a function, returning how long it took and, when allocations are counted, the
+// most heap it needed on top of what was already allocated
+fn measure<T>(f: impl FnOnce() -> T) -> (T, Duration, Option<usize>) {
+    #[cfg(feature = "bench")]
+    let base = allocator::reset_peak();
+    let start = Instant::now();
+    let result = f();
+    let time = start.elapsed();
+    #[cfg(feature = "bench")]
+    let peak = Some(allocator::peak_since(base));
+    #[cfg(not(feature = "bench"))]
+    let peak = None;
+    (result, time, peak)
+}
+
+struct Measurement {
+    input: String,
+    options: &'static str,
+    level: u8,
+    size: usize,
+    squashed: usize,
+    compress_time: Duration,
+    decompress_time: Duration,
+    compress_peak: Option<usize>,
+    decompress_peak: Option<usize>,
+}
+
+// `squash bench [--json] [--size=SIZE] [--corpus=NAME,...] [--options=SET,...]
+// [--levels=N,...] [--runs=N] [file...]`
+pub fn run(args: &[String]) -> Result<(), String> {
+    let mut json = false;
+    let mut size = 1 << 20;
+    let mut corpora: Vec<String> = corpus::CORPORA.iter().map(|c| String::from(*c)).collect();
+    let mut option_sets = vec![String::from("default")];
+    let mut levels = vec![DEFAULT_LEVEL, 9];
+    let mut runs = 1;
+    let mut files = vec![];
+    for arg in args {
+        let (name, value) = match arg.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (arg.as_str(), None),
+        };
+        match (name, value) {
+            ("--json", None) => json = true,
+            ("--size", Some(value)) => size = parse_size(value)? as usize,
+            ("--corpus", Some(value)) => corpora = list(value),
+            ("--options", Some(value)) => option_sets = list(value),
+            ("--levels", Some(value)) => {
+                levels = list(value)
+                    .iter()
+                    .map(|l| match l.parse::<u8>() {
+                        Ok(level @ 1..=9) => Ok(level),
+                        _ => Err(format!("bad level {}", l)),
+                    })
+                    .collect::<Result<_, _>>()?
+            }
+            ("--runs", Some(value)) => {
+                runs = value
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|n| *n > 0)
+                    .ok_or_else(|| format!("bad run count {}", value))?
+            }
+            _ if arg.starts_with('-') => return Err(format!("unknown option {}\n{}", arg, USAGE)),
+            _ => files.push(arg.clone()),
+        }
+    }
+    let option_sets: Vec<&'static str> = option_sets
+        .iter()
+        .map(|name| {
+            OPTION_SETS
+                .iter()
+                .find(|set| *set == name)
+                .copied()
+                .ok_or_else(|| format!("unknown option set {}", name))
+        })
+        .collect::<Result<_, _>>()?;
+
+    // named files replace the generated corpora
+    let mut inputs = vec![];
+    if files.is_empty() {
+        for name in &corpora {
+            let data =
+                corpus::generate(name, size).ok_or_else(|| format!("unknown corpus {}", name))?;
+            inputs.push((name.clone(), data));
+        }
+    } else {
+        for file in &files {
+            inputs.push((
+                file.clone(),
+                fs::read(file).map_err(|x| format!("{}: {}", file, x))?,
+            ));
+        }
+    }
+
+    if !json {
+        println!(
+            "{:<16} {:<8} {:>5} {:>10} {:>10} {:>7} {:>9} {:>9} {:>10} {:>10}",
+            "input",
+            "options",
+            "level",
+            "size",
+            "squashed",
+            "ratio",
+            "comp MB/s",
+            "dec MB/s",
+            "comp peak",
+            "dec peak"
+        );
+    }
+    for (name, data) in &inputs {
+        for set in &option_sets {
+            for level in &levels {
+                let result = bench(name, data, set, *level, runs)?;
+                if json {
+                    println!("{}", to_json(&result));
+                } else {
+                    println!("{}", to_text(&result));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn list(value: &str) -> Vec<String> {
+    value.split(',').map(String::from).collect()
+}
+
+fn squash_options(set: &str, level: u8) -> SquashOptions {
+    let mut options = SquashOptions::default_options();
+    options.level = level;
+    match set {
+        "no-text" => options.text_preprocessing = false,
+        "dedup" => options.dedup_window = Some(DEFAULT_DEDUP_WINDOW),
+        "halving" => options.model = Model::Halving,
+        _ => (),
+    }
+    options
+}
+
+// compress and decompress some data, keeping the fastest of the runs. The output
+// buffers are allocated up front so that the peaks are the compressor's own memory
+fn bench(
+    name: &str,
+    data: &[u8],
+    set: &'static str,
+    level: u8,
+    runs: usize,
+) -> Result<Measurement, String> {
+    let options = squash_options(set, level);
+    let mut result = Measurement {
+        input: String::from(name),
+        options: set,
+        level,
+        size: data.len(),
+        squashed: 0,
+        compress_time: Duration::MAX,
+        decompress_time: Duration::MAX,
+        compress_peak: None,
+        decompress_peak: None,
+    };
+    for _ in 0..runs {
+        let mut squashed = Vec::with_capacity(data.len() + data.len() / 16 + (1 << 20));
+        let (outcome, time, peak) =
+            measure(|| squash_with_options(&mut &data[..], &mut squashed, &options));
+        outcome.map_err(|x| format!("{}: {}", name, x))?;
+        result.squashed = squashed.len();
+        result.compress_time = result.compress_time.min(time);
+        result.compress_peak = result.compress_peak.max(peak);
+
+        let mut unsquashed = Vec::with_capacity(data.len());
+        let (outcome, time, peak) = measure(|| unsquash(&mut &squashed[..], &mut unsquashed));
+        outcome.map_err(|x| format!("{}: {}", name, x))?;
+        if unsquashed != data {
+            return Err(format!(
+                "{}: round trip failed with {} options at level {}",
+                name, set, level
+            ));
+        }
+        result.decompress_time = result.decompress_time.min(time);
+        result.decompress_peak = result.decompress_peak.max(peak);
+    }
+    Ok(result)
+}
+
+// throughput in megabytes of original data per second
+fn megabytes_per_second(size: usize, time: Duration) -> f64 {
+    size as f64 / 1e6 / time.as_secs_f64().max(1e-9)
+}
+
+fn ratio(result: &Measurement) -> f64 {
+    if result.size == 0 {
+        0.0
+    } else {
+        result.squashed as f64 / result.size as f64
+    }
+}
+
+fn to_text(result: &Measurement) -> String {
+    format!(
+        "{:<16} {:<8} {:>5} {:>10} {:>10} {:>7.3} {:>9.2} {:>9.2} {:>10} {:>10}",
+        result.input,
+        result.options,
+        result.level,
+        result.size,
+        result.squashed,
+        ratio(result),
+        megabytes_per_second(result.size, result.compress_time),
+        megabytes_per_second(result.size, result.decompress_time),
+        peak_size(result.compress_peak),
+        peak_size(result.decompress_peak)
+    )
+}
+
+fn peak_size(peak: Option<usize>) -> String {
+    peak.map_or(String::from("-"), |p| human_size(p as u64))
+}
+
+fn to_json(result: &Measurement) -> String {
+    format!(
+        "{{\"input\":{},\"options\":\"{}\",\"level\":{},\"size\":{},\"squashed\":{},\"ratio\":{:.4},\"compress_mb_s\":{:.3},\"decompress_mb_s\":{:.3},\"compress_peak\":{},\"decompress_peak\":{}}}",
+        json_string(&result.input),
+        result.options,
+        result.level,
+        result.size,
+        result.squashed,
+        ratio(result),
+        megabytes_per_second(result.size, result.compress_time),
+        megabytes_per_second(result.size, result.decompress_time),
+        optional(result.compress_peak),
+        optional(result.decompress_peak)
+    )
+}
@@ -0,0 +1,374 @@
+use super::password::PasswordSource;
+use super::progress::Progress;
+use super::{Options, USAGE};
+use squash::archive::CountingWriter;
+use squash::squash_algorithm::*;
+use std::fs;
+use std::io::{self, IsTerminal, Seek, Write};
+use std::path::{Path, PathBuf};
+
+pub const SUFFIX: &str = ".sq";
+
+// what became of one input file
+pub struct Report {
+    pub input_size: u64,
+    pub output_size: u64,
+    // where the output went, or None for standard output
+    pub output: Option<PathBuf>,
+}
+
+// each of these shows a progress bar on standard error if `progress` is set
+pub fn compress_stdin(options: &Options, progress: bool) -> Result<(), String> {
+    if io::stdout().is_terminal() && !options.force {
+        return Err(String::from(
+            "compressed data not written to a terminal (use -f to force)",
+        ));
+    }
+    let mut writer = io::BufWriter::new(io::stdout().lock());
+    squash_with_observer(
+        &mut io::stdin().lock(),
+        &mut writer,
+        &options.squash_options(),
+        &mut Progress::new("stdin", None, progress),
+    )
+    .and_then(|()| writer.flush())
+    .map_err(|x| format!("stdin: {}", x))
+}
+
+pub fn decompress_stdin(options: &Options, progress: bool) -> Result<(), String> {
+    let mut writer = io::BufWriter::new(io::stdout().lock());
+    let mut progress = Progress::new("stdin", None, progress);
+    unsquash_with_observer(
+        &mut io::stdin().lock(),
+        &mut writer,
+        &options.decode_limits(),
+        &mut progress,
+    )
+    .and_then(|()| writer.flush())
+    .map_err(|x| format!("stdin: {}", x))
+}
+
+pub fn compress_file(input: &Path, options: &Options, progress: bool) -> Result<Report, String> {
+    let name = input.display();
+    let input_size = check_regular_file(input)?;
+    if !options.stdout && input.to_string_lossy().ends_with(SUFFIX) {
+        return Err(format!(
+            "{}: already has {} suffix -- unchanged",
+            name, SUFFIX
+        ));
+    }
+    let mut squash_options = options.squash_options();
+    squash_options.metadata =
+        Some(Metadata::from_file(input).map_err(|x| format!("{}: {}", name, x))?);
+    let mut input_file = fs::File::open(input).map_err(|x| format!("{}: {}", name, x))?;
+    let mut progress = Progress::new(&name.to_string(), Some(input_size), progress);
+
+    if options.stdout {
+        let mut writer = CountingWriter::new(io::BufWriter::new(io::stdout().lock()));
+        squash_with_observer(&mut input_file, &mut writer, &squash_options, &mut progress)
+            .and_then(|()| writer.flush())
+            .map_err(|x| format!("{}: {}", name, x))?;
+        return Ok(Report {
+            input_size,
+            output_size: writer.count,
+            output: None,
+        });
+    }
+
+    let mut output = input.as_os_str().to_owned();
+    output.push(SUFFIX);
+    let output = PathBuf::from(output);
+    let (_, output_size) = write_output(&output, options.force, |writer| {
+        squash_with_observer(&mut input_file, writer, &squash_options, &mut progress)
+    })?;
+    if !options.keep {
+        fs::remove_file(input).map_err(|x| format!("{}: {}", name, x))?;
+    }
+    Ok(Report {
+        input_size,
+        output_size,
+        output: Some(output),
+    })
+}
+
+pub fn decompress_file(input: &Path, options: &Options, progress: bool) -> Result<Report, String> {
+    let name = input.display();
+    let input_size = check_regular_file(input)?;
+    if !options.stdout && !input.to_string_lossy().ends_with(SUFFIX) {
+        return Err(format!("{}: unknown suffix -- ignored", name));
+    }
+    let mut input_file = fs::File::open(input).map_err(|x| format!("{}: {}", name, x))?;
+    let metadata = read_metadata(&mut input_file)
+        .and_then(|m| {
+            input_file.rewind()?;
+            Ok(m)
+        })
+        .map_err(|x| format!("{}: {}", name, x))?;
+    let mut progress = Progress::new(&name.to_string(), Some(input_size), progress);
+
+    if options.stdout {
+        let mut writer = CountingWriter::new(io::BufWriter::new(io::stdout().lock()));
+        unsquash_with_observer(
+            &mut input_file,
+            &mut writer,
+            &options.decode_limits(),
+            &mut progress,
+        )
+        .and_then(|()| writer.flush())
+        .map_err(|x| format!("{}: {}", name, x))?;
+        return Ok(Report {
+            input_size,
+            output_size: writer.count,
+            output: None,
+        });
+    }
+
+    let stored = if options.use_stored_name {
+        stored_output_path(input, metadata.as_ref())
+    } else {
+        None
+    };
+    let output = match stored {
+        Some(output) => output,
+        None => match input.to_str().and_then(|i| i.strip_suffix(SUFFIX)) {
+            Some(stripped) if !stripped.is_empty() => PathBuf::from(stripped),
+            _ => return Err(format!("{}: unknown suffix -- ignored", name)),
+        },
+    };
+    check_not_input(input, &output)?;
+    let (output_file, output_size) = write_output(&output, options.force, |writer| {
+        unsquash_with_observer(
+            &mut input_file,
+            writer,
+            &options.decode_limits(),
+            &mut progress,
+        )
+    })?;
+    if let Some(metadata) = metadata {
+        metadata
+            .restore(&output_file)
+            .map_err(|x| format!("{}: unable to restore metadata: {}", output.display(), x))?;
+    }
+    if !options.keep {
+        fs::remove_file(input).map_err(|x| format!("{}: {}", name, x))?;
+    }
+    Ok(Report {
+        input_size,
+        output_size,
+        output: Some(output),
+    })
+}
+
+// `squash enc [--append] [--encrypt] [--password-fd=N] <input> <output>`
+pub fn enc(args: &[String]) -> Result<(), String> {
+    let (mut password, args) = PasswordSource::from_args(args)?;
+    let mut append = false;
+    let mut encrypt = false;
+    let mut files = vec![];
+    for arg in args {
+        match arg.as_str() {
+            "--append" => append = true,
+            "--encrypt" => encrypt = true,
+            _ if arg.starts_with('-') => return Err(format!("unknown option {}\n{}", arg, USAGE)),
+            _ => files.push(arg),
+        }
+    }
+    let [input, output] = files[..] else {
+        return Err(format!("enc takes an input and an output\n{}", USAGE));
+    };
+    if append && encrypt {
+        return Err(String::from("encrypted files can't be appended to"));
+    }
+    if password.is_from_fd() && !encrypt {
+        return Err(format!("--password-fd is for --encrypt\n{}", USAGE));
+    }
+    check_not_input(Path::new(input), Path::new(output))?;
+    if append {
+        return append_to(input, output);
+    }
+    let encryption = if encrypt {
+        Some(Encryption::new(password.get(true)?))
+    } else {
+        None
+    };
+    squash_to(input, output, encryption)
+}
+
+fn squash_to(input: &str, output: &str, encryption: Option<Encryption>) -> Result<(), String> {
+    let options = SquashOptions {
+        metadata: Metadata::from_file(Path::new(input)).ok(),
+        encryption,
+        ..SquashOptions::default_options()
+    };
+    let mut input_file = fs::File::open(input).map_err(|x| format!("{}: {}", input, x))?;
+    write_output(Path::new(output), true, |writer| {
+        squash_with_options(&mut input_file, writer, &options)
+    })?;
+    Ok(())
+}
+
+// `squash enc --append <input> <output>`, which starts a new output if there isn't one
+fn append_to(input: &str, output: &str) -> Result<(), String> {
+    if !Path::new(output).exists() {
+        return squash_to(input, output, None);
+    }
+    let mut input_file = fs::File::open(input).map_err(|x| format!("{}: {}", input, x))?;
+    let output_file = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(output)
+        .map_err(|x| format!("{}: {}", output, x))?;
+    let mut writer = SquashWriter::append_to(output_file, &SquashOptions::default_options())
+        .map_err(|x| format!("{}: {}", output, x))?;
+    io::copy(&mut input_file, &mut writer)
+        .and_then(|_| writer.finish())
+        .map_err(|x| format!("{}: {}", output, x))?;
+    Ok(())
+}
+
+// `squash dec [-f] [--password-fd=N] <input> [output]`, which falls back on the stored
+// name, and asks for the password if the input is encrypted
+pub fn dec(args: &[String]) -> Result<(), String> {
+    let (mut password, args) = PasswordSource::from_args(args)?;
+    let mut force = false;
+    let mut files = vec![];
+    for arg in args {
+        match arg.as_str() {
+            "-f" | "--force" => force = true,
+            _ if arg.starts_with('-') => return Err(format!("unknown option {}\n{}", arg, USAGE)),
+            _ => files.push(arg),
+        }
+    }
+    let (input, output) = match files[..] {
+        [input] => (input, None),
+        [input, output] => (input, Some(output)),
+        _ => {
+            return Err(format!(
+                "dec takes an input and an optional output\n{}",
+                USAGE
+            ))
+        }
+    };
+    let mut input_file = fs::File::open(input).map_err(|x| format!("{}: {}", input, x))?;
+    let (metadata, encrypted) = read_metadata(&mut input_file)
+        .and_then(|m| {
+            input_file.rewind()?;
+            let encrypted = is_encrypted(&mut input_file)?;
+            input_file.rewind()?;
+            Ok((m, encrypted))
+        })
+        .map_err(|x| format!("{}: {}", input, x))?;
+    let password = if encrypted {
+        Some(password.get(false)?)
+    } else {
+        None
+    };
+    // an output that's named is overwritten, as it always was, but the stored name
+    // comes from the stream, so what's there already is only overwritten with -f
+    let (output, force) = match output {
+        Some(output) => (PathBuf::from(output), true),
+        None => (
+            stored_output_path(Path::new(input), metadata.as_ref())
+                .ok_or_else(|| format!("{}: no output path given, and none stored", input))?,
+            force,
+        ),
+    };
+    check_not_input(Path::new(input), &output)?;
+    let limits = DecodeLimits::default_limits();
+    let (output_file, _) = write_output(&output, force, |writer| match &password {
+        Some(password) => unsquash_with_password(&mut input_file, writer, &limits, password),
+        None => unsquash_with_limits(&mut input_file, writer, &limits),
+    })?;
+    if let Some(metadata) = metadata {
+        metadata
+            .restore(&output_file)
+            .map_err(|x| format!("{}: unable to restore metadata: {}", output.display(), x))?;
+    }
+    Ok(())
+}
+
+// check the input is a regular file, returning its size
+fn check_regular_file(input: &Path) -> Result<u64, String> {
+    let metadata = fs::metadata(input).map_err(|x| format!("{}: {}", input.display(), x))?;
+    if !metadata.is_file() {
+        return Err(format!(
+            "{}: not a regular file -- ignored",
+            input.display()
+        ));
+    }
+    Ok(metadata.len())
+}
+
+// refuse to write the output over the input, which would destroy it before it's read
+fn check_not_input(input: &Path, output: &Path) -> Result<(), String> {
+    if same_file(input, output) {
+        return Err(format!(
+            "{}: the output would overwrite the input -- ignored",
+            input.display()
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn same_file(a: &Path, b: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match (fs::metadata(a), fs::metadata(b)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+
+#[cfg(not(unix))]
+fn same_file(a: &Path, b: &Path) -> bool {
+    match (fs::canonicalize(a), fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+// create an output file and fill it, removing it again if anything goes wrong.
+// Returns the file along with the number of bytes written to it
+pub fn write_output(
+    output: &Path,
+    force: bool,
+    fill: impl FnOnce(&mut dyn Write) -> io::Result<()>,
+) -> Result<(fs::File, u64), String> {
+    let name = output.display();
+    let file = fs::OpenOptions::new()
+        .write(true)
+        .create(force)
+        .truncate(force)
+        .create_new(!force)
+        .open(output)
+        .map_err(|x| match x.kind() {
+            io::ErrorKind::AlreadyExists => {
+                format!("{}: already exists (use -f to overwrite)", name)
+            }
+            _ => format!("{}: {}", name, x),
+        })?;
+    let mut writer = CountingWriter::new(io::BufWriter::new(file));
+    let result = fill(&mut writer).and_then(|()| writer.flush());
+    match result {
+        Ok(()) => {
+            let count = writer.count;
+            let file = writer.inner.into_inner().map_err(|x| x.to_string())?;
+            Ok((file, count))
+        }
+        Err(x) => {
+            drop(writer);
+            let _ = fs::remove_file(output);
+            Err(format!("{}: {}", name, x))
+        }
+    }
+}
+
+// where to decompress to using the stored name, next to the input.
+// Only a bare file name is accepted, so a stream can't direct its output elsewhere
+fn stored_output_path(input: &Path, metadata: Option<&Metadata>) -> Option<PathBuf> {
+    let name = Path::new(metadata?.name.as_ref()?);
+    if name.file_name()? != name.as_os_str() {
+        return None;
+    }
+    Some(input.with_file_name(name))
+}
@@ -0,0 +1,117 @@
+// deterministic synthetic data for benchmarking, so that runs on different builds
+// compress exactly the same bytes
+
+pub const CORPORA: [&str; 5] = ["text", "logs", "table", "random", "runs"];
+
+const WORDS: &str = "\
+    the of and to a in is it that was for on are with as they be at one have this \
+    from by hot word but what some we can out other were all there when up use your \
+    how said each which their time will way about many then them write would like \
+    these long make thing see him two look more compression";
+
+const LEVELS: [&str; 4] = ["INFO", "INFO", "WARN", "ERROR"];
+const PATHS: [&str; 6] = [
+    "/",
+    "/index.html",
+    "/api/v1/users",
+    "/api/v1/orders",
+    "/static/app.js",
+    "/login",
+];
+const STATUSES: [u16; 5] = [200, 200, 304, 404, 500];
+
+// xorshift64*, which is plenty for making test data
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        Rng(seed | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() >> 32) as usize % n
+    }
+
+    // small numbers much more often than big ones, roughly like word frequencies
+    fn skewed(&mut self, n: usize) -> usize {
+        let x = self.below(n);
+        self.below(x + 1)
+    }
+}
+
+// `size` bytes of the named corpus, or None if there's no such corpus
+pub fn generate(name: &str, size: usize) -> Option<Vec<u8>> {
+    let mut rng = Rng::new(0x5eed_0000 + CORPORA.iter().position(|c| *c == name)? as u64);
+    let words: Vec<&str> = WORDS.split_whitespace().collect();
+    let mut out = Vec::with_capacity(size + 256);
+    while out.len() < size {
+        match name {
+            "text" => sentence(&mut rng, &words, &mut out),
+            "logs" => log_line(&mut rng, out.len(), &mut out),
+            "table" => record(&mut rng, out.len(), &mut out),
+            "random" => out.extend_from_slice(&rng.next().to_le_bytes()),
+            _ => {
+                let byte = rng.below(256) as u8;
+                let length = 1 + rng.skewed(200);
+                out.resize(out.len() + length, byte);
+            }
+        }
+    }
+    out.truncate(size);
+    Some(out)
+}
+
+// a capitalised sentence of common words, sometimes ending a paragraph
+fn sentence(rng: &mut Rng, words: &[&str], out: &mut Vec<u8>) {
+    let length = 4 + rng.below(14);
+    for i in 0..length {
+        let word = words[rng.skewed(words.len())].as_bytes();
+        if i == 0 {
+            out.push(word[0].to_ascii_uppercase());
+            out.extend_from_slice(&word[1..]);
+        } else {
+            out.push(b' ');
+            out.extend_from_slice(word);
+            if i + 1 < length && rng.below(8) == 0 {
+                out.push(b',');
+            }
+        }
+    }
+    out.extend_from_slice(if rng.below(6) == 0 { b".\n\n" } else { b". " });
+}
+
+// an access log line, with timestamps that creep forward
+fn log_line(rng: &mut Rng, position: usize, out: &mut Vec<u8>) {
+    let seconds = 1_600_000_000 + position / 90;
+    let line = format!(
+        "{} {:<5} 10.0.{}.{} \"GET {}\" {} {} {}ms\n",
+        seconds,
+        LEVELS[rng.below(LEVELS.len())],
+        rng.below(4),
+        rng.below(256),
+        PATHS[rng.skewed(PATHS.len())],
+        STATUSES[rng.skewed(STATUSES.len())],
+        rng.below(50_000),
+        rng.skewed(2_000)
+    );
+    out.extend_from_slice(line.as_bytes());
+}
+
+// a fixed-size little-endian record: a sequential id, a category, a flag, a
+// measurement and a reserved zero field
+fn record(rng: &mut Rng, position: usize, out: &mut Vec<u8>) {
+    let id = (position / 20) as u32;
+    out.extend_from_slice(&id.to_le_bytes());
+    out.extend_from_slice(&(rng.skewed(16) as u16).to_le_bytes());
+    out.extend_from_slice(&(rng.below(2) as u16).to_le_bytes());
+    let measurement = 20.0 + (id as f64 / 100.0).sin() * 5.0 + rng.below(100) as f64 / 100.0;
+    out.extend_from_slice(&measurement.to_le_bytes());
+    out.extend_from_slice(&[0; 4]);
+}
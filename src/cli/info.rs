@@ -0,0 +1,318 @@
+use super::USAGE;
+use squash::squash_algorithm::*;
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::time::UNIX_EPOCH;
+
+// `squash info [--json] [--decode] <file...>`
+pub fn run(args: &[String]) -> Result<(), String> {
+    let mut json = false;
+    let mut decode = false;
+    let mut files = vec![];
+    for arg in args {
+        match arg.as_str() {
+            "--json" => json = true,
+            "--decode" => decode = true,
+            "-" => files.push(arg),
+            _ if arg.starts_with('-') => return Err(format!("unknown option {}\n{}", arg, USAGE)),
+            _ => files.push(arg),
+        }
+    }
+    if files.is_empty() {
+        return Err(format!("info takes the files to describe\n{}", USAGE));
+    }
+
+    let mut failed = false;
+    for file in files {
+        let info = if file == "-" {
+            inspect(&mut io::stdin().lock(), decode)
+        } else {
+            fs::File::open(file)
+                .map(io::BufReader::new)
+                .and_then(|mut reader| inspect(&mut reader, decode))
+        };
+        match info {
+            Ok(info) if json => println!("{}", to_json(file, &info)),
+            Ok(info) => print!("{}", to_text(file, &info)),
+            Err(x) => {
+                eprintln!("squash: {}: {}", file, x);
+                failed = true;
+            }
+        }
+    }
+    if failed {
+        Err(String::new())
+    } else {
+        Ok(())
+    }
+}
+
+fn model_name(model: Model) -> &'static str {
+    match model {
+        Model::Window => "window",
+        Model::Halving => "halving",
+    }
+}
+
+fn block_type_name(block_type: BlockType) -> &'static str {
+    match block_type {
+        BlockType::Full => "full",
+        BlockType::Stored => "stored",
+        BlockType::Order0 => "order-0",
+        BlockType::Encrypted => "encrypted",
+    }
+}
+
+// the decoded size of the stream, if every block was decoded
+fn decoded_size(info: &StreamInfo) -> Option<u64> {
+    info.blocks
+        .iter()
+        .map(|b| b.decoded_size.map(|s| s as u64))
+        .sum()
+}
+
+// compressed size as a percentage of the decoded size
+fn ratio(compressed: u64, decoded: u64) -> String {
+    if decoded == 0 {
+        String::from("-")
+    } else {
+        format!("{:.1}%", 100.0 * compressed as f64 / decoded as f64)
+    }
+}
+
+fn describe_encoder(encoder: &ArithmeticEncoder) -> String {
+    format!(
+        "memory {}, padding {}, recalculation every {}, {} model",
+        encoder.frequency_memory,
+        encoder.frequency_padding,
+        encoder.recalculation_frequency,
+        model_name(encoder.model)
+    )
+}
+
+fn to_text(file: &str, info: &StreamInfo) -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "{}:", file);
+    let _ = writeln!(out, "  format version    {}", info.version);
+    let _ = writeln!(
+        out,
+        "  arithmetic coder  {}",
+        describe_encoder(&info.arithmetic_encoder)
+    );
+    let _ = writeln!(out, "  block size        {} bytes", info.block_size);
+    if info.dictionary_words > 0 {
+        let _ = writeln!(out, "  text dictionary   {} words", info.dictionary_words);
+    }
+    if let Some(window) = info.dedup_window {
+        let _ = writeln!(out, "  dedup window      {} bytes", window);
+    }
+    if let Some(percent) = info.parity {
+        let bytes: u64 = info.parity_shards.iter().map(|&s| u64::from(s)).sum();
+        let _ = writeln!(
+            out,
+            "  parity            {}%, {} shard{} of {} bytes",
+            percent,
+            info.parity_shards.len(),
+            if info.parity_shards.len() == 1 {
+                ""
+            } else {
+                "s"
+            },
+            bytes
+        );
+    }
+    if let Some(kdf) = &info.encryption {
+        let _ = writeln!(
+            out,
+            "  encryption        ChaCha20-Poly1305, key from scrypt N=2^{} r={} p={}",
+            kdf.log_n, kdf.r, kdf.p
+        );
+    }
+    if let Some(metadata) = &info.metadata {
+        if let Some(name) = &metadata.name {
+            let _ = writeln!(out, "  original name     {}", name);
+        }
+        if let Some(size) = metadata.size {
+            let _ = writeln!(out, "  original size     {} bytes", size);
+        }
+    }
+    let _ = writeln!(out, "  header            {} bytes", info.header_length);
+    let _ = writeln!(out, "  blocks            {}", info.blocks.len());
+    match &info.trailer {
+        Some(trailer) => {
+            let _ = writeln!(
+                out,
+                "  trailer           {} blocks, {} bytes decoded",
+                trailer.blocks, trailer.length
+            );
+        }
+        None => {
+            let _ = writeln!(out, "  trailer           missing (cut off or cancelled)");
+        }
+    }
+    if info.blocks.is_empty() {
+        return out;
+    }
+
+    let _ = writeln!(
+        out,
+        "  {:>5}  {:>8}  {:<9}  {:<16}  {:>8}  {:>9}  {:>8}  {:>6}",
+        "block", "length", "type", "flags", "symbols", "end index", "decoded", "ratio"
+    );
+    for (i, block) in info.blocks.iter().enumerate() {
+        let mut flags = vec![];
+        if block.text {
+            flags.push(String::from("text"));
+        }
+        if let Some(ops) = block.dedup_ops {
+            flags.push(format!("dedup({})", ops));
+        }
+        if block.arithmetic_encoder.is_some() {
+            flags.push(String::from("config"));
+        }
+        let (symbols, end_index) = match &block.front_matter {
+            Some(front_matter) => (
+                front_matter.length.to_string(),
+                front_matter.end_index.to_string(),
+            ),
+            None => (String::from("-"), String::from("-")),
+        };
+        let (decoded, ratio) = match block.decoded_size {
+            Some(size) => (
+                size.to_string(),
+                ratio(u64::from(block.length), size as u64),
+            ),
+            None => (String::from("-"), String::from("-")),
+        };
+        let _ = writeln!(
+            out,
+            "  {:>5}  {:>8}  {:<9}  {:<16}  {:>8}  {:>9}  {:>8}  {:>6}",
+            i,
+            block.length,
+            block_type_name(block.block_type),
+            flags.join(","),
+            symbols,
+            end_index,
+            decoded,
+            ratio
+        );
+        if let Some(encoder) = &block.arithmetic_encoder {
+            let _ = writeln!(out, "         config: {}", describe_encoder(encoder));
+        }
+    }
+    let compressed = info.stored_size();
+    match decoded_size(info) {
+        Some(decoded) => {
+            let _ = writeln!(
+                out,
+                "  total             {} bytes, {} decoded ({})",
+                compressed,
+                decoded,
+                ratio(compressed, decoded)
+            );
+        }
+        None => {
+            let _ = writeln!(out, "  total             {} bytes", compressed);
+        }
+    }
+    out
+}
+
+fn encoder_json(encoder: &ArithmeticEncoder) -> String {
+    format!(
+        "{{\"frequency_memory\":{},\"frequency_padding\":{},\"recalculation_frequency\":{},\"model\":\"{}\"}}",
+        encoder.frequency_memory,
+        encoder.frequency_padding,
+        encoder.recalculation_frequency,
+        model_name(encoder.model)
+    )
+}
+
+fn metadata_json(metadata: &Metadata) -> String {
+    let mtime = metadata
+        .mtime
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs());
+    format!(
+        "{{\"name\":{},\"size\":{},\"mtime\":{},\"mode\":{}}}",
+        optional(metadata.name.as_deref().map(json_string)),
+        optional(metadata.size),
+        optional(mtime),
+        optional(metadata.mode)
+    )
+}
+
+fn to_json(file: &str, info: &StreamInfo) -> String {
+    let blocks: Vec<String> = info
+        .blocks
+        .iter()
+        .map(|block| {
+            format!(
+                "{{\"length\":{},\"type\":\"{}\",\"text\":{},\"dedup_ops\":{},\"arithmetic_encoder\":{},\"front_matter\":{},\"decoded_size\":{}}}",
+                block.length,
+                block_type_name(block.block_type),
+                block.text,
+                optional(block.dedup_ops),
+                optional(block.arithmetic_encoder.as_ref().map(encoder_json)),
+                optional(block.front_matter.map(|f| format!(
+                    "{{\"length\":{},\"end_index\":{}}}",
+                    f.length, f.end_index
+                ))),
+                optional(block.decoded_size)
+            )
+        })
+        .collect();
+    format!(
+        "{{\"file\":{},\"version\":{},\"arithmetic_encoder\":{},\"block_size\":{},\"dictionary_words\":{},\"dedup_window\":{},\"parity\":{},\"parity_shards\":[{}],\"encryption\":{},\"metadata\":{},\"header_length\":{},\"compressed_size\":{},\"decoded_size\":{},\"trailer\":{},\"blocks\":[{}]}}",
+        json_string(file),
+        info.version,
+        encoder_json(&info.arithmetic_encoder),
+        info.block_size,
+        info.dictionary_words,
+        optional(info.dedup_window),
+        optional(info.parity),
+        info.parity_shards
+            .iter()
+            .map(u32::to_string)
+            .collect::<Vec<_>>()
+            .join(","),
+        optional(info.encryption.map(|kdf| format!(
+            "{{\"kdf\":\"scrypt\",\"log_n\":{},\"r\":{},\"p\":{},\"cipher\":\"chacha20-poly1305\"}}",
+            kdf.log_n, kdf.r, kdf.p
+        ))),
+        optional(info.metadata.as_ref().map(metadata_json)),
+        info.header_length,
+        info.stored_size(),
+        optional(decoded_size(info)),
+        optional(info.trailer.map(|t| format!(
+            "{{\"length\":{},\"blocks\":{}}}",
+            t.length, t.blocks
+        ))),
+        blocks.join(",")
+    )
+}
+
+// a json value, or null
+pub fn optional(value: Option<impl ToString>) -> String {
+    value.map_or_else(|| String::from("null"), |v| v.to_string())
+}
+
+pub fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
@@ -0,0 +1,309 @@
+mod analyze;
+mod archive;
+mod batch;
+pub mod bench;
+mod compress;
+mod corpus;
+mod info;
+mod password;
+mod progress;
+mod recover;
+mod repair;
+mod tar;
+
+use squash::squash_algorithm::*;
+
+const USAGE: &str = "\
+usage: squash [options] [file...]
+       squash enc [--append] [--encrypt] [--password-fd=N] <input> <output>
+       squash dec [-f] [--password-fd=N] <input> [output]
+       squash create [options] [--solid] <archive.sqa> <path...>
+       squash list <archive.sqa>
+       squash extract [-f] [-v] [-C dir] <archive.sqa> [path...]
+       squash tar -c [-1 ... -9] [-f archive.tar.sq] <path...>
+       squash tar -x [-v] [--overwrite] [-f archive.tar.sq] [-C dir]
+       squash info [--json] [--decode] <file.sq...>
+       squash test [--bitflip[=SECONDS]] [--password-fd=N] <file.sq...>
+       squash repair [-f] [--bitflip[=SECONDS]] [--password-fd=N] <damaged.sq> <output>
+       squash recover [-f] <damaged.sq> <output>
+       squash analyze [--json] [--no-text] [--model=MODEL] <file...>
+       squash bench [--json] [--size=SIZE] [--corpus=NAME,...] [--options=SET,...]
+                    [--levels=N,...] [--runs=N] [file...]
+
+Compresses each file to file.sq, removing the original. With no files, or
+with a file of -, compresses standard input to standard output. Several files
+are processed in parallel, and a failure doesn't stop the rest. Symbolic links
+are always skipped.
+
+create puts files and directories in an archive, each file squashed on its own
+so that it can be extracted without the rest, or with --solid all squashed
+together, which suits lots of small files. extract restores everything below
+the current directory, or -C dir, or only the paths given.
+
+tar -c writes a squashed tar of the paths given, and tar -x unpacks one, to and
+from standard output and input unless -f names the archive. Only files and
+directories are unpacked, and never outside the destination.
+
+enc --append adds the input to the end of an existing squashed file, as new
+blocks after the ones already there, without recompressing them.
+
+enc --encrypt encrypts every block with ChaCha20-Poly1305, under a key derived
+from a password with scrypt. The password is asked for on the terminal, twice,
+or read from the first line of file descriptor N with --password-fd=N, and dec,
+test and repair get it the same way for an encrypted file. The header, which holds the
+original file's name, is authenticated but not encrypted.
+
+info describes each block of a squashed file, decoding them to find their sizes
+with --decode. --json prints one object per file instead.
+
+test checks that every block of a squashed file decodes to its checksum, and
+whether any damage can be repaired from the parity that compressing with
+--parity adds. repair rebuilds the damaged blocks from it, writing out the
+file just as it was squashed. With --bitflip, a damaged block that parity can't
+rebuild is searched for a single flipped bit, trying each bit in turn on every
+CPU for up to SECONDS in all (default 60), and the bits found are reported.
+
+recover salvages what it can of a damaged squashed file: every block that still
+decodes to its checksum is written to the output, and the damaged ranges that
+were skipped are reported.
+
+analyze runs each block of a file through every stage of the compressor and
+reports the entropy after each one, the share of zero ranks after the
+move-to-front, how much run-length encoding shrinks it, how close the
+arithmetic coder comes to the entropy, and the time each stage takes.
+
+bench compresses and decompresses each file given, or else generated text,
+logs, table, random and runs corpora (of 1M each unless --size says otherwise),
+with each option set (default, no-text, dedup or halving) and level, reporting
+the ratio and speed of each, and peak memory in builds with the bench feature.
+The corpora are the same on every run, so builds can be compared; --json prints
+one object per line.
+
+options:
+  -d, --decompress   decompress instead
+  -c, --stdout       write to standard output and keep the input files
+  -k, --keep         keep the input files
+  -f, --force        overwrite existing output files
+  -N, --name         when decompressing, use the file name stored in the header
+      --strict       when decompressing, fail on trailing data after the last
+                     stream instead of ignoring it
+  -r, --recursive    process the files in any directories given, and below them
+  -j, --jobs=N       process up to N files at once (default: one per CPU)
+  -v, --verbose      report the size of each file, even when there's only one
+  -q, --quiet        never report sizes or show progress
+  -1 ... -9          compression level (default 6); 7 and up search for the
+                     best arithmetic coder settings per block
+      --dedup[=SIZE] deduplicate repeats within SIZE bytes (default and most 256M)
+      --no-text      don't preprocess blocks that look like text
+      --model=MODEL  adapt the arithmetic coder with `window` or `halving`
+      --parity[=PCT] add Reed-Solomon parity of PCT percent (default 5) so that
+                     damaged blocks can be repaired
+  -h, --help         show this message
+";
+
+pub struct Options {
+    pub decompress: bool,
+    pub stdout: bool,
+    pub keep: bool,
+    pub force: bool,
+    pub use_stored_name: bool,
+    pub strict: bool,
+    pub recursive: bool,
+    // how many files to process at once, or None for one per CPU
+    pub jobs: Option<usize>,
+    pub verbose: bool,
+    pub quiet: bool,
+    pub level: u8,
+    pub text_preprocessing: bool,
+    pub dedup_window: Option<u64>,
+    pub model: Model,
+    // the parity percentage, if any
+    pub parity: Option<u8>,
+}
+
+impl Options {
+    fn default_options() -> Self {
+        Options {
+            decompress: false,
+            stdout: false,
+            keep: false,
+            force: false,
+            use_stored_name: false,
+            strict: false,
+            recursive: false,
+            jobs: None,
+            verbose: false,
+            quiet: false,
+            level: DEFAULT_LEVEL,
+            text_preprocessing: true,
+            dedup_window: None,
+            model: Model::Window,
+            parity: None,
+        }
+    }
+
+    pub fn squash_options(&self) -> SquashOptions {
+        SquashOptions {
+            level: self.level,
+            text_preprocessing: self.text_preprocessing,
+            dedup_window: self.dedup_window,
+            model: self.model,
+            metadata: None,
+            parity: self.parity,
+            encryption: None,
+            cancel: None,
+        }
+    }
+
+    pub fn decode_limits(&self) -> DecodeLimits {
+        DecodeLimits {
+            strict: self.strict,
+            ..DecodeLimits::default_limits()
+        }
+    }
+}
+
+pub fn run(args: &[String]) -> Result<(), String> {
+    match args.first().map(String::as_str) {
+        Some("enc") => compress::enc(&args[1..]),
+        Some("dec") => compress::dec(&args[1..]),
+        Some("create") => archive::create(&args[1..]),
+        Some("list") => archive::list(&args[1..]),
+        Some("extract") => archive::extract(&args[1..]),
+        Some("tar") => tar::run(&args[1..]),
+        Some("info") => info::run(&args[1..]),
+        Some("test") => repair::test(&args[1..]),
+        Some("repair") => repair::run(&args[1..]),
+        Some("recover") => recover::run(&args[1..]),
+        Some("analyze") => analyze::run(&args[1..]),
+        Some("bench") => bench::run(&args[1..]),
+        _ => {
+            let (options, files) = parse_args(args)?;
+            batch::run(&options, &files)
+        }
+    }
+}
+
+fn parse_args(args: &[String]) -> Result<(Options, Vec<String>), String> {
+    let mut options = Options::default_options();
+    let mut files = vec![];
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            files.extend(args.cloned());
+            break;
+        } else if let Some(long) = arg.strip_prefix("--") {
+            let (name, value) = match long.find('=') {
+                Some(i) => (&long[..i], Some(&long[i + 1..])),
+                None => (long, None),
+            };
+            match (name, value) {
+                ("decompress", None) => options.decompress = true,
+                ("stdout", None) => options.stdout = true,
+                ("keep", None) => options.keep = true,
+                ("force", None) => options.force = true,
+                ("name", None) => options.use_stored_name = true,
+                ("strict", None) => options.strict = true,
+                ("recursive", None) => options.recursive = true,
+                ("jobs", Some(jobs)) => options.jobs = Some(parse_jobs(jobs)?),
+                ("verbose", None) => options.verbose = true,
+                ("quiet", None) => options.quiet = true,
+                ("no-text", None) => options.text_preprocessing = false,
+                ("dedup", None) => options.dedup_window = Some(DEFAULT_DEDUP_WINDOW),
+                ("dedup", Some(size)) => options.dedup_window = Some(parse_window(size)?),
+                ("model", Some("window")) => options.model = Model::Window,
+                ("model", Some("halving")) => options.model = Model::Halving,
+                ("parity", None) => options.parity = Some(DEFAULT_PARITY),
+                ("parity", Some(percent)) => options.parity = Some(parse_percent(percent)?),
+                ("help", None) => {
+                    print!("{}", USAGE);
+                    return Ok((options, vec![]));
+                }
+                _ => return Err(format!("unknown option {}\n{}", arg, USAGE)),
+            }
+        } else if arg.len() > 1 && arg.starts_with('-') {
+            for (i, flag) in arg.char_indices().skip(1) {
+                match flag {
+                    'd' => options.decompress = true,
+                    'c' => options.stdout = true,
+                    'k' => options.keep = true,
+                    'f' => options.force = true,
+                    'N' => options.use_stored_name = true,
+                    'r' => options.recursive = true,
+                    'v' => options.verbose = true,
+                    'q' => options.quiet = true,
+                    // the count either follows directly, as in -j4, or is the next argument
+                    'j' => {
+                        let jobs = match &arg[i + 1..] {
+                            "" => args
+                                .next()
+                                .ok_or_else(|| format!("-j needs a count\n{}", USAGE))?,
+                            rest => rest,
+                        };
+                        options.jobs = Some(parse_jobs(jobs)?);
+                        break;
+                    }
+                    '1'..='9' => options.level = flag as u8 - b'0',
+                    'h' => {
+                        print!("{}", USAGE);
+                        return Ok((options, vec![]));
+                    }
+                    _ => return Err(format!("unknown option -{}\n{}", flag, USAGE)),
+                }
+            }
+        } else {
+            files.push(arg.clone());
+        }
+    }
+    if files.is_empty() {
+        files.push(String::from("-"));
+    }
+    Ok((options, files))
+}
+
+fn parse_jobs(jobs: &str) -> Result<usize, String> {
+    jobs.parse::<usize>()
+        .ok()
+        .filter(|n| *n > 0)
+        .ok_or_else(|| format!("bad job count {}", jobs))
+}
+
+// parse a percentage from 1 to 100, with or without the %
+fn parse_percent(percent: &str) -> Result<u8, String> {
+    percent
+        .strip_suffix('%')
+        .unwrap_or(percent)
+        .parse::<u8>()
+        .ok()
+        .filter(|n| (1..=100).contains(n))
+        .ok_or_else(|| format!("bad percentage {}", percent))
+}
+
+// parse a dedup window, no larger than decoders accept by default, so that whatever
+// is squashed can be decompressed again
+fn parse_window(size: &str) -> Result<u64, String> {
+    let window = parse_size(size)?;
+    if window > DecodeLimits::default_limits().max_window {
+        return Err(format!(
+            "dedup window {} is over the largest that decompresses, 256M",
+            size
+        ));
+    }
+    Ok(window)
+}
+
+// parse a size like 4096, 64K, 256M or 2G
+pub fn parse_size(size: &str) -> Result<u64, String> {
+    let (digits, multiplier) = match size.chars().last() {
+        Some('K') | Some('k') => (&size[..size.len() - 1], 1 << 10),
+        Some('M') | Some('m') => (&size[..size.len() - 1], 1 << 20),
+        Some('G') | Some('g') => (&size[..size.len() - 1], 1 << 30),
+        _ => (size, 1),
+    };
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .filter(|n| *n > 0)
+        .ok_or_else(|| format!("bad size {}", size))
+}
@@ -0,0 +1,127 @@
+use super::USAGE;
+use squash::squash_algorithm::Password;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::process::Command;
+
+// where a password comes from: the file descriptor given with --password-fd, or else
+// the terminal, where it's asked for. Either way it's only got once, the first time
+// it's needed
+pub struct PasswordSource {
+    fd: Option<u32>,
+    password: Option<Password>,
+}
+
+impl PasswordSource {
+    // take `--password-fd=N` out of the arguments
+    pub fn from_args(args: &[String]) -> Result<(PasswordSource, Vec<&String>), String> {
+        let mut fd = None;
+        let mut rest = vec![];
+        for arg in args {
+            match arg.split_once('=') {
+                Some(("--password-fd", n)) => {
+                    fd = Some(
+                        n.parse()
+                            .map_err(|_| format!("bad file descriptor {}\n{}", n, USAGE))?,
+                    );
+                }
+                _ => rest.push(arg),
+            }
+        }
+        Ok((PasswordSource { fd, password: None }, rest))
+    }
+
+    pub fn is_from_fd(&self) -> bool {
+        self.fd.is_some()
+    }
+
+    // the password, asked for twice when `confirm` is set, since data encrypted with
+    // a mistyped one is lost
+    pub fn get(&mut self, confirm: bool) -> Result<Password, String> {
+        if let Some(password) = &self.password {
+            return Ok(password.clone());
+        }
+        let password = match self.fd {
+            Some(fd) => read_fd(fd)?,
+            None => {
+                let password = ask("password: ")?;
+                if confirm && ask("again: ")? != password {
+                    return Err(String::from("the passwords don't match"));
+                }
+                password
+            }
+        };
+        if password.as_bytes().is_empty() {
+            return Err(String::from("empty password"));
+        }
+        self.password = Some(password.clone());
+        Ok(password)
+    }
+}
+
+// the first line read from a file descriptor, as /dev/fd has it. As with gpg's
+// --passphrase-fd, that's all that's read, so the writer needn't close it
+fn read_fd(fd: u32) -> Result<Password, String> {
+    fs::File::open(format!("/dev/fd/{}", fd))
+        .and_then(|mut file| read_line(&mut file))
+        .map_err(|x| {
+            format!(
+                "unable to read the password from file descriptor {}: {}",
+                fd, x
+            )
+        })
+}
+
+// ask on the terminal itself, so that standard input and output are left for data,
+// turning echo off while the password's typed if stty can
+fn ask(prompt: &str) -> Result<Password, String> {
+    let asked = || -> io::Result<Password> {
+        let mut tty = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/tty")?;
+        write!(tty, "{}", prompt)?;
+        tty.flush()?;
+        let echo_off = stty(&tty, "-echo");
+        let read = read_line(&mut &tty);
+        if echo_off {
+            stty(&tty, "echo");
+            writeln!(tty)?;
+        }
+        read
+    };
+    asked().map_err(|x| {
+        format!(
+            "unable to ask for the password ({}), so use --password-fd",
+            x
+        )
+    })
+}
+
+fn stty(tty: &fs::File, setting: &str) -> bool {
+    tty.try_clone().is_ok_and(|tty| {
+        Command::new("stty")
+            .arg(setting)
+            .stdin(tty)
+            .status()
+            .is_ok_and(|status| status.success())
+    })
+}
+
+// one line, without its line ending. It's read a byte at a time, so that nothing after
+// it is taken, straight into the buffer the Password wipes when it's dropped
+fn read_line(reader: &mut dyn Read) -> io::Result<Password> {
+    let mut line = Vec::with_capacity(1024);
+    loop {
+        let end = line.len();
+        line.push(0);
+        if reader.read(&mut line[end..])? == 0 || line[end] == b'\n' {
+            line.truncate(end);
+            break;
+        }
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Password::from(line))
+}
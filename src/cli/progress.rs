@@ -0,0 +1,107 @@
+use squash::squash_algorithm::{Event, Observer};
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+// nothing is drawn for anything quicker than this, and then no more often than this
+const DELAY: Duration = Duration::from_millis(300);
+const REDRAW: Duration = Duration::from_millis(100);
+const WIDTH: usize = 24;
+
+// a progress bar on standard error, updated as each block is finished, e.g.
+// "notes.txt [##########--------------]  42% 1.2M/2.9M 0.9 MB/s ETA 0:02"
+pub struct Progress {
+    label: String,
+    // the size of the input, when it's known, for the percentage and ETA
+    total: Option<u64>,
+    enabled: bool,
+    done: u64,
+    start: Instant,
+    drawn: Option<Instant>,
+}
+
+impl Progress {
+    pub fn new(label: &str, total: Option<u64>, enabled: bool) -> Self {
+        Progress {
+            label: String::from(label),
+            total,
+            enabled,
+            done: 0,
+            start: Instant::now(),
+            drawn: None,
+        }
+    }
+
+    fn draw(&mut self) {
+        let elapsed = self.start.elapsed();
+        let rate = self.done as f64 / elapsed.as_secs_f64().max(1e-9);
+        let mut line = format!("\r{} ", self.label);
+        match self.total.filter(|t| *t > 0) {
+            Some(total) => {
+                let fraction = (self.done as f64 / total as f64).min(1.0);
+                let filled = (fraction * WIDTH as f64) as usize;
+                let eta = (total.saturating_sub(self.done) as f64 / rate.max(1.0)) as u64;
+                line.push_str(&format!(
+                    "[{}{}] {:>3}% {}/{} {:.1} MB/s ETA {}:{:02}",
+                    "#".repeat(filled),
+                    "-".repeat(WIDTH - filled),
+                    (fraction * 100.0) as u32,
+                    human_size(self.done),
+                    human_size(total),
+                    rate / 1e6,
+                    eta / 60,
+                    eta % 60
+                ));
+            }
+            None => line.push_str(&format!("{} {:.1} MB/s", human_size(self.done), rate / 1e6)),
+        }
+        // and clear whatever was left over from a longer line
+        line.push_str("\x1b[K");
+        let _ = io::stderr().write_all(line.as_bytes());
+        self.drawn = Some(Instant::now());
+    }
+
+    fn clear(&mut self) {
+        if self.drawn.take().is_some() {
+            let _ = io::stderr().write_all(b"\r\x1b[K");
+        }
+    }
+}
+
+impl Observer for Progress {
+    fn observe(&mut self, event: &Event) {
+        if !self.enabled {
+            return;
+        }
+        match event {
+            Event::BlockFinished { input_size, .. } => {
+                self.done += input_size;
+                let due = match self.drawn {
+                    Some(drawn) => drawn.elapsed() >= REDRAW,
+                    None => self.start.elapsed() >= DELAY,
+                };
+                if due {
+                    self.draw();
+                }
+            }
+            Event::Finished { .. } => self.clear(),
+            _ => (),
+        }
+    }
+}
+
+// don't leave a half-finished bar behind if the stream fails
+impl Drop for Progress {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+// e.g. 1.5M
+pub fn human_size(size: u64) -> String {
+    match size {
+        s if s >= 1 << 30 => format!("{:.1}G", s as f64 / (1 << 30) as f64),
+        s if s >= 1 << 20 => format!("{:.1}M", s as f64 / (1 << 20) as f64),
+        s if s >= 1 << 10 => format!("{:.1}K", s as f64 / (1 << 10) as f64),
+        s => s.to_string(),
+    }
+}
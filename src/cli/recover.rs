@@ -0,0 +1,53 @@
+use super::compress::write_output;
+use super::USAGE;
+use squash::squash_algorithm::*;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// `squash recover [-f] <damaged.sq> <output>`
+pub fn run(args: &[String]) -> Result<(), String> {
+    let mut force = false;
+    let mut files = vec![];
+    for arg in args {
+        match arg.as_str() {
+            "-f" | "--force" => force = true,
+            _ if arg.starts_with('-') => return Err(format!("unknown option {}\n{}", arg, USAGE)),
+            _ => files.push(arg),
+        }
+    }
+    let [input, output] = files[..] else {
+        return Err(format!(
+            "recover takes a damaged file and an output\n{}",
+            USAGE
+        ));
+    };
+
+    let damaged = fs::read(input).map_err(|x| format!("{}: {}", input, x))?;
+    let mut recovery = None;
+    write_output(Path::new(output), force, |writer| {
+        let salvaged = recover(&damaged, writer)?;
+        // a file that's all damage is more likely not squashed at all
+        if salvaged.blocks == 0 && !salvaged.lost.is_empty() {
+            return Err(io::Error::other("nothing could be recovered"));
+        }
+        recovery = Some(salvaged);
+        Ok(())
+    })?;
+
+    let recovery = recovery.unwrap();
+    println!(
+        "{}: recovered {} block{}, {} bytes",
+        input,
+        recovery.blocks,
+        if recovery.blocks == 1 { "" } else { "s" },
+        recovery.recovered
+    );
+    for lost in &recovery.lost {
+        println!(
+            "  lost bytes {}..{} of the input, at byte {} of the output",
+            lost.input.start, lost.input.end, lost.output
+        );
+    }
+    Ok(())
+}
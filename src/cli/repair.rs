@@ -0,0 +1,173 @@
+use super::compress::write_output;
+use super::password::PasswordSource;
+use super::USAGE;
+use squash::squash_algorithm::*;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+// how long --bitflip searches for flipped bits, unless it says otherwise
+const DEFAULT_BITFLIP: Duration = Duration::from_secs(60);
+
+// the options that test and repair share, where to get a password, and the arguments
+// left over
+fn parse_args(args: &[String]) -> Result<(RepairOptions, PasswordSource, Vec<&String>), String> {
+    let (password, args) = PasswordSource::from_args(args)?;
+    let mut options = RepairOptions::default_options();
+    let mut rest = vec![];
+    for arg in args {
+        match arg.split_once('=') {
+            _ if arg == "--bitflip" => options.bitflip = Some(DEFAULT_BITFLIP),
+            Some(("--bitflip", seconds)) => {
+                let seconds = seconds
+                    .parse()
+                    .map_err(|_| format!("bad number of seconds {}", seconds))?;
+                options.bitflip = Some(Duration::from_secs(seconds));
+            }
+            _ => rest.push(arg),
+        }
+    }
+    Ok((options, password, rest))
+}
+
+// the options for `data`, with the password if it's encrypted
+fn with_password(
+    options: &RepairOptions,
+    data: &[u8],
+    password: &mut PasswordSource,
+) -> Result<RepairOptions, String> {
+    let mut options = options.clone();
+    if is_encrypted(&mut &data[..]).unwrap_or(false) {
+        options.password = Some(password.get(false)?);
+    }
+    Ok(options)
+}
+
+// `squash test [--bitflip[=SECONDS]] [--password-fd=N] <file.sq...>`
+pub fn test(args: &[String]) -> Result<(), String> {
+    let (options, mut password, files) = parse_args(args)?;
+    if let Some(arg) = files.iter().find(|arg| arg.starts_with('-')) {
+        return Err(format!("unknown option {}\n{}", arg, USAGE));
+    }
+    if files.is_empty() {
+        return Err(format!("test takes the files to check\n{}", USAGE));
+    }
+
+    let mut failed = false;
+    for file in files {
+        let tested = fs::read(file).map_err(|x| x.to_string()).and_then(|data| {
+            let options = with_password(&options, &data, &mut password)?;
+            repair_with_options(&data, &mut io::sink(), &options).map_err(|x| x.to_string())
+        });
+        match tested {
+            Ok(repair) if repair.damaged.is_empty() => println!("{}: ok", file),
+            Ok(repair) => {
+                println!(
+                    "{}: damaged, but repairable with squash repair{}",
+                    file,
+                    if repair.bits_flipped.is_empty() {
+                        ""
+                    } else {
+                        " --bitflip"
+                    }
+                );
+                print_damage(&repair);
+                failed = true;
+            }
+            Err(x) => {
+                eprintln!("squash: {}: {}", file, x);
+                failed = true;
+            }
+        }
+    }
+    if failed {
+        Err(String::new())
+    } else {
+        Ok(())
+    }
+}
+
+// `squash repair [-f] [--bitflip[=SECONDS]] [--password-fd=N] <damaged.sq> <output>`
+pub fn run(args: &[String]) -> Result<(), String> {
+    let (options, mut password, args) = parse_args(args)?;
+    let mut force = false;
+    let mut files = vec![];
+    for arg in args {
+        match arg.as_str() {
+            "-f" | "--force" => force = true,
+            _ if arg.starts_with('-') => return Err(format!("unknown option {}\n{}", arg, USAGE)),
+            _ => files.push(arg),
+        }
+    }
+    let [input, output] = files[..] else {
+        return Err(format!(
+            "repair takes a damaged file and an output\n{}",
+            USAGE
+        ));
+    };
+
+    let damaged = fs::read(input).map_err(|x| format!("{}: {}", input, x))?;
+    let options = with_password(&options, &damaged, &mut password)?;
+    // repaired first, so that a file beyond repair leaves no output behind
+    let mut repaired = vec![];
+    let repair = repair_with_options(&damaged, &mut repaired, &options)
+        .map_err(|x| format!("{}: {}", input, x))?;
+    write_output(Path::new(output), force, |writer| {
+        writer.write_all(&repaired)
+    })?;
+
+    if repair.damaged.is_empty() {
+        println!("{}: no damage found, copied as it was", input);
+        return Ok(());
+    }
+    let mut done = vec![];
+    let blocks = repair.blocks_rebuilt.len();
+    if blocks > 0 {
+        done.push(format!("rebuilt {} block{}", blocks, plural(blocks)));
+    }
+    if repair.shards_rebuilt > 0 {
+        done.push(format!(
+            "rebuilt {} parity shard{}",
+            repair.shards_rebuilt,
+            plural(repair.shards_rebuilt)
+        ));
+    }
+    let bits = repair.bits_flipped.len();
+    if bits > 0 {
+        done.push(format!("flipped {} bit{} back", bits, plural(bits)));
+    }
+    if done.is_empty() {
+        done.push(String::from("rewrote the trailer"));
+    }
+    println!("{}: {}", input, done.join(", "));
+    print_damage(&repair);
+    Ok(())
+}
+
+fn plural(n: usize) -> &'static str {
+    if n == 1 {
+        ""
+    } else {
+        "s"
+    }
+}
+
+fn print_damage(repair: &Repair) {
+    for range in &repair.damaged {
+        println!("  damaged bytes {}..{}", range.start, range.end);
+    }
+    if !repair.blocks_rebuilt.is_empty() {
+        let blocks: Vec<String> = repair.blocks_rebuilt.iter().map(u64::to_string).collect();
+        println!("  blocks {}", blocks.join(", "));
+    }
+    for flipped in &repair.bits_flipped {
+        println!(
+            "  bit {} of byte {} was flipped, in block {}",
+            flipped.bit, flipped.byte, flipped.block
+        );
+    }
+    if repair.search_timed_out {
+        println!("  the search for flipped bits ran out of time");
+    }
+}
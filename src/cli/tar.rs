@@ -0,0 +1,128 @@
+use super::compress::write_output;
+use super::{Options, USAGE};
+use squash::tar;
+use std::fs;
+use std::io::{self, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+
+// `squash tar -c [options] [-f <archive>] <path...>` or
+// `squash tar -x [options] [-f <archive>] [-C <dir>]`, where the archive defaults
+// to standard output or input
+pub fn run(args: &[String]) -> Result<(), String> {
+    let mut options = Options::default_options();
+    let mut mode = None;
+    let mut archive = None;
+    let mut destination = PathBuf::from(".");
+    let mut paths = vec![];
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            paths.extend(args.map(PathBuf::from));
+            break;
+        } else if let Some(long) = arg.strip_prefix("--") {
+            let (name, value) = match long.split_once('=') {
+                Some((name, value)) => (name, Some(value)),
+                None => (long, None),
+            };
+            match (name, value) {
+                ("create", None) => mode = Some('c'),
+                ("extract", None) => mode = Some('x'),
+                ("file", Some(file)) => archive = Some(String::from(file)),
+                ("directory", Some(dir)) => destination = PathBuf::from(dir),
+                ("verbose", None) => options.verbose = true,
+                ("overwrite", None) => options.force = true,
+                _ => return Err(format!("unknown option {}\n{}", arg, USAGE)),
+            }
+        } else if arg.len() > 1 && arg.starts_with('-') {
+            for (i, flag) in arg.char_indices().skip(1) {
+                match flag {
+                    'c' | 'x' => mode = Some(flag),
+                    'v' => options.verbose = true,
+                    '1'..='9' => options.level = flag as u8 - b'0',
+                    // these take a value, either following directly or as the next argument
+                    'f' | 'C' => {
+                        let value = match &arg[i + 1..] {
+                            "" => args
+                                .next()
+                                .ok_or_else(|| format!("-{} needs a value\n{}", flag, USAGE))?,
+                            rest => rest,
+                        };
+                        if flag == 'f' {
+                            archive = Some(String::from(value));
+                        } else {
+                            destination = PathBuf::from(value);
+                        }
+                        break;
+                    }
+                    _ => return Err(format!("unknown option -{}\n{}", flag, USAGE)),
+                }
+            }
+        } else {
+            paths.push(PathBuf::from(arg));
+        }
+    }
+    let archive = archive.filter(|a| a != "-");
+    match mode {
+        Some('c') if !paths.is_empty() => create(&paths, archive.as_deref(), &options),
+        Some('c') => Err(format!("tar -c needs something to archive\n{}", USAGE)),
+        Some(_) if !paths.is_empty() => Err(format!("tar -x takes no paths\n{}", USAGE)),
+        Some(_) => extract(archive.as_deref(), &destination, &options),
+        None => Err(format!("tar needs -c or -x\n{}", USAGE)),
+    }
+}
+
+fn create(paths: &[PathBuf], archive: Option<&str>, options: &Options) -> Result<(), String> {
+    let squash_options = options.squash_options();
+    let skipped = match archive {
+        Some(archive) => {
+            let mut skipped = vec![];
+            write_output(Path::new(archive), true, |writer| {
+                skipped = tar::create(paths, writer, &squash_options)?;
+                Ok(())
+            })?;
+            skipped
+        }
+        None => {
+            if io::stdout().is_terminal() {
+                return Err(String::from(
+                    "compressed data not written to a terminal (use -f to name an archive)",
+                ));
+            }
+            let mut writer = io::BufWriter::new(io::stdout().lock());
+            tar::create(paths, &mut writer, &squash_options)
+                .and_then(|skipped| writer.flush().map(|()| skipped))
+                .map_err(|x| format!("stdout: {}", x))?
+        }
+    };
+    for path in skipped {
+        eprintln!(
+            "squash: {}: not a regular file or directory -- skipped",
+            path.display()
+        );
+    }
+    Ok(())
+}
+
+fn extract(archive: Option<&str>, destination: &Path, options: &Options) -> Result<(), String> {
+    let extracted = match archive {
+        Some(archive) => {
+            let mut file = fs::File::open(archive).map_err(|x| format!("{}: {}", archive, x))?;
+            tar::extract(&mut file, destination, options.force)
+                .map_err(|x| format!("{}: {}", archive, x))?
+        }
+        None => tar::extract(&mut io::stdin().lock(), destination, options.force)
+            .map_err(|x| format!("stdin: {}", x))?,
+    };
+    if options.verbose {
+        for path in &extracted.extracted {
+            eprintln!("{}", path);
+        }
+    }
+    for path in &extracted.skipped {
+        eprintln!(
+            "squash: {}: not a regular file or directory -- skipped",
+            path
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,41 @@
+// Entry points for fuzzers, built with `--features fuzzing`. Each takes arbitrary
+// bytes and must return without panicking, whatever they are, so a harness only has
+// to pass its inputs along, e.g. with cargo-fuzz:
+//     fuzz_target!(|data: &[u8]| squash::fuzz::unsquash(data));
+
+use crate::squash_algorithm::{self, DecodeLimits};
+use std::io;
+
+// decode the bytes as a stream. Output is capped, so that the fuzzer isn't slowed
+// down by the streams that legitimately decode to a lot
+pub fn unsquash(data: &[u8]) {
+    let limits = DecodeLimits {
+        max_output: Some(1 << 24),
+        ..DecodeLimits::default_limits()
+    };
+    let _ = squash_algorithm::unsquash_with_limits(&mut &data[..], &mut io::sink(), &limits);
+}
+
+// describe the bytes as a stream, decoding each block
+pub fn inspect(data: &[u8]) {
+    let _ = squash_algorithm::inspect(&mut &data[..], true);
+}
+
+// salvage what can be salvaged from the bytes, as from a damaged stream
+pub fn recover(data: &[u8]) {
+    let _ = squash_algorithm::recover(data, &mut io::sink());
+}
+
+// rebuild what can be rebuilt of the bytes from their parity
+pub fn repair(data: &[u8]) {
+    let _ = squash_algorithm::repair(data, &mut io::sink());
+}
+
+// squash the bytes and check that they come back unchanged
+pub fn round_trip(data: &[u8]) {
+    let mut squashed = vec![];
+    squash_algorithm::squash(&mut &data[..], &mut squashed).unwrap();
+    let mut unsquashed = vec![];
+    squash_algorithm::unsquash(&mut &squashed[..], &mut unsquashed).unwrap();
+    assert!(unsquashed == data, "round trip changed the data");
+}
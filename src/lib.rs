@@ -1,4 +1,11 @@
 #![warn(clippy::all)]
 
+pub mod archive;
+#[cfg(test)]
+#[path = "../tests/common/mod.rs"]
+mod common;
+#[cfg(feature = "fuzzing")]
+pub mod fuzz;
 pub mod squash_algorithm;
 mod suffixarray;
+pub mod tar;
@@ -1,34 +1,20 @@
-use squash::squash_algorithm::*;
-use std::env;
-use std::fs;
+mod cli;
 
-fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() != 4 {
-        eprintln!("Bad args! {:?}", args);
-        return;
-    }
+use std::env;
+use std::process;
 
-    let mut input_file = match fs::File::open(&args[2]) {
-        Ok(x) => x,
-        Err(x) => panic!("Unable to open input file {}", x),
-    };
-    let mut output_file = match fs::File::create(&args[3]) {
-        Ok(x) => x,
-        Err(x) => panic!("Unable to open output file {}", x),
-    };
+// counts heap use, so that `squash bench` can report peak memory
+#[cfg(feature = "bench")]
+#[global_allocator]
+static ALLOCATOR: cli::bench::allocator::CountingAllocator =
+    cli::bench::allocator::CountingAllocator;
 
-    if args[1] == "enc" {
-        match squash(&mut input_file, &mut output_file) {
-            Ok(()) => (),
-            Err(x) => eprintln!("Error: {}", x),
-        }
-    } else if args[1] == "dec" {
-        match unsquash(&mut input_file, &mut output_file) {
-            Ok(()) => (),
-            Err(x) => eprintln!("Error: {}", x),
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if let Err(x) = cli::run(&args) {
+        if !x.is_empty() {
+            eprintln!("squash: {}", x);
         }
-    } else {
-        eprintln!("Bad args! {:?}", args)
+        process::exit(1);
     }
 }
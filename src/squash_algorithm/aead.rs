@@ -0,0 +1,346 @@
+// ChaCha20-Poly1305 authenticated encryption (RFC 8439). ChaCha20 encrypts, by
+// xoring the data with a keystream made from the key, a nonce and a block counter, and
+// Poly1305 tags the additional data and the ciphertext with a one-time key taken from
+// the start of the keystream. Opening checks the tag before decrypting anything, so
+// nothing that was changed, or sealed under another key or nonce, gets through
+
+use std::convert::TryInto;
+
+pub type Key = [u8; 32];
+pub type Nonce = [u8; 12];
+
+pub const TAG_LENGTH: usize = 16;
+
+// "expand 32-byte k"
+const CONSTANTS: [u32; 4] = [0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574];
+
+fn words<const N: usize>(bytes: &[u8]) -> [u32; N] {
+    let mut words = [0; N];
+    for (word, bytes) in words.iter_mut().zip(bytes.chunks_exact(4)) {
+        *word = u32::from_le_bytes(bytes.try_into().unwrap());
+    }
+    words
+}
+
+fn chacha_quarter_round(x: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    x[a] = x[a].wrapping_add(x[b]);
+    x[d] = (x[d] ^ x[a]).rotate_left(16);
+    x[c] = x[c].wrapping_add(x[d]);
+    x[b] = (x[b] ^ x[c]).rotate_left(12);
+    x[a] = x[a].wrapping_add(x[b]);
+    x[d] = (x[d] ^ x[a]).rotate_left(8);
+    x[c] = x[c].wrapping_add(x[d]);
+    x[b] = (x[b] ^ x[c]).rotate_left(7);
+}
+
+// 64 bytes of keystream
+fn chacha20_block(key: &Key, counter: u32, nonce: &Nonce) -> [u8; 64] {
+    let mut state = [0; 16];
+    state[..4].copy_from_slice(&CONSTANTS);
+    state[4..12].copy_from_slice(&words::<8>(key));
+    state[12] = counter;
+    state[13..].copy_from_slice(&words::<3>(nonce));
+    let mut x = state;
+    for _ in 0..10 {
+        // columns, then diagonals
+        chacha_quarter_round(&mut x, 0, 4, 8, 12);
+        chacha_quarter_round(&mut x, 1, 5, 9, 13);
+        chacha_quarter_round(&mut x, 2, 6, 10, 14);
+        chacha_quarter_round(&mut x, 3, 7, 11, 15);
+        chacha_quarter_round(&mut x, 0, 5, 10, 15);
+        chacha_quarter_round(&mut x, 1, 6, 11, 12);
+        chacha_quarter_round(&mut x, 2, 7, 8, 13);
+        chacha_quarter_round(&mut x, 3, 4, 9, 14);
+    }
+    let mut block = [0; 64];
+    for ((bytes, x), state) in block.chunks_exact_mut(4).zip(x).zip(state) {
+        bytes.copy_from_slice(&x.wrapping_add(state).to_le_bytes());
+    }
+    block
+}
+
+// encrypt or decrypt in place, with the keystream from block `counter` on
+fn chacha20(key: &Key, counter: u32, nonce: &Nonce, data: &mut [u8]) {
+    for (i, chunk) in data.chunks_mut(64).enumerate() {
+        let keystream = chacha20_block(key, counter.wrapping_add(i as u32), nonce);
+        for (byte, key) in chunk.iter_mut().zip(keystream) {
+            *byte ^= key;
+        }
+    }
+}
+
+// the accumulator and key of a Poly1305 tag being computed, in 26-bit limbs so that
+// their products fit in 64 bits
+struct Poly1305 {
+    r: [u32; 5],
+    s: [u32; 4],
+    h: [u32; 5],
+}
+
+const LIMB: u32 = (1 << 26) - 1;
+
+impl Poly1305 {
+    fn new(key: &[u8; 32]) -> Self {
+        let t = |i: usize| u32::from_le_bytes(key[i..i + 4].try_into().unwrap());
+        Poly1305 {
+            // clamped, as the algorithm requires
+            r: [
+                t(0) & 0x3ff_ffff,
+                (t(3) >> 2) & 0x3ff_ff03,
+                (t(6) >> 4) & 0x3ff_c0ff,
+                (t(9) >> 6) & 0x3f0_3fff,
+                (t(12) >> 8) & 0x00f_ffff,
+            ],
+            s: words::<4>(&key[16..]),
+            h: [0; 5],
+        }
+    }
+
+    // add a 16-byte block to the accumulator, with `high` as its 129th bit, and
+    // multiply it by r
+    fn block(&mut self, block: &[u8; 16], high: u32) {
+        let t = |i: usize| u32::from_le_bytes(block[i..i + 4].try_into().unwrap());
+        let h = &mut self.h;
+        h[0] += t(0) & LIMB;
+        h[1] += (t(3) >> 2) & LIMB;
+        h[2] += (t(6) >> 4) & LIMB;
+        h[3] += (t(9) >> 6) & LIMB;
+        h[4] += (t(12) >> 8) | (high << 24);
+
+        let [r0, r1, r2, r3, r4] = self.r.map(u64::from);
+        let [s1, s2, s3, s4] = [r1 * 5, r2 * 5, r3 * 5, r4 * 5];
+        let [h0, h1, h2, h3, h4] = h.map(u64::from);
+        let d = [
+            h0 * r0 + h1 * s4 + h2 * s3 + h3 * s2 + h4 * s1,
+            h0 * r1 + h1 * r0 + h2 * s4 + h3 * s3 + h4 * s2,
+            h0 * r2 + h1 * r1 + h2 * r0 + h3 * s4 + h4 * s3,
+            h0 * r3 + h1 * r2 + h2 * r1 + h3 * r0 + h4 * s4,
+            h0 * r4 + h1 * r3 + h2 * r2 + h3 * r1 + h4 * r0,
+        ];
+        let mut carry = 0;
+        for (h, d) in h.iter_mut().zip(d) {
+            let d = d + carry;
+            *h = d as u32 & LIMB;
+            carry = d >> 26;
+        }
+        // 2^130 is 5, modulo 2^130 - 5
+        let carry = u64::from(h[0]) + carry * 5;
+        h[0] = carry as u32 & LIMB;
+        h[1] += (carry >> 26) as u32;
+    }
+
+    // add data zero-padded to a whole number of blocks, as the AEAD lays it out
+    fn padded(&mut self, data: &[u8]) {
+        for chunk in data.chunks(16) {
+            let mut block = [0; 16];
+            block[..chunk.len()].copy_from_slice(chunk);
+            self.block(&block, 1);
+        }
+    }
+
+    fn finish(self) -> [u8; 16] {
+        let mut h = self.h;
+        let mut carry = 0;
+        for limb in h[1..].iter_mut() {
+            *limb += carry;
+            carry = *limb >> 26;
+            *limb &= LIMB;
+        }
+        h[0] += carry * 5;
+        h[1] += h[0] >> 26;
+        h[0] &= LIMB;
+
+        // h + 5 - 2^130, which is h reduced if h is at least 2^130 - 5. Chosen
+        // without branching, so the time taken doesn't depend on h
+        let mut g = [0; 5];
+        let mut carry = 5;
+        for (g, h) in g.iter_mut().zip(h) {
+            let sum = h + carry;
+            *g = sum & LIMB;
+            carry = sum >> 26;
+        }
+        let reduced = 0_u32.wrapping_sub(carry);
+        for (h, g) in h.iter_mut().zip(g) {
+            *h = (*h & !reduced) | (g & reduced);
+        }
+
+        // h modulo 2^128, plus s
+        let h = [
+            h[0] | (h[1] << 26),
+            (h[1] >> 6) | (h[2] << 20),
+            (h[2] >> 12) | (h[3] << 14),
+            (h[3] >> 18) | (h[4] << 8),
+        ];
+        let mut tag = [0; 16];
+        let mut carry = 0;
+        for ((bytes, h), s) in tag.chunks_exact_mut(4).zip(h).zip(self.s) {
+            let sum = u64::from(h) + u64::from(s) + carry;
+            bytes.copy_from_slice(&(sum as u32).to_le_bytes());
+            carry = sum >> 32;
+        }
+        tag
+    }
+}
+
+#[cfg(test)]
+fn poly1305(key: &[u8; 32], message: &[u8]) -> [u8; 16] {
+    let mut mac = Poly1305::new(key);
+    let mut chunks = message.chunks_exact(16);
+    for chunk in &mut chunks {
+        mac.block(chunk.try_into().unwrap(), 1);
+    }
+    let rest = chunks.remainder();
+    if !rest.is_empty() {
+        // a short last block is ended with a one, in place of the 129th bit
+        let mut block = [0; 16];
+        block[..rest.len()].copy_from_slice(rest);
+        block[rest.len()] = 1;
+        mac.block(&block, 0);
+    }
+    mac.finish()
+}
+
+fn tag(key: &Key, nonce: &Nonce, aad: &[u8], ciphertext: &[u8]) -> [u8; TAG_LENGTH] {
+    let one_time_key: [u8; 32] = chacha20_block(key, 0, nonce)[..32].try_into().unwrap();
+    let mut mac = Poly1305::new(&one_time_key);
+    mac.padded(aad);
+    mac.padded(ciphertext);
+    let mut lengths = [0; 16];
+    lengths[..8].copy_from_slice(&(aad.len() as u64).to_le_bytes());
+    lengths[8..].copy_from_slice(&(ciphertext.len() as u64).to_le_bytes());
+    mac.block(&lengths, 1);
+    mac.finish()
+}
+
+// encrypt the plaintext, followed by a tag over it and the additional data
+pub fn seal(key: &Key, nonce: &Nonce, aad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+    let mut sealed = plaintext.to_vec();
+    chacha20(key, 1, nonce, &mut sealed);
+    let tag = tag(key, nonce, aad, &sealed);
+    sealed.extend_from_slice(&tag);
+    sealed
+}
+
+// check the tag of what `seal` made, and decrypt it
+pub fn open(key: &Key, nonce: &Nonce, aad: &[u8], sealed: &[u8]) -> Result<Vec<u8>, &'static str> {
+    let split = sealed
+        .len()
+        .checked_sub(TAG_LENGTH)
+        .ok_or("encrypted data too short")?;
+    let (ciphertext, expected) = sealed.split_at(split);
+    // compared in constant time, so as not to give away how much of it matches
+    let difference = tag(key, nonce, aad, ciphertext)
+        .iter()
+        .zip(expected)
+        .fold(0, |difference, (a, b)| difference | (a ^ b));
+    if difference != 0 {
+        return Err("authentication failed");
+    }
+    let mut plaintext = ciphertext.to_vec();
+    chacha20(key, 1, nonce, &mut plaintext);
+    Ok(plaintext)
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::kdf::from_hex;
+    use super::*;
+
+    const SUNSCREEN: &[u8] = b"Ladies and Gentlemen of the class of '99: If I could offer you \
+        only one tip for the future, sunscreen would be it.";
+
+    fn key(bytes: Vec<u8>) -> Key {
+        bytes.try_into().unwrap()
+    }
+
+    #[test]
+    fn chacha20_known_answers() {
+        let sequential = key((0..32).collect());
+        // RFC 8439, section 2.3.2
+        let nonce = from_hex("000000090000004a00000000").try_into().unwrap();
+        assert_eq!(
+            chacha20_block(&sequential, 1, &nonce).to_vec(),
+            from_hex(
+                "10f1e7e4d13b5915500fdd1fa32071c4c7d1f4c733c068030422aa9ac3d46c4e
+                 d2826446079faa0914c2d705d98b02a2b5129cd1de164eb9cbd083e8a2503c4e"
+            )
+        );
+        // section 2.4.2
+        let nonce = from_hex("000000000000004a00000000").try_into().unwrap();
+        let mut data = SUNSCREEN.to_vec();
+        chacha20(&sequential, 1, &nonce, &mut data);
+        assert_eq!(
+            data,
+            from_hex(
+                "6e2e359a2568f98041ba0728dd0d6981e97e7aec1d4360c20a27afccfd9fae0b
+                 f91b65c5524733ab8f593dabcd62b3571639d624e65152ab8f530c359f0861d8
+                 07ca0dbf500d6a6156a38e088a22b65e52bc514d16ccf806818ce91ab7793736
+                 5af90bbf74a35be6b40b8eedf2785e42874d"
+            )
+        );
+    }
+
+    #[test]
+    fn poly1305_known_answers() {
+        // RFC 8439, section 2.5.2
+        let mac_key = key(from_hex(
+            "85d6be7857556d337f4452fe42d506a80103808afb0db2fd4abff6af4149f51b",
+        ));
+        assert_eq!(
+            poly1305(&mac_key, b"Cryptographic Forum Research Group").to_vec(),
+            from_hex("a8061dc1305136c6c22b8baf0c0127a9")
+        );
+        // appendix A.3, test vector 11: the accumulator ends up just past 2^130 - 5,
+        // which only the final reduction brings back
+        let mac_key = key(from_hex(
+            "0100000000000000000000000000000000000000000000000000000000000000",
+        ));
+        let message = from_hex(
+            "ffffffffffffffffffffffffffffffff
+             fbfefefefefefefefefefefefefefefe
+             01010101010101010101010101010101",
+        );
+        assert_eq!(poly1305(&mac_key, &message), [0; 16]);
+    }
+
+    #[test]
+    fn aead_known_answers() {
+        // RFC 8439, section 2.8.2
+        let aead_key = key((0x80..0xa0).collect());
+        let nonce = from_hex("070000004041424344454647").try_into().unwrap();
+        let aad = from_hex("50515253c0c1c2c3c4c5c6c7");
+        let sealed = seal(&aead_key, &nonce, &aad, SUNSCREEN);
+        assert_eq!(
+            sealed,
+            from_hex(
+                "d31a8d34648e60db7b86afbc53ef7ec2a4aded51296e08fea9e2b5a736ee62d6
+                 3dbea45e8ca9671282fafb69da92728b1a71de0a9e060b2905d6a5b67ecd3b36
+                 92ddbd7f2d778b8c9803aee328091b58fab324e4fad675945585808b4831d7bc
+                 3ff4def08e4b7a9de576d26586cec64b6116
+                 1ae10b594f09e26a7e902ecbd0600691"
+            )
+        );
+        assert_eq!(open(&aead_key, &nonce, &aad, &sealed).unwrap(), SUNSCREEN);
+
+        // any change to the key, the nonce, the additional data or what was sealed
+        // fails to open
+        let mut other_key = aead_key;
+        other_key[31] ^= 1;
+        assert!(open(&other_key, &nonce, &aad, &sealed).is_err());
+        let mut other_nonce = nonce;
+        other_nonce[0] ^= 1;
+        assert!(open(&aead_key, &other_nonce, &aad, &sealed).is_err());
+        assert!(open(&aead_key, &nonce, &aad[1..], &sealed).is_err());
+        for byte in [0, 50, sealed.len() - 1] {
+            let mut changed = sealed.clone();
+            changed[byte] ^= 0x80;
+            assert!(open(&aead_key, &nonce, &aad, &changed).is_err(), "{}", byte);
+        }
+        assert!(open(&aead_key, &nonce, &aad, &sealed[..sealed.len() - 1]).is_err());
+        assert!(open(&aead_key, &nonce, &aad, &sealed[..10]).is_err());
+        assert_eq!(
+            open(&aead_key, &nonce, &[], &seal(&aead_key, &nonce, &[], &[])),
+            Ok(vec![])
+        );
+    }
+}
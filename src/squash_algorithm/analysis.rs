@@ -0,0 +1,206 @@
+use std::convert::TryInto;
+use std::io;
+use std::time::{Duration, Instant};
+
+use super::arithmetic::*;
+use super::squash::*;
+use super::text::*;
+use super::transforms::*;
+
+// one step of the pipeline, described by what came out of it
+#[derive(Clone, Debug)]
+pub struct Stage {
+    pub name: &'static str,
+    // how many symbols came out: bytes, except after run-length encoding
+    pub symbols: usize,
+    // order-0 entropy of the output, in bits per symbol
+    pub entropy: f64,
+    pub time: Duration,
+}
+
+#[derive(Clone, Debug)]
+pub struct BlockAnalysis {
+    pub length: usize,
+    pub text: bool,
+    // input, then text (for text blocks), bwt, mtf, rle and arithmetic
+    pub stages: Vec<Stage>,
+    // the fraction of mtf ranks that are zero
+    pub mtf_zeros: f64,
+    // mtf symbols per rle symbol
+    pub rle_shrink: f64,
+    // what the arithmetic coder spent per rle symbol, not counting front matter,
+    // against the order-0 entropy of the rle symbols
+    pub bits_per_symbol: f64,
+    pub entropy_bound: f64,
+}
+
+// run each block of the input through every stage of the full pipeline, measuring
+// what each one does. Deduplication and the fallback block types are left out, and
+// only the stream's own arithmetic coder settings are used
+pub fn analyze(
+    reader: &mut dyn io::Read,
+    options: &SquashOptions,
+) -> io::Result<Vec<BlockAnalysis>> {
+    let encoder = ArithmeticEncoder {
+        model: options.model,
+        ..ArithmeticEncoder::default_encoder()
+    };
+    let mut block = vec![0; BLOCK_SIZE];
+    let mut bytes = read_block(reader, &mut block)?;
+    let dictionary = if options.text_preprocessing && looks_like_text(&block[0..bytes]) {
+        Dictionary::from_sample(&block[0..bytes])
+    } else {
+        Dictionary::empty()
+    };
+
+    let mut blocks = vec![];
+    while bytes > 0 {
+        blocks.push(analyze_block(
+            &block[0..bytes],
+            &dictionary,
+            &encoder,
+            options,
+        ));
+        bytes = read_block(reader, &mut block)?;
+    }
+    Ok(blocks)
+}
+
+fn analyze_block(
+    plaintext: &[u8],
+    dictionary: &Dictionary,
+    encoder: &ArithmeticEncoder,
+    options: &SquashOptions,
+) -> BlockAnalysis {
+    let mut stages = vec![byte_stage("input", plaintext, Duration::ZERO)];
+
+    let text = options.text_preprocessing && looks_like_text(plaintext);
+    let preprocessed;
+    let plaintext = if text {
+        let start = Instant::now();
+        preprocessed = text_transform(plaintext, dictionary);
+        stages.push(byte_stage("text", &preprocessed, start.elapsed()));
+        &preprocessed[..]
+    } else {
+        plaintext
+    };
+
+    let start = Instant::now();
+    let bwt_encoded = bw_transform(plaintext);
+    stages.push(byte_stage("bwt", &bwt_encoded.block, start.elapsed()));
+
+    let start = Instant::now();
+    let mtf_encoded = mtf_transform(&bwt_encoded.block);
+    stages.push(byte_stage("mtf", &mtf_encoded, start.elapsed()));
+
+    let start = Instant::now();
+    let rle_encoded = run_length_encode(&mtf_encoded);
+    let time = start.elapsed();
+    let mut counts = [0; 257];
+    for x in &rle_encoded {
+        counts[run_code(x) as usize] += 1;
+    }
+    let entropy_bound = entropy(&counts);
+    stages.push(Stage {
+        name: "rle",
+        symbols: rle_encoded.len(),
+        entropy: entropy_bound,
+        time,
+    });
+
+    let start = Instant::now();
+    let front_matter =
+        create_front_matter(rle_encoded.len().try_into().unwrap(), bwt_encoded.end_index);
+    let header_length = front_matter.len();
+    let packed = encoder.pack(front_matter, &rle_encoded, run_code, 257);
+    stages.push(byte_stage("arithmetic", &packed, start.elapsed()));
+
+    let zeros = mtf_encoded.iter().filter(|x| **x == 0).count();
+    BlockAnalysis {
+        length: stages[0].symbols,
+        text,
+        stages,
+        mtf_zeros: ratio(zeros, mtf_encoded.len()),
+        rle_shrink: ratio(mtf_encoded.len(), rle_encoded.len()),
+        bits_per_symbol: ratio(8 * (packed.len() - header_length), rle_encoded.len()),
+        entropy_bound,
+    }
+}
+
+fn byte_stage(name: &'static str, output: &[u8], time: Duration) -> Stage {
+    let mut counts = [0; 256];
+    for byte in output {
+        counts[usize::from(*byte)] += 1;
+    }
+    Stage {
+        name,
+        symbols: output.len(),
+        entropy: entropy(&counts),
+        time,
+    }
+}
+
+// the order-0 entropy of a distribution, in bits per symbol
+fn entropy(counts: &[usize]) -> f64 {
+    let total: usize = counts.iter().sum();
+    counts
+        .iter()
+        .filter(|count| **count > 0)
+        .map(|count| {
+            let p = *count as f64 / total as f64;
+            p * (1.0 / p).log2()
+        })
+        .sum()
+}
+
+fn ratio(a: usize, b: usize) -> f64 {
+    if b == 0 {
+        0.0
+    } else {
+        a as f64 / b as f64
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn stages() {
+        let text = "When you create a closure, Rust infers which trait to use \
+            based on how the closure uses the values from the environment.\n"
+            .repeat(100);
+        let blocks = analyze(&mut text.as_bytes(), &SquashOptions::default_options()).unwrap();
+        assert_eq!(blocks.len(), 1);
+        let block = &blocks[0];
+        assert_eq!(block.length, text.len());
+        assert!(block.text);
+        let names: Vec<&str> = block.stages.iter().map(|s| s.name).collect();
+        assert_eq!(names, ["input", "text", "bwt", "mtf", "rle", "arithmetic"]);
+
+        // the bwt adds an end marker, and the mtf keeps the length
+        let text_length = block.stages[1].symbols;
+        assert_eq!(block.stages[2].symbols, text_length + 1);
+        assert_eq!(block.stages[3].symbols, text_length + 1);
+        // otherwise the bwt only reorders bytes
+        assert!((block.stages[2].entropy - block.stages[1].entropy).abs() < 0.1);
+        // a repetitive block is mostly runs of repeated bytes after the bwt, which the
+        // mtf turns into a few very common ranks
+        assert!(block.stages[3].entropy < 1.0);
+        assert!((0.0..=1.0).contains(&block.mtf_zeros));
+        // runs of zeros only ever get shorter
+        assert!(block.rle_shrink >= 1.0);
+        assert!(block.stages[4].symbols <= block.stages[3].symbols);
+        assert_eq!(block.entropy_bound, block.stages[4].entropy);
+        assert!(block.bits_per_symbol > 0.0 && block.bits_per_symbol < 8.0);
+        assert!(block.stages[5].symbols < text.len() / 4);
+
+        let mut options = SquashOptions::default_options();
+        options.text_preprocessing = false;
+        let blocks = analyze(&mut text.as_bytes(), &options).unwrap();
+        assert!(!blocks[0].text);
+        assert_eq!(blocks[0].stages[1].name, "bwt");
+
+        assert!(analyze(&mut &[][..], &options).unwrap().is_empty());
+    }
+}
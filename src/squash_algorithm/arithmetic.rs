@@ -5,26 +5,116 @@ use std::convert::TryFrom;
 use std::convert::TryInto;
 use std::io;
 
+use super::cancel::{Cancel, Cancelled};
+
 const BIGGEST_BIT_64: u64 = 1 << 63;
+// how many symbols to pack between checks for cancellation
+const CANCEL_INTERVAL: usize = 1 << 12;
+// the largest configuration a stream may ask a decoder for. The window model holds
+// `frequency_memory` symbols, and the counts have to stay well inside a u32
+const MAX_FREQUENCY_MEMORY: u32 = 1 << 20;
+const MAX_FREQUENCY_PADDING: u32 = 1 << 16;
 
 // the likelihood of a number in the arithmetic coding
 // will never be considered less than padding / (padding * base + memory)
+#[derive(Clone, Copy, PartialEq, Debug)]
 pub struct ArithmeticEncoder {
     pub frequency_memory: u32,
     pub frequency_padding: u32,
     pub recalculation_frequency: u32,
+    pub model: Model,
+}
+
+// how the symbol frequencies adapt as the data goes by
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Model {
+    // count the last `frequency_memory` symbols exactly, forgetting each one
+    // as it leaves the window
+    Window,
+    // count every symbol, halving all the counts whenever they add up to
+    // `frequency_memory`, so that older symbols fade away gradually
+    Halving,
+}
+
+impl Model {
+    fn to_byte(self) -> u8 {
+        match self {
+            Model::Window => 0,
+            Model::Halving => 1,
+        }
+    }
+
+    fn from_byte(byte: u8) -> io::Result<Self> {
+        match byte {
+            0 => Ok(Model::Window),
+            1 => Ok(Model::Halving),
+            _ => Err(io::Error::other("unknown arithmetic model")),
+        }
+    }
 }
 
+// configurations worth trying on a block when searching for the best one.
+// Binary data tends to want a short memory and text a long one
+pub const CANDIDATE_ENCODERS: [ArithmeticEncoder; 6] = [
+    ArithmeticEncoder {
+        frequency_memory: 10_000,
+        frequency_padding: 50,
+        recalculation_frequency: 50,
+        model: Model::Window,
+    },
+    ArithmeticEncoder {
+        frequency_memory: 5_000,
+        frequency_padding: 1,
+        recalculation_frequency: 20,
+        model: Model::Window,
+    },
+    ArithmeticEncoder {
+        frequency_memory: 20_000,
+        frequency_padding: 1,
+        recalculation_frequency: 50,
+        model: Model::Window,
+    },
+    ArithmeticEncoder {
+        frequency_memory: 250_000,
+        frequency_padding: 1,
+        recalculation_frequency: 100,
+        model: Model::Window,
+    },
+    ArithmeticEncoder {
+        frequency_memory: 5_000,
+        frequency_padding: 1,
+        recalculation_frequency: 20,
+        model: Model::Halving,
+    },
+    ArithmeticEncoder {
+        frequency_memory: 60_000,
+        frequency_padding: 1,
+        recalculation_frequency: 50,
+        model: Model::Halving,
+    },
+];
+
 impl ArithmeticEncoder {
     pub fn default_encoder() -> Self {
         ArithmeticEncoder {
             frequency_memory: 10_000,
             frequency_padding: 50,
             recalculation_frequency: 50,
+            model: Model::Window,
         }
     }
 
     pub fn read_config(reader: &mut dyn io::Read) -> io::Result<Self> {
+        Self::read_config_with_model(reader, true)
+    }
+
+    // read arithmetic encoding metadata as written before it had a model byte,
+    // when the window model was the only one
+    pub fn read_config_without_model(reader: &mut dyn io::Read) -> io::Result<Self> {
+        Self::read_config_with_model(reader, false)
+    }
+
+    fn read_config_with_model(reader: &mut dyn io::Read, has_model: bool) -> io::Result<Self> {
         let mut buffer = [0; 4];
 
         reader.read_exact(&mut buffer)?;
@@ -33,15 +123,38 @@ impl ArithmeticEncoder {
         let frequency_padding = u32::from_le_bytes(buffer);
         reader.read_exact(&mut buffer)?;
         let recalculation_frequency = u32::from_le_bytes(buffer);
+        let model = if has_model {
+            let mut model = [0; 1];
+            reader.read_exact(&mut model)?;
+            Model::from_byte(model[0])?
+        } else {
+            Model::Window
+        };
+        if frequency_memory > MAX_FREQUENCY_MEMORY
+            || frequency_padding == 0
+            || frequency_padding > MAX_FREQUENCY_PADDING
+        {
+            return Err(io::Error::other(
+                "arithmetic encoding metadata out of range",
+            ));
+        }
 
         Ok(ArithmeticEncoder {
             frequency_memory,
             frequency_padding,
             recalculation_frequency,
+            model,
         })
     }
 
     pub fn write_config(&self, writer: &mut dyn io::Write) -> io::Result<()> {
+        self.write_config_without_model(writer)?;
+        writer.write_all(&[self.model.to_byte()])?;
+        Ok(())
+    }
+
+    // write arithmetic encoding metadata the way versions without a model byte did
+    pub fn write_config_without_model(&self, writer: &mut dyn io::Write) -> io::Result<()> {
         writer.write_all(&self.frequency_memory.to_le_bytes())?;
         writer.write_all(&self.frequency_padding.to_le_bytes())?;
         writer.write_all(&self.recalculation_frequency.to_le_bytes())?;
@@ -55,29 +168,39 @@ impl ArithmeticEncoder {
         encode: fn(&T) -> u32,
         base: u32,
     ) -> Vec<u8> {
+        self.pack_with_cancel(front_matter, plaintext, encode, base, Cancel::never())
+            .unwrap()
+    }
+
+    // pack, checking every so often whether to give up
+    pub(crate) fn pack_with_cancel<T>(
+        &self,
+        front_matter: Vec<u8>,
+        plaintext: &[T],
+        encode: fn(&T) -> u32,
+        base: u32,
+        cancel: Cancel,
+    ) -> Result<Vec<u8>, Cancelled> {
         let mut out = Packer::from_vec(front_matter);
-        let mut queue: VecDeque<u32> =
-            VecDeque::with_capacity(self.frequency_memory.try_into().unwrap());
-        let mut frequencies: Vec<u32> = Vec::with_capacity(base as usize);
+        let mut frequencies = Frequencies::new(self, base);
         let mut frequency_map: HashMap<u32, u64> = HashMap::new();
         let mut bottom: u64 = 0;
         let mut top: u64 = !0;
         let mut time_till_recalculated = 0;
         let mut total = 0;
-        let total_padding = base * self.frequency_padding;
-        for _ in 0..base {
-            frequencies.push(self.frequency_padding);
-        }
-        for item in plaintext {
+        for (i, item) in plaintext.iter().enumerate() {
+            if i % CANCEL_INTERVAL == 0 {
+                cancel.check()?;
+            }
             if time_till_recalculated == 0 {
                 time_till_recalculated = self.recalculation_frequency;
                 let mut total_so_far: u64 = 0;
-                for (i, freq) in frequencies.iter().enumerate() {
+                for (i, freq) in frequencies.counts.iter().enumerate() {
                     frequency_map.insert(u32::try_from(i).unwrap(), total_so_far);
                     total_so_far += u64::from(*freq);
                 }
                 frequency_map.insert(base, total_so_far);
-                total = u64::from(total_padding) + u64::try_from(queue.len()).unwrap();
+                total = total_so_far;
             } else {
                 time_till_recalculated -= 1;
             }
@@ -96,28 +219,25 @@ impl ArithmeticEncoder {
                 bottom <<= 1;
                 top <<= 1;
             }
-            queue.push_back(code);
-            frequencies[code as usize] += 1;
-            if queue.len() > self.frequency_memory.try_into().unwrap() {
-                frequencies[queue.pop_front().unwrap() as usize] -= 1;
-            }
+            frequencies.update(code);
         }
         out.push(1, 1);
-        out.finish()
+        Ok(out.finish())
     }
 
+    // unpack `length` symbols. `decode` is only ever given codes below `base`, and
+    // ciphertext that no packing could have made is an error rather than a panic
+    #[allow(clippy::manual_next_back)]
     pub fn unpack<T>(
         &self,
         ciphertext: &[u8],
         decode: fn(u32) -> T,
         base: u32,
         length: usize,
-    ) -> Vec<T> {
+    ) -> Result<Vec<T>, &'static str> {
         let mut unpacker = Unpacker::from_vec(ciphertext);
         let mut out: Vec<T> = Vec::with_capacity(ciphertext.len());
-        let mut queue: VecDeque<u32> =
-            VecDeque::with_capacity(self.frequency_memory.try_into().unwrap());
-        let mut frequencies: Vec<u32> = Vec::with_capacity(base as usize);
+        let mut frequencies = Frequencies::new(self, base);
         let mut frequency_map: HashMap<u32, u64> = HashMap::new();
         let mut frequency_map_reverse: BTreeMap<u64, u32> = BTreeMap::new();
         let mut time_till_recalculated = 0;
@@ -138,25 +258,26 @@ impl ArithmeticEncoder {
             }
             operating_bit >>= 1;
         }
-        let total_padding = base * self.frequency_padding;
-        for _ in 0..base {
-            frequencies.push(self.frequency_padding);
-        }
         for _ in 0..length {
             if time_till_recalculated == 0 {
                 time_till_recalculated = self.recalculation_frequency;
                 frequency_map_reverse.clear();
                 let mut total_so_far: u64 = 0;
-                for (i, freq) in frequencies.iter().enumerate() {
+                for (i, freq) in frequencies.counts.iter().enumerate() {
                     frequency_map.insert(u32::try_from(i).unwrap(), total_so_far);
                     frequency_map_reverse.insert(total_so_far, u32::try_from(i).unwrap());
                     total_so_far += u64::from(*freq);
                 }
                 frequency_map.insert(base, total_so_far);
-                total = u64::from(total_padding) + u64::try_from(queue.len()).unwrap();
+                total = total_so_far;
             } else {
                 time_till_recalculated -= 1;
             }
+            // packing keeps the code inside the range, and the range wider than the
+            // total, so either failing means the ciphertext was corrupted
+            if unpacked < bottom || unpacked > top || top - bottom < total {
+                return Err("corrupt arithmetic code");
+            }
             let diff = top - bottom;
             let cap = u64::try_from(
                 (u128::from(unpacked - bottom) * u128::from(total)) / u128::from(diff),
@@ -196,13 +317,62 @@ impl ArithmeticEncoder {
                     }
                 }
             }
-            queue.push_back(code);
-            frequencies[code as usize] += 1;
-            if queue.len() > self.frequency_memory.try_into().unwrap() {
-                frequencies[queue.pop_front().unwrap() as usize] -= 1;
+            frequencies.update(code);
+        }
+        Ok(out)
+    }
+}
+
+// the adaptive symbol counts that the coder's probabilities are drawn from.
+// Every count starts at, and never falls below, the frequency padding
+struct Frequencies {
+    counts: Vec<u32>,
+    model: Model,
+    memory: usize,
+    padding: u32,
+    // the symbols in the window, for the window model
+    queue: VecDeque<u32>,
+    // the sum of the counts above their padding, for the halving model
+    seen: usize,
+}
+
+impl Frequencies {
+    fn new(encoder: &ArithmeticEncoder, base: u32) -> Self {
+        let memory = encoder.frequency_memory.try_into().unwrap();
+        Frequencies {
+            counts: vec![encoder.frequency_padding; base as usize],
+            model: encoder.model,
+            memory,
+            padding: encoder.frequency_padding,
+            queue: match encoder.model {
+                Model::Window => VecDeque::with_capacity(memory),
+                Model::Halving => VecDeque::new(),
+            },
+            seen: 0,
+        }
+    }
+
+    fn update(&mut self, code: u32) {
+        self.counts[code as usize] += 1;
+        match self.model {
+            Model::Window => {
+                self.queue.push_back(code);
+                if self.queue.len() > self.memory {
+                    self.counts[self.queue.pop_front().unwrap() as usize] -= 1;
+                }
+            }
+            Model::Halving => {
+                self.seen += 1;
+                if self.seen >= self.memory.max(2) {
+                    self.seen = 0;
+                    for count in &mut self.counts {
+                        let above = (*count - self.padding) / 2;
+                        *count = self.padding + above;
+                        self.seen += above as usize;
+                    }
+                }
             }
         }
-        out
     }
 }
 
@@ -226,6 +396,7 @@ impl Packer {
             next_bit: 1,
         }
     }
+    #[allow(clippy::needless_else)]
     fn push(&mut self, bits: u8, length: u8) {
         assert!(length <= 8);
         for bit_offset in 0..length {
@@ -258,6 +429,7 @@ impl<'a> Unpacker<'a> {
             next_bit: 1,
         }
     }
+    #[allow(clippy::needless_else)]
     fn pop(&mut self, length: u8) -> Option<u8> {
         assert!(length <= 8);
         let mut out = 0;
@@ -302,35 +474,80 @@ mod test {
         let test = b"ddabdaddabccda";
         let alphabet_size = 4;
         let enc = encoder.pack(vec![], test, |a| u32::from(a - b"a"[0]), alphabet_size);
-        let dec = encoder.unpack(
-            &enc,
-            |b| u8::try_from(b).unwrap() + b"a"[0],
-            alphabet_size,
-            test.len(),
-        );
+        let dec = encoder
+            .unpack(
+                &enc,
+                |b| u8::try_from(b).unwrap() + b"a"[0],
+                alphabet_size,
+                test.len(),
+            )
+            .unwrap();
         assert_eq!(&test[..], &dec[..]);
 
         let test = b"qwertyqweyrtqwyeeewteyyrqwwerttqywetrtrrrrrrrrrrwert";
         let alphabet_size = 26;
         let enc = encoder.pack(vec![], test, |a| u32::from(a - b"a"[0]), alphabet_size);
-        let dec = encoder.unpack(
-            &enc,
-            |b| u8::try_from(b).unwrap() + b"a"[0],
-            alphabet_size,
-            test.len(),
-        );
+        let dec = encoder
+            .unpack(
+                &enc,
+                |b| u8::try_from(b).unwrap() + b"a"[0],
+                alphabet_size,
+                test.len(),
+            )
+            .unwrap();
         assert_eq!(&test[..], &dec[..]);
 
         let packed_text = encoder.pack(vec![], TEXT.as_bytes(), |a| u32::from(*a), 256);
         assert_eq!(
-            String::from_utf8_lossy(&encoder.unpack(
-                &packed_text,
-                |b| u8::try_from(b).unwrap(),
-                256,
-                TEXT.len()
-            )),
+            String::from_utf8_lossy(
+                &encoder
+                    .unpack(&packed_text, |b| u8::try_from(b).unwrap(), 256, TEXT.len())
+                    .unwrap()
+            ),
+            TEXT
+        );
+    }
+
+    #[test]
+    fn halving_model_test() {
+        let encoder = ArithmeticEncoder {
+            frequency_memory: 300,
+            frequency_padding: 1,
+            recalculation_frequency: 10,
+            model: Model::Halving,
+        };
+        let packed_text = encoder.pack(vec![], TEXT.as_bytes(), |a| u32::from(*a), 256);
+        assert!(packed_text.len() < TEXT.len());
+        assert_eq!(
+            String::from_utf8_lossy(
+                &encoder
+                    .unpack(&packed_text, |b| u8::try_from(b).unwrap(), 256, TEXT.len())
+                    .unwrap()
+            ),
             TEXT
         );
+
+        let mut config = vec![];
+        encoder.write_config(&mut config).unwrap();
+        assert_eq!(
+            ArithmeticEncoder::read_config(&mut &config[..]).unwrap(),
+            encoder
+        );
+        // a window too big to allocate
+        config[0..4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(ArithmeticEncoder::read_config(&mut &config[..]).is_err());
+
+        // the older form has no model byte, and always means the window model
+        let mut config = vec![];
+        encoder.write_config_without_model(&mut config).unwrap();
+        assert_eq!(config.len(), 12);
+        assert_eq!(
+            ArithmeticEncoder::read_config_without_model(&mut &config[..]).unwrap(),
+            ArithmeticEncoder {
+                model: Model::Window,
+                ..encoder
+            }
+        );
     }
 
     #[test]
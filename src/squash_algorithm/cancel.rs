@@ -0,0 +1,48 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// the error a squash stops with when its cancellation flag is set, wrapped in an
+// io::Error; `is_cancelled` tells it apart from any other
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "cancelled")
+    }
+}
+
+impl Error for Cancelled {}
+
+impl From<Cancelled> for io::Error {
+    fn from(cancelled: Cancelled) -> Self {
+        io::Error::other(cancelled)
+    }
+}
+
+pub fn is_cancelled(error: &io::Error) -> bool {
+    error.get_ref().is_some_and(|e| e.is::<Cancelled>())
+}
+
+// what the long-running loops check every so often
+#[derive(Clone, Copy)]
+pub struct Cancel<'a>(Option<&'a AtomicBool>);
+
+impl<'a> Cancel<'a> {
+    pub fn new(flag: Option<&'a AtomicBool>) -> Self {
+        Cancel(flag)
+    }
+
+    pub fn never() -> Self {
+        Cancel(None)
+    }
+
+    pub fn check(self) -> Result<(), Cancelled> {
+        match self.0 {
+            Some(flag) if flag.load(Ordering::Relaxed) => Err(Cancelled),
+            _ => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,404 @@
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::convert::TryInto;
+use std::io;
+
+// Long-range deduplication across blocks. Each block is cut into content-defined
+// chunks with a rolling hash, and chunks that were already seen somewhere in the
+// last `window` bytes of the stream are replaced with back-references, which the
+// decoder resolves from output it has already emitted. Whatever isn't matched is
+// gathered up as literal bytes and squashed as usual.
+
+pub const DEFAULT_WINDOW: u64 = 1 << 28;
+
+// chunk boundaries fall where the low bits of the rolling hash are all zero,
+// giving chunks of around 4 KiB
+const BOUNDARY_MASK: u64 = (1 << 12) - 1;
+const MIN_CHUNK: usize = 1 << 10;
+const MAX_CHUNK: usize = 1 << 14;
+// chunks shorter than this (only ever found at the end of a block) aren't matched
+const MIN_MATCH: usize = 64;
+
+const GEAR: [u64; 256] = gear_table();
+
+// the random values the rolling hash mixes in for each byte (splitmix64)
+const fn gear_table() -> [u64; 256] {
+    let mut table = [0; 256];
+    let mut state: u64 = 0x5155_4153_4853_4551;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Op {
+    // take this many bytes from the block's literals
+    Literal(u32),
+    // copy this many bytes from an earlier position in the stream
+    Copy { source: u64, length: u32 },
+}
+
+const OP_LITERAL: u8 = 0;
+const OP_COPY: u8 = 1;
+
+// the most recent `window` bytes of a stream
+#[derive(Clone)]
+pub struct History {
+    window: u64,
+    start: u64,
+    bytes: VecDeque<u8>,
+}
+
+impl History {
+    pub fn new(window: u64) -> Self {
+        History::starting_at(window, 0)
+    }
+
+    // the history of a stream whose first `start` bytes aren't known
+    pub fn starting_at(window: u64, start: u64) -> Self {
+        History {
+            window,
+            start,
+            bytes: VecDeque::new(),
+        }
+    }
+
+    // the stream position just past the last byte seen
+    pub fn end(&self) -> u64 {
+        self.start + self.bytes.len() as u64
+    }
+
+    // carry on after `length` bytes that weren't seen, forgetting everything before
+    pub fn skip(&mut self, length: u64) {
+        *self = History::starting_at(self.window, self.end() + length);
+    }
+
+    pub fn extend(&mut self, bytes: &[u8]) {
+        self.bytes.extend(bytes);
+        if self.bytes.len() as u64 > self.window {
+            let excess = self.bytes.len() - self.window as usize;
+            self.bytes.drain(..excess);
+            self.start += excess as u64;
+        }
+    }
+
+    fn get(&self, position: u64) -> u8 {
+        self.bytes[(position - self.start) as usize]
+    }
+
+    fn matches(&self, position: u64, bytes: &[u8]) -> bool {
+        let from = (position - self.start) as usize;
+        self.bytes.range(from..from + bytes.len()).eq(bytes.iter())
+    }
+}
+
+pub struct Deduplicator {
+    history: History,
+    // chunk fingerprint => the most recent stream position of that chunk
+    index: HashMap<u64, u64>,
+}
+
+impl Deduplicator {
+    pub fn new(window: u64) -> Self {
+        Deduplicator::starting_at(window, 0)
+    }
+
+    // deduplicate the rest of a stream that's already `start` bytes long, matching
+    // only what comes after that
+    pub fn starting_at(window: u64, start: u64) -> Self {
+        Deduplicator {
+            history: History::starting_at(window, start),
+            index: HashMap::new(),
+        }
+    }
+
+    // split a block into literal bytes and copies of earlier data.
+    // Returns None if nothing in the block could be matched.
+    pub fn deduplicate(&mut self, block: &[u8]) -> Option<(Vec<Op>, Vec<u8>)> {
+        let block_start = self.history.end();
+        self.history.extend(block);
+        let history = &self.history;
+
+        let mut ops = vec![];
+        let mut literals = vec![];
+        let mut literal_start = 0;
+        let mut position = 0;
+        while position < block.len() {
+            let chunk_end = next_boundary(block, position);
+            let chunk = &block[position..chunk_end];
+            if chunk.len() < MIN_MATCH {
+                position = chunk_end;
+                continue;
+            }
+            let fingerprint = fingerprint(chunk);
+            let here = block_start + position as u64;
+            let found = self.index.insert(fingerprint, here).filter(|source| {
+                *source >= history.start
+                    && *source + chunk.len() as u64 <= here
+                    && history.matches(*source, chunk)
+            });
+            let source = match found {
+                Some(source) => source,
+                None => {
+                    position = chunk_end;
+                    continue;
+                }
+            };
+            // the old chunk is still the better reference for whatever follows it
+            self.index.insert(fingerprint, source);
+
+            // grow the match backwards into pending literals and forwards past the chunk.
+            // The copied bytes must all come before the copy's destination.
+            let (mut source, mut start) = (source, position);
+            while start > literal_start
+                && source > history.start
+                && history.get(source - 1) == block[start - 1]
+            {
+                source -= 1;
+                start -= 1;
+            }
+            let destination = block_start + start as u64;
+            let mut end = chunk_end;
+            while end < block.len()
+                && source + ((end - start) as u64) < destination
+                && history.get(source + (end - start) as u64) == block[end]
+            {
+                end += 1;
+            }
+
+            if start > literal_start {
+                ops.push(Op::Literal((start - literal_start).try_into().unwrap()));
+                literals.extend_from_slice(&block[literal_start..start]);
+            }
+            ops.push(Op::Copy {
+                source,
+                length: (end - start).try_into().unwrap(),
+            });
+            literal_start = end;
+            position = end;
+        }
+        if ops.is_empty() {
+            return None;
+        }
+        if literal_start < block.len() {
+            ops.push(Op::Literal(
+                (block.len() - literal_start).try_into().unwrap(),
+            ));
+            literals.extend_from_slice(&block[literal_start..]);
+        }
+        Some((ops, literals))
+    }
+}
+
+// find where the chunk starting at `start` ends
+fn next_boundary(block: &[u8], start: usize) -> usize {
+    let mut hash: u64 = 0;
+    let limit = block.len().min(start + MAX_CHUNK);
+    for (i, byte) in block.iter().enumerate().take(limit).skip(start) {
+        hash = (hash << 1).wrapping_add(GEAR[usize::from(*byte)]);
+        if i + 1 - start >= MIN_CHUNK && hash & BOUNDARY_MASK == 0 {
+            return i + 1;
+        }
+    }
+    limit
+}
+
+// FNV-1a. Matches are checked byte for byte, so this only has to be well spread
+fn fingerprint(chunk: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in chunk {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+// rebuild a block from its ops, its literals, and the stream's history, failing if it
+// would come to more than `max_length` bytes
+pub fn resolve(
+    ops: &[Op],
+    literals: &[u8],
+    history: &History,
+    max_length: usize,
+) -> Result<Vec<u8>, &'static str> {
+    let mut out: Vec<u8> = Vec::with_capacity(literals.len().min(max_length));
+    let mut literals = literals;
+    for op in ops {
+        match *op {
+            Op::Literal(length) => {
+                let length = usize::try_from(length).unwrap();
+                if length > literals.len() {
+                    return Err("literal runs past the end of the block");
+                }
+                if out.len() + length > max_length {
+                    return Err("deduplicated block too long");
+                }
+                let (taken, rest) = literals.split_at(length);
+                out.extend_from_slice(taken);
+                literals = rest;
+            }
+            Op::Copy { source, length } => {
+                let destination = history.end() + out.len() as u64;
+                let end = source
+                    .checked_add(u64::from(length))
+                    .ok_or("copy out of range")?;
+                if source < history.start || end > destination {
+                    return Err("copy refers outside the window");
+                }
+                if out.len() + length as usize > max_length {
+                    return Err("deduplicated block too long");
+                }
+                for position in source..end {
+                    let byte = if position < history.end() {
+                        history.get(position)
+                    } else {
+                        out[(position - history.end()) as usize]
+                    };
+                    out.push(byte);
+                }
+            }
+        }
+    }
+    if !literals.is_empty() {
+        return Err("unused literals at the end of the block");
+    }
+    Ok(out)
+}
+
+pub fn write_ops(ops: &[Op], out: &mut Vec<u8>) {
+    out.extend_from_slice(&u32::try_from(ops.len()).unwrap().to_le_bytes());
+    for op in ops {
+        match op {
+            Op::Literal(length) => {
+                out.push(OP_LITERAL);
+                out.extend_from_slice(&length.to_le_bytes());
+            }
+            Op::Copy { source, length } => {
+                out.push(OP_COPY);
+                out.extend_from_slice(&source.to_le_bytes());
+                out.extend_from_slice(&length.to_le_bytes());
+            }
+        }
+    }
+}
+
+// read an op list off the front of a block, returning the rest of the block
+pub fn read_ops(block: &[u8]) -> Result<(&[u8], Vec<Op>), &'static str> {
+    let mut reader = block;
+    let mut read = |n: usize| -> Result<&[u8], &'static str> {
+        if reader.len() < n {
+            return Err("truncated op list");
+        }
+        let (taken, rest) = reader.split_at(n);
+        reader = rest;
+        Ok(taken)
+    };
+    let count = u32::from_le_bytes(read(4)?.try_into().unwrap());
+    let mut ops = Vec::with_capacity(count.min(1 << 16) as usize);
+    for _ in 0..count {
+        let op = match read(1)?[0] {
+            OP_LITERAL => Op::Literal(u32::from_le_bytes(read(4)?.try_into().unwrap())),
+            OP_COPY => Op::Copy {
+                source: u64::from_le_bytes(read(8)?.try_into().unwrap()),
+                length: u32::from_le_bytes(read(4)?.try_into().unwrap()),
+            },
+            _ => return Err("unknown op"),
+        };
+        ops.push(op);
+    }
+    Ok((reader, ops))
+}
+
+pub fn read_window(reader: &mut dyn io::Read) -> io::Result<u64> {
+    let mut eight_bytes = [0; 8];
+    reader.read_exact(&mut eight_bytes)?;
+    Ok(u64::from_le_bytes(eight_bytes))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // deterministic noise that won't deduplicate by accident
+    fn noise(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn dedup_round_trip() {
+        let repeated = noise(50_000, 1);
+        let mut second = noise(3_000, 2);
+        second.extend_from_slice(&repeated[1_234..]);
+        second.extend_from_slice(&noise(5_000, 3));
+        let blocks = vec![repeated.clone(), second, noise(10_000, 4)];
+
+        let mut deduplicator = Deduplicator::new(DEFAULT_WINDOW);
+        let mut history = History::new(DEFAULT_WINDOW);
+        let mut copied = 0;
+        for block in &blocks {
+            let out = match deduplicator.deduplicate(block) {
+                Some((ops, literals)) => {
+                    let mut serialised = vec![];
+                    write_ops(&ops, &mut serialised);
+                    let (rest, read) = read_ops(&serialised).unwrap();
+                    assert!(rest.is_empty());
+                    assert_eq!(read, ops);
+                    copied += block.len() - literals.len();
+                    resolve(&ops, &literals, &history, block.len()).unwrap()
+                }
+                None => block.clone(),
+            };
+            assert_eq!(&out, block);
+            history.extend(&out);
+        }
+        assert!(copied > 45_000);
+    }
+
+    #[test]
+    fn window_is_respected() {
+        let repeated = noise(20_000, 5);
+        let mut deduplicator = Deduplicator::new(30_000);
+        assert!(deduplicator.deduplicate(&repeated).is_none());
+        assert!(deduplicator.deduplicate(&noise(20_000, 6)).is_none());
+        assert!(deduplicator.deduplicate(&repeated).is_none());
+    }
+
+    #[test]
+    fn bad_copies() {
+        let mut history = History::new(100);
+        history.extend(&[1; 150]);
+        let ops = [Op::Copy {
+            source: 10,
+            length: 5,
+        }];
+        assert!(resolve(&ops, &[], &history, 100).is_err());
+        let ops = [Op::Copy {
+            source: 140,
+            length: 20,
+        }];
+        assert!(resolve(&ops, &[], &history, 100).is_err());
+        let ops = [Op::Copy {
+            source: 140,
+            length: 10,
+        }];
+        assert_eq!(resolve(&ops, &[], &history, 100).unwrap(), vec![1; 10]);
+        // fine on its own, but not repeated past the block size
+        assert!(resolve(&[ops[0]; 11], &[], &history, 100).is_err());
+    }
+}
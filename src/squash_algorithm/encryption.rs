@@ -0,0 +1,225 @@
+// Password-based encryption of a stream. The key is derived from the password with
+// scrypt, under a random salt that the header keeps along with the scrypt parameters,
+// and each block is sealed with ChaCha20-Poly1305 once it's squashed, under a nonce
+// made from its index in the stream: a block that's been changed, moved, or taken from
+// another stream fails to open. The header and trailer aren't encrypted, but they're
+// tagged too: the header so that a wrong password is caught before any block is read,
+// and the trailer so that a stream can't be cut short between blocks unnoticed
+
+use std::collections::hash_map::RandomState;
+use std::convert::TryInto;
+use std::fmt;
+use std::fs;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, Read};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::aead::*;
+use super::kdf::*;
+
+const SALT_LENGTH: usize = 16;
+
+// what each nonce is for, so that nothing in a stream shares one with anything else.
+// The rest of the nonce is the index of the block
+const BLOCK_NONCES: u32 = 0;
+const HEADER_NONCES: u32 = 1;
+const TRAILER_NONCES: u32 = 2;
+
+// a password, which debug output leaves out, and which is wiped from memory when it's
+// dropped
+#[derive(Clone, PartialEq)]
+pub struct Password(Vec<u8>);
+
+impl Password {
+    pub fn new(password: &[u8]) -> Self {
+        Password(password.to_vec())
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+// taking the bytes over, so that no copy of them is left behind
+impl From<Vec<u8>> for Password {
+    fn from(password: Vec<u8>) -> Self {
+        Password(password)
+    }
+}
+
+impl Drop for Password {
+    fn drop(&mut self) {
+        // all of it, including whatever's left past the end from before a truncation
+        self.0.resize(self.0.capacity(), 0);
+        wipe(&mut self.0);
+    }
+}
+
+impl fmt::Debug for Password {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Password(..)")
+    }
+}
+
+// how to encrypt a stream
+#[derive(Clone, Debug)]
+pub struct Encryption {
+    pub password: Password,
+    // how much deriving the key from the password costs, and so guessing it
+    pub kdf: KdfParams,
+}
+
+impl Encryption {
+    pub fn new(password: Password) -> Self {
+        Encryption {
+            password,
+            kdf: KdfParams::default_params(),
+        }
+    }
+}
+
+// what the header of an encrypted stream says about its key
+#[derive(Clone, PartialEq, Debug)]
+pub struct KeyHeader {
+    pub kdf: KdfParams,
+    pub salt: [u8; SALT_LENGTH],
+    // over the rest of the header, which comes before it
+    pub tag: [u8; TAG_LENGTH],
+}
+
+impl KeyHeader {
+    // with a fresh salt, and the tag to be filled in once the rest of the header is
+    // written
+    pub fn new(kdf: KdfParams) -> Self {
+        KeyHeader {
+            kdf,
+            salt: random_salt(),
+            tag: [0; TAG_LENGTH],
+        }
+    }
+
+    // as it's stored
+    pub fn length() -> usize {
+        1 + 4 + 4 + SALT_LENGTH + TAG_LENGTH
+    }
+
+    pub fn derive_key(&self, password: &Password) -> Key {
+        let mut key = [0; 32];
+        scrypt(password.as_bytes(), &self.salt, &self.kdf, &mut key);
+        key
+    }
+
+    pub fn write(&self, writer: &mut dyn io::Write) -> io::Result<()> {
+        writer.write_all(&[self.kdf.log_n])?;
+        writer.write_all(&self.kdf.r.to_le_bytes())?;
+        writer.write_all(&self.kdf.p.to_le_bytes())?;
+        writer.write_all(&self.salt)?;
+        writer.write_all(&self.tag)?;
+        Ok(())
+    }
+
+    pub fn read(reader: &mut dyn io::Read) -> io::Result<Self> {
+        let mut bytes = vec![0; Self::length()];
+        reader.read_exact(&mut bytes)?;
+        let kdf = KdfParams {
+            log_n: bytes[0],
+            r: u32::from_le_bytes(bytes[1..5].try_into().unwrap()),
+            p: u32::from_le_bytes(bytes[5..9].try_into().unwrap()),
+        };
+        kdf.check()
+            .map_err(|x| io::Error::new(io::ErrorKind::InvalidData, x))?;
+        Ok(KeyHeader {
+            kdf,
+            salt: bytes[9..9 + SALT_LENGTH].try_into().unwrap(),
+            tag: bytes[9 + SALT_LENGTH..].try_into().unwrap(),
+        })
+    }
+}
+
+fn nonce(purpose: u32, index: u64) -> Nonce {
+    let mut nonce = [0; 12];
+    nonce[..4].copy_from_slice(&purpose.to_le_bytes());
+    nonce[4..].copy_from_slice(&index.to_le_bytes());
+    nonce
+}
+
+// a tag over some bytes, without encrypting them
+fn tag(key: &Key, nonce: &Nonce, data: &[u8]) -> [u8; TAG_LENGTH] {
+    seal(key, nonce, data, &[]).try_into().unwrap()
+}
+
+// the tag over everything in the header before it
+pub fn header_tag(key: &Key, header: &[u8]) -> [u8; TAG_LENGTH] {
+    tag(key, &nonce(HEADER_NONCES, 0), header)
+}
+
+pub fn trailer_tag(key: &Key, trailer: &[u8]) -> [u8; TAG_LENGTH] {
+    tag(key, &nonce(TRAILER_NONCES, 0), trailer)
+}
+
+// encrypt a squashed block, the `index`th of its stream, adding its tag
+pub fn seal_block(key: &Key, index: u64, block: &[u8]) -> Vec<u8> {
+    seal(key, &nonce(BLOCK_NONCES, index), &[], block)
+}
+
+pub fn open_block(key: &Key, index: u64, sealed: &[u8]) -> Result<Vec<u8>, &'static str> {
+    open(key, &nonce(BLOCK_NONCES, index), &[], sealed).map_err(|_| "block fails authentication")
+}
+
+// a salt only needs to be different for every stream, not secret, so when there's no
+// system random source it's taken from the random keys std gives each hash map
+fn random_salt() -> [u8; SALT_LENGTH] {
+    let mut salt = [0; SALT_LENGTH];
+    let from_system = fs::File::open("/dev/urandom").and_then(|mut f| f.read_exact(&mut salt));
+    if from_system.is_err() {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |t| t.as_nanos());
+        for half in salt.chunks_exact_mut(8) {
+            let mut hasher = RandomState::new().build_hasher();
+            hasher.write_u128(now);
+            half.copy_from_slice(&hasher.finish().to_le_bytes());
+        }
+    }
+    salt
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn blocks_open_only_in_place() {
+        let key = [7; 32];
+        let sealed = seal_block(&key, 3, b"squashed");
+        assert_eq!(sealed.len(), 8 + TAG_LENGTH);
+        assert_eq!(open_block(&key, 3, &sealed), Ok(b"squashed".to_vec()));
+        assert!(open_block(&key, 4, &sealed).is_err());
+        assert!(open_block(&[8; 32], 3, &sealed).is_err());
+        // nor does anything else sealed under the same index
+        assert_ne!(header_tag(&key, b""), trailer_tag(&key, b""));
+        assert_ne!(&seal_block(&key, 0, b"")[..], &header_tag(&key, b"")[..]);
+    }
+
+    #[test]
+    fn password_not_shown() {
+        let shown = format!("{:?}", Encryption::new(Password::new(b"hunter2")));
+        assert!(shown.contains("password: Password(..)"), "{}", shown);
+        // as bytes, h u n
+        assert!(!shown.contains("104, 117, 110"), "{}", shown);
+    }
+
+    #[test]
+    fn key_header_round_trip() {
+        let header = KeyHeader::new(KdfParams::default_params());
+        assert_ne!(header.salt, KeyHeader::new(header.kdf).salt);
+        let mut bytes = vec![];
+        header.write(&mut bytes).unwrap();
+        assert_eq!(bytes.len(), KeyHeader::length());
+        assert_eq!(KeyHeader::read(&mut &bytes[..]).unwrap(), header);
+        // parameters that would take too much memory are refused
+        bytes[0] = 40;
+        assert!(KeyHeader::read(&mut &bytes[..]).is_err());
+        assert!(KeyHeader::read(&mut &bytes[..10]).is_err());
+    }
+}
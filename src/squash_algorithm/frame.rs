@@ -0,0 +1,255 @@
+use std::convert::TryInto;
+use std::io;
+
+use super::aead::{Key, TAG_LENGTH};
+use super::crc::crc32;
+use super::encryption::*;
+use super::parity::*;
+use super::squash::*;
+
+// starts every block and the trailer, so they can be found again in a damaged stream
+// (the first digits of e, much as bzip2 uses those of pi)
+pub const SYNC_MARKER: [u8; 6] = [0x27, 0x18, 0x28, 0x18, 0x28, 0x45];
+// starts each parity shard in place of the sync marker (the digits of e that follow)
+pub const PARITY_MARKER: [u8; 6] = [0x90, 0x45, 0x23, 0x53, 0x60, 0x28];
+
+// the end of a stream: a block length of zero, which no block can have, then the
+// stream's decoded length and its number of blocks
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Trailer {
+    pub length: u64,
+    pub blocks: u32,
+}
+
+// what follows the header: length-prefixed blocks, then the trailer. After the first
+// version, each of those starts with the sync marker, and a block's length is
+// followed by the checksum of what it decodes to. In streams with parity, each group
+// of blocks is followed by its parity shards, framed the same way but with the
+// parity marker and a checksum of the shard itself
+pub enum Frame {
+    Block {
+        block: Vec<u8>,
+        checksum: Option<u32>,
+    },
+    Parity(Shard),
+    End(Trailer),
+    // the magic number of another stream, where a block length would be. No block
+    // can be that long, so it's only ever the start of a stream
+    NextStream,
+}
+
+// a frame, as far as the start of its block
+pub enum FrameStart {
+    Block {
+        length: usize,
+        checksum: Option<u32>,
+    },
+    Parity {
+        length: usize,
+        checksum: u32,
+    },
+    End(Trailer),
+    NextStream,
+}
+
+// read the next block or the trailer, or None if the input ends first. Blocks longer
+// than the stream's block size allows are refused before anything is allocated for them
+pub fn read_frame(reader: &mut dyn io::Read, context: &StreamContext) -> io::Result<Option<Frame>> {
+    Ok(match read_frame_start(reader, context)? {
+        Some(FrameStart::Block { length, checksum }) => {
+            let mut block = vec![0; length];
+            reader.read_exact(&mut block)?;
+            Some(Frame::Block { block, checksum })
+        }
+        Some(FrameStart::Parity { length, checksum }) => {
+            let mut shard = vec![0; length];
+            reader.read_exact(&mut shard)?;
+            if crc32(&shard) != checksum {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "parity shard checksum mismatch",
+                ));
+            }
+            let shard = Shard::read(&shard, max_frame_length(context.block_size))
+                .map_err(|x| io::Error::new(io::ErrorKind::InvalidData, x))?;
+            Some(Frame::Parity(shard))
+        }
+        Some(FrameStart::End(trailer)) => Some(Frame::End(trailer)),
+        Some(FrameStart::NextStream) => Some(Frame::NextStream),
+        None => None,
+    })
+}
+
+pub fn read_frame_start(
+    reader: &mut dyn io::Read,
+    context: &StreamContext,
+) -> io::Result<Option<FrameStart>> {
+    let mut four_bytes: [u8; 4] = [0; 4];
+    match reader.read(&mut four_bytes)? {
+        0 => return Ok(None),
+        n => reader.read_exact(&mut four_bytes[n..])?,
+    }
+    if u32::from_le_bytes(four_bytes) == MAGIC_NUMBER {
+        return Ok(Some(FrameStart::NextStream));
+    }
+    if context.version > OLDEST_VERSION {
+        let mut marker = [0; 6];
+        marker[..4].copy_from_slice(&four_bytes);
+        reader.read_exact(&mut marker[4..])?;
+        if marker == PARITY_MARKER {
+            return read_parity_start(reader, context).map(Some);
+        }
+        if marker != SYNC_MARKER {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "missing sync marker",
+            ));
+        }
+        reader.read_exact(&mut four_bytes)?;
+    }
+    let block_len = u32::from_le_bytes(four_bytes);
+    if block_len == 0 {
+        if context.version == OLDEST_VERSION {
+            return Err(io::Error::other("empty block"));
+        }
+        let mut trailer = [0; 12];
+        reader.read_exact(&mut trailer)?;
+        // an encrypted stream's trailer is followed by its tag, which is checked
+        // whenever the key is known
+        if context.encrypted {
+            let mut tag = [0; TAG_LENGTH];
+            reader.read_exact(&mut tag)?;
+            if context
+                .key
+                .is_some_and(|key| trailer_tag(&key, &trailer) != tag)
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "trailer fails authentication",
+                ));
+            }
+        }
+        return Ok(Some(FrameStart::End(Trailer {
+            length: u64::from_le_bytes(trailer[0..8].try_into().unwrap()),
+            blocks: u32::from_le_bytes(trailer[8..12].try_into().unwrap()),
+        })));
+    }
+    let length: usize = block_len.try_into().unwrap();
+    if length > max_frame_length(context.block_size) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "block longer than the block size allows",
+        ));
+    }
+    let checksum = if context.version > OLDEST_VERSION {
+        reader.read_exact(&mut four_bytes)?;
+        Some(u32::from_le_bytes(four_bytes))
+    } else {
+        None
+    };
+    Ok(Some(FrameStart::Block { length, checksum }))
+}
+
+// fail on a parity shard in a stream whose header doesn't say it has them
+pub fn check_parity_expected(context: &StreamContext) -> io::Result<()> {
+    if context.parity.is_none() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "parity shard in a stream without parity",
+        ));
+    }
+    Ok(())
+}
+
+// the rest of a parity shard's frame start, once its marker has been read
+pub fn read_parity_start(
+    reader: &mut dyn io::Read,
+    context: &StreamContext,
+) -> io::Result<FrameStart> {
+    let mut eight_bytes = [0; 8];
+    reader.read_exact(&mut eight_bytes)?;
+    let length: usize = u32::from_le_bytes(eight_bytes[0..4].try_into().unwrap())
+        .try_into()
+        .unwrap();
+    // a shard is as long as the longest block of its group, after a description of
+    // each of them
+    if length > max_frame_length(context.block_size) + 7 + 256 * 12 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "parity shard longer than the block size allows",
+        ));
+    }
+    Ok(FrameStart::Parity {
+        length,
+        checksum: u32::from_le_bytes(eight_bytes[4..8].try_into().unwrap()),
+    })
+}
+
+// the bytes stored around each block in a given format version: its length, and
+// after the first version, the sync marker and checksum
+pub fn frame_overhead(version: u8) -> u64 {
+    if version > OLDEST_VERSION {
+        SYNC_MARKER.len() as u64 + 4 + 4
+    } else {
+        4
+    }
+}
+
+// the longest a block can be as stored. Its body is never bigger than its plaintext,
+// and it may have flags, a configuration and an op list before that, and a tag after it
+// when it's encrypted. Op lists take at most 18 bytes for every 64 bytes of plaintext,
+// since that's the shortest copy
+pub fn max_frame_length(block_size: usize) -> usize {
+    block_size + block_size / 2 + 64
+}
+
+// the zero block length, then the trailer itself, after the sync marker in versions
+// that have one, and before the tag in encrypted streams
+pub fn trailer_length(version: u8, encrypted: bool) -> u64 {
+    let marker = if version > OLDEST_VERSION {
+        SYNC_MARKER.len() as u64
+    } else {
+        0
+    };
+    let tag = if encrypted { TAG_LENGTH as u64 } else { 0 };
+    marker + 4 + 8 + 4 + tag
+}
+
+// write a trailer, tagged with the key of an encrypted stream
+pub fn write_trailer(
+    writer: &mut dyn io::Write,
+    trailer: &Trailer,
+    key: Option<&Key>,
+) -> io::Result<()> {
+    let mut bytes = trailer.length.to_le_bytes().to_vec();
+    bytes.extend_from_slice(&trailer.blocks.to_le_bytes());
+    writer.write_all(&SYNC_MARKER)?;
+    writer.write_all(&0_u32.to_le_bytes())?;
+    writer.write_all(&bytes)?;
+    if let Some(key) = key {
+        writer.write_all(&trailer_tag(key, &bytes))?;
+    }
+    Ok(())
+}
+
+// the next place at or after `from` where a block, a parity shard, a trailer or a
+// stream could start
+pub fn next_sync_point(data: &[u8], from: usize) -> usize {
+    let magic = MAGIC_NUMBER.to_le_bytes();
+    (from..data.len())
+        .find(|&i| {
+            let rest = &data[i..];
+            rest.starts_with(&SYNC_MARKER)
+                || rest.starts_with(&PARITY_MARKER)
+                || rest.starts_with(&magic)
+        })
+        .unwrap_or(data.len())
+}
+
+// whether bytes where a trailer should be are the start of a block or shard instead
+pub fn looks_like_frame(bytes: &[u8]) -> bool {
+    bytes.starts_with(&PARITY_MARKER)
+        || (bytes.starts_with(&SYNC_MARKER)
+            && bytes.len() >= 10
+            && bytes[6..10] != 0_u32.to_le_bytes())
+}
@@ -0,0 +1,508 @@
+// Deriving a key from a password with scrypt (RFC 7914), which is built on PBKDF2
+// with HMAC-SHA-256 and the Salsa20/8 core. Scrypt fills a table as big as its
+// parameters ask and then reads it back in an order that depends on what's in it, so
+// every guess at a password costs that much memory as well as time
+
+use std::convert::TryInto;
+use std::ptr;
+use std::sync::atomic::{compiler_fence, Ordering};
+
+const ROUND_CONSTANTS: [u32; 64] = [
+    0x428a_2f98,
+    0x7137_4491,
+    0xb5c0_fbcf,
+    0xe9b5_dba5,
+    0x3956_c25b,
+    0x59f1_11f1,
+    0x923f_82a4,
+    0xab1c_5ed5,
+    0xd807_aa98,
+    0x1283_5b01,
+    0x2431_85be,
+    0x550c_7dc3,
+    0x72be_5d74,
+    0x80de_b1fe,
+    0x9bdc_06a7,
+    0xc19b_f174,
+    0xe49b_69c1,
+    0xefbe_4786,
+    0x0fc1_9dc6,
+    0x240c_a1cc,
+    0x2de9_2c6f,
+    0x4a74_84aa,
+    0x5cb0_a9dc,
+    0x76f9_88da,
+    0x983e_5152,
+    0xa831_c66d,
+    0xb003_27c8,
+    0xbf59_7fc7,
+    0xc6e0_0bf3,
+    0xd5a7_9147,
+    0x06ca_6351,
+    0x1429_2967,
+    0x27b7_0a85,
+    0x2e1b_2138,
+    0x4d2c_6dfc,
+    0x5338_0d13,
+    0x650a_7354,
+    0x766a_0abb,
+    0x81c2_c92e,
+    0x9272_2c85,
+    0xa2bf_e8a1,
+    0xa81a_664b,
+    0xc24b_8b70,
+    0xc76c_51a3,
+    0xd192_e819,
+    0xd699_0624,
+    0xf40e_3585,
+    0x106a_a070,
+    0x19a4_c116,
+    0x1e37_6c08,
+    0x2748_774c,
+    0x34b0_bcb5,
+    0x391c_0cb3,
+    0x4ed8_aa4a,
+    0x5b9c_ca4f,
+    0x682e_6ff3,
+    0x748f_82ee,
+    0x78a5_636f,
+    0x84c8_7814,
+    0x8cc7_0208,
+    0x90be_fffa,
+    0xa450_6ceb,
+    0xbef9_a3f7,
+    0xc671_78f2,
+];
+
+const INITIAL_STATE: [u32; 8] = [
+    0x6a09_e667,
+    0xbb67_ae85,
+    0x3c6e_f372,
+    0xa54f_f53a,
+    0x510e_527f,
+    0x9b05_688c,
+    0x1f83_d9ab,
+    0x5be0_cd19,
+];
+
+// the most memory a key derivation may take, whatever a header asks for
+const MAX_MEMORY: u64 = 1 << 30;
+// and the most times over it may be done
+const MAX_PARALLELISM: u32 = 16;
+
+// the cost of deriving a key: scrypt's N = 2^log_n, r and p
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct KdfParams {
+    // the table has 2^log_n entries
+    pub log_n: u8,
+    // each of 128 * r bytes
+    pub r: u32,
+    // and is filled this many times over
+    pub p: u32,
+}
+
+impl KdfParams {
+    // 32M of memory, which takes a tenth of a second or so
+    pub fn default_params() -> Self {
+        KdfParams {
+            log_n: 15,
+            r: 8,
+            p: 1,
+        }
+    }
+
+    // how much memory deriving a key takes, in bytes
+    pub fn memory(&self) -> Option<u64> {
+        1_u64
+            .checked_shl(self.log_n.into())
+            .and_then(|n| n.checked_mul(128 * u64::from(self.r)))
+    }
+
+    // check that the parameters are ones scrypt takes, and don't cost too much
+    pub fn check(&self) -> Result<(), &'static str> {
+        if self.log_n == 0 || self.r == 0 || self.p == 0 {
+            return Err("key derivation parameters out of range");
+        }
+        if self.p > MAX_PARALLELISM || self.memory().unwrap_or(u64::MAX) > MAX_MEMORY {
+            return Err("key derivation takes more memory than allowed");
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+struct Sha256 {
+    state: [u32; 8],
+    // input waiting for a whole block
+    pending: Vec<u8>,
+    length: u64,
+}
+
+impl Sha256 {
+    fn new() -> Self {
+        Sha256 {
+            state: INITIAL_STATE,
+            pending: Vec::with_capacity(64),
+            length: 0,
+        }
+    }
+
+    fn update(&mut self, mut data: &[u8]) {
+        self.length += data.len() as u64;
+        if !self.pending.is_empty() {
+            let taken = data.len().min(64 - self.pending.len());
+            self.pending.extend_from_slice(&data[..taken]);
+            data = &data[taken..];
+            if self.pending.len() < 64 {
+                return;
+            }
+            compress(&mut self.state, &self.pending);
+            self.pending.clear();
+        }
+        let mut blocks = data.chunks_exact(64);
+        for block in &mut blocks {
+            compress(&mut self.state, block);
+        }
+        self.pending.extend_from_slice(blocks.remainder());
+    }
+
+    fn finish(mut self) -> [u8; 32] {
+        let bits = self.length * 8;
+        self.update(&[0x80]);
+        while self.pending.len() != 56 {
+            self.update(&[0]);
+        }
+        self.update(&bits.to_be_bytes());
+        let mut hash = [0; 32];
+        for (bytes, word) in hash.chunks_exact_mut(4).zip(self.state) {
+            bytes.copy_from_slice(&word.to_be_bytes());
+        }
+        hash
+    }
+}
+
+// run one 64-byte block through the compression function
+fn compress(state: &mut [u32; 8], block: &[u8]) {
+    let mut schedule = [0_u32; 64];
+    for (word, bytes) in schedule.iter_mut().zip(block.chunks_exact(4)) {
+        *word = u32::from_be_bytes(bytes.try_into().unwrap());
+    }
+    for i in 16..64 {
+        let (w15, w2) = (schedule[i - 15], schedule[i - 2]);
+        let s0 = w15.rotate_right(7) ^ w15.rotate_right(18) ^ (w15 >> 3);
+        let s1 = w2.rotate_right(17) ^ w2.rotate_right(19) ^ (w2 >> 10);
+        schedule[i] = schedule[i - 16]
+            .wrapping_add(s0)
+            .wrapping_add(schedule[i - 7])
+            .wrapping_add(s1);
+    }
+    let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = *state;
+    for (k, w) in ROUND_CONSTANTS.iter().zip(schedule) {
+        let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+        let choice = (e & f) ^ (!e & g);
+        let t1 = h
+            .wrapping_add(s1)
+            .wrapping_add(choice)
+            .wrapping_add(*k)
+            .wrapping_add(w);
+        let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+        let majority = (a & b) ^ (a & c) ^ (b & c);
+        let t2 = s0.wrapping_add(majority);
+        h = g;
+        g = f;
+        f = e;
+        e = d.wrapping_add(t1);
+        d = c;
+        c = b;
+        b = a;
+        a = t1.wrapping_add(t2);
+    }
+    for (word, x) in state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+        *word = word.wrapping_add(x);
+    }
+}
+
+#[cfg(test)]
+fn sha256(data: &[u8]) -> [u8; 32] {
+    let mut hash = Sha256::new();
+    hash.update(data);
+    hash.finish()
+}
+
+// HMAC-SHA-256, keyed once and cloned for each message
+#[derive(Clone)]
+struct Hmac {
+    inner: Sha256,
+    outer: Sha256,
+}
+
+impl Hmac {
+    fn new(key: &[u8]) -> Self {
+        let mut block = [0; 64];
+        if key.len() > 64 {
+            let mut hash = Sha256::new();
+            hash.update(key);
+            block[..32].copy_from_slice(&hash.finish());
+        } else {
+            block[..key.len()].copy_from_slice(key);
+        }
+        let mut inner = Sha256::new();
+        inner.update(&block.map(|b| b ^ 0x36));
+        let mut outer = Sha256::new();
+        outer.update(&block.map(|b| b ^ 0x5c));
+        wipe(&mut block);
+        Hmac { inner, outer }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        self.inner.update(data);
+    }
+
+    fn finish(self) -> [u8; 32] {
+        let mut outer = self.outer;
+        outer.update(&self.inner.finish());
+        outer.finish()
+    }
+}
+
+// PBKDF2 with HMAC-SHA-256, filling `out`
+fn pbkdf2(password: &[u8], salt: &[u8], iterations: u32, out: &mut [u8]) {
+    let keyed = Hmac::new(password);
+    for (i, chunk) in out.chunks_mut(32).enumerate() {
+        let mut mac = keyed.clone();
+        mac.update(salt);
+        mac.update(&(i as u32 + 1).to_be_bytes());
+        let mut u = mac.finish();
+        let mut t = u;
+        for _ in 1..iterations {
+            let mut mac = keyed.clone();
+            mac.update(&u);
+            u = mac.finish();
+            for (t, u) in t.iter_mut().zip(u) {
+                *t ^= u;
+            }
+        }
+        chunk.copy_from_slice(&t[..chunk.len()]);
+    }
+}
+
+fn salsa_quarter_round(x: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    x[b] ^= x[a].wrapping_add(x[d]).rotate_left(7);
+    x[c] ^= x[b].wrapping_add(x[a]).rotate_left(9);
+    x[d] ^= x[c].wrapping_add(x[b]).rotate_left(13);
+    x[a] ^= x[d].wrapping_add(x[c]).rotate_left(18);
+}
+
+// the Salsa20 core, cut to 8 rounds, on a 64-byte block as little-endian words
+fn salsa20_8(block: &mut [u32; 16]) {
+    let mut x = *block;
+    for _ in 0..4 {
+        // columns, then rows
+        salsa_quarter_round(&mut x, 0, 4, 8, 12);
+        salsa_quarter_round(&mut x, 5, 9, 13, 1);
+        salsa_quarter_round(&mut x, 10, 14, 2, 6);
+        salsa_quarter_round(&mut x, 15, 3, 7, 11);
+        salsa_quarter_round(&mut x, 0, 1, 2, 3);
+        salsa_quarter_round(&mut x, 5, 6, 7, 4);
+        salsa_quarter_round(&mut x, 10, 11, 8, 9);
+        salsa_quarter_round(&mut x, 15, 12, 13, 14);
+    }
+    for (word, x) in block.iter_mut().zip(x) {
+        *word = word.wrapping_add(x);
+    }
+}
+
+// scrypt's BlockMix, from 2r 64-byte blocks of input to as many of output: each is
+// mixed into the last, the evens going to the first half of the output and the odds
+// to the second
+fn block_mix(input: &[u32], output: &mut [u32], r: usize) {
+    let mut x: [u32; 16] = input[(2 * r - 1) * 16..].try_into().unwrap();
+    for (i, block) in input.chunks_exact(16).enumerate() {
+        for (x, word) in x.iter_mut().zip(block) {
+            *x ^= word;
+        }
+        salsa20_8(&mut x);
+        let to = i / 2 + (i % 2) * r;
+        output[to * 16..(to + 1) * 16].copy_from_slice(&x);
+    }
+}
+
+// scrypt's ROMix: fill a table of n entries by mixing the block over and over, then
+// mix it with n entries taken from the table wherever the block itself says
+fn ro_mix(block: &mut [u32], n: usize, r: usize) {
+    let words = 32 * r;
+    let mut table = vec![0; words * n];
+    let mut x = block.to_vec();
+    let mut y = vec![0; words];
+    for entry in table.chunks_exact_mut(words) {
+        entry.copy_from_slice(&x);
+        block_mix(&x, &mut y, r);
+        std::mem::swap(&mut x, &mut y);
+    }
+    for _ in 0..n {
+        // the first word of the last 64 bytes, and n is a power of two
+        let j = x[(2 * r - 1) * 16] as usize & (n - 1);
+        for (x, entry) in x.iter_mut().zip(&table[j * words..(j + 1) * words]) {
+            *x ^= entry;
+        }
+        block_mix(&x, &mut y, r);
+        std::mem::swap(&mut x, &mut y);
+    }
+    block.copy_from_slice(&x);
+}
+
+// derive a key from a password and salt, filling `out`. The parameters must have
+// been checked
+pub fn scrypt(password: &[u8], salt: &[u8], params: &KdfParams, out: &mut [u8]) {
+    let (r, p) = (params.r as usize, params.p as usize);
+    let mut bytes = vec![0; p * 128 * r];
+    pbkdf2(password, salt, 1, &mut bytes);
+    let mut words: Vec<u32> = bytes
+        .chunks_exact(4)
+        .map(|w| u32::from_le_bytes(w.try_into().unwrap()))
+        .collect();
+    for block in words.chunks_exact_mut(32 * r) {
+        ro_mix(block, 1 << params.log_n, r);
+    }
+    for (bytes, word) in bytes.chunks_exact_mut(4).zip(words) {
+        bytes.copy_from_slice(&word.to_le_bytes());
+    }
+    pbkdf2(password, &bytes, 1, out);
+}
+
+// overwrite secrets with zeros once they're done with, in a way the compiler can't
+// leave out because they're never read again
+pub fn wipe(bytes: &mut [u8]) {
+    for byte in bytes {
+        // safe, since it's a valid, aligned &mut u8
+        unsafe { ptr::write_volatile(byte, 0) };
+    }
+    compiler_fence(Ordering::SeqCst);
+}
+
+#[cfg(test)]
+pub fn from_hex(hex: &str) -> Vec<u8> {
+    let digits: Vec<u8> = hex.bytes().filter(u8::is_ascii_hexdigit).collect();
+    digits
+        .chunks_exact(2)
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).unwrap(), 16).unwrap())
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sha256_known_answers() {
+        // FIPS 180-2, appendix B
+        assert_eq!(
+            sha256(b"abc").to_vec(),
+            from_hex("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad")
+        );
+        assert_eq!(
+            sha256(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq").to_vec(),
+            from_hex("248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1")
+        );
+        // fed in pieces that straddle the blocks
+        let mut hash = Sha256::new();
+        for _ in 0..1000 {
+            hash.update(&[b'a'; 1000]);
+        }
+        assert_eq!(
+            hash.finish().to_vec(),
+            from_hex("cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0")
+        );
+    }
+
+    #[test]
+    fn hmac_known_answers() {
+        // RFC 4231, test cases 2 and 6
+        let mut mac = Hmac::new(b"Jefe");
+        mac.update(b"what do ya want for nothing?");
+        assert_eq!(
+            mac.finish().to_vec(),
+            from_hex("5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843")
+        );
+        let mut mac = Hmac::new(&[0xaa; 131]);
+        mac.update(b"Test Using Larger Than Block-Size Key - Hash Key First");
+        assert_eq!(
+            mac.finish().to_vec(),
+            from_hex("60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54")
+        );
+    }
+
+    #[test]
+    fn scrypt_known_answers() {
+        // RFC 7914, section 8
+        let input = from_hex(
+            "7e879a214f3ec9867ca940e641718f26baee555b8c61c1b50df846116dcd3b1d
+             ee24f319df9b3d8514121e4b5ac5aa3276021d2909c74829edebc68db8b8c25e",
+        );
+        let mut block: [u32; 16] = [0; 16];
+        for (word, bytes) in block.iter_mut().zip(input.chunks_exact(4)) {
+            *word = u32::from_le_bytes(bytes.try_into().unwrap());
+        }
+        salsa20_8(&mut block);
+        let output: Vec<u8> = block.iter().flat_map(|w| w.to_le_bytes()).collect();
+        assert_eq!(
+            output,
+            from_hex(
+                "a41f859c6608cc993b81cacb020cef05044b2181a2fd337dfd7b1c6396682f29
+                 b4393168e3c9e6bcfe6bc5b7a06d96bae424cc102c91745c24ad673dc7618f81"
+            )
+        );
+
+        // section 11
+        let mut out = [0; 64];
+        pbkdf2(b"passwd", b"salt", 1, &mut out);
+        assert_eq!(
+            out.to_vec(),
+            from_hex(
+                "55ac046e56e3089fec1691c22544b605f94185216dde0465e68b9d57c20dacbc
+                 49ca9cccf179b645991664b39d77ef317c71b845b1e30bd509112041d3a19783"
+            )
+        );
+
+        // section 12
+        let params = |log_n, r, p| KdfParams { log_n, r, p };
+        scrypt(b"", b"", &params(4, 1, 1), &mut out);
+        assert_eq!(
+            out.to_vec(),
+            from_hex(
+                "77d6576238657b203b19ca42c18a0497f16b4844e3074ae8dfdffa3fede21442
+                 fcd0069ded0948f8326a753a0fc81f17e8d3e0fb2e0d3628cf35e20c38d18906"
+            )
+        );
+        scrypt(b"password", b"NaCl", &params(10, 8, 16), &mut out);
+        assert_eq!(
+            out.to_vec(),
+            from_hex(
+                "fdbabe1c9d3472007856e7190d01e9fe7c6ad7cbc8237830e77376634b373162
+                 2eaf30d92e22a3886ff109279d9830dac727afb94a83ee6d8360cbdfa2cc0640"
+            )
+        );
+    }
+
+    #[test]
+    fn params() {
+        assert_eq!(KdfParams::default_params().memory(), Some(32 << 20));
+        assert!(KdfParams::default_params().check().is_ok());
+        for (log_n, r, p) in [
+            (0, 8, 1),
+            (15, 0, 1),
+            (15, 8, 0),
+            (15, 8, 17),
+            (24, 8, 1),
+            (200, 1, 1),
+        ] {
+            assert!(
+                KdfParams { log_n, r, p }.check().is_err(),
+                "{} {} {}",
+                log_n,
+                r,
+                p
+            );
+        }
+    }
+}
@@ -0,0 +1,218 @@
+use std::convert::TryFrom;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Details of the original file, optionally stored in the stream header
+// so that they can be restored on decompression.
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct Metadata {
+    // the file's name, without any directories
+    pub name: Option<String>,
+    pub size: Option<u64>,
+    pub mtime: Option<SystemTime>,
+    // unix permission bits
+    pub mode: Option<u32>,
+}
+
+// which fields are present, in the first byte of the metadata section
+const HAS_NAME: u8 = 1;
+const HAS_SIZE: u8 = 2;
+const HAS_MTIME: u8 = 4;
+const HAS_MODE: u8 = 8;
+const KNOWN_FIELDS: u8 = HAS_NAME | HAS_SIZE | HAS_MTIME | HAS_MODE;
+
+impl Metadata {
+    // collect the metadata of a file on disk. Names that aren't valid UTF-8 are left out
+    pub fn from_file(path: &Path) -> io::Result<Self> {
+        let fs_metadata = fs::metadata(path)?;
+        Ok(Metadata {
+            name: path.file_name().and_then(|n| n.to_str()).map(String::from),
+            size: Some(fs_metadata.len()),
+            mtime: fs_metadata.modified().ok(),
+            mode: file_mode(&fs_metadata),
+        })
+    }
+
+    // set a file's mtime and permissions to the stored ones
+    pub fn restore(&self, file: &fs::File) -> io::Result<()> {
+        if let Some(mtime) = self.mtime {
+            file.set_modified(mtime)?;
+        }
+        if let Some(mode) = self.mode {
+            set_file_mode(file, mode)?;
+        }
+        Ok(())
+    }
+
+    pub fn write(&self, writer: &mut dyn io::Write) -> io::Result<()> {
+        let mut fields = 0;
+        if self.name.is_some() {
+            fields |= HAS_NAME;
+        }
+        if self.size.is_some() {
+            fields |= HAS_SIZE;
+        }
+        if self.mtime.is_some() {
+            fields |= HAS_MTIME;
+        }
+        if self.mode.is_some() {
+            fields |= HAS_MODE;
+        }
+        writer.write_all(&[fields])?;
+        if let Some(name) = &self.name {
+            let len =
+                u16::try_from(name.len()).map_err(|_| io::Error::other("file name is too long"))?;
+            writer.write_all(&len.to_le_bytes())?;
+            writer.write_all(name.as_bytes())?;
+        }
+        if let Some(size) = self.size {
+            writer.write_all(&size.to_le_bytes())?;
+        }
+        if let Some(mtime) = self.mtime {
+            // seconds either side of the epoch, plus nanoseconds forward from there
+            let (seconds, nanos) = match mtime.duration_since(UNIX_EPOCH) {
+                Ok(after) => (after.as_secs() as i64, after.subsec_nanos()),
+                Err(before) => {
+                    let before = before.duration();
+                    match before.subsec_nanos() {
+                        0 => (-(before.as_secs() as i64), 0),
+                        n => (-(before.as_secs() as i64) - 1, 1_000_000_000 - n),
+                    }
+                }
+            };
+            writer.write_all(&seconds.to_le_bytes())?;
+            writer.write_all(&nanos.to_le_bytes())?;
+        }
+        if let Some(mode) = self.mode {
+            writer.write_all(&mode.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    pub fn read(reader: &mut dyn io::Read) -> io::Result<Self> {
+        let mut one_byte = [0; 1];
+        let mut two_bytes = [0; 2];
+        let mut four_bytes = [0; 4];
+        let mut eight_bytes = [0; 8];
+        reader.read_exact(&mut one_byte)?;
+        let fields = one_byte[0];
+        if fields & !KNOWN_FIELDS != 0 {
+            return Err(io::Error::other("unsupported metadata fields"));
+        }
+        let mut metadata = Metadata::default();
+        if fields & HAS_NAME != 0 {
+            reader.read_exact(&mut two_bytes)?;
+            let mut name = vec![0; usize::from(u16::from_le_bytes(two_bytes))];
+            reader.read_exact(&mut name)?;
+            metadata.name = Some(
+                String::from_utf8(name)
+                    .map_err(|_| io::Error::other("stored file name isn't UTF-8"))?,
+            );
+        }
+        if fields & HAS_SIZE != 0 {
+            reader.read_exact(&mut eight_bytes)?;
+            metadata.size = Some(u64::from_le_bytes(eight_bytes));
+        }
+        if fields & HAS_MTIME != 0 {
+            reader.read_exact(&mut eight_bytes)?;
+            let seconds = i64::from_le_bytes(eight_bytes);
+            reader.read_exact(&mut four_bytes)?;
+            let nanos = u32::from_le_bytes(four_bytes);
+            if nanos >= 1_000_000_000 {
+                return Err(io::Error::other("malformed mtime"));
+            }
+            let mtime = if seconds >= 0 {
+                UNIX_EPOCH.checked_add(Duration::new(seconds as u64, nanos))
+            } else {
+                UNIX_EPOCH
+                    .checked_sub(Duration::from_secs(seconds.unsigned_abs()))
+                    .and_then(|t| t.checked_add(Duration::from_nanos(u64::from(nanos))))
+            };
+            metadata.mtime = Some(mtime.ok_or_else(|| io::Error::other("mtime out of range"))?);
+        }
+        if fields & HAS_MODE != 0 {
+            reader.read_exact(&mut four_bytes)?;
+            metadata.mode = Some(u32::from_le_bytes(four_bytes));
+        }
+        Ok(metadata)
+    }
+}
+
+#[cfg(unix)]
+fn file_mode(fs_metadata: &fs::Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(fs_metadata.permissions().mode() & 0o7777)
+}
+
+#[cfg(not(unix))]
+fn file_mode(fs_metadata: &fs::Metadata) -> Option<u32> {
+    Some(if fs_metadata.permissions().readonly() {
+        0o444
+    } else {
+        0o644
+    })
+}
+
+// only the permissions are restored: the stream can't be trusted with setuid, setgid
+// or sticky bits
+#[cfg(unix)]
+fn set_file_mode(file: &fs::File, mode: u32) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    file.set_permissions(fs::Permissions::from_mode(mode & 0o777))
+}
+
+#[cfg(not(unix))]
+fn set_file_mode(file: &fs::File, mode: u32) -> io::Result<()> {
+    let mut permissions = file.metadata()?.permissions();
+    permissions.set_readonly(mode & 0o222 == 0);
+    file.set_permissions(permissions)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn metadata_serialisation() {
+        let all = Metadata {
+            name: Some(String::from("squashed ünïcödé.txt")),
+            size: Some(123_456_789_012),
+            mtime: Some(UNIX_EPOCH + Duration::new(1_600_000_000, 123_456_789)),
+            mode: Some(0o640),
+        };
+        let before_epoch = Metadata {
+            mtime: Some(UNIX_EPOCH - Duration::new(1_000, 250)),
+            ..Metadata::default()
+        };
+        for metadata in &[all, before_epoch, Metadata::default()] {
+            let mut serialised = vec![];
+            metadata.write(&mut serialised).unwrap();
+            let read = Metadata::read(&mut &serialised[..]).unwrap();
+            assert_eq!(&read, metadata);
+        }
+        assert!(Metadata::read(&mut &[0x10][..]).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn special_mode_bits_not_restored() {
+        use std::os::unix::fs::PermissionsExt;
+        let setuid = Metadata {
+            mode: Some(0o4755),
+            ..Metadata::default()
+        };
+        let mut serialised = vec![];
+        setuid.write(&mut serialised).unwrap();
+        let path = std::env::temp_dir().join(format!("squash-mode-{}", std::process::id()));
+        let file = fs::File::create(&path).unwrap();
+        Metadata::read(&mut &serialised[..])
+            .unwrap()
+            .restore(&file)
+            .unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(mode & 0o7777, 0o755);
+    }
+}
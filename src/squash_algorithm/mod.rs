@@ -1,5 +1,38 @@
+mod aead;
+mod analysis;
 mod arithmetic;
+mod cancel;
+mod crc;
+mod dedup;
+mod encryption;
+mod frame;
+mod kdf;
+mod metadata;
+mod observer;
+mod parity;
+#[cfg(test)]
+mod properties;
+mod repair;
 mod squash;
+mod text;
 mod transforms;
 
-pub use self::squash::{squash, unsquash};
+pub use self::analysis::{analyze, BlockAnalysis, Stage};
+pub use self::arithmetic::{ArithmeticEncoder, Model};
+pub(crate) use self::cancel::Cancel;
+pub use self::cancel::{is_cancelled, Cancelled};
+pub use self::dedup::DEFAULT_WINDOW as DEFAULT_DEDUP_WINDOW;
+pub use self::encryption::{Encryption, Password};
+pub use self::frame::Trailer;
+pub use self::kdf::KdfParams;
+pub use self::metadata::Metadata;
+pub use self::observer::{Event, Observer};
+pub use self::parity::DEFAULT_PARITY;
+pub use self::repair::{
+    recover, repair, repair_with_options, FlippedBit, LostRange, Recovery, Repair, RepairOptions,
+};
+pub use self::squash::{
+    inspect, is_encrypted, read_metadata, squash, squash_with_observer, squash_with_options,
+    unsquash, unsquash_with_limits, unsquash_with_observer, unsquash_with_password, BlockInfo,
+    BlockType, DecodeLimits, FrontMatter, SquashOptions, SquashWriter, StreamInfo, DEFAULT_LEVEL,
+};
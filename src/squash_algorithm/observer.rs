@@ -0,0 +1,41 @@
+use std::time::Duration;
+
+// what's happening to a stream as it's squashed or unsquashed. Sizes are in bytes,
+// with input being what was read and output what was written, so when squashing a
+// block's input is plaintext and its output is the block as stored (with its
+// length), and when unsquashing it's the other way around
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Event {
+    // the stream header has been written or read, which happens again for each
+    // stream when several are unsquashed one after another
+    Header {
+        length: u64,
+    },
+    BlockStarted {
+        index: usize,
+        input_size: u64,
+    },
+    BlockFinished {
+        index: usize,
+        input_size: u64,
+        output_size: u64,
+        elapsed: Duration,
+    },
+    // the whole stream is done, header included
+    Finished {
+        input_size: u64,
+        output_size: u64,
+        elapsed: Duration,
+    },
+}
+
+// something to tell about progress, for progress bars or metrics
+pub trait Observer {
+    fn observe(&mut self, event: &Event);
+}
+
+impl<F: FnMut(&Event)> Observer for F {
+    fn observe(&mut self, event: &Event) {
+        self(event)
+    }
+}
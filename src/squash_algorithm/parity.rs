@@ -0,0 +1,338 @@
+// Reed–Solomon parity over groups of blocks, so that damaged or missing blocks can be
+// rebuilt. Each group of blocks, as stored, gets a number of parity shards as long as
+// its longest block, and any of the group's blocks can be rebuilt as long as no more
+// of them are lost than there are shards left. The code is systematic over GF(2^8):
+// the blocks are kept as they are, and each shard is a sum of them weighted by a row
+// of a Cauchy matrix, every square submatrix of which is invertible
+
+use std::convert::TryFrom;
+use std::convert::TryInto;
+
+// blocks in each group, except the last
+pub const GROUP_BLOCKS: usize = 20;
+
+// the parity percentage suggested for archives: a shard for each group
+pub const DEFAULT_PARITY: u8 = 5;
+
+// x^8 + x^4 + x^3 + x^2 + 1, for which 2 generates every nonzero element
+const POLYNOMIAL: u16 = 0x11d;
+
+struct Tables {
+    // powers of 2, twice over so that a sum of two logs can index it directly
+    exp: [u8; 510],
+    log: [u8; 256],
+}
+
+const TABLES: Tables = tables();
+
+const fn tables() -> Tables {
+    let mut exp = [0; 510];
+    let mut log = [0; 256];
+    let mut x: u16 = 1;
+    let mut i = 0;
+    while i < 255 {
+        exp[i] = x as u8;
+        exp[i + 255] = x as u8;
+        log[x as usize] = i as u8;
+        x <<= 1;
+        if x & 0x100 != 0 {
+            x ^= POLYNOMIAL;
+        }
+        i += 1;
+    }
+    Tables { exp, log }
+}
+
+fn mul(a: u8, b: u8) -> u8 {
+    if a == 0 || b == 0 {
+        return 0;
+    }
+    TABLES.exp[TABLES.log[a as usize] as usize + TABLES.log[b as usize] as usize]
+}
+
+// of a nonzero element
+fn inverse(a: u8) -> u8 {
+    TABLES.exp[255 - TABLES.log[a as usize] as usize]
+}
+
+// the weight of block `block` in shard `shard`: 1 / (x + y), with the shards' x
+// counting down from 255 and the blocks' y up from 0, so that they never meet while
+// there are no more than 256 of them between them
+fn coefficient(shard: usize, block: usize) -> u8 {
+    inverse((255 - shard as u8) ^ block as u8)
+}
+
+// dst += c * src, byte by byte
+fn mul_add(dst: &mut [u8], c: u8, src: &[u8]) {
+    let products: [u8; 256] = std::array::from_fn(|x| mul(c, x as u8));
+    for (d, s) in dst.iter_mut().zip(src) {
+        *d ^= products[*s as usize];
+    }
+}
+
+// what a shard records about each block of its group, so that a lost block can be
+// rebuilt whole, framing and all
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Slot {
+    // as stored
+    pub length: u32,
+    // of what the block decodes to, as in its frame
+    pub checksum: u32,
+    // what the block decodes to, so that what follows it can be decoded even while
+    // it's missing
+    pub decoded_length: u32,
+}
+
+// one parity shard of a group of blocks
+#[derive(Clone, PartialEq, Debug)]
+pub struct Shard {
+    // the index in the stream of the group's first block
+    pub group_start: u32,
+    // which of the group's shards this is, and how many there are
+    pub index: u8,
+    pub shards: u8,
+    pub slots: Vec<Slot>,
+    pub data: Vec<u8>,
+}
+
+// how many parity shards a group of `blocks` blocks gets, for parity of `percent`
+// percent
+pub fn shards_for(blocks: usize, percent: u8) -> usize {
+    (blocks * usize::from(percent)).div_ceil(100).max(1)
+}
+
+// the parity shards of a group of blocks, each given as stored with its slot
+pub fn encode(group_start: u32, blocks: &[(&[u8], Slot)], shards: usize) -> Vec<Shard> {
+    let length = blocks.iter().map(|(b, _)| b.len()).max().unwrap_or(0);
+    let slots: Vec<Slot> = blocks.iter().map(|(_, slot)| *slot).collect();
+    (0..shards)
+        .map(|index| {
+            let mut data = vec![0; length];
+            for (block, (bytes, _)) in blocks.iter().enumerate() {
+                mul_add(&mut data, coefficient(index, block), bytes);
+            }
+            Shard {
+                group_start,
+                index: u8::try_from(index).unwrap(),
+                shards: u8::try_from(shards).unwrap(),
+                slots: slots.clone(),
+                data,
+            }
+        })
+        .collect()
+}
+
+// fill in the missing blocks of a group from the others and its intact shards, all of
+// which must belong to it
+pub fn rebuild(blocks: &mut [Option<Vec<u8>>], shards: &[&Shard]) -> Result<(), &'static str> {
+    let missing: Vec<usize> = (0..blocks.len()).filter(|&b| blocks[b].is_none()).collect();
+    if missing.is_empty() {
+        return Ok(());
+    }
+    if missing.len() > shards.len() {
+        return Err("more blocks lost than there are parity shards to rebuild them");
+    }
+    let shards = &shards[..missing.len()];
+
+    // each shard, less the blocks that are there, is a sum of the missing ones
+    let mut sums: Vec<Vec<u8>> = shards
+        .iter()
+        .map(|shard| {
+            let mut sum = shard.data.clone();
+            for (block, bytes) in blocks.iter().enumerate() {
+                if let Some(bytes) = bytes {
+                    mul_add(&mut sum, coefficient(shard.index.into(), block), bytes);
+                }
+            }
+            sum
+        })
+        .collect();
+
+    // so invert the matrix of their weights, by Gauss-Jordan elimination on the
+    // sums alongside it
+    let mut matrix: Vec<Vec<u8>> = shards
+        .iter()
+        .map(|shard| {
+            missing
+                .iter()
+                .map(|&block| coefficient(shard.index.into(), block))
+                .collect()
+        })
+        .collect();
+    for column in 0..missing.len() {
+        let pivot = (column..missing.len())
+            .find(|&row| matrix[row][column] != 0)
+            .ok_or("parity shards don't determine the missing blocks")?;
+        matrix.swap(column, pivot);
+        sums.swap(column, pivot);
+        let scale = inverse(matrix[column][column]);
+        for x in matrix[column].iter_mut() {
+            *x = mul(*x, scale);
+        }
+        let scaled: Vec<u8> = sums[column].iter().map(|x| mul(*x, scale)).collect();
+        sums[column] = scaled;
+        for row in 0..missing.len() {
+            let factor = matrix[row][column];
+            if row == column || factor == 0 {
+                continue;
+            }
+            let pivot_row = matrix[column].clone();
+            mul_add(&mut matrix[row], factor, &pivot_row);
+            let pivot_sum = sums[column].clone();
+            mul_add(&mut sums[row], factor, &pivot_sum);
+        }
+    }
+
+    for (sum, &block) in sums.into_iter().zip(&missing) {
+        let length = shards[0].slots[block].length as usize;
+        if length > sum.len() {
+            return Err("block longer than its parity");
+        }
+        blocks[block] = Some(sum[..length].to_vec());
+    }
+    Ok(())
+}
+
+impl Shard {
+    // as stored, not counting its frame
+    pub fn length(&self) -> usize {
+        7 + self.slots.len() * 12 + self.data.len()
+    }
+
+    pub fn write(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.group_start.to_le_bytes());
+        out.push(u8::try_from(self.slots.len()).unwrap());
+        out.push(self.shards);
+        out.push(self.index);
+        for slot in &self.slots {
+            out.extend_from_slice(&slot.length.to_le_bytes());
+            out.extend_from_slice(&slot.checksum.to_le_bytes());
+            out.extend_from_slice(&slot.decoded_length.to_le_bytes());
+        }
+        out.extend_from_slice(&self.data);
+    }
+
+    // read a shard, checking that it describes a group that could have been written
+    pub fn read(bytes: &[u8], max_block: usize) -> Result<Shard, &'static str> {
+        if bytes.len() < 7 {
+            return Err("parity shard too short");
+        }
+        let group_start = u32::from_le_bytes(bytes[0..4].try_into().unwrap());
+        let (blocks, shards, index) = (bytes[4], bytes[5], bytes[6]);
+        if blocks == 0 || shards == 0 || index >= shards {
+            return Err("parity shard out of range");
+        }
+        // beyond this, the shards' and blocks' weights would meet
+        if usize::from(blocks) + usize::from(shards) > 256 {
+            return Err("too many blocks and parity shards in a group");
+        }
+        let slots_end = 7 + usize::from(blocks) * 12;
+        if bytes.len() < slots_end {
+            return Err("parity shard too short");
+        }
+        let slots: Vec<Slot> = bytes[7..slots_end]
+            .chunks_exact(12)
+            .map(|slot| Slot {
+                length: u32::from_le_bytes(slot[0..4].try_into().unwrap()),
+                checksum: u32::from_le_bytes(slot[4..8].try_into().unwrap()),
+                decoded_length: u32::from_le_bytes(slot[8..12].try_into().unwrap()),
+            })
+            .collect();
+        let data = &bytes[slots_end..];
+        let longest = slots.iter().map(|s| s.length as usize).max().unwrap();
+        if data.len() != longest {
+            return Err("parity shard length doesn't match its blocks");
+        }
+        if slots
+            .iter()
+            .any(|s| s.length == 0 || s.length as usize > max_block)
+        {
+            return Err("block longer than the block size allows");
+        }
+        Ok(Shard {
+            group_start,
+            index,
+            shards,
+            slots,
+            data: data.to_vec(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn field() {
+        // every nonzero element has an inverse, and multiplication distributes
+        for a in 1..=255 {
+            assert_eq!(mul(a, inverse(a)), 1, "{}", a);
+            assert_eq!(mul(a, 1), a);
+            assert_eq!(mul(a, 0), 0);
+        }
+        assert_eq!(mul(2, 0x80), 0x1d);
+        for (a, b, c) in [(3, 7, 200), (0x53, 0xca, 0x11), (255, 254, 253)] {
+            assert_eq!(mul(a, b ^ c), mul(a, b) ^ mul(a, c));
+            assert_eq!(mul(mul(a, b), c), mul(a, mul(b, c)));
+        }
+    }
+
+    #[test]
+    fn rebuilds_any_lost_blocks() {
+        let blocks: Vec<Vec<u8>> = (0..6_u32)
+            .map(|b| (0..100 + b * 37).map(|i| (i * 31 + b * 7) as u8).collect())
+            .collect();
+        let slot = |block: &Vec<u8>| Slot {
+            length: block.len() as u32,
+            checksum: 0,
+            decoded_length: 0,
+        };
+        let group: Vec<(&[u8], Slot)> = blocks.iter().map(|b| (&b[..], slot(b))).collect();
+        let shards = encode(40, &group, 3);
+        assert_eq!(shards.len(), 3);
+        assert!(shards.iter().all(|s| s.data.len() == 100 + 5 * 37));
+
+        // every combination of up to three lost blocks, from any three shards
+        for lost in 0..1_u32 << blocks.len() {
+            if lost.count_ones() > 3 {
+                continue;
+            }
+            let mut damaged: Vec<Option<Vec<u8>>> = blocks
+                .iter()
+                .enumerate()
+                .map(|(b, block)| (lost & 1 << b == 0).then(|| block.clone()))
+                .collect();
+            let available: Vec<&Shard> = shards.iter().rev().collect();
+            rebuild(&mut damaged, &available).unwrap();
+            let rebuilt: Vec<Vec<u8>> = damaged.into_iter().map(Option::unwrap).collect();
+            assert_eq!(rebuilt, blocks, "lost {:b}", lost);
+        }
+
+        // but no more than there are shards
+        let mut damaged: Vec<Option<Vec<u8>>> = blocks.iter().cloned().map(Some).collect();
+        damaged[0] = None;
+        damaged[1] = None;
+        assert!(rebuild(&mut damaged, &[&shards[2]]).is_err());
+    }
+
+    #[test]
+    fn shards_round_trip() {
+        let block = vec![9; 50];
+        let slot = Slot {
+            length: 50,
+            checksum: 0xdead_beef,
+            decoded_length: 70,
+        };
+        let shard = encode(7, &[(&block, slot)], 2).remove(1);
+        let mut bytes = vec![];
+        shard.write(&mut bytes);
+        assert_eq!(Shard::read(&bytes, 1000), Ok(shard));
+        assert!(Shard::read(&bytes[..bytes.len() - 1], 1000).is_err());
+        assert!(Shard::read(&bytes, 10).is_err());
+        assert_eq!(shards_for(20, 5), 1);
+        assert_eq!(shards_for(20, 10), 2);
+        assert_eq!(shards_for(3, 5), 1);
+        assert_eq!(shards_for(20, 100), 20);
+    }
+}
@@ -0,0 +1,221 @@
+// Seeded random-input checks of each stage against a naive reference or its own
+// inverse. The inputs lean towards the edge cases that have caught the transforms out
+// before: empty and one-byte blocks, a single repeated symbol, tiny alphabets, and
+// long runs. Every check names the seed and case that failed, so it can be replayed.
+
+use super::arithmetic::*;
+use super::squash::*;
+use super::transforms::*;
+use crate::common::Rng;
+use crate::suffixarray::SuffixArray;
+use std::iter;
+
+// `count` inputs of up to `max_length` bytes, starting with the smallest ones
+fn inputs(seed: u64, count: usize, max_length: usize) -> Vec<Vec<u8>> {
+    let mut rng = Rng::new(seed);
+    let mut out = vec![
+        vec![],
+        vec![0],
+        vec![255],
+        vec![7, 7],
+        vec![0; 100],
+        vec![1, 0],
+    ];
+    while out.len() < count {
+        let length = match rng.below(4) {
+            0 => rng.below(4),
+            1 => rng.below(64),
+            _ => rng.below(max_length + 1),
+        };
+        let alphabet = [1, 2, 3, 4, 16, 256][rng.below(6)];
+        // zero is the byte the run-length encoding cares about
+        let lowest = if rng.below(2) == 0 { 0 } else { rng.below(256) };
+        let longest_run = [1, 4, 100][rng.below(3)];
+        let mut input = Vec::with_capacity(length);
+        while input.len() < length {
+            let byte = ((lowest + rng.below(alphabet)) % 256) as u8;
+            let run = (1 + rng.below(longest_run)).min(length - input.len());
+            input.resize(input.len() + run, byte);
+        }
+        out.push(input);
+    }
+    out
+}
+
+// sort every rotation of the text with a sentinel below every byte on the end, and
+// take the last column, writing the sentinel as '$'
+fn naive_bwt(plaintext: &[u8]) -> BwVec {
+    if plaintext.is_empty() {
+        return BwVec {
+            block: vec![],
+            end_index: 0,
+        };
+    }
+    let text: Vec<u16> = plaintext
+        .iter()
+        .map(|b| u16::from(*b) + 1)
+        .chain(iter::once(0))
+        .collect();
+    let rotation = |start: usize| text[start..].iter().chain(&text[..start]);
+    let mut rotations: Vec<usize> = (0..text.len()).collect();
+    rotations.sort_by(|a, b| rotation(*a).cmp(rotation(*b)));
+    let mut block = vec![];
+    let mut end_index = 0;
+    for (row, start) in rotations.iter().enumerate() {
+        match text[(start + text.len() - 1) % text.len()] {
+            0 => {
+                end_index = row as u32;
+                block.push(b'$');
+            }
+            symbol => block.push((symbol - 1) as u8),
+        }
+    }
+    BwVec { block, end_index }
+}
+
+// every suffix, the empty one included, sorted
+fn naive_suffix_array(text: &[u8]) -> Vec<usize> {
+    let mut suffixes: Vec<usize> = (0..=text.len()).collect();
+    suffixes.sort_by(|a, b| text[*a..].cmp(&text[*b..]));
+    suffixes
+}
+
+#[test]
+fn suffix_array_matches_naive_sort() {
+    for (case, input) in inputs(1, 400, 300).iter().enumerate() {
+        assert_eq!(
+            SuffixArray::from_array(input).raw(),
+            naive_suffix_array(input),
+            "seed 1 case {}: {:?}",
+            case,
+            input
+        );
+    }
+}
+
+#[test]
+fn bwt_matches_rotation_sort() {
+    for (case, input) in inputs(2, 400, 300).iter().enumerate() {
+        let transformed = bw_transform(input);
+        assert_eq!(
+            transformed,
+            naive_bwt(input),
+            "seed 2 case {}: {:?}",
+            case,
+            input
+        );
+        assert_eq!(
+            &bw_untransform(&transformed).unwrap(),
+            input,
+            "seed 2 case {}",
+            case
+        );
+    }
+}
+
+#[test]
+fn mtf_and_rle_round_trip() {
+    for (case, input) in inputs(3, 400, 5000).iter().enumerate() {
+        let moved = mtf_transform(input);
+        assert_eq!(moved.len(), input.len(), "seed 3 case {}", case);
+        assert_eq!(&mtf_untransform(&moved), input, "seed 3 case {}", case);
+
+        let encoded = run_length_encode(input);
+        assert!(encoded.len() <= input.len(), "seed 3 case {}", case);
+        assert_eq!(
+            &run_length_decode(&encoded, input.len()).unwrap(),
+            input,
+            "seed 3 case {}",
+            case
+        );
+    }
+}
+
+#[test]
+fn bijective_round_trip() {
+    let mut rng = Rng::new(4);
+    let boundaries = (1..32).flat_map(|bit| {
+        let power = 1_u32 << bit;
+        [power - 1, power, power + 1]
+    });
+    let numbers = (1..=5000)
+        .chain(boundaries)
+        .chain(iter::once(u32::MAX))
+        .chain((0..5000).map(|_| (rng.next() >> 32) as u32 | 1));
+    for number in numbers {
+        let encoded = to_bijective(number);
+        assert!(encoded.len() <= 32, "{}", number);
+        assert_eq!(from_bijective(&encoded), number);
+    }
+}
+
+// pack random symbols below `base` and check they unpack unchanged
+fn arithmetic_round_trip(encoder: &ArithmeticEncoder, base: u32, length: usize, rng: &mut Rng) {
+    // skewed towards low codes, as real blocks are
+    let symbols: Vec<u32> = (0..length)
+        .map(|_| {
+            let high = rng.below(base as usize) + 1;
+            rng.below(high) as u32
+        })
+        .collect();
+    let packed = encoder.pack(vec![], &symbols, |x| *x, base);
+    let unpacked = encoder.unpack(&packed, |x| x, base, length);
+    match unpacked {
+        Ok(unpacked) if unpacked == symbols => (),
+        _ => panic!(
+            "{:?} with base {} failed on {} symbols",
+            encoder, base, length
+        ),
+    }
+}
+
+#[test]
+fn arithmetic_round_trip_edge_alphabets() {
+    let mut rng = Rng::new(5);
+    let mut encoders = vec![ArithmeticEncoder::default_encoder()];
+    encoders.extend(CANDIDATE_ENCODERS);
+    encoders.push(ArithmeticEncoder {
+        frequency_memory: 1,
+        frequency_padding: 1,
+        recalculation_frequency: 0,
+        model: Model::Halving,
+    });
+    for encoder in &encoders {
+        for base in [1, 2, 3, 256, 257] {
+            for length in [0, 1, 2, 3, 17, 1000, 5000] {
+                arithmetic_round_trip(encoder, base, length, &mut rng);
+            }
+        }
+    }
+    // and lengths of several whole blocks' worth of symbols
+    for base in [1, 2, 257] {
+        arithmetic_round_trip(
+            &ArithmeticEncoder::default_encoder(),
+            base,
+            3 * BLOCK_SIZE + 5,
+            &mut rng,
+        );
+    }
+}
+
+#[test]
+fn streams_round_trip_at_block_boundaries() {
+    let mut rng = Rng::new(6);
+    for length in [0, 1, 2, BLOCK_SIZE - 1, BLOCK_SIZE, BLOCK_SIZE + 1] {
+        let input: Vec<u8> = (0..length).map(|_| b"ab\0"[rng.below(3)]).collect();
+        let mut squashed = vec![];
+        squash(&mut &input[..], &mut squashed).unwrap();
+        let mut unsquashed = vec![];
+        unsquash(&mut &squashed[..], &mut unsquashed).unwrap();
+        assert!(unsquashed == input, "{} bytes", length);
+    }
+}
+
+#[test]
+fn naive_references_agree_with_known_answers() {
+    let banana = naive_bwt(b"banana");
+    assert_eq!(banana.block, b"annb$aa");
+    assert_eq!(banana.end_index, 4);
+    assert_eq!(naive_suffix_array(b"aba"), [3, 2, 0, 1]);
+    assert_eq!(naive_suffix_array(b""), [0]);
+}
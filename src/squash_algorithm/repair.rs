@@ -0,0 +1,1173 @@
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::convert::TryInto;
+use std::io;
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::arithmetic::*;
+use super::crc::crc32;
+use super::dedup::*;
+use super::encryption::*;
+use super::frame::*;
+use super::parity::*;
+use super::squash::*;
+use super::text::*;
+
+// what could be salvaged from a damaged stream
+#[derive(Clone, PartialEq, Debug)]
+pub struct Recovery {
+    // the blocks that decoded and matched their checksums
+    pub blocks: usize,
+    // the bytes written from them
+    pub recovered: u64,
+    pub lost: Vec<LostRange>,
+}
+
+// a stretch of a damaged stream that couldn't be decoded
+#[derive(Clone, PartialEq, Debug)]
+pub struct LostRange {
+    // the damaged bytes of the squashed input
+    pub input: Range<u64>,
+    // where the gap falls in the salvaged output
+    pub output: u64,
+}
+
+// decode every intact block of a damaged stream, or of several concatenated ones.
+// Wherever something fails to decode, the rest of the input is searched for the next
+// sync marker or stream header, and decoding carries on from there. Only blocks whose
+// checksums match are written out, so what's salvaged is exactly what was squashed,
+// with gaps where the lost ranges were. Encrypted streams are refused: `repair` can
+// still rebuild them from their parity
+pub fn recover(damaged: &[u8], writer: &mut dyn io::Write) -> io::Result<Recovery> {
+    // until a header is read, the stream is assumed to be written with the defaults,
+    // which is enough for blocks that need neither a dictionary nor back-references
+    let mut context = StreamContext {
+        version: FILETYPE_VERSION,
+        arithmetic_encoder: ArithmeticEncoder::default_encoder(),
+        dictionary: Dictionary::empty(),
+        block_size: BLOCK_SIZE,
+        history: None,
+        parity: None,
+        encrypted: false,
+        key: None,
+    };
+    let mut recovery = Recovery {
+        blocks: 0,
+        recovered: 0,
+        lost: vec![],
+    };
+    let mut damage_start = None;
+    let mut position = 0;
+    while position < damaged.len() {
+        let mut reader = &damaged[position..];
+        match recover_frame(&mut reader, &mut context) {
+            Ok(_) if context.encrypted => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "can't recover an encrypted stream",
+                ))
+            }
+            Ok(decoded) => {
+                if let Some(start) = damage_start.take() {
+                    recovery.lost.push(LostRange {
+                        input: start..position as u64,
+                        output: recovery.recovered,
+                    });
+                }
+                if let Some(decoded) = decoded {
+                    writer.write_all(&decoded)?;
+                    recovery.recovered += decoded.len() as u64;
+                    recovery.blocks += 1;
+                }
+                position = damaged.len() - reader.len();
+            }
+            Err(_) => {
+                damage_start.get_or_insert(position as u64);
+                // back-references count from the start of the stream, so guess that
+                // a whole block was lost, as it is except at the end of a stream. If
+                // that's wrong, the checksums will tell
+                if let Some(history) = &mut context.history {
+                    history.skip(context.block_size as u64);
+                }
+                position = next_sync_point(damaged, position + 1);
+            }
+        }
+    }
+    if let Some(start) = damage_start {
+        recovery.lost.push(LostRange {
+            input: start..damaged.len() as u64,
+            output: recovery.recovered,
+        });
+    }
+    Ok(recovery)
+}
+
+// read a header, a trailer or a block, returning what the block decodes to
+fn recover_frame(reader: &mut &[u8], context: &mut StreamContext) -> io::Result<Option<Vec<u8>>> {
+    if reader.starts_with(&MAGIC_NUMBER.to_le_bytes()) {
+        let header = read_header(reader, &DecodeLimits::default_limits())?;
+        *context = StreamContext {
+            version: header.version,
+            arithmetic_encoder: header.arithmetic_encoder,
+            dictionary: header.dictionary,
+            block_size: header.block_size.try_into().unwrap(),
+            history: header.dedup_window.map(History::new),
+            parity: header.parity,
+            encrypted: header.encryption.is_some(),
+            key: None,
+        };
+        return Ok(None);
+    }
+    match read_frame(reader, context)? {
+        Some(Frame::Block { block, checksum }) => {
+            // the index is only for opening encrypted blocks, and those aren't recovered
+            let decoded = decode_checked(&block, checksum, 0, context)?;
+            if let Some(history) = &mut context.history {
+                history.extend(&decoded);
+            }
+            Ok(Some(decoded))
+        }
+        Some(Frame::Parity(_)) | Some(Frame::End(_)) => Ok(None),
+        Some(Frame::NextStream) | None => Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+    }
+}
+
+// what repairing a stream found, and put right
+#[derive(Clone, PartialEq, Debug)]
+pub struct Repair {
+    // the damaged blocks, parity shards and trailers, where they were in the input
+    pub damaged: Vec<Range<u64>>,
+    // the blocks rebuilt from parity, by their index, counting on through
+    // concatenated streams
+    pub blocks_rebuilt: Vec<u64>,
+    // the parity shards that were damaged or lost, and have been written anew
+    pub shards_rebuilt: usize,
+    // the bits found to be flipped, and flipped back
+    pub bits_flipped: Vec<FlippedBit>,
+    // whether the search for flipped bits ran out of time, leaving blocks unsearched
+    pub search_timed_out: bool,
+}
+
+// a single bit that was flipped in a block's frame, found by the checksum
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct FlippedBit {
+    // the block, counting on through concatenated streams
+    pub block: u64,
+    // where the bit is in the input, counting bits from the least significant
+    pub byte: u64,
+    pub bit: u8,
+}
+
+#[derive(Clone, Debug)]
+pub struct RepairOptions {
+    // look for a single flipped bit in each damaged block that parity can't rebuild,
+    // by flipping each of its bits in turn until it decodes to its checksum, spending
+    // no more than this on the search over the whole input
+    pub bitflip: Option<Duration>,
+    // how many threads to search on, or None for one per CPU
+    pub threads: Option<usize>,
+    // to check the blocks of encrypted streams with, which can't be repaired without it
+    pub password: Option<Password>,
+}
+
+impl RepairOptions {
+    pub fn default_options() -> Self {
+        RepairOptions {
+            bitflip: None,
+            threads: None,
+            password: None,
+        }
+    }
+}
+
+// rebuild the damaged and missing blocks of a stream, or of several concatenated
+// ones, from their parity, writing out the repaired stream. Blocks are found by their
+// sync markers, as recover finds them, and the parity shards after each group of
+// blocks say exactly where each of the group's blocks should be, how long it is and
+// what it decodes to. A block that's missing from its place, or doesn't decode to its
+// checksum, is rebuilt, as long as no more of its group are lost than there are
+// shards left. Anything else that's damaged, like the header or blocks outside any
+// group, fails the repair, and nothing is written: `recover` salvages what it can of
+// those. An undamaged stream is written out just as it was
+pub fn repair(damaged: &[u8], writer: &mut dyn io::Write) -> io::Result<Repair> {
+    repair_with_options(damaged, writer, &RepairOptions::default_options())
+}
+
+pub fn repair_with_options(
+    damaged: &[u8],
+    writer: &mut dyn io::Write,
+    options: &RepairOptions,
+) -> io::Result<Repair> {
+    let search = options.bitflip.map(|limit| BitSearch {
+        deadline: Instant::now() + limit,
+        threads: options
+            .threads
+            .unwrap_or_else(|| thread::available_parallelism().map_or(1, |n| n.get())),
+    });
+    let mut repair = Repair {
+        damaged: vec![],
+        blocks_rebuilt: vec![],
+        shards_rebuilt: 0,
+        bits_flipped: vec![],
+        search_timed_out: false,
+    };
+    let mut repaired = vec![];
+    let mut position = 0;
+    let mut blocks = 0;
+    loop {
+        let (end, stream_blocks) = repair_stream(
+            damaged,
+            position,
+            &mut repaired,
+            &mut repair,
+            blocks,
+            search,
+            options.password.as_ref(),
+        )
+        .map_err(|x| match repair.search_timed_out {
+            true => io::Error::new(
+                x.kind(),
+                format!("{} (the search for flipped bits ran out of time)", x),
+            ),
+            false => x,
+        })?;
+        position = end;
+        blocks += u64::from(stream_blocks);
+        if position == damaged.len() {
+            break;
+        }
+        if !damaged[position..].starts_with(&MAGIC_NUMBER.to_le_bytes()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "trailing data after the stream",
+            ));
+        }
+    }
+    merge_ranges(&mut repair.damaged);
+    writer.write_all(&repaired)?;
+    Ok(repair)
+}
+
+// a block found while scanning a stream for repair
+struct Scanned {
+    block: Range<usize>,
+    checksum: Option<u32>,
+}
+
+fn unrepairable(message: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("damaged beyond repair: {}", message),
+    )
+}
+
+fn merge_ranges(ranges: &mut Vec<Range<u64>>) {
+    ranges.sort_by_key(|r| r.start);
+    let mut merged: Vec<Range<u64>> = vec![];
+    for range in ranges.drain(..) {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+            _ => merged.push(range),
+        }
+    }
+    *ranges = merged;
+}
+
+// repair the stream starting at `start`, adding it to `repaired`. Returns where the
+// stream ended and how many blocks it had
+fn repair_stream(
+    data: &[u8],
+    start: usize,
+    repaired: &mut Vec<u8>,
+    repair: &mut Repair,
+    blocks_before: u64,
+    search: Option<BitSearch>,
+    password: Option<&Password>,
+) -> io::Result<(usize, u32)> {
+    let mut reader = &data[start..];
+    let header = read_header(&mut reader, &DecodeLimits::default_limits())
+        .map_err(|x| unrepairable(&format!("the header is damaged ({})", x)))?;
+    let key = stream_key(&header, password)?;
+    let header_end = data.len() - reader.len();
+    repaired.extend_from_slice(&data[start..header_end]);
+    let context = StreamContext {
+        version: header.version,
+        arithmetic_encoder: header.arithmetic_encoder,
+        dictionary: header.dictionary,
+        block_size: header.block_size.try_into().unwrap(),
+        history: header.dedup_window.map(History::new),
+        parity: header.parity,
+        encrypted: key.is_some(),
+        key,
+    };
+    let synced = context.version > OLDEST_VERSION;
+
+    // find every frame there is, up to the end of the stream: its trailer, or else the
+    // next stream or the end of the input. Blocks that parse are kept whether they
+    // decode or not, and sorted out once their group's parity says where they belong
+    let mut frames = BTreeMap::new();
+    let mut shards: Vec<(usize, Shard)> = vec![];
+    let mut trailer = None;
+    let mut covered = header_end;
+    let mut position = header_end;
+    let stop = loop {
+        if position >= data.len() {
+            break data.len();
+        }
+        let mut reader = &data[position..];
+        let frame = read_frame(&mut reader, &context);
+        let end = data.len() - reader.len();
+        match frame {
+            Ok(Some(Frame::Block { block, checksum })) => {
+                let block = end - block.len()..end;
+                frames.insert(position, Scanned { block, checksum });
+                covered = covered.max(end);
+            }
+            Ok(Some(Frame::Parity(shard))) => {
+                check_parity_expected(&context)?;
+                shards.push((position, shard));
+                covered = covered.max(end);
+            }
+            Ok(Some(Frame::End(found))) => {
+                trailer = Some((position, found));
+                break end;
+            }
+            // a magic number inside a block is only a coincidence
+            Ok(Some(Frame::NextStream)) if position >= covered => break position,
+            // without sync markers, there's no finding what comes after damage
+            _ if !synced => break position,
+            _ => (),
+        }
+        position = if synced {
+            next_sync_point(data, position + 1)
+        } else {
+            end
+        };
+    };
+
+    // then follow the frames from the header, group by group, to the trailer
+    let mut stream = StreamRepair {
+        data,
+        frames,
+        context,
+        cursor: header_end,
+        blocks: 0,
+        length: 0,
+        repaired,
+        repair,
+        blocks_before,
+        search,
+    };
+    let overhead = frame_overhead(stream.context.version) as usize;
+    let mut shards = shards.into_iter().peekable();
+    while let Some((position, first)) = shards.next() {
+        // the group's other shards, as long as they agree with the first
+        let mut group = vec![first];
+        while let Some((_, shard)) = shards.next_if(|(_, s)| s.group_start == group[0].group_start)
+        {
+            let first = &group[0];
+            if shard.shards == first.shards
+                && shard.slots == first.slots
+                && group.iter().all(|s| s.index != shard.index)
+            {
+                group.push(shard);
+            }
+        }
+        let frame_length = overhead + group[0].length();
+        let shards_start = position
+            .checked_sub(usize::from(group[0].index) * frame_length)
+            .ok_or_else(|| unrepairable("parity shards out of place"))?;
+        stream.repair_group(&group, shards_start, frame_length)?;
+    }
+
+    let version = stream.context.version;
+    let key = stream.context.key;
+    let trailer_length = if version > OLDEST_VERSION {
+        trailer_length(version, key.is_some()) as usize
+    } else {
+        0
+    };
+    let blocks_end = match trailer {
+        Some((position, _)) => position,
+        None => stop.saturating_sub(trailer_length),
+    };
+    stream.leftovers_until(blocks_end)?;
+    let whole = Trailer {
+        length: stream.length,
+        blocks: stream.blocks,
+    };
+    match trailer {
+        _ if version == OLDEST_VERSION => (),
+        Some((_, found)) if found == whole => repaired.extend_from_slice(&data[blocks_end..stop]),
+        // nothing's missing before it, so the trailer itself is what's damaged, and
+        // can be written anew. Unless what's there looks like more of the stream
+        _ if !looks_like_frame(&data[blocks_end..stop]) => {
+            write_trailer(repaired, &whole, key.as_ref())?;
+            repair.damaged.push(blocks_end as u64..stop as u64);
+        }
+        _ => return Err(unrepairable("the end of the stream is lost")),
+    }
+    Ok((stop, whole.blocks))
+}
+
+// a stream being repaired, as its frames are followed in order
+struct StreamRepair<'a> {
+    data: &'a [u8],
+    // the blocks found, by where they start
+    frames: BTreeMap<usize, Scanned>,
+    context: StreamContext,
+    // where the next frame should start
+    cursor: usize,
+    // the blocks so far, and what they decode to
+    blocks: u32,
+    length: u64,
+    repaired: &'a mut Vec<u8>,
+    repair: &'a mut Repair,
+    // in the streams before this one
+    blocks_before: u64,
+    search: Option<BitSearch>,
+}
+
+#[derive(Clone, Copy)]
+pub struct BitSearch {
+    pub deadline: Instant,
+    pub threads: usize,
+}
+
+impl StreamRepair<'_> {
+    // decode the block with the given index in this stream
+    fn decode(&mut self, block: &[u8], checksum: Option<u32>, index: u64) -> io::Result<Vec<u8>> {
+        let decoded = decode_checked(block, checksum, index, &self.context)?;
+        if let Some(history) = &mut self.context.history {
+            history.extend(&decoded);
+        }
+        Ok(decoded)
+    }
+
+    // keep the blocks from the cursor up to `end`, which aren't in any group, so they
+    // must follow one another without a gap and all decode
+    fn leftovers_until(&mut self, end: usize) -> io::Result<()> {
+        while self.cursor < end {
+            let index = self.blocks_before + u64::from(self.blocks);
+            let (block, checksum) = self.leftover_frame(index, end).ok_or_else(|| {
+                unrepairable(&format!(
+                    "bytes {}..{} are damaged, and not covered by parity",
+                    self.cursor, end
+                ))
+            })?;
+            let bytes = self.input(block.clone());
+            let decoded = match self.decode(&bytes, checksum, u64::from(self.blocks)) {
+                Ok(decoded) => decoded,
+                Err(x) => checksum
+                    .and_then(|c| self.decode_flipped(index, self.cursor, block.clone(), c))
+                    .ok_or_else(|| {
+                        unrepairable(&format!(
+                            "block {} is damaged, and has no parity ({})",
+                            index, x
+                        ))
+                    })?,
+            };
+            let frame = self.input(self.cursor..block.end);
+            self.repaired.extend_from_slice(&frame);
+            self.blocks += 1;
+            self.length += decoded.len() as u64;
+            self.cursor = block.end;
+        }
+        if self.cursor != end {
+            return Err(unrepairable("blocks overrun their parity"));
+        }
+        Ok(())
+    }
+
+    // the block and checksum of the frame at the cursor. If there's none there, or it
+    // doesn't end where the next one starts, its sync marker or length may have a
+    // flipped bit
+    fn leftover_frame(&mut self, index: u64, end: usize) -> Option<(Range<usize>, Option<u32>)> {
+        let frame = self
+            .frames
+            .get(&self.cursor)
+            .map(|f| (f.block.clone(), f.checksum));
+        let next = self
+            .frames
+            .range(self.cursor + 1..)
+            .next()
+            .map_or(end, |(&position, _)| position.min(end));
+        if frame.as_ref().is_some_and(|(block, _)| block.end == next)
+            || self.context.version == OLDEST_VERSION
+        {
+            return frame;
+        }
+        let overhead = frame_overhead(self.context.version) as usize;
+        let length = u32::try_from(next.checked_sub(self.cursor + overhead)?).ok()?;
+        let expected = [&SYNC_MARKER[..], &length.to_le_bytes()].concat();
+        if !self.flip_header(index, self.cursor..next, &expected) {
+            return frame;
+        }
+        let checksum = self.data[self.cursor + 10..self.cursor + 14]
+            .try_into()
+            .unwrap();
+        Some((
+            self.cursor + overhead..next,
+            Some(u32::from_le_bytes(checksum)),
+        ))
+    }
+
+    // what's in the input, with any bits found to be flipped put right
+    fn input(&self, range: Range<usize>) -> Vec<u8> {
+        let mut bytes = self.data[range.clone()].to_vec();
+        for flipped in &self.repair.bits_flipped {
+            if range.contains(&(flipped.byte as usize)) {
+                bytes[flipped.byte as usize - range.start] ^= 1 << flipped.bit;
+            }
+        }
+        bytes
+    }
+
+    fn flipped(&mut self, block: u64, frame: Range<usize>, bit: usize) {
+        self.repair.bits_flipped.push(FlippedBit {
+            block,
+            byte: (bit / 8) as u64,
+            bit: (bit % 8) as u8,
+        });
+        self.repair
+            .damaged
+            .push(frame.start as u64..frame.end as u64);
+    }
+
+    // whether the frame header at the start of `frame` differs from `expected` in
+    // just one bit, when searching for flipped bits, and if so put it right
+    fn flip_header(&mut self, block: u64, frame: Range<usize>, expected: &[u8]) -> bool {
+        let header = match self.data.get(frame.start..frame.start + expected.len()) {
+            Some(header) if self.search.is_some() => header,
+            _ => return false,
+        };
+        let differences: Vec<usize> = (0..expected.len() * 8)
+            .filter(|&bit| (header[bit / 8] ^ expected[bit / 8]) & 1 << (bit % 8) != 0)
+            .collect();
+        match differences[..] {
+            [bit] => {
+                self.flipped(block, frame.clone(), frame.start * 8 + bit);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // decode a block that fails, by finding the bit whose flip puts it right, in its
+    // checksum or in the block itself, as long as there's time left to search
+    fn decode_flipped(
+        &mut self,
+        index: u64,
+        frame: usize,
+        block: Range<usize>,
+        checksum: u32,
+    ) -> Option<Vec<u8>> {
+        let search = self.search?;
+        let bytes = self.input(block.clone());
+        let in_stream = index - self.blocks_before;
+        // a flipped bit in the checksum leaves the block decoding as it should
+        let decoded = decode_checked(&bytes, None, in_stream, &self.context).ok();
+        let difference = decoded.as_ref().map_or(0, |d| {
+            crc32(if self.context.encrypted { &bytes } else { d }) ^ checksum
+        });
+        let (bit, decoded) = if difference.count_ones() == 1 {
+            let bit = (frame + 10) * 8 + difference.trailing_zeros() as usize;
+            (bit, decoded.unwrap())
+        } else {
+            let (found, timed_out) =
+                search_bit_flips(&bytes, checksum, in_stream, &self.context, search);
+            self.repair.search_timed_out |= timed_out;
+            let (bit, decoded) = found?;
+            (block.start * 8 + bit, decoded)
+        };
+        self.flipped(index, frame..block.end, bit);
+        if let Some(history) = &mut self.context.history {
+            history.extend(&decoded);
+        }
+        Some(decoded)
+    }
+
+    // rebuild the blocks of a group that are missing from `found` or `lost`, and
+    // decode the whole group in order from `history`. Fails with the first block
+    // that doesn't decode to what it should, or if the group can't be rebuilt
+    fn rebuild_group(
+        &mut self,
+        group: &[Shard],
+        found: &[Option<Vec<u8>>],
+        lost: &[usize],
+        history: &Option<History>,
+    ) -> io::Result<Result<Vec<Vec<u8>>, usize>> {
+        let mut blocks: Vec<Option<Vec<u8>>> = found
+            .iter()
+            .enumerate()
+            .map(|(b, block)| block.clone().filter(|_| !lost.contains(&b)))
+            .collect();
+        let shards: Vec<&Shard> = group.iter().collect();
+        rebuild(&mut blocks, &shards).map_err(unrepairable)?;
+        self.context.history = history.clone();
+        for (b, (block, slot)) in blocks.iter().zip(&group[0].slots).enumerate() {
+            let index = u64::from(self.blocks) + b as u64;
+            let decoded = self.decode(block.as_ref().unwrap(), Some(slot.checksum), index);
+            if decoded.map_or(true, |d| d.len() != slot.decoded_length as usize) {
+                return Ok(Err(b));
+            }
+        }
+        Ok(Ok(blocks.into_iter().map(Option::unwrap).collect()))
+    }
+
+    // check a group of blocks against its intact parity shards, which start at
+    // `shards_start`, rebuilding any that are missing or damaged
+    fn repair_group(
+        &mut self,
+        group: &[Shard],
+        shards_start: usize,
+        frame_length: usize,
+    ) -> io::Result<()> {
+        let data = self.data;
+        let first = &group[0];
+        let overhead = frame_overhead(self.context.version) as usize;
+        let blocks_length: usize = first
+            .slots
+            .iter()
+            .map(|s| overhead + s.length as usize)
+            .sum();
+        let group_position = shards_start
+            .checked_sub(blocks_length)
+            .filter(|&p| p >= self.cursor)
+            .ok_or_else(|| unrepairable("parity shards out of place"))?;
+        self.leftovers_until(group_position)?;
+        if first.group_start != self.blocks
+            || first
+                .slots
+                .iter()
+                .any(|s| s.decoded_length as usize > self.context.block_size)
+        {
+            return Err(unrepairable("parity shards out of place"));
+        }
+
+        // the blocks that are in their places, and which of them don't decode to what
+        // they should. Missing blocks are stood in for by zeros, so that later ones
+        // can still find anything they copy from elsewhere
+        let history = self.context.history.clone();
+        let index = self.blocks_before + u64::from(self.blocks);
+        let mut found: Vec<Option<Vec<u8>>> = vec![];
+        let mut frames = vec![];
+        let mut failed = vec![];
+        let mut position = group_position;
+        for (b, slot) in first.slots.iter().enumerate() {
+            let frame = position..position + overhead + slot.length as usize;
+            let expected = [
+                &SYNC_MARKER[..],
+                &slot.length.to_le_bytes(),
+                &slot.checksum.to_le_bytes(),
+            ]
+            .concat();
+            let block = self
+                .frames
+                .get(&position)
+                .filter(|f| f.block.len() == slot.length as usize)
+                .filter(|f| f.checksum == Some(slot.checksum))
+                .is_some()
+                .then(|| data[frame.start + overhead..frame.end].to_vec())
+                .or_else(|| {
+                    self.flip_header(index + b as u64, frame.clone(), &expected)
+                        .then(|| self.input(frame.start + overhead..frame.end))
+                });
+            let in_stream = u64::from(self.blocks) + b as u64;
+            let decoded = block
+                .as_ref()
+                .and_then(|block| {
+                    decode_checked(block, Some(slot.checksum), in_stream, &self.context).ok()
+                })
+                .filter(|d| d.len() == slot.decoded_length as usize);
+            if block.is_some() && decoded.is_none() {
+                failed.push(b);
+            }
+            if let Some(history) = &mut self.context.history {
+                history.extend(&decoded.unwrap_or_else(|| vec![0; slot.decoded_length as usize]));
+            }
+            found.push(block);
+            position = frame.end;
+            frames.push(frame);
+        }
+
+        let mut lost: Vec<usize> = (0..found.len()).filter(|&b| found[b].is_none()).collect();
+        let blocks: Vec<Vec<u8>> = if lost.is_empty() && failed.is_empty() {
+            found.into_iter().map(Option::unwrap).collect()
+        } else {
+            // a block that fails may only be copying from one that's damaged, so if
+            // there are too many to rebuild them all, find those that are damaged one
+            // at a time. Every block before the first that fails, once the group is
+            // rebuilt and decoded in order, is intact; so it's damaged itself, unless
+            // it was rebuilt, and then a later block that was taken as intact isn't.
+            // One that's damaged itself may only have a flipped bit
+            if lost.len() + failed.len() <= group.len() {
+                lost.extend(&failed);
+            }
+            let unrepairable = |x: &str| {
+                unrepairable(&format!(
+                    "blocks {}..{}: {}",
+                    index,
+                    index + first.slots.len() as u64,
+                    x
+                ))
+            };
+            loop {
+                if lost.len() > group.len() {
+                    return Err(unrepairable(
+                        "more blocks lost than there are parity shards to rebuild them",
+                    ));
+                }
+                let fails_at = match self.rebuild_group(group, &found, &lost, &history)? {
+                    Ok(blocks) => break blocks,
+                    Err(b) if !lost.contains(&b) => {
+                        let frame = frames[b].clone();
+                        let block = frame.start + overhead..frame.end;
+                        let checksum = first.slots[b].checksum;
+                        match self.decode_flipped(index + b as u64, frame.start, block, checksum) {
+                            Some(_) => {
+                                found[b] = Some(self.input(frame.start + overhead..frame.end))
+                            }
+                            None => lost.push(b),
+                        }
+                        continue;
+                    }
+                    Err(b) => b,
+                };
+                let mut next = None;
+                for &later in failed
+                    .iter()
+                    .filter(|&&l| l > fails_at && !lost.contains(&l))
+                {
+                    let with = [&lost[..], &[later]].concat();
+                    match self.rebuild_group(group, &found, &with, &history)? {
+                        Err(b) if b <= fails_at => (),
+                        _ => {
+                            next = Some(later);
+                            break;
+                        }
+                    }
+                }
+                lost.push(
+                    next.ok_or_else(|| unrepairable("the damaged blocks can't be told apart"))?,
+                );
+            }
+        };
+        lost.sort_unstable();
+
+        // write the group out, framing rebuilt blocks anew, followed by all its shards
+        let mut position = group_position;
+        let stored: Vec<(&[u8], Slot)> = blocks
+            .iter()
+            .zip(&first.slots)
+            .map(|(block, slot)| (&block[..], *slot))
+            .collect();
+        for (b, (block, slot)) in stored.iter().enumerate() {
+            let frame_end = position + overhead + block.len();
+            if lost.contains(&b) {
+                self.repaired.extend_from_slice(&SYNC_MARKER);
+                self.repaired.extend_from_slice(&slot.length.to_le_bytes());
+                self.repaired
+                    .extend_from_slice(&slot.checksum.to_le_bytes());
+                self.repaired.extend_from_slice(block);
+                self.repair.damaged.push(position as u64..frame_end as u64);
+                self.repair.blocks_rebuilt.push(index + b as u64);
+            } else {
+                let frame = self.input(position..frame_end);
+                self.repaired.extend_from_slice(&frame);
+            }
+            self.length += u64::from(slot.decoded_length);
+            position = frame_end;
+        }
+        self.blocks += first.slots.len() as u32;
+        for shard in encode(first.group_start, &stored, first.shards.into()) {
+            if group.iter().all(|s| s.index != shard.index) {
+                self.repair.shards_rebuilt += 1;
+                self.repair
+                    .damaged
+                    .push(position as u64..(position + frame_length) as u64);
+            }
+            let mut bytes = vec![];
+            shard.write(&mut bytes);
+            self.repaired.extend_from_slice(&PARITY_MARKER);
+            self.repaired
+                .extend_from_slice(&u32::try_from(bytes.len()).unwrap().to_le_bytes());
+            self.repaired
+                .extend_from_slice(&crc32(&bytes).to_le_bytes());
+            self.repaired.extend_from_slice(&bytes);
+            position += frame_length;
+        }
+        self.cursor = position;
+        Ok(())
+    }
+}
+
+// find the one bit of a block that, flipped, makes it decode to `checksum`, trying
+// every bit in turn on the search's threads until its deadline. Returns the bit and
+// what the block decodes to with it flipped, and whether time ran out first
+fn search_bit_flips(
+    block: &[u8],
+    checksum: u32,
+    index: u64,
+    context: &StreamContext,
+    search: BitSearch,
+) -> (Option<(usize, Vec<u8>)>, bool) {
+    let next_byte = AtomicUsize::new(0);
+    let found = Mutex::new(None);
+    let timed_out = AtomicBool::new(false);
+    thread::scope(|scope| {
+        for _ in 0..search.threads {
+            scope.spawn(|| {
+                let mut candidate = block.to_vec();
+                loop {
+                    let byte = next_byte.fetch_add(1, Ordering::Relaxed);
+                    if byte >= block.len() || found.lock().unwrap().is_some() {
+                        return;
+                    }
+                    if Instant::now() >= search.deadline {
+                        timed_out.store(true, Ordering::Relaxed);
+                        return;
+                    }
+                    for bit in 0..8 {
+                        candidate[byte] ^= 1 << bit;
+                        if let Ok(decoded) =
+                            decode_checked(&candidate, Some(checksum), index, context)
+                        {
+                            *found.lock().unwrap() = Some((byte * 8 + bit, decoded));
+                            return;
+                        }
+                        candidate[byte] ^= 1 << bit;
+                    }
+                }
+            });
+        }
+    });
+    (found.into_inner().unwrap(), timed_out.into_inner())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const TEXT: &str = "When you create a closure, Rust infers which \
+        trait to use based on how the closure uses the values from the environment. All \
+        closures implement FnOnce because they can all be called at least once. Closures \
+        that don't move the captured variables also implement FnMut, and closures that \
+        don't need mutable access to the captured variables also implement Fn. In Listing \
+        13-12, the equal_to_x closure borrows x immutably (so equal_to_x has the Fn trait \
+        ) because the body of the closure only needs to read the value in x.\n\
+        If you want to force the closure to take ownership of the values it uses in the \
+        environment, you can use the move keyword before the parameter list. This technique \
+        is mostly useful when passing a closure to a new thread to move the data so it's \
+        owned by the new thread.\n";
+
+    #[test]
+    fn recovery() {
+        let recovered = |stream: &[u8]| {
+            let mut out = vec![];
+            let recovery = recover(stream, &mut out).unwrap();
+            assert_eq!(recovery.recovered, out.len() as u64);
+            (out, recovery)
+        };
+        let input: Vec<u8> = TEXT.bytes().cycle().take(BLOCK_SIZE * 3 + 100).collect();
+        let mut squashed = vec![];
+        squash(&mut &input[..], &mut squashed).unwrap();
+        // where each block's frame starts, and where the trailer does
+        let info = inspect(&mut &squashed[..], false).unwrap();
+        let mut frames = vec![info.header_length as usize];
+        for block in &info.blocks {
+            let end = frames.last().unwrap()
+                + frame_overhead(FILETYPE_VERSION) as usize
+                + block.length as usize;
+            frames.push(end);
+        }
+        let without = |block: usize| {
+            let range = block * BLOCK_SIZE..((block + 1) * BLOCK_SIZE).min(input.len());
+            [&input[..range.start], &input[range.end..]].concat()
+        };
+
+        // an intact stream is recovered whole
+        let (out, recovery) = recovered(&squashed);
+        assert!(out == input);
+        assert_eq!(recovery.blocks, 4);
+        assert_eq!(recovery.lost, []);
+
+        // a block that doesn't decode to its checksum is refused by unsquash, and
+        // left out by recover, which carries on with the next one
+        let mut mismatched = squashed.clone();
+        mismatched[frames[1] + 10] ^= 1;
+        let error = unsquash(&mut &mismatched[..], &mut vec![]).unwrap_err();
+        assert_eq!(error.to_string(), "block checksum mismatch");
+        let (out, recovery) = recovered(&mismatched);
+        assert!(out == without(1));
+        assert_eq!(recovery.blocks, 3);
+        assert_eq!(
+            recovery.lost,
+            [LostRange {
+                input: frames[1] as u64..frames[2] as u64,
+                output: BLOCK_SIZE as u64
+            }]
+        );
+
+        // as is one whose length is garbled, found again from the next sync marker
+        let mut garbled = squashed.clone();
+        garbled[frames[2] + 6..frames[2] + 10].copy_from_slice(&[0x12, 0x34, 0x56, 0x00]);
+        let (out, recovery) = recovered(&garbled);
+        assert!(out == without(2));
+        assert_eq!(recovery.lost[0].input, frames[2] as u64..frames[3] as u64);
+
+        // a stream cut short keeps every block before the cut
+        let cut = &squashed[..frames[3] + 20];
+        let (out, recovery) = recovered(cut);
+        assert!(out == input[..BLOCK_SIZE * 3]);
+        assert_eq!(
+            recovery.lost,
+            [LostRange {
+                input: frames[3] as u64..cut.len() as u64,
+                output: BLOCK_SIZE as u64 * 3
+            }]
+        );
+
+        // without its header, blocks that need no dictionary still decode
+        let options = SquashOptions {
+            text_preprocessing: false,
+            ..SquashOptions::default_options()
+        };
+        let mut plain = vec![];
+        squash_with_options(&mut TEXT.as_bytes(), &mut plain, &options).unwrap();
+        let header_length = inspect(&mut &plain[..], false).unwrap().header_length;
+        plain[..8].fill(0);
+        let (out, recovery) = recovered(&plain);
+        assert_eq!(out, TEXT.as_bytes());
+        assert_eq!(recovery.lost[0].input, 0..header_length);
+    }
+
+    #[test]
+    fn parity_repair() {
+        let repaired = |stream: &[u8]| {
+            let mut out = vec![];
+            repair(stream, &mut out).map(|repair| (out, repair))
+        };
+        // where each block's frame starts, then the trailer's
+        let sync_points = |stream: &[u8]| -> Vec<usize> {
+            (0..stream.len())
+                .filter(|&i| stream[i..].starts_with(&SYNC_MARKER))
+                .collect()
+        };
+        let input: Vec<u8> = TEXT.bytes().cycle().take(BLOCK_SIZE * 3 + 100).collect();
+        let options = SquashOptions {
+            parity: Some(50),
+            ..SquashOptions::default_options()
+        };
+        let mut squashed = vec![];
+        squash_with_options(&mut &input[..], &mut squashed, &options).unwrap();
+        let info = inspect(&mut &squashed[..], false).unwrap();
+        assert_eq!(info.parity, Some(50));
+        assert_eq!(info.parity_shards.len(), 2);
+        assert_eq!(info.stored_size(), squashed.len() as u64);
+        let mut unsquashed = vec![];
+        unsquash(&mut &squashed[..], &mut unsquashed).unwrap();
+        assert!(unsquashed == input);
+        let frames = sync_points(&squashed);
+        assert_eq!(frames.len(), 5);
+        let shards = (0..squashed.len())
+            .find(|&i| squashed[i..].starts_with(&PARITY_MARKER))
+            .unwrap();
+        let range = |start: usize, end: usize| start as u64..end as u64;
+
+        // an intact stream is left as it was
+        let (out, report) = repaired(&squashed).unwrap();
+        assert!(out == squashed);
+        assert_eq!(
+            report,
+            Repair {
+                damaged: vec![],
+                blocks_rebuilt: vec![],
+                shards_rebuilt: 0,
+                bits_flipped: vec![],
+                search_timed_out: false,
+            }
+        );
+
+        // two damaged blocks are rebuilt byte for byte from the two shards, even one
+        // whose length is garbled
+        let mut damaged = squashed.clone();
+        damaged[frames[0] + 20] ^= 0x40;
+        damaged[frames[2] + 7] ^= 0x12;
+        assert!(unsquash(&mut &damaged[..], &mut vec![]).is_err());
+        let (out, report) = repaired(&damaged).unwrap();
+        assert!(out == squashed);
+        assert_eq!(report.blocks_rebuilt, [0, 2]);
+        assert_eq!(
+            report.damaged,
+            [range(frames[0], frames[1]), range(frames[2], frames[3])]
+        );
+
+        // as are a lost shard and a damaged trailer, along with a block
+        let mut damaged = squashed.clone();
+        damaged[frames[1] + 30] ^= 1;
+        damaged[shards + 40] ^= 1;
+        let end = damaged.len();
+        damaged[end - 3] ^= 1;
+        let (out, report) = repaired(&damaged).unwrap();
+        assert!(out == squashed);
+        assert_eq!(report.blocks_rebuilt, [1]);
+        assert_eq!(report.shards_rebuilt, 1);
+        assert_eq!(report.damaged.last(), Some(&range(frames[4], end)));
+
+        // but not more blocks than there are shards left, and then nothing's written
+        let mut damaged = squashed.clone();
+        for frame in &frames[..3] {
+            damaged[frame + 30] ^= 1;
+        }
+        let mut out = vec![];
+        let error = repair(&damaged, &mut out).unwrap_err();
+        assert!(error.to_string().starts_with("damaged beyond repair"));
+        assert!(out.is_empty());
+
+        // nor anything in a stream without parity
+        let mut plain = vec![];
+        squash(&mut &input[..], &mut plain).unwrap();
+        assert!(repaired(&plain).unwrap().0 == plain);
+        let frame = sync_points(&plain)[1];
+        plain[frame + 30] ^= 1;
+        assert!(repaired(&plain).is_err());
+
+        // groups further on are found by where their shards say they are, in
+        // concatenated streams too. Noise is stored as it is, so this is quick
+        let mut seed = 1_u32;
+        let noise: Vec<u8> = (0..BLOCK_SIZE * 21 + 10)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (seed >> 24) as u8
+            })
+            .collect();
+        let options = SquashOptions {
+            parity: Some(DEFAULT_PARITY),
+            ..SquashOptions::default_options()
+        };
+        let mut noisy = vec![];
+        squash_with_options(&mut &noise[..], &mut noisy, &options).unwrap();
+        assert_eq!(
+            inspect(&mut &noisy[..], false).unwrap().parity_shards.len(),
+            2
+        );
+        let both = [&squashed[..], &noisy[..]].concat();
+        let mut damaged = both.clone();
+        let frames = sync_points(&both);
+        for block in [1, 4 + 3, 4 + 20] {
+            let frame = frames[block + usize::from(block > 3)];
+            damaged[frame + 100] ^= 1;
+        }
+        let (out, report) = repaired(&damaged).unwrap();
+        assert!(out == both);
+        assert_eq!(report.blocks_rebuilt, [1, 7, 24]);
+
+        // blocks that copy from a damaged one fail along with it, but only the one
+        // that's damaged needs rebuilding
+        let repeated = noise[..BLOCK_SIZE].repeat(3);
+        let options = SquashOptions {
+            dedup_window: Some(1 << 20),
+            parity: Some(10),
+            ..SquashOptions::default_options()
+        };
+        let mut deduplicated = vec![];
+        squash_with_options(&mut &repeated[..], &mut deduplicated, &options).unwrap();
+        let mut damaged = deduplicated.clone();
+        damaged[sync_points(&deduplicated)[0] + 30] ^= 1;
+        let (out, report) = repaired(&damaged).unwrap();
+        assert!(out == deduplicated);
+        assert_eq!(report.blocks_rebuilt, [0]);
+    }
+
+    #[test]
+    fn bit_flip_repair() {
+        let searching = |limit: u64| RepairOptions {
+            bitflip: Some(Duration::from_secs(limit)),
+            threads: Some(2),
+            ..RepairOptions::default_options()
+        };
+        let repaired = |stream: &[u8], options: &RepairOptions| {
+            let mut out = vec![];
+            repair_with_options(stream, &mut out, options).map(|repair| (out, repair))
+        };
+        let flipped = |stream: &[u8], byte: usize, bit: u8| {
+            let mut flipped = stream.to_vec();
+            flipped[byte] ^= 1 << bit;
+            flipped
+        };
+        let mut squashed = vec![];
+        squash(&mut TEXT.repeat(4).as_bytes(), &mut squashed).unwrap();
+        let frame = (0..squashed.len())
+            .find(|&i| squashed[i..].starts_with(&SYNC_MARKER))
+            .unwrap();
+        let trailer = squashed.len() - trailer_length(FILETYPE_VERSION, false) as usize;
+
+        // a flipped bit anywhere in the frame, in the block, its checksum, its length
+        // or its sync marker, is found and flipped back, even without parity
+        let middle = (frame + trailer) / 2;
+        for (byte, bit) in [(middle, 3), (frame + 12, 0), (frame + 7, 6), (frame + 2, 1)] {
+            let damaged = flipped(&squashed, byte, bit);
+            assert!(repair(&damaged, &mut vec![]).is_err());
+            let (out, report) = repaired(&damaged, &searching(60)).unwrap();
+            assert!(out == squashed, "byte {}", byte);
+            assert_eq!(
+                report.bits_flipped,
+                [FlippedBit {
+                    block: 0,
+                    byte: byte as u64,
+                    bit
+                }]
+            );
+            assert_eq!(report.damaged.len(), 1);
+            assert_eq!(report.damaged[0], frame as u64..trailer as u64);
+        }
+
+        // but not once the time's up
+        let damaged = flipped(&squashed, middle, 3);
+        let error = repaired(&damaged, &searching(0)).unwrap_err();
+        assert!(error.to_string().starts_with("damaged beyond repair"));
+
+        // in a group, flipped bits are looked for once there's more damage than parity
+        // can rebuild, and what the search doesn't find in time, parity rebuilds.
+        // Noise is stored as it is, so a bit flipped near the start of a block is
+        // found quickly, and a block that's badly damaged soon runs out the time
+        let mut seed = 7_u32;
+        let noise: Vec<u8> = (0..BLOCK_SIZE * 10)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (seed >> 24) as u8
+            })
+            .collect();
+        let options = SquashOptions {
+            parity: Some(10),
+            ..SquashOptions::default_options()
+        };
+        let mut noisy = vec![];
+        squash_with_options(&mut &noise[..], &mut noisy, &options).unwrap();
+        let frames: Vec<usize> = (0..noisy.len())
+            .filter(|&i| noisy[i..].starts_with(&SYNC_MARKER))
+            .collect();
+        let block = |b: usize| frames[b] + frame_overhead(FILETYPE_VERSION) as usize;
+        let mut damaged = flipped(&noisy, block(3) + 2, 5);
+        damaged[block(5) + 1] ^= 1;
+        damaged[block(7) + 10..block(7) + 20].fill(0x55);
+        let (out, report) = repaired(&damaged, &searching(1)).unwrap();
+        assert!(out == noisy);
+        assert_eq!(report.blocks_rebuilt, [7]);
+        let bits: Vec<(u64, u64, u8)> = report
+            .bits_flipped
+            .iter()
+            .map(|f| (f.block, f.byte, f.bit))
+            .collect();
+        assert_eq!(
+            bits,
+            [(3, block(3) as u64 + 2, 5), (5, block(5) as u64 + 1, 0)]
+        );
+        assert!(report.search_timed_out);
+    }
+}
@@ -1,114 +1,1442 @@
 use std::convert::TryFrom;
 use std::convert::TryInto;
 use std::io;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::time::Instant;
 
+use super::aead::{Key, TAG_LENGTH};
 use super::arithmetic::*;
+use super::cancel::*;
+use super::crc::crc32;
+use super::dedup::*;
+use super::encryption::*;
+use super::frame::*;
+use super::kdf::KdfParams;
+use super::metadata::Metadata;
+use super::observer::*;
+use super::parity::*;
+use super::text::*;
 use super::transforms::*;
 
-const BLOCK_SIZE: usize = 1 << 18;
-const MAGIC_NUMBER: u32 = 0xca55_e77e; // cassette :)
-const FILETYPE_VERSION: u8 = 1;
+pub const BLOCK_SIZE: usize = 1 << 18;
+pub const MAGIC_NUMBER: u32 = 0xca55_e77e; // cassette :)
+pub const FILETYPE_VERSION: u8 = 2;
+
+// the first version is still decoded. It had no block size (always BLOCK_SIZE), no
+// model byte, no stream or block flags, no sync markers or checksums and no trailer
+pub const OLDEST_VERSION: u8 = 1;
+
+// the largest block size a decoder accepts unless its limits say otherwise
+const DEFAULT_MAX_BLOCK: u32 = 1 << 24;
+
+// stream flags, written after the block size
+const STREAM_DICTIONARY: u8 = 1; // a text dictionary follows the flags
+const STREAM_DEDUP: u8 = 2; // the deduplication window follows, as a u64
+const STREAM_METADATA: u8 = 4; // the original file's metadata follows
+const STREAM_PARITY: u8 = 8; // the parity percentage follows, as a u8
+const STREAM_ENCRYPTED: u8 = 16; // blocks are encrypted, and the key's salt and tag follow
+const KNOWN_STREAM_FLAGS: u8 =
+    STREAM_DICTIONARY | STREAM_DEDUP | STREAM_METADATA | STREAM_PARITY | STREAM_ENCRYPTED;
+
+// block flags, the first byte of every block
+const BLOCK_TEXT: u8 = 1; // the block went through the text transform
+const BLOCK_DEDUP: u8 = 2; // an op list of literals and back-references follows the flags
+const BLOCK_CONFIG: u8 = 16; // the block's own arithmetic encoding metadata follows the flags
+const KNOWN_BLOCK_FLAGS: u8 = BLOCK_TEXT | BLOCK_DEDUP | BLOCK_TYPE_MASK | BLOCK_CONFIG;
+
+// block types, held in two bits of the block flags
+const BLOCK_TYPE_MASK: u8 = 0b1100;
+const BLOCK_FULL: u8 = 0b0000; // the whole bwt/mtf/rle/arithmetic pipeline
+const BLOCK_STORED: u8 = 0b0100; // raw bytes
+const BLOCK_ORDER0: u8 = 0b1000; // order-0 arithmetic coding of the bytes
+
+// blocks with more order-0 entropy than this (in bits per byte) are stored without trying
+const STORED_ENTROPY: f64 = 7.95;
+
+pub const DEFAULT_LEVEL: u8 = 6;
+// from this level up, every block searches for its best arithmetic encoder configuration
+const SEARCH_LEVEL: u8 = 7;
+
+#[derive(Clone)]
+pub struct SquashOptions {
+    // how hard to work, from 1 to 9
+    pub level: u8,
+    // run blocks that look like UTF-8 text through the text transform
+    pub text_preprocessing: bool,
+    // replace chunks repeated within this many bytes with back-references to them
+    pub dedup_window: Option<u64>,
+    // how the arithmetic coder adapts to the data
+    pub model: Model,
+    // details of the original file to store in the header
+    pub metadata: Option<Metadata>,
+    // add Reed–Solomon parity of this percentage, from 1 to 100, to each group of
+    // blocks, so that damaged blocks can be repaired
+    pub parity: Option<u8>,
+    // encrypt every block with a key derived from a password. The header isn't
+    // encrypted, so it goes without a text dictionary, which is taken from the data
+    pub encryption: Option<Encryption>,
+    // set this from another thread to stop partway, with a `Cancelled` error
+    pub cancel: Option<Arc<AtomicBool>>,
+}
+
+impl SquashOptions {
+    pub fn default_options() -> Self {
+        SquashOptions {
+            level: DEFAULT_LEVEL,
+            text_preprocessing: true,
+            dedup_window: None,
+            model: Model::Window,
+            metadata: None,
+            parity: None,
+            encryption: None,
+            cancel: None,
+        }
+    }
+}
+
+// bounds on what decoding a stream may cost, for streams from untrusted sources, and
+// how strictly it's read. Going over any of them fails with an InvalidData error
+#[derive(Clone, Debug)]
+pub struct DecodeLimits {
+    // the largest block size a stream may declare, which bounds the memory that
+    // decoding each block takes
+    pub max_block: u32,
+    // the largest deduplication window a stream may declare, which bounds how much of
+    // the output is kept to copy from. Streams squashed with a larger one need this
+    // raised to decode
+    pub max_window: u64,
+    // the most bytes the input may decode to, over all of its streams
+    pub max_output: Option<u64>,
+    // the most bytes the input may decode to for each byte of it read
+    pub max_ratio: Option<f64>,
+    // refuse anything after the last stream that isn't another stream, instead of
+    // ignoring it
+    pub strict: bool,
+}
+
+impl DecodeLimits {
+    pub fn default_limits() -> Self {
+        DecodeLimits {
+            max_block: DEFAULT_MAX_BLOCK,
+            max_window: DEFAULT_WINDOW,
+            max_output: None,
+            max_ratio: None,
+            strict: false,
+        }
+    }
+}
+
+// everything written at the start of a stream, before the first block
+pub struct Header {
+    pub version: u8,
+    pub arithmetic_encoder: ArithmeticEncoder,
+    // no block decodes to more than this
+    pub block_size: u32,
+    pub dictionary: Dictionary,
+    pub dedup_window: Option<u64>,
+    pub metadata: Option<Metadata>,
+    pub parity: Option<u8>,
+    pub encryption: Option<KeyHeader>,
+}
+
+// everything about a stream that its blocks need to be encoded or decoded
+pub struct StreamContext {
+    // the format version the blocks are laid out in
+    pub version: u8,
+    pub arithmetic_encoder: ArithmeticEncoder,
+    pub dictionary: Dictionary,
+    pub block_size: usize,
+    // recent output, kept when the stream uses long-range deduplication
+    pub history: Option<History>,
+    // the parity percentage, when groups of blocks are followed by parity shards
+    pub parity: Option<u8>,
+    // whether the blocks are encrypted, and the key to them once it's known
+    pub encrypted: bool,
+    pub key: Option<Key>,
+}
 
 // read from input stream, compress, and write to output stream
 pub fn squash(reader: &mut dyn io::Read, writer: &mut dyn io::Write) -> io::Result<()> {
-    // write file metadata
-    writer.write_all(&MAGIC_NUMBER.to_le_bytes())?;
-    writer.write_all(&FILETYPE_VERSION.to_le_bytes())?;
+    squash_with_options(reader, writer, &SquashOptions::default_options())
+}
 
-    let arithmetic_encoder = ArithmeticEncoder::default_encoder();
-    arithmetic_encoder.write_config(writer)?; // write arithmetic encoding metadata
+// read from input stream, compress, and write to output stream
+pub fn squash_with_options(
+    reader: &mut dyn io::Read,
+    writer: &mut dyn io::Write,
+    options: &SquashOptions,
+) -> io::Result<()> {
+    squash_with_observer(reader, writer, options, &mut |_: &Event| {})
+}
+
+// read from input stream, compress, and write to output stream, telling the observer
+// about each step
+pub fn squash_with_observer(
+    reader: &mut dyn io::Read,
+    writer: &mut dyn io::Write,
+    options: &SquashOptions,
+    observer: &mut dyn Observer,
+) -> io::Result<()> {
+    let start = Instant::now();
+    let cancel = Cancel::new(options.cancel.as_deref());
+    // the first block is read ahead so the text dictionary can be built from it
+    let mut block = vec![0; BLOCK_SIZE];
+    let mut bytes = read_block(reader, &mut block)?;
+    let mut squasher = SquashWriter::new(writer, options);
+    let mut input_size = 0;
+    let mut output_size = squasher.start(&block[0..bytes])?;
+    observer.observe(&Event::Header {
+        length: output_size,
+    });
 
     // block by block, compress and write data into the file
+    let mut index = 0;
+    while bytes > 0 {
+        cancel.check()?;
+        let block_start = Instant::now();
+        observer.observe(&Event::BlockStarted {
+            index,
+            input_size: bytes as u64,
+        });
+        let stored = squasher.write_block(&block[0..bytes])?;
+        input_size += bytes as u64;
+        output_size += stored;
+        observer.observe(&Event::BlockFinished {
+            index,
+            input_size: bytes as u64,
+            output_size: stored,
+            elapsed: block_start.elapsed(),
+        });
+        index += 1;
+        bytes = read_block(reader, &mut block)?;
+    }
+    // only a stream that was finished gets a trailer
+    output_size += squasher.finish_counted()?.1;
+    observer.observe(&Event::Finished {
+        input_size,
+        output_size,
+        elapsed: start.elapsed(),
+    });
+    Ok(())
+}
+
+// squashes everything written to it into a stream, a block at a time. `finish` writes
+// whatever is left and the trailer: a writer dropped without finishing leaves a
+// stream that won't decode
+pub struct SquashWriter<W: io::Write> {
+    writer: W,
+    options: SquashOptions,
+    // None until the header is written, which waits for the first block so that the
+    // text dictionary can be built from it
+    context: Option<StreamContext>,
+    deduplicator: Option<Deduplicator>,
+    // plaintext waiting for a whole block's worth
+    pending: Vec<u8>,
+    block_size: usize,
+    // what the trailer will say
+    trailer: Trailer,
+    // the blocks since the last parity shards, as stored, in streams with parity
+    group: Vec<(Vec<u8>, Slot)>,
+    // set when appending to a stream in place: the trailer is written after every
+    // block and this steps back over it, so the stream stays whole between blocks
+    step_back: Option<fn(&mut W) -> io::Result<()>>,
+}
+
+impl<W: io::Write> SquashWriter<W> {
+    // start a new stream
+    pub fn new(writer: W, options: &SquashOptions) -> Self {
+        SquashWriter {
+            writer,
+            options: options.clone(),
+            context: None,
+            deduplicator: options.dedup_window.map(Deduplicator::new),
+            pending: vec![],
+            block_size: BLOCK_SIZE,
+            trailer: Trailer {
+                length: 0,
+                blocks: 0,
+            },
+            group: vec![],
+            step_back: None,
+        }
+    }
+
+    // squash what's left, write the trailer, and hand back the writer
+    pub fn finish(self) -> io::Result<W> {
+        self.finish_counted().map(|(writer, _)| writer)
+    }
+
+    // finish, also returning how much was written in doing so
+    fn finish_counted(mut self) -> io::Result<(W, u64)> {
+        let pending = std::mem::take(&mut self.pending);
+        let mut written = self.start(&pending)?;
+        if !pending.is_empty() {
+            written += self.write_block(&pending)?;
+        }
+        written += self.write_parity()?;
+        // when appending, this is where the trailer already is
+        let key = self.context.as_ref().and_then(|c| c.key);
+        write_trailer(&mut self.writer, &self.trailer, key.as_ref())?;
+        written += trailer_length(FILETYPE_VERSION, key.is_some());
+        self.writer.flush()?;
+        Ok((self.writer, written))
+    }
+
+    // write the header, if it hasn't been, building the text dictionary from the
+    // first block. Returns the header's length
+    fn start(&mut self, first_block: &[u8]) -> io::Result<u64> {
+        if self.context.is_some() {
+            return Ok(0);
+        }
+        let options = &self.options;
+        if options
+            .parity
+            .is_some_and(|percent| !(1..=100).contains(&percent))
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "parity percentage must be from 1 to 100",
+            ));
+        }
+        if let Some(encryption) = &options.encryption {
+            encryption
+                .kdf
+                .check()
+                .map_err(|x| io::Error::new(io::ErrorKind::InvalidInput, x))?;
+        }
+        let dictionary = if options.text_preprocessing
+            && options.encryption.is_none()
+            && looks_like_text(first_block)
+        {
+            Dictionary::from_sample(first_block)
+        } else {
+            Dictionary::empty()
+        };
+        let header = Header {
+            version: FILETYPE_VERSION,
+            arithmetic_encoder: ArithmeticEncoder {
+                model: options.model,
+                ..ArithmeticEncoder::default_encoder()
+            },
+            block_size: BLOCK_SIZE.try_into().unwrap(),
+            dictionary,
+            dedup_window: options.dedup_window,
+            metadata: options.metadata.clone(),
+            parity: options.parity,
+            encryption: options.encryption.as_ref().map(|e| KeyHeader::new(e.kdf)),
+        };
+        let mut header_bytes = vec![];
+        write_header(&mut header_bytes, &header)?;
+        // the tag ends the header, and is over everything before it
+        let key = match (&header.encryption, &options.encryption) {
+            (Some(key_header), Some(encryption)) => {
+                let key = key_header.derive_key(&encryption.password);
+                let tag_start = header_bytes.len() - TAG_LENGTH;
+                let tag = header_tag(&key, &header_bytes[..tag_start]);
+                header_bytes[tag_start..].copy_from_slice(&tag);
+                Some(key)
+            }
+            _ => None,
+        };
+        self.writer.write_all(&header_bytes)?;
+        self.context = Some(StreamContext {
+            version: header.version,
+            arithmetic_encoder: header.arithmetic_encoder,
+            dictionary: header.dictionary,
+            block_size: BLOCK_SIZE,
+            history: None,
+            parity: header.parity,
+            encrypted: key.is_some(),
+            key,
+        });
+        Ok(header_bytes.len() as u64)
+    }
+
+    // squash and write one block, of at most the block size, returning its length
+    // as stored, with that of any parity shards that it completes
+    fn write_block(&mut self, plaintext: &[u8]) -> io::Result<u64> {
+        self.start(plaintext)?;
+        let context = self.context.as_ref().unwrap();
+        let options = &self.options;
+        let squashed = match self
+            .deduplicator
+            .as_mut()
+            .and_then(|d| d.deduplicate(plaintext))
+        {
+            Some((ops, literals)) => encode_block(&literals, Some(&ops), context, options)?,
+            None => encode_block(plaintext, None, context, options)?,
+        };
+        // an encrypted block's checksum is of the block as stored, so that it gives
+        // nothing away about the plaintext
+        let (squashed, checksum) = match &context.key {
+            Some(key) => {
+                let sealed = seal_block(key, u64::from(self.trailer.blocks), &squashed);
+                let checksum = crc32(&sealed);
+                (sealed, checksum)
+            }
+            None => (squashed, crc32(plaintext)),
+        };
+        let squashed_len = u32::try_from(squashed.len()).unwrap().to_le_bytes();
+        self.writer.write_all(&SYNC_MARKER)?;
+        self.writer.write_all(&squashed_len)?;
+        self.writer.write_all(&checksum.to_le_bytes())?;
+        self.writer.write_all(&squashed)?;
+        self.trailer.length += plaintext.len() as u64;
+        self.trailer.blocks += 1;
+        let mut stored = frame_overhead(FILETYPE_VERSION) + squashed.len() as u64;
+        if context.parity.is_some() {
+            let slot = Slot {
+                length: u32::try_from(squashed.len()).unwrap(),
+                checksum,
+                decoded_length: u32::try_from(plaintext.len()).unwrap(),
+            };
+            self.group.push((squashed, slot));
+            if self.group.len() == GROUP_BLOCKS {
+                stored += self.write_parity()?;
+            }
+        }
+        if let Some(step_back) = self.step_back {
+            write_trailer(&mut self.writer, &self.trailer, None)?;
+            step_back(&mut self.writer)?;
+        }
+        Ok(stored)
+    }
+
+    // write the parity shards of the blocks since the last ones, if the stream has
+    // parity, returning their length as stored
+    fn write_parity(&mut self) -> io::Result<u64> {
+        let percent = match self.context.as_ref().and_then(|c| c.parity) {
+            Some(percent) if !self.group.is_empty() => percent,
+            _ => return Ok(0),
+        };
+        let group: Vec<(&[u8], Slot)> = self.group.iter().map(|(b, s)| (&b[..], *s)).collect();
+        let group_start = self.trailer.blocks - group.len() as u32;
+        let mut stored = 0;
+        for shard in encode(group_start, &group, shards_for(group.len(), percent)) {
+            let mut bytes = vec![];
+            shard.write(&mut bytes);
+            self.writer.write_all(&PARITY_MARKER)?;
+            self.writer
+                .write_all(&u32::try_from(bytes.len()).unwrap().to_le_bytes())?;
+            self.writer.write_all(&crc32(&bytes).to_le_bytes())?;
+            self.writer.write_all(&bytes)?;
+            stored += frame_overhead(FILETYPE_VERSION) + bytes.len() as u64;
+        }
+        self.group.clear();
+        Ok(stored)
+    }
+}
+
+impl<W: io::Read + io::Write + io::Seek> SquashWriter<W> {
+    // carry on a finished stream in place, adding new blocks after its last one. The
+    // blocks already there are left as they are, and aren't decoded, so the new ones
+    // take the header's settings (the text dictionary, arithmetic configuration and
+    // deduplication window) and can only deduplicate against each other
+    pub fn append_to(mut file: W, options: &SquashOptions) -> io::Result<Self> {
+        file.rewind()?;
+        let header = read_header(&mut file, &DecodeLimits::default_limits())?;
+        if header.version != FILETYPE_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "can only append to a stream of the current format version",
+            ));
+        }
+        if header.encryption.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "can't append to an encrypted stream",
+            ));
+        }
+        let context = StreamContext {
+            version: header.version,
+            arithmetic_encoder: header.arithmetic_encoder,
+            dictionary: header.dictionary,
+            block_size: header.block_size.try_into().unwrap(),
+            history: None,
+            parity: header.parity,
+            encrypted: false,
+            key: None,
+        };
+
+        // skip over the blocks to the trailer, which must end the file
+        let mut blocks = 0;
+        let trailer = loop {
+            match read_frame_start(&mut file, &context)? {
+                Some(FrameStart::Block { length, .. }) => {
+                    file.seek(io::SeekFrom::Current(length.try_into().unwrap()))?;
+                    blocks += 1;
+                }
+                Some(FrameStart::Parity { length, .. }) => {
+                    file.seek(io::SeekFrom::Current(length.try_into().unwrap()))?;
+                }
+                Some(FrameStart::End(trailer)) => break trailer,
+                Some(FrameStart::NextStream) | None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "stream ends without its trailer",
+                    ))
+                }
+            }
+        };
+        if trailer.blocks != blocks {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "trailer doesn't match the stream",
+            ));
+        }
+        let end = file.stream_position()?;
+        if file.seek(io::SeekFrom::End(0))? != end {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "trailing data after the stream",
+            ));
+        }
+        // new blocks go where the trailer is now
+        file.seek(io::SeekFrom::Start(
+            end - trailer_length(FILETYPE_VERSION, false),
+        ))?;
+
+        Ok(SquashWriter {
+            writer: file,
+            options: options.clone(),
+            deduplicator: header
+                .dedup_window
+                .map(|window| Deduplicator::starting_at(window, trailer.length)),
+            block_size: context.block_size,
+            context: Some(context),
+            pending: vec![],
+            trailer,
+            group: vec![],
+            step_back: Some(|file| {
+                let trailer_length = trailer_length(FILETYPE_VERSION, false) as i64;
+                file.seek(io::SeekFrom::Current(-trailer_length))?;
+                Ok(())
+            }),
+        })
+    }
+}
+
+impl<W: io::Write> io::Write for SquashWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let taken = buf.len().min(self.block_size - self.pending.len());
+        self.pending.extend_from_slice(&buf[..taken]);
+        if self.pending.len() == self.block_size {
+            let block = std::mem::take(&mut self.pending);
+            self.write_block(&block)?;
+        }
+        Ok(taken)
+    }
+
+    // blocks are only squashed once they're full, so this doesn't write a partial one
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+// read from input stream, decompress, and write to output stream
+pub fn unsquash(reader: &mut dyn io::Read, writer: &mut dyn io::Write) -> io::Result<()> {
+    unsquash_with_limits(reader, writer, &DecodeLimits::default_limits())
+}
+
+// read from input stream, decompress, and write to output stream, stopping with an
+// error as soon as the stream goes over any of the limits
+pub fn unsquash_with_limits(
+    reader: &mut dyn io::Read,
+    writer: &mut dyn io::Write,
+    limits: &DecodeLimits,
+) -> io::Result<()> {
+    unsquash_with_observer(reader, writer, limits, &mut |_: &Event| {})
+}
+
+// read from input stream, decompress, and write to output stream, within the limits
+// and telling the observer about each step. Streams that follow one another, as
+// squashed files concatenated together do, are decoded one after the other
+pub fn unsquash_with_observer(
+    reader: &mut dyn io::Read,
+    writer: &mut dyn io::Write,
+    limits: &DecodeLimits,
+    observer: &mut dyn Observer,
+) -> io::Result<()> {
+    unsquash_streams(reader, writer, limits, None, observer)
+}
+
+// read from input stream, decrypt it with the password, decompress, and write to
+// output stream, within the limits. Streams that aren't encrypted decode as usual,
+// while without the password, encrypted ones fail with an InvalidInput error
+pub fn unsquash_with_password(
+    reader: &mut dyn io::Read,
+    writer: &mut dyn io::Write,
+    limits: &DecodeLimits,
+    password: &Password,
+) -> io::Result<()> {
+    unsquash_streams(reader, writer, limits, Some(password), &mut |_: &Event| {})
+}
+
+fn unsquash_streams(
+    reader: &mut dyn io::Read,
+    writer: &mut dyn io::Write,
+    limits: &DecodeLimits,
+    password: Option<&Password>,
+    observer: &mut dyn Observer,
+) -> io::Result<()> {
+    let start = Instant::now();
+    let mut totals = Totals {
+        input_size: 0,
+        output_size: 0,
+        blocks: 0,
+    };
+    let mut header = read_header(reader, limits)?;
     loop {
-        let mut block = vec![0; BLOCK_SIZE];
-        let bytes = reader.read(&mut block)?;
-        match bytes {
-            0 => {
+        match unsquash_stream(
+            reader,
+            writer,
+            header,
+            limits,
+            password,
+            observer,
+            &mut totals,
+        )? {
+            StreamEnd::Eof => break,
+            StreamEnd::NextStream => header = read_header_after_magic(reader, limits)?,
+            StreamEnd::TrailingData if limits.strict => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "trailing data after the stream",
+                ))
+            }
+            StreamEnd::TrailingData => break,
+        }
+    }
+    observer.observe(&Event::Finished {
+        input_size: totals.input_size,
+        output_size: totals.output_size,
+        elapsed: start.elapsed(),
+    });
+    Ok(())
+}
+
+// how far through the input unsquashing has got, over every stream so far
+struct Totals {
+    input_size: u64,
+    output_size: u64,
+    blocks: usize,
+}
+
+// what follows the end of a stream
+enum StreamEnd {
+    // nothing at all
+    Eof,
+    // the magic number of another stream, which has been read
+    NextStream,
+    // anything else, some of which has been read
+    TrailingData,
+}
+
+// decode the blocks of the stream whose header has been read, up to its trailer
+fn unsquash_stream(
+    reader: &mut dyn io::Read,
+    writer: &mut dyn io::Write,
+    header: Header,
+    limits: &DecodeLimits,
+    password: Option<&Password>,
+    observer: &mut dyn Observer,
+    totals: &mut Totals,
+) -> io::Result<StreamEnd> {
+    let header_length = header_length(&header)?;
+    let key = stream_key(&header, password)?;
+    totals.input_size += header_length;
+    observer.observe(&Event::Header {
+        length: header_length,
+    });
+    let mut context = StreamContext {
+        version: header.version,
+        arithmetic_encoder: header.arithmetic_encoder,
+        dictionary: header.dictionary,
+        block_size: header.block_size.try_into().unwrap(),
+        history: header.dedup_window.map(History::new),
+        parity: header.parity,
+        encrypted: key.is_some(),
+        key,
+    };
+
+    // read and uncompress each block in turn, up to the trailer
+    let mut output_size = 0;
+    let mut blocks = 0;
+    loop {
+        let (block, checksum) = match read_frame(reader, &context)? {
+            Some(Frame::Block { block, checksum }) => (block, checksum),
+            // there for repairs, and not needed to decode an intact stream
+            Some(Frame::Parity(shard)) => {
+                check_parity_expected(&context)?;
+                totals.input_size += frame_overhead(context.version) + shard.length() as u64;
+                continue;
+            }
+            Some(Frame::End(trailer)) => {
+                if trailer.length != output_size || trailer.blocks as usize != blocks {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "trailer doesn't match the stream",
+                    ));
+                }
+                totals.input_size += trailer_length(context.version, context.encrypted);
+                return read_stream_end(reader);
+            }
+            // streams from before the trailer simply end after their last block
+            Some(Frame::NextStream) if context.version == OLDEST_VERSION => {
+                return Ok(StreamEnd::NextStream)
+            }
+            None if context.version == OLDEST_VERSION => return Ok(StreamEnd::Eof),
+            Some(Frame::NextStream) | None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "stream ends without its trailer",
+                ))
+            }
+        };
+        let block_start = Instant::now();
+        let index = totals.blocks;
+        let stored = frame_overhead(context.version) + block.len() as u64;
+        observer.observe(&Event::BlockStarted {
+            index,
+            input_size: stored,
+        });
+        let decoded = decode_checked(&block, checksum, blocks as u64, &context)?;
+        totals.input_size += stored;
+        totals.output_size += decoded.len() as u64;
+        check_limits(limits, totals.input_size, totals.output_size)?;
+        if let Some(history) = &mut context.history {
+            history.extend(&decoded);
+        }
+        writer.write_all(&decoded)?;
+        observer.observe(&Event::BlockFinished {
+            index,
+            input_size: stored,
+            output_size: decoded.len() as u64,
+            elapsed: block_start.elapsed(),
+        });
+        output_size += decoded.len() as u64;
+        blocks += 1;
+        totals.blocks += 1;
+    }
+}
+
+// look at what comes after a stream's trailer
+fn read_stream_end(reader: &mut dyn io::Read) -> io::Result<StreamEnd> {
+    let mut four_bytes: [u8; 4] = [0; 4];
+    Ok(match read_block(reader, &mut four_bytes)? {
+        0 => StreamEnd::Eof,
+        4 if u32::from_le_bytes(four_bytes) == MAGIC_NUMBER => StreamEnd::NextStream,
+        _ => StreamEnd::TrailingData,
+    })
+}
+
+// fail once a stream has decoded to more than its limits allow, having read
+// `input_size` bytes of it
+fn check_limits(limits: &DecodeLimits, input_size: u64, output_size: u64) -> io::Result<()> {
+    if limits.max_output.is_some_and(|max| output_size > max) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "decoded size over the limit",
+        ));
+    }
+    if limits
+        .max_ratio
+        .is_some_and(|max| output_size as f64 > input_size as f64 * max)
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "compression ratio over the limit",
+        ));
+    }
+    Ok(())
+}
+
+// read the header at the start of a stream, returning the original file's metadata if stored
+pub fn read_metadata(reader: &mut dyn io::Read) -> io::Result<Option<Metadata>> {
+    Ok(read_header(reader, &DecodeLimits::default_limits())?.metadata)
+}
+
+// read the header at the start of a stream, returning whether its blocks are encrypted
+pub fn is_encrypted(reader: &mut dyn io::Read) -> io::Result<bool> {
+    Ok(read_header(reader, &DecodeLimits::default_limits())?
+        .encryption
+        .is_some())
+}
+
+// what a stream's header says
+pub struct StreamInfo {
+    pub version: u8,
+    pub arithmetic_encoder: ArithmeticEncoder,
+    pub block_size: u32,
+    pub dictionary_words: usize,
+    pub dedup_window: Option<u64>,
+    pub metadata: Option<Metadata>,
+    // the parity percentage, if the stream has parity
+    pub parity: Option<u8>,
+    // what deriving the key costs, if the stream is encrypted
+    pub encryption: Option<KdfParams>,
+    pub header_length: u64,
+    pub blocks: Vec<BlockInfo>,
+    // the length of each parity shard, as stored, not counting its frame
+    pub parity_shards: Vec<u32>,
+    // None if the stream stops short of its trailer, as one that was cut off or
+    // cancelled does
+    pub trailer: Option<Trailer>,
+}
+
+impl StreamInfo {
+    // the whole stream as it's stored: the header, each block and parity shard with
+    // the length (and sync marker and checksum) before it, and the trailer
+    pub fn stored_size(&self) -> u64 {
+        let overhead = frame_overhead(self.version);
+        let blocks: u64 = self
+            .blocks
+            .iter()
+            .map(|b| overhead + u64::from(b.length))
+            .sum();
+        let parity: u64 = self
+            .parity_shards
+            .iter()
+            .map(|&length| overhead + u64::from(length))
+            .sum();
+        let trailer = if self.trailer.is_some() {
+            trailer_length(self.version, self.encryption.is_some())
+        } else {
+            0
+        };
+        self.header_length + blocks + parity + trailer
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum BlockType {
+    Full,
+    Stored,
+    Order0,
+    // which of the others it is is encrypted along with the rest of it
+    Encrypted,
+}
+
+// what a block says about itself
+pub struct BlockInfo {
+    // as stored, not counting the length, sync marker or checksum before it
+    pub length: u32,
+    pub block_type: BlockType,
+    pub text: bool,
+    // the number of literals and back-references, in a deduplicated block
+    pub dedup_ops: Option<usize>,
+    // the block's own arithmetic encoder configuration, if it has one
+    pub arithmetic_encoder: Option<ArithmeticEncoder>,
+    // for the arithmetic coded block types
+    pub front_matter: Option<FrontMatter>,
+    // how big the block is once decoded, when the stream was decoded
+    pub decoded_size: Option<usize>,
+}
+
+// read through a stream, describing its header and each of its blocks. With `decode`,
+// every block is decoded too, to find its size. The blocks of an encrypted stream
+// can't be decoded, or described beyond their lengths
+pub fn inspect(reader: &mut dyn io::Read, decode: bool) -> io::Result<StreamInfo> {
+    let header = read_header(reader, &DecodeLimits::default_limits())?;
+    if decode && header.encryption.is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "the stream is encrypted, so its blocks can't be decoded",
+        ));
+    }
+    let header_length = header_length(&header)?;
+    let mut info = StreamInfo {
+        version: header.version,
+        arithmetic_encoder: header.arithmetic_encoder,
+        block_size: header.block_size,
+        dictionary_words: header.dictionary.len(),
+        dedup_window: header.dedup_window,
+        metadata: header.metadata,
+        parity: header.parity,
+        encryption: header.encryption.as_ref().map(|e| e.kdf),
+        header_length,
+        blocks: vec![],
+        parity_shards: vec![],
+        trailer: None,
+    };
+    let mut context = StreamContext {
+        version: header.version,
+        arithmetic_encoder: header.arithmetic_encoder,
+        dictionary: header.dictionary,
+        block_size: header.block_size.try_into().unwrap(),
+        history: header.dedup_window.map(History::new),
+        parity: header.parity,
+        encrypted: header.encryption.is_some(),
+        key: None,
+    };
+
+    while let Some(frame) = read_frame(reader, &context)? {
+        let (block, checksum) = match frame {
+            Frame::Block { block, checksum } => (block, checksum),
+            Frame::Parity(shard) => {
+                check_parity_expected(&context)?;
+                info.parity_shards.push(shard.length() as u32);
+                continue;
+            }
+            Frame::End(trailer) => {
+                info.trailer = Some(trailer);
                 break;
             }
-            n => {
-                let squashed = squash_block(&block[0..n], &arithmetic_encoder);
-                let squashed_len = u32::try_from(squashed.len()).unwrap().to_le_bytes();
-                writer.write_all(&squashed_len)?;
-                writer.write_all(&squashed)?;
+            // only the first of several concatenated streams is described
+            Frame::NextStream => break,
+        };
+        if context.encrypted {
+            info.blocks.push(BlockInfo {
+                length: block.len() as u32,
+                block_type: BlockType::Encrypted,
+                text: false,
+                dedup_ops: None,
+                arithmetic_encoder: None,
+                front_matter: None,
+                decoded_size: None,
+            });
+            continue;
+        }
+        let parts = split_block(&block, context.version).map_err(io::Error::other)?;
+        let block_type = match parts.flags & BLOCK_TYPE_MASK {
+            BLOCK_FULL => BlockType::Full,
+            BLOCK_STORED => BlockType::Stored,
+            _ => BlockType::Order0,
+        };
+        let front_matter = match block_type {
+            BlockType::Stored => None,
+            _ => Some(get_front_matter(parts.body).map_err(io::Error::other)?.1),
+        };
+        let decoded_size = if decode {
+            let decoded = decode_checked(&block, checksum, info.blocks.len() as u64, &context)?;
+            if let Some(history) = &mut context.history {
+                history.extend(&decoded);
             }
+            Some(decoded.len())
+        } else {
+            None
         };
+        info.blocks.push(BlockInfo {
+            length: block.len() as u32,
+            block_type,
+            text: parts.flags & BLOCK_TEXT != 0,
+            dedup_ops: parts.ops.map(|ops| ops.len()),
+            arithmetic_encoder: parts.arithmetic_encoder,
+            front_matter,
+            decoded_size,
+        });
+    }
+    Ok(info)
+}
+
+// how long a header is once written
+fn header_length(header: &Header) -> io::Result<u64> {
+    let mut header_bytes = vec![];
+    write_header(&mut header_bytes, header)?;
+    Ok(header_bytes.len() as u64)
+}
+
+fn write_header(writer: &mut dyn io::Write, header: &Header) -> io::Result<()> {
+    // write file metadata
+    writer.write_all(&MAGIC_NUMBER.to_le_bytes())?;
+    writer.write_all(&header.version.to_le_bytes())?;
+    // write arithmetic encoding metadata
+    if header.version == OLDEST_VERSION {
+        return header.arithmetic_encoder.write_config_without_model(writer);
+    }
+    header.arithmetic_encoder.write_config(writer)?;
+    writer.write_all(&header.block_size.to_le_bytes())?;
+
+    let mut stream_flags = 0;
+    if !header.dictionary.is_empty() {
+        stream_flags |= STREAM_DICTIONARY;
+    }
+    if header.dedup_window.is_some() {
+        stream_flags |= STREAM_DEDUP;
+    }
+    if header.metadata.is_some() {
+        stream_flags |= STREAM_METADATA;
+    }
+    if header.parity.is_some() {
+        stream_flags |= STREAM_PARITY;
+    }
+    if header.encryption.is_some() {
+        stream_flags |= STREAM_ENCRYPTED;
+    }
+    writer.write_all(&[stream_flags])?;
+    if !header.dictionary.is_empty() {
+        header.dictionary.write(writer)?;
+    }
+    if let Some(window) = header.dedup_window {
+        writer.write_all(&window.to_le_bytes())?;
+    }
+    if let Some(metadata) = &header.metadata {
+        metadata.write(writer)?;
+    }
+    if let Some(percent) = header.parity {
+        writer.write_all(&[percent])?;
+    }
+    // last, since its tag is over everything else
+    if let Some(key_header) = &header.encryption {
+        key_header.write(writer)?;
     }
     Ok(())
 }
 
-// read from input stream, decompress, and write to output stream
-pub fn unsquash(reader: &mut dyn io::Read, writer: &mut dyn io::Write) -> io::Result<()> {
+// derive the key of an encrypted stream from the password, checking it against the
+// header's tag. None for a stream that isn't encrypted
+pub fn stream_key(header: &Header, password: Option<&Password>) -> io::Result<Option<Key>> {
+    let key_header = match &header.encryption {
+        Some(key_header) => key_header,
+        None => return Ok(None),
+    };
+    let password = password.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "the stream is encrypted, and needs a password",
+        )
+    })?;
+    let key = key_header.derive_key(password);
+    let mut header_bytes = vec![];
+    write_header(&mut header_bytes, header)?;
+    header_bytes.truncate(header_bytes.len() - TAG_LENGTH);
+    if header_tag(&key, &header_bytes) != key_header.tag {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "wrong password, or the header is damaged",
+        ));
+    }
+    Ok(Some(key))
+}
+
+pub fn read_header(reader: &mut dyn io::Read, limits: &DecodeLimits) -> io::Result<Header> {
+    let mut four_bytes: [u8; 4] = [0; 4];
+    reader.read_exact(&mut four_bytes)?;
+    if u32::from_le_bytes(four_bytes) != MAGIC_NUMBER {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not in squash format",
+        ));
+    }
+    read_header_after_magic(reader, limits)
+}
+
+// read the rest of a header, once its magic number has been read
+fn read_header_after_magic(reader: &mut dyn io::Read, limits: &DecodeLimits) -> io::Result<Header> {
     // buffers
     let mut one_byte: [u8; 1] = [0; 1];
     let mut four_bytes: [u8; 4] = [0; 4];
 
     // read file metadata
-    reader.read_exact(&mut four_bytes)?;
-    let magic_number = u32::from_le_bytes(four_bytes);
     reader.read_exact(&mut one_byte)?;
     let version_number = u8::from_le_bytes(one_byte);
-    assert_eq!(magic_number, MAGIC_NUMBER);
-    assert_eq!(version_number, FILETYPE_VERSION);
+    if !(OLDEST_VERSION..=FILETYPE_VERSION).contains(&version_number) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "unsupported format version",
+        ));
+    }
 
     // read arithmetic encoding metadata
-    let arithmetic_encoder = ArithmeticEncoder::read_config(reader)?;
+    let arithmetic_encoder = if version_number == OLDEST_VERSION {
+        ArithmeticEncoder::read_config_without_model(reader)?
+    } else {
+        ArithmeticEncoder::read_config(reader)?
+    };
 
-    loop {
-        // read the length of the next block
-        let block_len = match reader.read(&mut four_bytes)? {
-            0 => {
-                break;
-            }
-            4 => u32::from_le_bytes(four_bytes),
-            _ => {
-                panic!("expected something");
-            }
-        };
-        // read and uncompress the next block
-        let mut block = vec![0; block_len.try_into().unwrap()];
-        match reader.read(&mut block)? {
-            0 => {
-                panic!("expected something");
+    let block_size = if version_number == OLDEST_VERSION {
+        BLOCK_SIZE.try_into().unwrap()
+    } else {
+        reader.read_exact(&mut four_bytes)?;
+        u32::from_le_bytes(four_bytes)
+    };
+    if block_size == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "block size of zero",
+        ));
+    }
+    if block_size > limits.max_block {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "block size over the limit",
+        ));
+    }
+
+    let stream_flags = if version_number == OLDEST_VERSION {
+        0
+    } else {
+        reader.read_exact(&mut one_byte)?;
+        one_byte[0]
+    };
+    if stream_flags & !KNOWN_STREAM_FLAGS != 0 {
+        return Err(io::Error::other("unsupported stream flags"));
+    }
+    let dictionary = if stream_flags & STREAM_DICTIONARY != 0 {
+        Dictionary::read(reader)?
+    } else {
+        Dictionary::empty()
+    };
+    let dedup_window = if stream_flags & STREAM_DEDUP != 0 {
+        let window = read_window(reader)?;
+        if window > limits.max_window {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "dedup window over the limit",
+            ));
+        }
+        Some(window)
+    } else {
+        None
+    };
+    let metadata = if stream_flags & STREAM_METADATA != 0 {
+        Some(Metadata::read(reader)?)
+    } else {
+        None
+    };
+    let parity = if stream_flags & STREAM_PARITY != 0 {
+        reader.read_exact(&mut one_byte)?;
+        if !(1..=100).contains(&one_byte[0]) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "parity percentage out of range",
+            ));
+        }
+        Some(one_byte[0])
+    } else {
+        None
+    };
+    let encryption = if stream_flags & STREAM_ENCRYPTED != 0 {
+        Some(KeyHeader::read(reader)?)
+    } else {
+        None
+    };
+    Ok(Header {
+        version: version_number,
+        arithmetic_encoder,
+        block_size,
+        dictionary,
+        dedup_window,
+        metadata,
+        parity,
+        encryption,
+    })
+}
+
+// fill a buffer from the reader, returning less than a full buffer only at the end of input
+pub fn read_block(reader: &mut dyn io::Read, block: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < block.len() {
+        match reader.read(&mut block[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+// run a block through any preprocessing and then squash it, prefixed by its block flags.
+// A deduplicated block is given as its op list and the literals left over
+fn encode_block(
+    plaintext: &[u8],
+    ops: Option<&[Op]>,
+    context: &StreamContext,
+    options: &SquashOptions,
+) -> Result<Vec<u8>, Cancelled> {
+    let cancel = Cancel::new(options.cancel.as_deref());
+    // the stream's own configuration is always tried first, so it wins any ties
+    let mut encoders = vec![context.arithmetic_encoder];
+    if options.level >= SEARCH_LEVEL {
+        encoders.extend(
+            CANDIDATE_ENCODERS
+                .iter()
+                .filter(|e| **e != context.arithmetic_encoder),
+        );
+    }
+
+    // try the full pipeline unless the block looks incompressible, and fall back to
+    // cheaper block types whenever it doesn't pay for itself
+    let mut body = None;
+    if order0_entropy(plaintext) < STORED_ENTROPY {
+        let (text_flag, (encoder, squashed)) =
+            if options.text_preprocessing && looks_like_text(plaintext) {
+                let preprocessed = text_transform(plaintext, &context.dictionary);
+                (
+                    BLOCK_TEXT,
+                    squash_block_with_best(&preprocessed, &encoders, cancel)?,
+                )
+            } else {
+                (0, squash_block_with_best(plaintext, &encoders, cancel)?)
+            };
+        if squashed.len() < plaintext.len() {
+            body = Some((BLOCK_FULL | text_flag, encoder, squashed));
+        } else {
+            let (encoder, packed) = order0_pack_with_best(plaintext, &encoders, cancel)?;
+            if packed.len() < plaintext.len() {
+                body = Some((BLOCK_ORDER0, encoder, packed));
             }
-            _ => match unsquash_block(&block, &arithmetic_encoder) {
-                Ok(x) => {
-                    writer.write_all(&x)?;
-                }
-                Err(s) => {
-                    return Err(io::Error::new(io::ErrorKind::Other, s));
-                }
-            },
-        };
+        }
     }
-    Ok(())
+    let (type_flags, encoder, mut body) =
+        body.unwrap_or_else(|| (BLOCK_STORED, context.arithmetic_encoder, plaintext.to_vec()));
+
+    let mut block_flags = type_flags;
+    if ops.is_some() {
+        block_flags |= BLOCK_DEDUP;
+    }
+    if encoder != context.arithmetic_encoder {
+        block_flags |= BLOCK_CONFIG;
+    }
+    let mut out = vec![block_flags];
+    if encoder != context.arithmetic_encoder {
+        encoder.write_config(&mut out).unwrap();
+    }
+    if let Some(ops) = ops {
+        write_ops(ops, &mut out);
+    }
+    out.append(&mut body);
+    Ok(out)
+}
+
+// the order-0 entropy of some data, in bits per byte
+fn order0_entropy(data: &[u8]) -> f64 {
+    let mut counts = [0_usize; 256];
+    for byte in data {
+        counts[usize::from(*byte)] += 1;
+    }
+    let total = data.len() as f64;
+    counts
+        .iter()
+        .filter(|count| **count > 0)
+        .map(|count| {
+            let p = *count as f64 / total;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+// arithmetic code the bytes of a block directly, without any transforms
+#[cfg(test)]
+fn order0_pack(plaintext: &[u8], arithmetic_encoder: &ArithmeticEncoder) -> Vec<u8> {
+    order0_pack_with_best(plaintext, &[*arithmetic_encoder], Cancel::never())
+        .unwrap()
+        .1
+}
+
+// order-0 pack a block with each of the given encoders, keeping the smallest result
+fn order0_pack_with_best(
+    plaintext: &[u8],
+    encoders: &[ArithmeticEncoder],
+    cancel: Cancel,
+) -> Result<(ArithmeticEncoder, Vec<u8>), Cancelled> {
+    let front_matter = create_front_matter(plaintext.len().try_into().unwrap(), 0);
+    smallest(encoders, |encoder| {
+        encoder.pack_with_cancel(
+            front_matter.clone(),
+            plaintext,
+            |x| u32::from(*x),
+            256,
+            cancel,
+        )
+    })
+}
+
+// run a packing function with each encoder, keeping the first of the smallest results
+fn smallest(
+    encoders: &[ArithmeticEncoder],
+    pack: impl Fn(&ArithmeticEncoder) -> Result<Vec<u8>, Cancelled>,
+) -> Result<(ArithmeticEncoder, Vec<u8>), Cancelled> {
+    let mut best: Option<(ArithmeticEncoder, Vec<u8>)> = None;
+    for encoder in encoders {
+        let packed = pack(encoder)?;
+        if best.as_ref().map_or(true, |(_, b)| packed.len() < b.len()) {
+            best = Some((*encoder, packed));
+        }
+    }
+    Ok(best.unwrap())
+}
+
+fn order0_unpack(
+    ciphertext: &[u8],
+    arithmetic_encoder: &ArithmeticEncoder,
+    max_length: usize,
+) -> Result<Vec<u8>, &'static str> {
+    let (body, front_matter) = get_front_matter(ciphertext)?;
+    if front_matter.length as usize > max_length {
+        return Err("block longer than the block size");
+    }
+    arithmetic_encoder.unpack(
+        body,
+        |x| u8::try_from(x).unwrap(),
+        256,
+        front_matter.length.try_into().unwrap(),
+    )
+}
+
+// the parts of a block, as laid out by `encode_block`
+struct BlockParts<'a> {
+    flags: u8,
+    // the block's own arithmetic encoder configuration, if it has one
+    arithmetic_encoder: Option<ArithmeticEncoder>,
+    ops: Option<Vec<Op>>,
+    body: &'a [u8],
+}
+
+// split a block laid out in the given format version. Blocks of the first version
+// have no flags, and are all bodies of the full pipeline
+fn split_block(block: &[u8], version: u8) -> Result<BlockParts<'_>, &'static str> {
+    if version == OLDEST_VERSION {
+        return Ok(BlockParts {
+            flags: BLOCK_FULL,
+            arithmetic_encoder: None,
+            ops: None,
+            body: block,
+        });
+    }
+    let (flags, mut body) = match block.split_first() {
+        Some((flags, body)) => (*flags, body),
+        None => return Err("empty block"),
+    };
+    if flags & !KNOWN_BLOCK_FLAGS != 0 {
+        return Err("unsupported block flags");
+    }
+    if flags & BLOCK_TYPE_MASK == BLOCK_TYPE_MASK {
+        return Err("unknown block type");
+    }
+    let arithmetic_encoder = if flags & BLOCK_CONFIG != 0 {
+        let config = ArithmeticEncoder::read_config(&mut body);
+        Some(config.map_err(|_| "truncated block config")?)
+    } else {
+        None
+    };
+    let (body, ops) = if flags & BLOCK_DEDUP != 0 {
+        let (body, ops) = read_ops(body)?;
+        (body, Some(ops))
+    } else {
+        (body, None)
+    };
+    Ok(BlockParts {
+        flags,
+        arithmetic_encoder,
+        ops,
+        body,
+    })
+}
+
+// unsquash a block and undo whatever preprocessing its block flags call for. Every
+// stage is held to the stream's block size, so nothing can decode to more than that
+fn decode_block(block: &[u8], context: &StreamContext) -> Result<Vec<u8>, &'static str> {
+    let parts = split_block(block, context.version)?;
+    let arithmetic_encoder = parts
+        .arithmetic_encoder
+        .unwrap_or(context.arithmetic_encoder);
+    let text = parts.flags & BLOCK_TEXT != 0;
+    // the text transform can make a block up to twice as long
+    let max_length = if text {
+        context.block_size * 2
+    } else {
+        context.block_size
+    };
+    let mut unsquashed = match parts.flags & BLOCK_TYPE_MASK {
+        BLOCK_FULL => unsquash_block(parts.body, &arithmetic_encoder, max_length)?,
+        BLOCK_STORED if parts.body.len() <= max_length => parts.body.to_vec(),
+        BLOCK_STORED => return Err("block longer than the block size"),
+        BLOCK_ORDER0 => order0_unpack(parts.body, &arithmetic_encoder, max_length)?,
+        _ => return Err("unknown block type"),
+    };
+    if text {
+        unsquashed = text_untransform(&unsquashed, &context.dictionary, context.block_size)?;
+    }
+    match (parts.ops, &context.history) {
+        (None, _) if unsquashed.len() > context.block_size => {
+            Err("block longer than the block size")
+        }
+        (None, _) => Ok(unsquashed),
+        (Some(ops), Some(history)) => resolve(&ops, &unsquashed, history, context.block_size),
+        (Some(_), None) => Err("back-references in a stream without deduplication"),
+    }
+}
+
+// decode the block with the given index in its stream, and check it against its
+// checksum, if it has one. An encrypted block's checksum is of the block as stored,
+// and it's opened before it's decoded, which checks that it's in its place
+pub fn decode_checked(
+    block: &[u8],
+    checksum: Option<u32>,
+    index: u64,
+    context: &StreamContext,
+) -> io::Result<Vec<u8>> {
+    let mismatch = || io::Error::new(io::ErrorKind::InvalidData, "block checksum mismatch");
+    if !context.encrypted {
+        let decoded = decode_block(block, context).map_err(io::Error::other)?;
+        if checksum.is_some_and(|checksum| checksum != crc32(&decoded)) {
+            return Err(mismatch());
+        }
+        return Ok(decoded);
+    }
+    if checksum.is_some_and(|checksum| checksum != crc32(block)) {
+        return Err(mismatch());
+    }
+    let key = context.key.as_ref().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "the stream is encrypted, and needs a password",
+        )
+    })?;
+    let opened =
+        open_block(key, index, block).map_err(|x| io::Error::new(io::ErrorKind::InvalidData, x))?;
+    decode_block(&opened, context).map_err(io::Error::other)
 }
 
 // squash a block of plaintext
+#[cfg(test)]
 fn squash_block(plaintext: &[u8], arithmetic_encoder: &ArithmeticEncoder) -> Vec<u8> {
-    let bwt_encoded = bw_transform(plaintext);
+    squash_block_with_best(plaintext, &[*arithmetic_encoder], Cancel::never())
+        .unwrap()
+        .1
+}
+
+// squash a block of plaintext, packing it with each of the given encoders and
+// keeping the smallest result
+fn squash_block_with_best(
+    plaintext: &[u8],
+    encoders: &[ArithmeticEncoder],
+    cancel: Cancel,
+) -> Result<(ArithmeticEncoder, Vec<u8>), Cancelled> {
+    let bwt_encoded = bw_transform_with_cancel(plaintext, cancel)?;
     let mtf_encoded = mtf_transform(&bwt_encoded.block);
     let rle_encoded = run_length_encode(&mtf_encoded);
     let front_matter =
         create_front_matter(rle_encoded.len().try_into().unwrap(), bwt_encoded.end_index);
-    arithmetic_encoder.pack(
-        front_matter,
-        &rle_encoded,
-        |x| match x {
-            RunEncoded::Byte(n) => u32::from(*n),
-            RunEncoded::ZeroRun(Bijective::A) => 0,
-            RunEncoded::ZeroRun(Bijective::B) => 256,
-        },
-        257,
-    )
+    smallest(encoders, |encoder| {
+        encoder.pack_with_cancel(front_matter.clone(), &rle_encoded, run_code, 257, cancel)
+    })
+}
+
+// the arithmetic coded symbol for each item of a run-length encoded block
+pub fn run_code(x: &RunEncoded) -> u32 {
+    match x {
+        RunEncoded::Byte(n) => u32::from(*n),
+        RunEncoded::ZeroRun(Bijective::A) => 0,
+        RunEncoded::ZeroRun(Bijective::B) => 256,
+    }
 }
 
-// unsquash a block of compressed data
+// unsquash a block of compressed data that was at most `max_length` bytes long
 fn unsquash_block(
     ciphertext: &[u8],
     arithmetic_encoder: &ArithmeticEncoder,
+    max_length: usize,
 ) -> Result<Vec<u8>, &'static str> {
     let (body, front_matter) = get_front_matter(ciphertext)?;
+    // the burrows-wheeler transform adds a byte, and run-length encoding never adds any
+    let max_transformed = max_length + 1;
+    if front_matter.length as usize > max_transformed {
+        return Err("block longer than the block size");
+    }
     let arithmetic_decoded = arithmetic_encoder.unpack(
         body,
         |x| match x {
@@ -118,22 +1446,25 @@ fn unsquash_block(
         },
         257,
         front_matter.length.try_into().unwrap(),
-    );
-    let rle_decoded = run_length_decode(&arithmetic_decoded);
+    )?;
+    let rle_decoded = run_length_decode(&arithmetic_decoded, max_transformed)?;
     let mtf_decoded = mtf_untransform(&rle_decoded);
-    let bw_decoded = bw_untransform(&BwVec {
+    bw_untransform(&BwVec {
         block: mtf_decoded,
         end_index: front_matter.end_index,
-    });
-    Ok(bw_decoded)
+    })
 }
 
-struct FrontMatter {
-    length: u32,
-    end_index: u32,
+// the start of every arithmetic coded block
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct FrontMatter {
+    // how many symbols were coded
+    pub length: u32,
+    // where the burrows-wheeler transform put the end of the block
+    pub end_index: u32,
 }
 
-fn create_front_matter(length: u32, end_index: u32) -> Vec<u8> {
+pub fn create_front_matter(length: u32, end_index: u32) -> Vec<u8> {
     let mut front_matter: Vec<u8> = Vec::with_capacity(8);
     front_matter.extend_from_slice(&end_index.to_le_bytes()[..]);
     front_matter.extend_from_slice(&length.to_le_bytes()[..]);
@@ -154,6 +1485,7 @@ fn get_front_matter(body: &[u8]) -> Result<(&[u8], FrontMatter), &'static str> {
 
 #[cfg(test)]
 mod test {
+    use super::super::repair::*;
     use super::*;
 
     const TEXT: &str = "When you create a closure, Rust infers which \
@@ -173,7 +1505,7 @@ mod test {
         let plaintext = TEXT.as_bytes();
         let arithmetic_encoder = ArithmeticEncoder::default_encoder();
         let squashed = squash_block(plaintext, &arithmetic_encoder);
-        let unsquashed = unsquash_block(&squashed, &arithmetic_encoder).unwrap();
+        let unsquashed = unsquash_block(&squashed, &arithmetic_encoder, plaintext.len()).unwrap();
         assert_eq!(
             String::from_utf8_lossy(plaintext),
             String::from_utf8_lossy(&unsquashed[..])
@@ -200,17 +1532,19 @@ mod test {
         );
 
         let (body, front_matter) = get_front_matter(&arith_encoded).unwrap();
-        let arith_decoded = arithmetic_encoder.unpack(
-            body,
-            |x| match x {
-                0 => RunEncoded::ZeroRun(Bijective::A),
-                256 => RunEncoded::ZeroRun(Bijective::B),
-                n => RunEncoded::Byte(u8::try_from(n).unwrap()),
-            },
-            257,
-            front_matter.length.try_into().unwrap(),
-        );
-        let rle_decoded = run_length_decode(&arith_decoded);
+        let arith_decoded = arithmetic_encoder
+            .unpack(
+                body,
+                |x| match x {
+                    0 => RunEncoded::ZeroRun(Bijective::A),
+                    256 => RunEncoded::ZeroRun(Bijective::B),
+                    n => RunEncoded::Byte(u8::try_from(n).unwrap()),
+                },
+                257,
+                front_matter.length.try_into().unwrap(),
+            )
+            .unwrap();
+        let rle_decoded = run_length_decode(&arith_decoded, mtf_encoded.len()).unwrap();
         let mtf_decoded = mtf_untransform(&rle_decoded);
         assert_eq!(arith_decoded, rle_encoded);
         assert_eq!(rle_decoded.len(), mtf_encoded.len());
@@ -219,12 +1553,771 @@ mod test {
         let bw_decoded = bw_untransform(&BwVec {
             block: mtf_decoded,
             end_index: front_matter.end_index,
-        });
+        })
+        .unwrap();
         assert_eq!(bwt_encoded.end_index, front_matter.end_index);
         assert_eq!(rle_encoded.len(), front_matter.length.try_into().unwrap());
         assert_eq!(String::from_utf8_lossy(&bw_decoded), TEXT);
     }
 
+    #[test]
+    fn stream_test() {
+        let mut input = TEXT.repeat(40).into_bytes();
+        input.extend((0..BLOCK_SIZE).map(|i| (i * i % 251) as u8));
+        input.extend_from_within(0..BLOCK_SIZE / 2);
+        let settings = [
+            (DEFAULT_LEVEL, true, None, None),
+            (DEFAULT_LEVEL, false, None, None),
+            (DEFAULT_LEVEL, true, Some(1 << 20), None),
+            (9, true, None, None),
+            (DEFAULT_LEVEL, true, Some(1 << 20), Some(30)),
+        ];
+        for (level, text_preprocessing, dedup_window, parity) in &settings {
+            let options = SquashOptions {
+                level: *level,
+                text_preprocessing: *text_preprocessing,
+                dedup_window: *dedup_window,
+                model: Model::Window,
+                metadata: None,
+                parity: *parity,
+                encryption: None,
+                cancel: None,
+            };
+            let mut squashed = vec![];
+            squash_with_options(&mut &input[..], &mut squashed, &options).unwrap();
+            let mut unsquashed = vec![];
+            unsquash(&mut &squashed[..], &mut unsquashed).unwrap();
+            assert_eq!(unsquashed, input);
+        }
+    }
+
+    #[test]
+    fn stored_metadata() {
+        let metadata = Metadata {
+            name: Some(String::from("closures.txt")),
+            size: Some(TEXT.len() as u64),
+            mtime: None,
+            mode: Some(0o600),
+        };
+        let options = SquashOptions {
+            metadata: Some(metadata.clone()),
+            ..SquashOptions::default_options()
+        };
+        let mut squashed = vec![];
+        squash_with_options(&mut TEXT.as_bytes(), &mut squashed, &options).unwrap();
+        assert_eq!(read_metadata(&mut &squashed[..]).unwrap(), Some(metadata));
+        let mut unsquashed = vec![];
+        unsquash(&mut &squashed[..], &mut unsquashed).unwrap();
+        assert_eq!(unsquashed, TEXT.as_bytes());
+
+        let mut squashed = vec![];
+        squash(&mut TEXT.as_bytes(), &mut squashed).unwrap();
+        assert_eq!(read_metadata(&mut &squashed[..]).unwrap(), None);
+    }
+
+    #[test]
+    fn block_types() {
+        let context = StreamContext {
+            version: FILETYPE_VERSION,
+            arithmetic_encoder: ArithmeticEncoder::default_encoder(),
+            dictionary: Dictionary::empty(),
+            block_size: BLOCK_SIZE,
+            history: None,
+            parity: None,
+            encrypted: false,
+            key: None,
+        };
+        let options = SquashOptions::default_options();
+        // noise is stored, while skewed noise and text are worth squashing
+        let mut state: u32 = 1;
+        let noise: Vec<u8> = (0..20_000)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                (state >> 16) as u8
+            })
+            .collect();
+        let skewed: Vec<u8> = noise.iter().map(|b| b & 0x8f).collect();
+        let expected = [
+            (&noise[..], BLOCK_STORED),
+            (&skewed[..], BLOCK_FULL),
+            (TEXT.as_bytes(), BLOCK_FULL),
+        ];
+        for (plaintext, block_type) in &expected {
+            let encoded = encode_block(plaintext, None, &context, &options).unwrap();
+            assert_eq!(encoded[0] & BLOCK_TYPE_MASK, *block_type);
+            assert!(encoded.len() <= plaintext.len() + 1);
+            assert_eq!(&decode_block(&encoded, &context).unwrap(), plaintext);
+        }
+
+        let mut encoded = vec![BLOCK_ORDER0];
+        encoded.append(&mut order0_pack(&skewed, &context.arithmetic_encoder));
+        assert!(encoded.len() < skewed.len());
+        assert_eq!(decode_block(&encoded, &context).unwrap(), skewed);
+    }
+
+    #[test]
+    fn per_block_config() {
+        let context = StreamContext {
+            version: FILETYPE_VERSION,
+            arithmetic_encoder: ArithmeticEncoder::default_encoder(),
+            dictionary: Dictionary::empty(),
+            block_size: BLOCK_SIZE,
+            history: None,
+            parity: None,
+            encrypted: false,
+            key: None,
+        };
+        let mut options = SquashOptions::default_options();
+        let plaintext = TEXT.repeat(10);
+        let default = encode_block(plaintext.as_bytes(), None, &context, &options).unwrap();
+        assert_eq!(default[0] & BLOCK_CONFIG, 0);
+        options.level = 9;
+        let searched = encode_block(plaintext.as_bytes(), None, &context, &options).unwrap();
+        assert_ne!(searched[0] & BLOCK_CONFIG, 0);
+        assert!(searched.len() < default.len());
+        assert_eq!(
+            decode_block(&searched, &context).unwrap(),
+            plaintext.as_bytes()
+        );
+    }
+
+    #[test]
+    fn inspection() {
+        // a text block, then an incompressible one, then a repeat of the first that
+        // dedup turns into nothing but references, leaving no literals to code
+        let mut input = TEXT.repeat(700).into_bytes();
+        input.truncate(BLOCK_SIZE);
+        let mut state: u32 = 1;
+        input.extend((0..BLOCK_SIZE).map(|_| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            (state >> 16) as u8
+        }));
+        input.extend_from_within(0..BLOCK_SIZE);
+        let options = SquashOptions {
+            dedup_window: Some(1 << 20),
+            ..SquashOptions::default_options()
+        };
+        let mut squashed = vec![];
+        squash_with_options(&mut &input[..], &mut squashed, &options).unwrap();
+
+        let info = inspect(&mut &squashed[..], false).unwrap();
+        assert_eq!(info.version, FILETYPE_VERSION);
+        assert!(info.dictionary_words > 0);
+        assert_eq!(info.dedup_window, Some(1 << 20));
+        let types: Vec<BlockType> = info.blocks.iter().map(|b| b.block_type).collect();
+        assert_eq!(
+            types,
+            [BlockType::Full, BlockType::Stored, BlockType::Stored]
+        );
+        assert!(info.blocks[0].text);
+        assert_eq!(info.blocks[0].dedup_ops, None);
+        assert!(info.blocks[2].dedup_ops.is_some());
+        assert!(info.blocks[0].front_matter.is_some());
+        assert_eq!(info.blocks[1].front_matter, None);
+        assert!(info.blocks.iter().all(|b| b.decoded_size.is_none()));
+        let stored: u64 = info
+            .blocks
+            .iter()
+            .map(|b| frame_overhead(FILETYPE_VERSION) + u64::from(b.length))
+            .sum();
+        assert_eq!(
+            info.header_length + stored + trailer_length(FILETYPE_VERSION, false),
+            squashed.len() as u64
+        );
+        assert_eq!(info.stored_size(), squashed.len() as u64);
+        assert_eq!(
+            info.trailer,
+            Some(Trailer {
+                length: input.len() as u64,
+                blocks: 3
+            })
+        );
+
+        let info = inspect(&mut &squashed[..], true).unwrap();
+        let sizes: Vec<usize> = info
+            .blocks
+            .iter()
+            .map(|b| b.decoded_size.unwrap())
+            .collect();
+        assert_eq!(sizes, [BLOCK_SIZE; 3]);
+    }
+
+    #[test]
+    fn observer_events() {
+        let mut input = TEXT.repeat(700).into_bytes();
+        input.truncate(BLOCK_SIZE + BLOCK_SIZE / 2);
+
+        // the sizes each event gives, with the timings left out
+        fn sizes(event: &Event) -> (&'static str, u64, u64) {
+            match *event {
+                Event::Header { length } => ("header", length, 0),
+                Event::BlockStarted { index, input_size } => ("started", index as u64, input_size),
+                Event::BlockFinished {
+                    input_size,
+                    output_size,
+                    ..
+                } => ("finished", input_size, output_size),
+                Event::Finished {
+                    input_size,
+                    output_size,
+                    ..
+                } => ("done", input_size, output_size),
+            }
+        }
+
+        let mut events = vec![];
+        let mut squashed = vec![];
+        squash_with_observer(
+            &mut &input[..],
+            &mut squashed,
+            &SquashOptions::default_options(),
+            &mut |event: &Event| events.push(sizes(event)),
+        )
+        .unwrap();
+        let info = inspect(&mut &squashed[..], false).unwrap();
+        let stored: Vec<u64> = info
+            .blocks
+            .iter()
+            .map(|b| frame_overhead(FILETYPE_VERSION) + u64::from(b.length))
+            .collect();
+        let half = (BLOCK_SIZE / 2) as u64;
+        let full = BLOCK_SIZE as u64;
+        assert_eq!(
+            events,
+            [
+                ("header", info.header_length, 0),
+                ("started", 0, full),
+                ("finished", full, stored[0]),
+                ("started", 1, half),
+                ("finished", half, stored[1]),
+                ("done", full + half, squashed.len() as u64),
+            ]
+        );
+
+        let mut events = vec![];
+        let mut unsquashed = vec![];
+        let limits = DecodeLimits::default_limits();
+        unsquash_with_observer(
+            &mut &squashed[..],
+            &mut unsquashed,
+            &limits,
+            &mut |event: &Event| events.push(sizes(event)),
+        )
+        .unwrap();
+        assert_eq!(unsquashed, input);
+        assert_eq!(
+            events,
+            [
+                ("header", info.header_length, 0),
+                ("started", 0, stored[0]),
+                ("finished", stored[0], full),
+                ("started", 1, stored[1]),
+                ("finished", stored[1], half),
+                ("done", squashed.len() as u64, full + half),
+            ]
+        );
+    }
+
+    #[test]
+    fn cancellation() {
+        let mut input = TEXT.repeat(700).into_bytes();
+        input.truncate(3 * BLOCK_SIZE);
+
+        // cancelled as soon as the first block is done
+        let flag = Arc::new(AtomicBool::new(false));
+        let options = SquashOptions {
+            cancel: Some(flag.clone()),
+            ..SquashOptions::default_options()
+        };
+        let mut squashed = vec![];
+        let error = squash_with_observer(
+            &mut &input[..],
+            &mut squashed,
+            &options,
+            &mut |event: &Event| {
+                if let Event::BlockFinished { .. } = event {
+                    flag.store(true, std::sync::atomic::Ordering::Relaxed);
+                }
+            },
+        )
+        .unwrap_err();
+        assert!(is_cancelled(&error));
+
+        // what was written so far can't pass for a whole stream
+        let info = inspect(&mut &squashed[..], false).unwrap();
+        assert_eq!(info.blocks.len(), 1);
+        assert_eq!(info.trailer, None);
+        let error = unsquash(&mut &squashed[..], &mut vec![]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+        assert!(!is_cancelled(&error));
+
+        // and the long loops inside a block give up too
+        let set = AtomicBool::new(true);
+        let cancel = Cancel::new(Some(&set));
+        assert_eq!(
+            bw_transform_with_cancel(TEXT.as_bytes(), cancel),
+            Err(Cancelled)
+        );
+        let encoder = ArithmeticEncoder::default_encoder();
+        assert_eq!(
+            encoder.pack_with_cancel(vec![], TEXT.as_bytes(), |x| u32::from(*x), 256, cancel),
+            Err(Cancelled)
+        );
+    }
+
+    #[test]
+    fn trailer() {
+        let input = TEXT.repeat(10);
+        let mut squashed = vec![];
+        squash(&mut input.as_bytes(), &mut squashed).unwrap();
+
+        // cut off anywhere, the stream is refused rather than decoded in part
+        for cut in [
+            1,
+            8,
+            trailer_length(FILETYPE_VERSION, false) as usize,
+            trailer_length(FILETYPE_VERSION, false) as usize + 1,
+        ] {
+            let truncated = &squashed[..squashed.len() - cut];
+            assert!(unsquash(&mut &truncated[..], &mut vec![]).is_err());
+        }
+
+        // a trailer that disagrees with the blocks before it
+        let mut wrong = squashed.clone();
+        let end = wrong.len();
+        wrong[end - 12] ^= 1;
+        let error = unsquash(&mut &wrong[..], &mut vec![]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        // an empty input still gets a trailer
+        let mut squashed = vec![];
+        squash(&mut &[][..], &mut squashed).unwrap();
+        let info = inspect(&mut &squashed[..], false).unwrap();
+        assert_eq!(
+            info.trailer,
+            Some(Trailer {
+                length: 0,
+                blocks: 0
+            })
+        );
+        let mut unsquashed = vec![];
+        unsquash(&mut &squashed[..], &mut unsquashed).unwrap();
+        assert!(unsquashed.is_empty());
+    }
+
+    #[test]
+    fn limits() {
+        let input = TEXT.repeat(10);
+        let mut squashed = vec![];
+        squash(&mut input.as_bytes(), &mut squashed).unwrap();
+        let unsquash_limited = |stream: &[u8], limits: &DecodeLimits| {
+            unsquash_with_limits(&mut &stream[..], &mut vec![], limits)
+        };
+        let limits = DecodeLimits::default_limits();
+        assert!(unsquash_limited(&squashed, &limits).is_ok());
+
+        // the block size comes after the magic number, version and arithmetic config
+        let mut huge = squashed.clone();
+        huge[18..22].copy_from_slice(&u32::MAX.to_le_bytes());
+        let error = unsquash_limited(&huge, &limits).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        let small = DecodeLimits {
+            max_block: 1 << 12,
+            ..DecodeLimits::default_limits()
+        };
+        assert!(unsquash_limited(&squashed, &small).is_err());
+        // and blocks are held to whatever it says
+        let mut shrunk = squashed.clone();
+        shrunk[18..22].copy_from_slice(&64_u32.to_le_bytes());
+        assert!(unsquash_limited(&shrunk, &limits).is_err());
+
+        // as is the dedup window, which follows the flags when there's no dictionary
+        let options = SquashOptions {
+            text_preprocessing: false,
+            dedup_window: Some(1 << 20),
+            ..SquashOptions::default_options()
+        };
+        let mut deduplicated = vec![];
+        squash_with_options(&mut input.as_bytes(), &mut deduplicated, &options).unwrap();
+        assert!(unsquash_limited(&deduplicated, &limits).is_ok());
+        let narrow = DecodeLimits {
+            max_window: 1 << 19,
+            ..DecodeLimits::default_limits()
+        };
+        let error = unsquash_limited(&deduplicated, &narrow).unwrap_err();
+        assert_eq!(error.to_string(), "dedup window over the limit");
+        let mut wide = deduplicated.clone();
+        assert_eq!(wide[23..31], (1_u64 << 20).to_le_bytes());
+        wide[23..31].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(unsquash_limited(&wide, &limits).is_err());
+
+        // a block length far beyond the block size isn't allocated
+        let mut empty = vec![];
+        squash(&mut &[][..], &mut empty).unwrap();
+        let header = &empty[..empty.len() - trailer_length(FILETYPE_VERSION, false) as usize];
+        let mut long = header.to_vec();
+        long.extend_from_slice(&SYNC_MARKER);
+        long.extend_from_slice(&0xffff_fff0_u32.to_le_bytes());
+        let error = unsquash_limited(&long, &limits).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        // nor a block claiming billions of symbols, or an end index past its end
+        for front_matter in [
+            create_front_matter(u32::MAX, 0),
+            create_front_matter(4, 1000),
+        ] {
+            let mut crafted = header.to_vec();
+            let mut block = vec![BLOCK_FULL];
+            block.extend_from_slice(&front_matter);
+            block.extend_from_slice(&[0x55; 8]);
+            crafted.extend_from_slice(&SYNC_MARKER);
+            crafted.extend_from_slice(&u32::try_from(block.len()).unwrap().to_le_bytes());
+            crafted.extend_from_slice(&0_u32.to_le_bytes());
+            crafted.extend_from_slice(&block);
+            assert!(unsquash_limited(&crafted, &limits).is_err());
+        }
+
+        // the output and ratio limits stop a stream partway
+        let capped = DecodeLimits {
+            max_output: Some(input.len() as u64 - 1),
+            ..DecodeLimits::default_limits()
+        };
+        let error = unsquash_limited(&squashed, &capped).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        let capped = DecodeLimits {
+            max_output: Some(input.len() as u64),
+            ..DecodeLimits::default_limits()
+        };
+        assert!(unsquash_limited(&squashed, &capped).is_ok());
+        let ratio = input.len() as f64 / squashed.len() as f64;
+        let capped = DecodeLimits {
+            max_ratio: Some(ratio / 2.0),
+            ..DecodeLimits::default_limits()
+        };
+        assert!(unsquash_limited(&squashed, &capped).is_err());
+        let capped = DecodeLimits {
+            max_ratio: Some(ratio * 2.0),
+            ..DecodeLimits::default_limits()
+        };
+        assert!(unsquash_limited(&squashed, &capped).is_ok());
+    }
+
+    #[test]
+    fn concatenated_streams() {
+        let text = TEXT.repeat(10);
+        let mut first = vec![];
+        squash(&mut text.as_bytes(), &mut first).unwrap();
+        // a second stream with settings of its own, which mustn't leak into the first
+        let mut repeated = (0..20_000_u32)
+            .map(|i| ((i * i) >> 7) as u8)
+            .collect::<Vec<_>>();
+        repeated.extend_from_within(..);
+        let mut second = vec![];
+        let options = SquashOptions {
+            text_preprocessing: false,
+            dedup_window: Some(1 << 20),
+            model: Model::Halving,
+            ..SquashOptions::default_options()
+        };
+        squash_with_options(&mut &repeated[..], &mut second, &options).unwrap();
+        let mut empty = vec![];
+        squash(&mut &[][..], &mut empty).unwrap();
+
+        let strict = DecodeLimits {
+            strict: true,
+            ..DecodeLimits::default_limits()
+        };
+        let unsquash_strictly = |stream: &[u8]| {
+            let mut unsquashed = vec![];
+            unsquash_with_limits(&mut &stream[..], &mut unsquashed, &strict).map(|()| unsquashed)
+        };
+        let concatenated = [&first[..], &empty, &second, &first].concat();
+        let mut expected = text.as_bytes().to_vec();
+        expected.extend_from_slice(&repeated);
+        expected.extend_from_slice(text.as_bytes());
+        assert!(unsquash_strictly(&concatenated).unwrap() == expected);
+
+        // every stream's header and blocks are observed, and the totals cover them all
+        let mut headers = 0;
+        let mut finished = None;
+        unsquash_with_observer(
+            &mut &concatenated[..],
+            &mut vec![],
+            &DecodeLimits::default_limits(),
+            &mut |event: &Event| match event {
+                Event::Header { .. } => headers += 1,
+                Event::Finished {
+                    input_size,
+                    output_size,
+                    ..
+                } => finished = Some((*input_size, *output_size)),
+                _ => (),
+            },
+        )
+        .unwrap();
+        assert_eq!(headers, 4);
+        assert_eq!(
+            finished,
+            Some((concatenated.len() as u64, expected.len() as u64))
+        );
+
+        // the output limit counts every stream
+        let capped = DecodeLimits {
+            max_output: Some(text.len() as u64 + 1),
+            ..DecodeLimits::default_limits()
+        };
+        let doubled = [&first[..], &first].concat();
+        assert!(unsquash_with_limits(&mut &doubled[..], &mut vec![], &capped).is_err());
+
+        // anything else after a stream is ignored, unless decoding strictly
+        for garbage in [&b"\0"[..], b"\xca\x55", b"junk", b"PK\x03\x04 and more"] {
+            let trailing = [&first[..], garbage].concat();
+            let mut unsquashed = vec![];
+            unsquash(&mut &trailing[..], &mut unsquashed).unwrap();
+            assert!(unsquashed == text.as_bytes());
+            let error = unsquash_strictly(&trailing).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        }
+
+        // a second stream that's cut short, or a first without its trailer, is refused
+        let cut = &concatenated[..first.len() + empty.len() + 30];
+        assert!(unsquash(&mut &cut[..], &mut vec![]).is_err());
+        let untrailed = [
+            &first[..first.len() - trailer_length(FILETYPE_VERSION, false) as usize],
+            &first,
+        ]
+        .concat();
+        assert!(unsquash(&mut &untrailed[..], &mut vec![]).is_err());
+    }
+
+    #[test]
+    fn squash_writer() {
+        // written in dribs and drabs, over more than one block, the stream comes out
+        // just as squash makes it
+        let input: Vec<u8> = TEXT.bytes().cycle().take(BLOCK_SIZE * 2 + 100).collect();
+        let mut expected = vec![];
+        squash(&mut &input[..], &mut expected).unwrap();
+        let mut writer = SquashWriter::new(vec![], &SquashOptions::default_options());
+        for piece in input.chunks(7919) {
+            io::Write::write_all(&mut writer, piece).unwrap();
+        }
+        assert!(writer.finish().unwrap() == expected);
+
+        // and with nothing written, it's an empty stream
+        let mut expected = vec![];
+        squash(&mut &[][..], &mut expected).unwrap();
+        let writer = SquashWriter::new(vec![], &SquashOptions::default_options());
+        assert_eq!(writer.finish().unwrap(), expected);
+    }
+
+    #[test]
+    fn append() {
+        let unsquashed = |stream: &[u8]| {
+            let mut out = vec![];
+            unsquash(&mut &stream[..], &mut out).map(|()| out)
+        };
+        let append = |stream: &[u8], more: &[u8], options: &SquashOptions| {
+            let mut writer = SquashWriter::append_to(io::Cursor::new(stream.to_vec()), options)?;
+            io::Write::write_all(&mut writer, more)?;
+            Ok::<_, io::Error>(writer.finish()?.into_inner())
+        };
+        let options = SquashOptions::default_options();
+        let mut first = vec![];
+        squash(&mut TEXT.as_bytes(), &mut first).unwrap();
+        let second: Vec<u8> = (0..BLOCK_SIZE + 5000).map(|i| (i % 251) as u8).collect();
+
+        // the blocks already there are left alone, and the trailer covers them all
+        let appended = append(&first, &second, &options).unwrap();
+        let kept = first.len() - trailer_length(FILETYPE_VERSION, false) as usize;
+        assert_eq!(appended[..kept], first[..kept]);
+        let mut expected = TEXT.as_bytes().to_vec();
+        expected.extend_from_slice(&second);
+        assert!(unsquashed(&appended).unwrap() == expected);
+        let info = inspect(&mut &appended[..], false).unwrap();
+        assert_eq!(info.blocks.len(), 3);
+        assert_eq!(
+            info.trailer,
+            Some(Trailer {
+                length: expected.len() as u64,
+                blocks: 3
+            })
+        );
+        // again, and with nothing at all
+        let twice = append(&appended, TEXT.as_bytes(), &options).unwrap();
+        expected.extend_from_slice(TEXT.as_bytes());
+        assert!(unsquashed(&twice).unwrap() == expected);
+        assert_eq!(append(&twice, b"", &options).unwrap(), twice);
+
+        // an append that stops partway still leaves a whole stream behind, of the
+        // blocks that were finished
+        let mut writer = SquashWriter::append_to(io::Cursor::new(first.clone()), &options).unwrap();
+        io::Write::write_all(&mut writer, &second).unwrap();
+        let interrupted = writer.writer.into_inner();
+        let decoded = unsquashed(&interrupted).unwrap();
+        assert!(decoded[..] == expected[..TEXT.len() + BLOCK_SIZE]);
+
+        // a deduplicated stream carries on deduplicating, from where it ended
+        let dedup = SquashOptions {
+            dedup_window: Some(DEFAULT_WINDOW),
+            ..options.clone()
+        };
+        let noise: Vec<u8> = (0..30_000_u64)
+            .map(|i| (i.wrapping_mul(0x9e37_79b9_7f4a_7c15) >> 56) as u8)
+            .collect();
+        let mut deduplicated = vec![];
+        squash_with_options(&mut &noise[..], &mut deduplicated, &dedup).unwrap();
+        let repeated = [&noise[..], &noise].concat();
+        let appended = append(&deduplicated, &repeated, &options).unwrap();
+        let info = inspect(&mut &appended[..], false).unwrap();
+        assert!(info.blocks[1].dedup_ops.is_some());
+        assert!(unsquashed(&appended).unwrap() == [&noise[..], &repeated].concat());
+
+        // streams that can't be carried on are refused, untouched
+        let untrailed = &first[..kept];
+        assert!(append(untrailed, b"more", &options).is_err());
+        let trailing = [&first[..], b"junk"].concat();
+        assert!(append(&trailing, b"more", &options).is_err());
+        let mut older = first.clone();
+        older[4] = FILETYPE_VERSION - 1;
+        assert!(append(&older, b"more", &options).is_err());
+        let mut miscounted = first.clone();
+        let end = miscounted.len();
+        miscounted[end - 4] ^= 1;
+        assert!(append(&miscounted, b"more", &options).is_err());
+    }
+
+    #[test]
+    fn encryption() {
+        let encryption = Encryption {
+            password: Password::new(b"hunter2"),
+            // cheap, since it's derived over and over
+            kdf: KdfParams {
+                log_n: 4,
+                r: 1,
+                p: 1,
+            },
+        };
+        let unsquashed = |stream: &[u8], password: &[u8]| {
+            let mut out = vec![];
+            let limits = DecodeLimits::default_limits();
+            unsquash_with_password(
+                &mut &stream[..],
+                &mut out,
+                &limits,
+                &Password::new(password),
+            )
+            .map(|()| out)
+        };
+        let error = |stream: &[u8]| unsquashed(stream, b"hunter2").unwrap_err().to_string();
+        let sync_points = |stream: &[u8]| -> Vec<usize> {
+            (0..stream.len())
+                .filter(|&i| stream[i..].starts_with(&SYNC_MARKER))
+                .collect()
+        };
+        let input: Vec<u8> = TEXT.bytes().cycle().take(BLOCK_SIZE * 3 + 100).collect();
+        let options = SquashOptions {
+            encryption: Some(encryption.clone()),
+            ..SquashOptions::default_options()
+        };
+        let mut squashed = vec![];
+        squash_with_options(&mut &input[..], &mut squashed, &options).unwrap();
+        assert!(is_encrypted(&mut &squashed[..]).unwrap());
+        assert!(unsquashed(&squashed, b"hunter2").unwrap() == input);
+        assert!(!squashed.windows(32).any(|w| w == &input[..32]));
+
+        // only the password opens it
+        let error_without = unsquash(&mut &squashed[..], &mut vec![]).unwrap_err();
+        assert_eq!(error_without.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(
+            error_without.to_string(),
+            "the stream is encrypted, and needs a password"
+        );
+        assert_eq!(
+            unsquashed(&squashed, b"hunter3").unwrap_err().to_string(),
+            "wrong password, or the header is damaged"
+        );
+
+        // the header says how the key was derived, and nothing about the blocks but
+        // their lengths
+        let info = inspect(&mut &squashed[..], false).unwrap();
+        assert_eq!(info.encryption, Some(encryption.kdf));
+        assert_eq!(info.dictionary_words, 0);
+        assert_eq!(info.blocks.len(), 4);
+        assert!(info
+            .blocks
+            .iter()
+            .all(|b| b.block_type == BlockType::Encrypted));
+        assert_eq!(info.stored_size(), squashed.len() as u64);
+        assert!(inspect(&mut &squashed[..], true).is_err());
+
+        // a block that's changed is caught by its checksum, and by its tag even when
+        // the checksum's changed to match
+        let frames = sync_points(&squashed);
+        assert_eq!(frames.len(), 5);
+        let mut changed = squashed.clone();
+        changed[frames[1] + 30] ^= 1;
+        assert_eq!(error(&changed), "block checksum mismatch");
+        let checksum = crc32(&changed[frames[1] + 14..frames[2]]);
+        changed[frames[1] + 10..frames[1] + 14].copy_from_slice(&checksum.to_le_bytes());
+        assert_eq!(error(&changed), "block fails authentication");
+
+        // as are blocks that are swapped, or taken from another stream with the same
+        // password
+        let swapped = [
+            &squashed[..frames[0]],
+            &squashed[frames[1]..frames[2]],
+            &squashed[frames[0]..frames[1]],
+            &squashed[frames[2]..],
+        ]
+        .concat();
+        assert_eq!(error(&swapped), "block fails authentication");
+        let mut other = vec![];
+        squash_with_options(&mut &input[..], &mut other, &options).unwrap();
+        let other_frames = sync_points(&other);
+        let taken = [
+            &squashed[..frames[0]],
+            &other[other_frames[0]..other_frames[1]],
+            &squashed[frames[1]..],
+        ]
+        .concat();
+        assert_eq!(error(&taken), "block fails authentication");
+
+        // and a stream cut short, even with the trailer changed to match
+        let mut cut = [&squashed[..frames[3]], &squashed[frames[4]..]].concat();
+        let trailer = frames[3];
+        cut[trailer + 10..trailer + 18].copy_from_slice(&(BLOCK_SIZE as u64 * 3).to_le_bytes());
+        cut[trailer + 18..trailer + 22].copy_from_slice(&3_u32.to_le_bytes());
+        assert_eq!(error(&cut), "trailer fails authentication");
+        assert!(unsquashed(&squashed[..squashed.len() - 1], b"hunter2").is_err());
+
+        // encrypted streams are neither appended to nor recovered, but with the
+        // password they're repaired from their parity
+        let appended = SquashWriter::append_to(
+            io::Cursor::new(squashed.clone()),
+            &SquashOptions::default_options(),
+        );
+        assert!(appended.is_err());
+        assert!(recover(&squashed, &mut vec![]).is_err());
+        let options = SquashOptions {
+            parity: Some(50),
+            ..options
+        };
+        let mut protected = vec![];
+        squash_with_options(&mut &input[..], &mut protected, &options).unwrap();
+        let mut damaged = protected.clone();
+        damaged[sync_points(&protected)[2] + 30] ^= 1;
+        let repairing = RepairOptions {
+            password: Some(Password::new(b"hunter2")),
+            ..RepairOptions::default_options()
+        };
+        assert!(format!("{:?}", repairing).contains("password: Some(Password(..))"));
+        let mut out = vec![];
+        let report = repair_with_options(&damaged, &mut out, &repairing).unwrap();
+        assert!(out == protected);
+        assert_eq!(report.blocks_rebuilt, [2]);
+        assert!(repair(&damaged, &mut vec![]).is_err());
+    }
+
     #[test]
     fn front_matter() {
         let len = 352_354_634;
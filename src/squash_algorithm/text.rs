@@ -0,0 +1,353 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io;
+
+// A reversible preprocessing stage for blocks of UTF-8 text, applied before the
+// burrows-wheeler transform. Capital letters are folded into escape flags so that
+// "The" and "the" share contexts, and frequent words are swapped for two-byte codes
+// from a dictionary built out of the first block of the stream.
+//
+// Every escape byte used here is one that can never appear in valid UTF-8, so the
+// original text never needs escaping.
+
+// the next word has an upper case first letter, e.g. "Hello"
+const CAPITALISED: u8 = 0xff;
+// the next word is entirely upper case, e.g. "HELLO"
+const UPPER_CASE: u8 = 0xfe;
+// a dictionary code is one of these lead bytes followed by any byte
+const CODE_LEADS: [u8; 11] = [
+    0xc0, 0xc1, 0xf5, 0xf6, 0xf7, 0xf8, 0xf9, 0xfa, 0xfb, 0xfc, 0xfd,
+];
+
+pub const MAX_DICTIONARY_WORDS: usize = CODE_LEADS.len() * 256;
+// a code costs two bytes, so shorter words aren't worth replacing
+const MIN_WORD_LENGTH: usize = 3;
+const MAX_WORD_LENGTH: usize = 32;
+
+// the fraction of a block (in parts per thousand) that may be control characters
+// before it stops looking like text
+const MAX_CONTROL_PER_MILLE: usize = 10;
+
+pub struct Dictionary {
+    words: Vec<Vec<u8>>,
+    codes: HashMap<Vec<u8>, u16>,
+}
+
+impl Dictionary {
+    pub fn empty() -> Self {
+        Dictionary {
+            words: vec![],
+            codes: HashMap::new(),
+        }
+    }
+
+    // build a dictionary out of the most valuable words in a sample of text
+    pub fn from_sample(sample: &[u8]) -> Self {
+        let mut counts: HashMap<Vec<u8>, usize> = HashMap::new();
+        for word in Words::new(sample) {
+            if let Word::Letters(letters) = word {
+                if (MIN_WORD_LENGTH..=MAX_WORD_LENGTH).contains(&letters.len()) {
+                    *counts.entry(letters.to_ascii_lowercase()).or_insert(0) += 1;
+                }
+            }
+        }
+        // each use of a word saves (length - 2) bytes, and the word costs
+        // (length + 1) bytes in the dictionary
+        let mut scored: Vec<(usize, Vec<u8>)> = counts
+            .into_iter()
+            .map(|(word, count)| ((word.len() - 2) * count, word))
+            .filter(|(saved, word)| *saved > word.len() + 1)
+            .map(|(saved, word)| (saved - word.len() - 1, word))
+            .collect();
+        scored.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.cmp(&b.1)));
+        scored.truncate(MAX_DICTIONARY_WORDS);
+        Dictionary::from_words(scored.into_iter().map(|(_, word)| word).collect())
+    }
+
+    fn from_words(words: Vec<Vec<u8>>) -> Self {
+        let codes = words
+            .iter()
+            .enumerate()
+            .map(|(i, word)| (word.clone(), u16::try_from(i).unwrap()))
+            .collect();
+        Dictionary { words, codes }
+    }
+
+    pub fn len(&self) -> usize {
+        self.words.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.words.is_empty()
+    }
+
+    pub fn read(reader: &mut dyn io::Read) -> io::Result<Self> {
+        let mut two_bytes = [0; 2];
+        let mut one_byte = [0; 1];
+        reader.read_exact(&mut two_bytes)?;
+        let count = usize::from(u16::from_le_bytes(two_bytes));
+        if count > MAX_DICTIONARY_WORDS {
+            return Err(io::Error::other("text dictionary is too large"));
+        }
+        let mut words = Vec::with_capacity(count);
+        for _ in 0..count {
+            reader.read_exact(&mut one_byte)?;
+            let mut word = vec![0; usize::from(one_byte[0])];
+            reader.read_exact(&mut word)?;
+            if word.is_empty()
+                || word.len() > MAX_WORD_LENGTH
+                || !word.iter().all(u8::is_ascii_lowercase)
+            {
+                return Err(io::Error::other("malformed text dictionary"));
+            }
+            words.push(word);
+        }
+        Ok(Dictionary::from_words(words))
+    }
+
+    pub fn write(&self, writer: &mut dyn io::Write) -> io::Result<()> {
+        writer.write_all(&u16::try_from(self.words.len()).unwrap().to_le_bytes())?;
+        for word in &self.words {
+            writer.write_all(&[u8::try_from(word.len()).unwrap()])?;
+            writer.write_all(word)?;
+        }
+        Ok(())
+    }
+}
+
+// decide whether a block is UTF-8 text worth preprocessing
+pub fn looks_like_text(block: &[u8]) -> bool {
+    // the block may begin or end partway through a multi-byte character
+    let start = block
+        .iter()
+        .take(3)
+        .take_while(|b| **b & 0xc0 == 0x80)
+        .count();
+    match std::str::from_utf8(&block[start..]) {
+        Ok(_) => (),
+        Err(e) if e.error_len().is_none() => (),
+        Err(_) => return false,
+    }
+    let control = block
+        .iter()
+        .filter(|b| (**b < 0x20 && !b"\t\n\r\x0c".contains(b)) || **b == 0x7f)
+        .count();
+    !block.is_empty() && control * 1000 <= block.len() * MAX_CONTROL_PER_MILLE
+}
+
+// fold capitals and replace dictionary words. The plaintext must not contain any
+// of the escape bytes, which is guaranteed if `looks_like_text` accepted it.
+pub fn text_transform(plaintext: &[u8], dictionary: &Dictionary) -> Vec<u8> {
+    let mut out = Vec::with_capacity(plaintext.len());
+    for word in Words::new(plaintext) {
+        match word {
+            Word::Other(bytes) => out.extend_from_slice(bytes),
+            Word::Letters(letters) => {
+                let lower = if letters.len() > 1 && letters.iter().all(u8::is_ascii_uppercase) {
+                    out.push(UPPER_CASE);
+                    letters.to_ascii_lowercase()
+                } else if letters[0].is_ascii_uppercase()
+                    && letters[1..].iter().all(u8::is_ascii_lowercase)
+                {
+                    out.push(CAPITALISED);
+                    letters.to_ascii_lowercase()
+                } else if letters.iter().all(u8::is_ascii_lowercase) {
+                    letters.to_vec()
+                } else {
+                    // mixed case words like "iPhone" are left alone
+                    out.extend_from_slice(letters);
+                    continue;
+                };
+                match dictionary.codes.get(&lower) {
+                    Some(code) => {
+                        out.push(CODE_LEADS[usize::from(*code >> 8)]);
+                        out.push(*code as u8);
+                    }
+                    None => out.extend_from_slice(&lower),
+                }
+            }
+        }
+    }
+    out
+}
+
+// undo the text transform, failing if it would come to more than `max_length` bytes
+pub fn text_untransform(
+    ciphertext: &[u8],
+    dictionary: &Dictionary,
+    max_length: usize,
+) -> Result<Vec<u8>, &'static str> {
+    let mut out = Vec::with_capacity((ciphertext.len() + ciphertext.len() / 2).min(max_length));
+    let mut case = Case::Unchanged;
+    let mut index = 0;
+    while index < ciphertext.len() {
+        let byte = ciphertext[index];
+        index += 1;
+        if byte == CAPITALISED {
+            case = Case::Capitalised;
+        } else if byte == UPPER_CASE {
+            case = Case::Upper;
+        } else if let Some(lead) = CODE_LEADS.iter().position(|l| *l == byte) {
+            let low = *ciphertext.get(index).ok_or("truncated dictionary code")?;
+            index += 1;
+            let word = dictionary
+                .words
+                .get(lead << 8 | usize::from(low))
+                .ok_or("unknown dictionary code")?;
+            for letter in word {
+                out.push(case.apply(*letter));
+            }
+        } else {
+            out.push(case.apply(byte));
+        }
+        if out.len() > max_length {
+            return Err("text decoded block too long");
+        }
+    }
+    Ok(out)
+}
+
+enum Case {
+    Unchanged,
+    Capitalised,
+    Upper,
+}
+
+impl Case {
+    // recase one byte of output, updating the state for the byte after it
+    fn apply(&mut self, byte: u8) -> u8 {
+        if !byte.is_ascii_alphabetic() {
+            *self = Case::Unchanged;
+            return byte;
+        }
+        match self {
+            Case::Unchanged => byte,
+            Case::Capitalised => {
+                *self = Case::Unchanged;
+                byte.to_ascii_uppercase()
+            }
+            Case::Upper => byte.to_ascii_uppercase(),
+        }
+    }
+}
+
+enum Word<'a> {
+    // a maximal run of ascii letters
+    Letters(&'a [u8]),
+    // a run of anything else
+    Other(&'a [u8]),
+}
+
+struct Words<'a> {
+    text: &'a [u8],
+}
+
+impl<'a> Words<'a> {
+    fn new(text: &'a [u8]) -> Self {
+        Words { text }
+    }
+}
+
+impl<'a> Iterator for Words<'a> {
+    type Item = Word<'a>;
+    fn next(&mut self) -> Option<Word<'a>> {
+        let first = self.text.first()?.is_ascii_alphabetic();
+        let len = self
+            .text
+            .iter()
+            .take_while(|b| b.is_ascii_alphabetic() == first)
+            .count();
+        let (word, rest) = self.text.split_at(len);
+        self.text = rest;
+        Some(if first {
+            Word::Letters(word)
+        } else {
+            Word::Other(word)
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const TEXT: &str = "When you create a closure, Rust infers which \
+        trait to use based on how the closure uses the values from the environment. ALL \
+        closures implement FnOnce because they can all be called at least once. Closures \
+        that don't move the captured variables also implement FnMut, and closures that \
+        don't need mutable access to the captured variables also implement Fn. I think \
+        ünïcödé is fine too, and so is a MiXeD cAsE word.\n";
+
+    #[test]
+    fn text_round_trip() {
+        let dictionary = Dictionary::from_sample(TEXT.as_bytes());
+        assert!(!dictionary.is_empty());
+        let transformed = text_transform(TEXT.as_bytes(), &dictionary);
+        assert!(transformed.len() < TEXT.len());
+        let restored = text_untransform(&transformed, &dictionary, TEXT.len()).unwrap();
+        assert_eq!(String::from_utf8_lossy(&restored), TEXT);
+
+        let empty = Dictionary::empty();
+        let transformed = text_transform(TEXT.as_bytes(), &empty);
+        let restored = text_untransform(&transformed, &empty, TEXT.len()).unwrap();
+        assert_eq!(String::from_utf8_lossy(&restored), TEXT);
+    }
+
+    #[test]
+    fn dictionary_serialisation() {
+        let dictionary = Dictionary::from_sample(TEXT.as_bytes());
+        let mut serialised = vec![];
+        dictionary.write(&mut serialised).unwrap();
+        let read = Dictionary::read(&mut &serialised[..]).unwrap();
+        assert_eq!(read.words, dictionary.words);
+        assert_eq!(read.codes, dictionary.codes);
+
+        // words longer than the encoder ever chooses
+        let long = Dictionary::from_words(vec![b"x".repeat(MAX_WORD_LENGTH + 1)]);
+        let mut serialised = vec![];
+        long.write(&mut serialised).unwrap();
+        assert!(Dictionary::read(&mut &serialised[..]).is_err());
+    }
+
+    #[test]
+    fn dictionary_pays_for_itself() {
+        // the dictionary in the header plus the transformed text
+        fn cost(text: &[u8], dictionary: &Dictionary) -> usize {
+            let mut serialised = vec![];
+            dictionary.write(&mut serialised).unwrap();
+            serialised.len() + text_transform(text, dictionary).len()
+        }
+
+        // "then" is used twice, saving 4 bytes for a 5 byte entry, and "closures"
+        // twice, saving 12 bytes for a 9 byte entry
+        let text = b"then closures, then closures";
+        let dictionary = Dictionary::from_sample(text);
+        assert_eq!(dictionary.words, vec![b"closures".to_vec()]);
+
+        // taking every repeated word, as scoring by uses after the first did,
+        // comes out bigger
+        let repeated = Dictionary::from_words(vec![b"closures".to_vec(), b"then".to_vec()]);
+        assert!(cost(text, &dictionary) < cost(text, &repeated));
+        assert!(cost(TEXT.as_bytes(), &Dictionary::from_sample(TEXT.as_bytes())) < TEXT.len());
+    }
+
+    #[test]
+    fn text_detection() {
+        assert!(looks_like_text(TEXT.as_bytes()));
+        assert!(looks_like_text(&"ünï".as_bytes()[1..4]));
+        assert!(!looks_like_text(&[0xff, 0x00, 0x12, 0x34]));
+        assert!(!looks_like_text(b""));
+    }
+
+    #[test]
+    fn bad_codes() {
+        let dictionary = Dictionary::empty();
+        assert!(text_untransform(&[b'a', 0xc0], &dictionary, 16).is_err());
+        assert!(text_untransform(&[0xc0, 0x00], &dictionary, 16).is_err());
+
+        // a code can stand for up to 32 bytes, which mustn't overrun the block
+        let dictionary = Dictionary::from_words(vec![b"x".repeat(MAX_WORD_LENGTH)]);
+        assert!(text_untransform(&[0xc0, 0x00], &dictionary, 32).is_ok());
+        assert!(text_untransform(&[0xc0, 0x00, 0xc0, 0x00], &dictionary, 32).is_err());
+    }
+}
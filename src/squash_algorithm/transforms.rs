@@ -1,3 +1,4 @@
+use super::cancel::{Cancel, Cancelled};
 use crate::suffixarray::SuffixArray;
 use std::convert::TryInto;
 
@@ -11,13 +12,18 @@ pub struct BwVec {
 
 // do a burrows-wheeler transform on a plaintext
 pub fn bw_transform(plaintext: &[u8]) -> BwVec {
+    bw_transform_with_cancel(plaintext, Cancel::never()).unwrap()
+}
+
+// do a burrows-wheeler transform on a plaintext, unless cancelled partway
+pub fn bw_transform_with_cancel(plaintext: &[u8], cancel: Cancel) -> Result<BwVec, Cancelled> {
     if plaintext.is_empty() {
-        return BwVec {
+        return Ok(BwVec {
             block: vec![],
             end_index: 0,
-        };
+        });
     }
-    let suffix_array = SuffixArray::from_array(plaintext);
+    let suffix_array = SuffixArray::from_array_with_cancel(plaintext, cancel)?;
     let mut out = Vec::with_capacity(plaintext.len());
     let mut end = 0;
     for (s_index, s_val) in suffix_array.raw().iter().enumerate() {
@@ -29,14 +35,20 @@ pub fn bw_transform(plaintext: &[u8]) -> BwVec {
             out.push(plaintext[p_index - 1]);
         }
     }
-    BwVec {
+    Ok(BwVec {
         block: out,
         end_index: end.try_into().unwrap(),
-    }
+    })
 }
 
 // undo a burrows-wheeler transform, leaving plaintext
-pub fn bw_untransform(ciphertext: &BwVec) -> Vec<u8> {
+pub fn bw_untransform(ciphertext: &BwVec) -> Result<Vec<u8>, &'static str> {
+    if ciphertext.block.is_empty() {
+        return Ok(vec![]);
+    }
+    if ciphertext.end_index as usize >= ciphertext.block.len() {
+        return Err("end index out of range");
+    }
     let mut out = vec![0; ciphertext.block.len() - 1];
 
     // counts stores the number of items of each
@@ -74,7 +86,7 @@ pub fn bw_untransform(ciphertext: &BwVec) -> Vec<u8> {
 
         next_index = sections[next_item as usize] + char_position;
     }
-    out
+    Ok(out)
 }
 
 // do a move-to-front transform on some data
@@ -148,17 +160,21 @@ pub fn run_length_encode(plaintext: &[u8]) -> Vec<RunEncoded> {
     }
 }
 
-// undo run-length encoding
-pub fn run_length_decode(ciphertext: &[RunEncoded]) -> Vec<u8> {
-    let mut out = Vec::with_capacity(ciphertext.len());
+// undo run-length encoding, failing if it would come to more than `max_length` bytes
+pub fn run_length_decode(
+    ciphertext: &[RunEncoded],
+    max_length: usize,
+) -> Result<Vec<u8>, &'static str> {
+    let mut out = Vec::with_capacity(ciphertext.len().min(max_length));
     let mut index = 0;
     loop {
         if index >= ciphertext.len() {
             break;
         }
-        if let RunEncoded::Byte(b) = ciphertext[index] {
+        let run = if let RunEncoded::Byte(b) = ciphertext[index] {
             out.push(b);
             index += 1;
+            0
         } else {
             let mut zeros = vec![];
             while index < ciphertext.len() {
@@ -169,12 +185,17 @@ pub fn run_length_decode(ciphertext: &[RunEncoded]) -> Vec<u8> {
                     break;
                 }
             }
-            for _ in 0..from_bijective(&zeros) {
-                out.push(0);
+            if zeros.len() > 32 {
+                return Err("zero run too long");
             }
+            from_bijective(&zeros) as usize
+        };
+        if out.len() + run > max_length {
+            return Err("run-length decoded block too long");
         }
+        out.resize(out.len() + run, 0);
     }
-    out
+    Ok(out)
 }
 
 #[derive(PartialEq, Debug, Clone, Copy)]
@@ -189,6 +210,10 @@ pub fn to_bijective(num: u32) -> Vec<Bijective> {
     if num == 0 {
         panic!("can't use zero");
     }
+    // the one number that needs 32 digits, which the sieve below can't reach
+    if num == u32::MAX {
+        return vec![Bijective::A; 32];
+    }
     let mut sieve = 0;
     let mut sieve_increment = 2;
     loop {
@@ -239,26 +264,32 @@ pub fn from_bijective(num: &[Bijective]) -> u32 {
 fn bwt_test() {
     let test = b"banana_banana";
     let enc = bw_transform(test);
-    assert_eq!(bw_untransform(&enc), test);
+    assert_eq!(bw_untransform(&enc).unwrap(), test);
 
     let test = b"banana_banana$";
     let enc = bw_transform(test);
-    assert_eq!(bw_untransform(&enc), test);
+    assert_eq!(bw_untransform(&enc).unwrap(), test);
 
     let test = b"blooby blabby blam. man manam malamla. blom blooby blop.";
     let enc = bw_transform(test);
     assert_eq!(
-        String::from_utf8_lossy(&bw_untransform(&enc)),
+        String::from_utf8_lossy(&bw_untransform(&enc).unwrap()),
         String::from_utf8_lossy(test)
     );
 
     let test = b"abcdabcdefghefgh";
     let enc = bw_transform(test);
-    assert_eq!(bw_untransform(&enc), test);
+    assert_eq!(bw_untransform(&enc).unwrap(), test);
 
     let test = b"toblerone bars";
     let enc = bw_transform(test);
-    assert_eq!(bw_untransform(&enc), test);
+    assert_eq!(bw_untransform(&enc).unwrap(), test);
+
+    let bad = BwVec {
+        block: enc.block,
+        end_index: 15,
+    };
+    assert!(bw_untransform(&bad).is_err());
 }
 
 #[test]
@@ -279,6 +310,12 @@ fn mtf_test() {
 fn rle_test() {
     let test = b"bbfdddeejreewwwer";
     let enc = run_length_encode(test);
-    assert_eq!(run_length_decode(&enc), test);
+    assert_eq!(run_length_decode(&enc, test.len()).unwrap(), test);
+    assert!(run_length_decode(&enc, test.len() - 1).is_err());
     assert_eq!(run_length_encode(b""), []);
+
+    // a run of four billion zeros is refused rather than allocated
+    let zeros = |n, z| (0..n).map(|_| RunEncoded::ZeroRun(z)).collect::<Vec<_>>();
+    assert!(run_length_decode(&zeros(31, Bijective::B), 1 << 18).is_err());
+    assert!(run_length_decode(&zeros(33, Bijective::A), 1 << 18).is_err());
 }
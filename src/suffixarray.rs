@@ -1,3 +1,4 @@
+use crate::squash_algorithm::{Cancel, Cancelled};
 use std::cmp::Ordering;
 
 // A suffix array is a sorted list of all the suffixes of a given text.
@@ -25,7 +26,16 @@ fn suffix_compare(a: &Suffix, b: &Suffix) -> Ordering {
 }
 
 impl<'a> SuffixArray<'a> {
-    pub fn from_array(body: &'a [u8]) -> SuffixArray {
+    #[cfg(test)]
+    pub fn from_array(body: &'a [u8]) -> SuffixArray<'a> {
+        Self::from_array_with_cancel(body, Cancel::never()).unwrap()
+    }
+
+    // build the suffix array, giving up between passes if cancelled
+    pub fn from_array_with_cancel(
+        body: &'a [u8],
+        cancel: Cancel,
+    ) -> Result<SuffixArray<'a>, Cancelled> {
         // special thanks to https://www.geeksforgeeks.org/suffix-array-set-2-a-nlognlogn-algorithm/
         // for providing the algorithm I have re-implemented here
         let mut array: Vec<Suffix> = vec![
@@ -57,6 +67,7 @@ impl<'a> SuffixArray<'a> {
             if k >= 2 * array.len() {
                 break;
             }
+            cancel.check()?;
 
             let mut rank = 0;
             let mut prev_rank = array[0].rank.0;
@@ -89,10 +100,10 @@ impl<'a> SuffixArray<'a> {
             k *= 2;
         }
 
-        SuffixArray {
+        Ok(SuffixArray {
             _text: body,
             array: array.iter().map(|a| a.index).collect(),
-        }
+        })
     }
     pub fn raw(self) -> Vec<usize> {
         self.array
@@ -0,0 +1,779 @@
+use crate::archive::{self, EntryKind};
+use crate::squash_algorithm::{squash_with_options, unsquash, Metadata, SquashOptions};
+use std::convert::TryFrom;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Reading and writing tar streams, so that `.tar.sq` files can be made and unpacked
+// without any other tools. The tar data is generated and parsed on the fly as it
+// passes through the compressor, and never exists anywhere in full.
+//
+// Archives are written in ustar format, with pax extended headers for paths and
+// sizes that ustar can't hold. Reading also understands GNU long names and base-256
+// numbers. Only files and directories are extracted; links and other special files
+// are skipped.
+
+const BLOCK: usize = 512;
+// the largest pax header or GNU long name that will be read
+const MAX_EXTENDED_HEADER: u64 = 1 << 20;
+
+// header field offsets and lengths
+const NAME: (usize, usize) = (0, 100);
+const MODE: (usize, usize) = (100, 8);
+const UID: (usize, usize) = (108, 8);
+const GID: (usize, usize) = (116, 8);
+const SIZE: (usize, usize) = (124, 12);
+const MTIME: (usize, usize) = (136, 12);
+const CHECKSUM: (usize, usize) = (148, 8);
+const TYPE: usize = 156;
+const MAGIC: (usize, usize) = (257, 8);
+const PREFIX: (usize, usize) = (345, 155);
+
+const USTAR_MAGIC: &[u8; 8] = b"ustar\x0000";
+
+// entry types
+const REGULAR: u8 = b'0';
+const OLD_REGULAR: u8 = 0;
+const DIRECTORY: u8 = b'5';
+const PAX_HEADER: u8 = b'x';
+const PAX_GLOBAL_HEADER: u8 = b'g';
+const GNU_LONG_NAME: u8 = b'L';
+
+// what a tar extraction did
+pub struct Extracted {
+    // the paths of the files and directories extracted
+    pub extracted: Vec<String>,
+    // the paths of links and special files, which aren't extracted
+    pub skipped: Vec<String>,
+}
+
+// squash a tar of the given files and directories, and everything below the
+// directories. Returns the symbolic links and special files that were left out
+pub fn create(
+    inputs: &[PathBuf],
+    writer: &mut dyn Write,
+    options: &SquashOptions,
+) -> io::Result<Vec<PathBuf>> {
+    let mut stream = TarStream::new(inputs)?;
+    let options = SquashOptions {
+        metadata: None,
+        ..options.clone()
+    };
+    squash_with_options(&mut stream, writer, &options)?;
+    Ok(stream.skipped)
+}
+
+// unsquash a tar below `destination`. Paths that would escape it are refused
+pub fn extract(
+    reader: &mut dyn Read,
+    destination: &Path,
+    overwrite: bool,
+) -> io::Result<Extracted> {
+    let mut extractor = TarExtractor::new(destination, overwrite);
+    unsquash(reader, &mut extractor)?;
+    extractor.finish()
+}
+
+// a tar of some files and directories, generated as it's read
+pub struct TarStream {
+    entries: std::vec::IntoIter<(PathBuf, archive::Entry)>,
+    skipped: Vec<PathBuf>,
+    // headers or padding waiting to be read
+    pending: Vec<u8>,
+    position: usize,
+    // the file whose data is being read, and the padding that follows it
+    file: Option<(PathBuf, io::Take<fs::File>, usize)>,
+    finished: bool,
+}
+
+impl TarStream {
+    pub fn new(inputs: &[PathBuf]) -> io::Result<Self> {
+        let mut sources = vec![];
+        let mut skipped = vec![];
+        for input in inputs {
+            archive::collect(input, &mut sources, &mut skipped)?;
+        }
+        Ok(TarStream {
+            entries: sources.into_iter(),
+            skipped,
+            pending: vec![],
+            position: 0,
+            file: None,
+            finished: false,
+        })
+    }
+
+    // the symbolic links and special files being left out
+    pub fn skipped(&self) -> &[PathBuf] {
+        &self.skipped
+    }
+}
+
+impl Read for TarStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.position < self.pending.len() {
+                let length = buf.len().min(self.pending.len() - self.position);
+                buf[..length].copy_from_slice(&self.pending[self.position..][..length]);
+                self.position += length;
+                return Ok(length);
+            }
+            if let Some((path, file, padding)) = &mut self.file {
+                let read = file.read(buf)?;
+                if read > 0 || buf.is_empty() {
+                    return Ok(read);
+                }
+                if file.limit() > 0 {
+                    return Err(io::Error::other(format!(
+                        "{}: file shrank while it was being archived",
+                        path.display()
+                    )));
+                }
+                self.pending = vec![0; *padding];
+                self.position = 0;
+                self.file = None;
+                continue;
+            }
+            match self.entries.next() {
+                Some((source, entry)) => {
+                    self.pending = headers(&entry);
+                    self.position = 0;
+                    if entry.kind == EntryKind::File {
+                        let file = fs::File::open(&source)?.take(entry.size());
+                        let padding = padding(entry.size());
+                        self.file = Some((source, file, padding));
+                    }
+                }
+                // the end of a tar is marked by two empty blocks
+                None if !self.finished => {
+                    self.pending = vec![0; 2 * BLOCK];
+                    self.position = 0;
+                    self.finished = true;
+                }
+                None => return Ok(0),
+            }
+        }
+    }
+}
+
+// the zeros needed to fill out the last block of some data
+fn padding(size: u64) -> usize {
+    (BLOCK - (size % BLOCK as u64) as usize) % BLOCK
+}
+
+// the header blocks for an entry: a pax header if ustar can't describe it, then the
+// ustar header
+fn headers(entry: &archive::Entry) -> Vec<u8> {
+    let mut name = entry.path.clone();
+    if entry.kind == EntryKind::Directory {
+        name.push('/');
+    }
+    let mut pax = vec![];
+    let mut header = [0; BLOCK];
+
+    let (prefix, short_name) = split_name(&name).unwrap_or_else(|| {
+        pax_record(&mut pax, "path", &name);
+        ("", "")
+    });
+    header[PREFIX.0..][..prefix.len()].copy_from_slice(prefix.as_bytes());
+    header[NAME.0..][..short_name.len()].copy_from_slice(short_name.as_bytes());
+
+    let mode = entry.metadata.mode.unwrap_or(0o644) & 0o7777;
+    octal(&mut header, MODE, u64::from(mode));
+    octal(&mut header, UID, 0);
+    octal(&mut header, GID, 0);
+    if !octal(&mut header, SIZE, entry.size()) {
+        pax_record(&mut pax, "size", &entry.size().to_string());
+    }
+    let mtime = entry
+        .metadata
+        .mtime
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_secs());
+    if !octal(&mut header, MTIME, mtime) {
+        pax_record(&mut pax, "mtime", &mtime.to_string());
+    }
+    header[TYPE] = match entry.kind {
+        EntryKind::File => REGULAR,
+        EntryKind::Directory => DIRECTORY,
+    };
+    header[MAGIC.0..][..MAGIC.1].copy_from_slice(USTAR_MAGIC);
+    set_checksum(&mut header);
+
+    let mut blocks = vec![];
+    if !pax.is_empty() {
+        let mut pax_header = [0; BLOCK];
+        let pax_name = b"PaxHeader";
+        pax_header[NAME.0..][..pax_name.len()].copy_from_slice(pax_name);
+        octal(&mut pax_header, MODE, 0o644);
+        octal(&mut pax_header, UID, 0);
+        octal(&mut pax_header, GID, 0);
+        octal(&mut pax_header, SIZE, pax.len() as u64);
+        octal(&mut pax_header, MTIME, 0);
+        pax_header[TYPE] = PAX_HEADER;
+        pax_header[MAGIC.0..][..MAGIC.1].copy_from_slice(USTAR_MAGIC);
+        set_checksum(&mut pax_header);
+        blocks.extend_from_slice(&pax_header);
+        blocks.extend_from_slice(&pax);
+        blocks.resize(blocks.len() + padding(pax.len() as u64), 0);
+    }
+    blocks.extend_from_slice(&header);
+    blocks
+}
+
+// split a name into a ustar prefix and name, if it will fit
+fn split_name(name: &str) -> Option<(&str, &str)> {
+    if name.len() <= NAME.1 {
+        return Some(("", name));
+    }
+    // a directory's trailing slash can't be where it splits
+    let trimmed = name.trim_end_matches('/');
+    trimmed
+        .match_indices('/')
+        .map(|(i, _)| (&name[..i], &name[i + 1..]))
+        .find(|(prefix, rest)| prefix.len() <= PREFIX.1 && rest.len() <= NAME.1)
+}
+
+// write a number as zero-padded octal, if it fits
+fn octal(header: &mut [u8; BLOCK], (offset, length): (usize, usize), value: u64) -> bool {
+    let digits = format!("{:0width$o}", value, width = length - 1);
+    if digits.len() >= length {
+        return false;
+    }
+    header[offset..][..length - 1].copy_from_slice(digits.as_bytes());
+    header[offset + length - 1] = 0;
+    true
+}
+
+fn set_checksum(header: &mut [u8; BLOCK]) {
+    let checksum = checksum(header);
+    // six digits, a NUL and a space, for historical reasons
+    let digits = format!("{:06o}\0 ", checksum);
+    header[CHECKSUM.0..][..CHECKSUM.1].copy_from_slice(digits.as_bytes());
+}
+
+// the sum of the header's bytes, counting the checksum field as spaces
+fn checksum(header: &[u8; BLOCK]) -> u64 {
+    header
+        .iter()
+        .enumerate()
+        .map(|(i, b)| {
+            if (CHECKSUM.0..CHECKSUM.0 + CHECKSUM.1).contains(&i) {
+                u64::from(b' ')
+            } else {
+                u64::from(*b)
+            }
+        })
+        .sum()
+}
+
+// append a "length key=value\n" record, where the length counts the whole record
+fn pax_record(pax: &mut Vec<u8>, key: &str, value: &str) {
+    let rest = key.len() + value.len() + 3;
+    let mut length = rest + 1;
+    while length.to_string().len() + rest != length {
+        length = length.to_string().len() + rest;
+    }
+    pax.extend_from_slice(format!("{} {}={}\n", length, key, value).as_bytes());
+}
+
+// parses a tar stream as it's written, extracting it below a destination
+pub struct TarExtractor {
+    destination: PathBuf,
+    overwrite: bool,
+    state: State,
+    // a header being collected
+    header: Vec<u8>,
+    // overrides for the next entry, from pax headers and GNU long names
+    next_path: Option<String>,
+    next_size: Option<u64>,
+    next_mtime: Option<SystemTime>,
+    // directories have their metadata restored once everything is written
+    directories: Vec<(PathBuf, Metadata)>,
+    extracted: Vec<String>,
+    skipped: Vec<String>,
+}
+
+enum State {
+    Header,
+    Data {
+        remaining: u64,
+        padding: usize,
+        sink: Sink,
+    },
+    Padding(usize),
+    // after the end-of-archive marker, where anything more is ignored
+    End,
+}
+
+// where an entry's data goes
+enum Sink {
+    File(io::BufWriter<fs::File>, Metadata),
+    Pax(Vec<u8>),
+    LongName(Vec<u8>),
+    Discard,
+}
+
+impl TarExtractor {
+    pub fn new(destination: &Path, overwrite: bool) -> Self {
+        TarExtractor {
+            destination: destination.to_path_buf(),
+            overwrite,
+            state: State::Header,
+            header: Vec::with_capacity(BLOCK),
+            next_path: None,
+            next_size: None,
+            next_mtime: None,
+            directories: vec![],
+            extracted: vec![],
+            skipped: vec![],
+        }
+    }
+
+    // check the tar was complete, and set the directories' metadata
+    pub fn finish(self) -> io::Result<Extracted> {
+        match self.state {
+            State::End => (),
+            // some writers leave off the end marker
+            State::Header if self.header.is_empty() => (),
+            _ => return Err(io::Error::other("tar is truncated")),
+        }
+        archive::restore_directories(
+            self.directories
+                .iter()
+                .map(|(path, metadata)| (path.as_path(), metadata)),
+        );
+        Ok(Extracted {
+            extracted: self.extracted,
+            skipped: self.skipped,
+        })
+    }
+
+    // start on the entry described by a complete header block
+    fn begin_entry(&mut self) -> io::Result<()> {
+        let header = <[u8; BLOCK]>::try_from(&self.header[..]).unwrap();
+        self.header.clear();
+        if header.iter().all(|b| *b == 0) {
+            self.state = State::End;
+            return Ok(());
+        }
+        if number(&header, CHECKSUM)? != checksum(&header) {
+            return Err(io::Error::other("tar header checksum doesn't match"));
+        }
+
+        let mut size = number(&header, SIZE)?;
+        let kind = header[TYPE];
+        if kind == PAX_HEADER || kind == PAX_GLOBAL_HEADER || kind == GNU_LONG_NAME {
+            if size > MAX_EXTENDED_HEADER {
+                return Err(io::Error::other("tar extended header is too large"));
+            }
+            let sink = match kind {
+                PAX_HEADER => Sink::Pax(vec![]),
+                GNU_LONG_NAME => Sink::LongName(vec![]),
+                // global headers apply to every entry, but nothing in them is used here
+                _ => Sink::Discard,
+            };
+            self.begin_data(size, sink)?;
+            return Ok(());
+        }
+
+        let name = match self.next_path.take() {
+            Some(path) => path,
+            None => {
+                let name = field_string(&header, NAME)?;
+                let prefix = if header[MAGIC.0..][..6] == USTAR_MAGIC[..6] {
+                    field_string(&header, PREFIX)?
+                } else {
+                    String::new()
+                };
+                if prefix.is_empty() {
+                    name
+                } else {
+                    format!("{}/{}", prefix, name)
+                }
+            }
+        };
+        if let Some(pax_size) = self.next_size.take() {
+            size = pax_size;
+        }
+        let metadata = Metadata {
+            name: None,
+            size: None,
+            mtime: match self.next_mtime.take() {
+                Some(mtime) => Some(mtime),
+                None => Some(UNIX_EPOCH + Duration::from_secs(number(&header, MTIME)?)),
+            },
+            // a tar from elsewhere isn't trusted with setuid, setgid or sticky bits
+            mode: Some(number(&header, MODE)? as u32 & 0o777),
+        };
+
+        let is_directory = kind == DIRECTORY || (kind == OLD_REGULAR && name.ends_with('/'));
+        let is_file = !is_directory && (kind == REGULAR || kind == OLD_REGULAR);
+        if !is_directory && !is_file {
+            self.skipped.push(name);
+            return self.begin_data(size, Sink::Discard);
+        }
+        let path = match relative_path(&name)? {
+            Some(path) => self.destination.join(path),
+            // the root of the tar, like "./"
+            None => return self.begin_data(size, Sink::Discard),
+        };
+        self.extracted.push(name);
+        if is_directory {
+            fs::create_dir_all(&path)?;
+            self.directories.push((path, metadata));
+            return self.begin_data(size, Sink::Discard);
+        }
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let file = archive::create_file(&path, self.overwrite)?;
+        self.begin_data(size, Sink::File(io::BufWriter::new(file), metadata))
+    }
+
+    fn begin_data(&mut self, size: u64, sink: Sink) -> io::Result<()> {
+        self.state = State::Data {
+            remaining: size,
+            padding: padding(size),
+            sink,
+        };
+        if size == 0 {
+            self.end_data()?;
+        }
+        Ok(())
+    }
+
+    // all of an entry's data has arrived
+    fn end_data(&mut self) -> io::Result<()> {
+        let (padding, sink) = match std::mem::replace(&mut self.state, State::Header) {
+            State::Data { padding, sink, .. } => (padding, sink),
+            _ => unreachable!(),
+        };
+        match sink {
+            Sink::File(file, metadata) => {
+                let file = file.into_inner().map_err(|x| x.into_error())?;
+                metadata.restore(&file)?;
+            }
+            Sink::Pax(records) => self.read_pax(&records)?,
+            Sink::LongName(name) => {
+                let name = name.split(|b| *b == 0).next().unwrap_or(&[]);
+                self.next_path = Some(utf8(name)?);
+            }
+            Sink::Discard => (),
+        }
+        if padding > 0 {
+            self.state = State::Padding(padding);
+        }
+        Ok(())
+    }
+
+    fn read_pax(&mut self, mut records: &[u8]) -> io::Result<()> {
+        let malformed = || io::Error::other("malformed pax header");
+        while !records.is_empty() {
+            let space = records
+                .iter()
+                .position(|b| *b == b' ')
+                .ok_or_else(malformed)?;
+            let length: usize = utf8(&records[..space])?.parse().map_err(|_| malformed())?;
+            if length <= space || length > records.len() || records[length - 1] != b'\n' {
+                return Err(malformed());
+            }
+            let record = utf8(&records[space + 1..length - 1])?;
+            records = &records[length..];
+            let (key, value) = record.split_once('=').ok_or_else(malformed)?;
+            match key {
+                "path" => self.next_path = Some(String::from(value)),
+                "size" => self.next_size = Some(value.parse().map_err(|_| malformed())?),
+                "mtime" => {
+                    // decimal seconds, possibly with a fraction, possibly negative
+                    let seconds: f64 = value.parse().map_err(|_| malformed())?;
+                    let offset = Duration::try_from_secs_f64(seconds.abs()).ok();
+                    let mtime = if seconds >= 0.0 {
+                        offset.and_then(|o| UNIX_EPOCH.checked_add(o))
+                    } else {
+                        offset.and_then(|o| UNIX_EPOCH.checked_sub(o))
+                    };
+                    self.next_mtime = Some(mtime.ok_or_else(malformed)?);
+                }
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Write for TarExtractor {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let length = match &mut self.state {
+            State::Header => {
+                let length = buf.len().min(BLOCK - self.header.len());
+                self.header.extend_from_slice(&buf[..length]);
+                if self.header.len() == BLOCK {
+                    self.begin_entry()?;
+                }
+                length
+            }
+            State::Data {
+                remaining, sink, ..
+            } => {
+                let length = buf
+                    .len()
+                    .min(usize::try_from(*remaining).unwrap_or(usize::MAX));
+                let data = &buf[..length];
+                match sink {
+                    Sink::File(file, _) => file.write_all(data)?,
+                    Sink::Pax(bytes) | Sink::LongName(bytes) => bytes.extend_from_slice(data),
+                    Sink::Discard => (),
+                }
+                *remaining -= length as u64;
+                if *remaining == 0 {
+                    self.end_data()?;
+                }
+                length
+            }
+            State::Padding(remaining) => {
+                let length = buf.len().min(*remaining);
+                *remaining -= length;
+                if *remaining == 0 {
+                    self.state = State::Header;
+                }
+                length
+            }
+            State::End => buf.len(),
+        };
+        Ok(length)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// read a numeric header field, in octal or GNU base-256
+fn number(header: &[u8; BLOCK], (offset, length): (usize, usize)) -> io::Result<u64> {
+    let field = &header[offset..][..length];
+    let malformed = || io::Error::other("malformed number in tar header");
+    if field[0] & 0x80 != 0 {
+        let mut value: u64 = u64::from(field[0] & 0x7f);
+        for byte in &field[1..] {
+            value = value
+                .checked_mul(256)
+                .map(|v| v + u64::from(*byte))
+                .ok_or_else(malformed)?;
+        }
+        return Ok(value);
+    }
+    // octal digits, which may be padded with spaces and end in a NUL or space
+    let mut value: u64 = 0;
+    for byte in field
+        .iter()
+        .skip_while(|b| **b == b' ')
+        .take_while(|b| **b != 0 && **b != b' ')
+    {
+        if !(b'0'..=b'7').contains(byte) {
+            return Err(malformed());
+        }
+        value = value
+            .checked_mul(8)
+            .map(|v| v + u64::from(byte - b'0'))
+            .ok_or_else(malformed)?;
+    }
+    Ok(value)
+}
+
+// read a NUL-terminated string field
+fn field_string(header: &[u8; BLOCK], (offset, length): (usize, usize)) -> io::Result<String> {
+    let field = &header[offset..][..length];
+    utf8(field.split(|b| *b == 0).next().unwrap_or(&[]))
+}
+
+fn utf8(bytes: &[u8]) -> io::Result<String> {
+    String::from_utf8(bytes.to_vec()).map_err(|_| io::Error::other("tar path isn't UTF-8"))
+}
+
+// where a path in a tar should be extracted to, relative to the destination, or None
+// for the root itself. Absolute paths and paths that climb out with `..` are refused
+fn relative_path(name: &str) -> io::Result<Option<PathBuf>> {
+    if name.starts_with('/') {
+        return Err(io::Error::other(format!("{}: unsafe path in tar", name)));
+    }
+    let parts: Vec<&str> = name
+        .split('/')
+        .filter(|part| !part.is_empty() && *part != ".")
+        .collect();
+    if parts.is_empty() {
+        return Ok(None);
+    }
+    archive::safe_path(&parts.join("/"))
+        .map(Some)
+        .map_err(|_| io::Error::other(format!("{}: unsafe path in tar", name)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::common::scratch_dir;
+
+    fn entry(kind: EntryKind, path: &str, size: u64) -> archive::Entry {
+        archive::Entry {
+            kind,
+            path: String::from(path),
+            offset: 0,
+            length: 0,
+            metadata: Metadata {
+                size: Some(size),
+                mode: Some(0o644),
+                ..Metadata::default()
+            },
+        }
+    }
+
+    // feed a tar to an extractor a few bytes at a time
+    fn extract_tar(tar: &[u8], destination: &Path) -> io::Result<Extracted> {
+        let mut extractor = TarExtractor::new(destination, false);
+        for chunk in tar.chunks(97) {
+            extractor.write_all(chunk)?;
+        }
+        extractor.finish()
+    }
+
+    #[test]
+    fn tar_round_trip() {
+        let dir = scratch_dir("tar-round-trip");
+        let long = "long".repeat(30);
+        let longer = "longer/".repeat(50);
+        let files = [
+            (
+                format!("tree/{}/{}.txt", long, long),
+                "split across prefix and name",
+            ),
+            (
+                format!("tree/{}end.txt", longer),
+                "only fits in a pax header",
+            ),
+            (String::from("tree/empty"), ""),
+            (String::from("tree/ünïcödé"), "the quick brown fox"),
+        ];
+        for (path, contents) in &files {
+            let path = dir.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents.repeat(100)).unwrap();
+        }
+        let file = fs::File::open(dir.join(&files[0].0)).unwrap();
+        file.set_modified(UNIX_EPOCH + Duration::from_secs(1_000_000_000))
+            .unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            file.set_permissions(fs::Permissions::from_mode(0o4755))
+                .unwrap();
+        }
+
+        let mut tar = vec![];
+        TarStream::new(&[dir.join("tree")])
+            .unwrap()
+            .read_to_end(&mut tar)
+            .unwrap();
+        assert_eq!(tar.len() % BLOCK, 0);
+
+        let out = dir.join("out");
+        let extracted = extract_tar(&tar, &out).unwrap();
+        assert!(extracted.skipped.is_empty());
+        // the paths are stored as given, less the root
+        let root: PathBuf = dir
+            .components()
+            .filter(|c| matches!(c, std::path::Component::Normal(_)))
+            .collect();
+        for (path, contents) in &files {
+            let extracted = out.join(&root).join(path);
+            assert_eq!(
+                fs::read_to_string(&extracted).unwrap(),
+                contents.repeat(100)
+            );
+        }
+        let first = out.join(&root).join(&files[0].0);
+        assert_eq!(
+            fs::metadata(&first).unwrap().modified().unwrap(),
+            UNIX_EPOCH + Duration::from_secs(1_000_000_000)
+        );
+        // the setuid bit is written to the tar, but not extracted
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert!(tar.windows(7).any(|w| w == b"0004755"));
+            let mode = fs::metadata(&first).unwrap().permissions().mode();
+            assert_eq!(mode & 0o7777, 0o755);
+        }
+
+        // and compressed
+        let mut squashed = vec![];
+        create(
+            &[dir.join("tree")],
+            &mut squashed,
+            &SquashOptions::default_options(),
+        )
+        .unwrap();
+        let out = dir.join("squashed");
+        extract(&mut &squashed[..], &out, false).unwrap();
+        for (path, contents) in &files {
+            let extracted = out.join(&root).join(path);
+            assert_eq!(
+                fs::read_to_string(&extracted).unwrap(),
+                contents.repeat(100)
+            );
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn escaping_paths() {
+        let dir = scratch_dir("tar-escaping");
+        for path in &["../evil", "/etc/evil", "a/../../evil"] {
+            let mut tar = headers(&entry(EntryKind::File, path, 0));
+            tar.extend_from_slice(&[0; 2 * BLOCK]);
+            assert!(extract_tar(&tar, &dir.join("out")).is_err(), "{}", path);
+        }
+        assert!(!dir.join("evil").exists());
+
+        // links are skipped, along with their data
+        let mut link = headers(&entry(EntryKind::File, "link", 5));
+        link[TYPE] = b'2';
+        set_checksum(<&mut [u8; BLOCK]>::try_from(&mut link[..]).unwrap());
+        link.extend_from_slice(b"hello");
+        link.resize(2 * BLOCK, 0);
+        link.extend_from_slice(&headers(&entry(EntryKind::Directory, "./kept", 0)));
+        let extracted = extract_tar(&link, &dir.join("out")).unwrap();
+        assert_eq!(extracted.skipped, ["link"]);
+        assert_eq!(extracted.extracted, ["./kept/"]);
+        assert!(dir.join("out/kept").is_dir());
+        assert!(!dir.join("out/link").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn damaged_tars() {
+        let dir = scratch_dir("tar-damaged");
+        let mut tar = headers(&entry(EntryKind::File, "file", 600));
+        tar.resize(3 * BLOCK, b'x');
+        assert!(extract_tar(&tar[..2 * BLOCK], &dir.join("truncated")).is_err());
+        tar[0] = b'F';
+        assert!(extract_tar(&tar, &dir.join("checksum")).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn pax_record_lengths() {
+        for length in 0..200 {
+            let mut pax = vec![];
+            pax_record(&mut pax, "path", &"x".repeat(length));
+            let stated: usize =
+                std::str::from_utf8(&pax[..pax.iter().position(|b| *b == b' ').unwrap()])
+                    .unwrap()
+                    .parse()
+                    .unwrap();
+            assert_eq!(stated, pax.len());
+        }
+    }
+}
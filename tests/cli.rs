@@ -0,0 +1,811 @@
+mod common;
+
+use common::scratch_dir;
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const TEXT: &str = "When you create a closure, Rust infers which \
+    trait to use based on how the closure uses the values from the environment. All \
+    closures implement FnOnce because they can all be called at least once.\n";
+
+fn squash() -> Command {
+    Command::new(env!("CARGO_BIN_EXE_squash"))
+}
+
+#[test]
+fn compress_and_decompress_in_place() {
+    let dir = scratch_dir("cli-in-place");
+    let file = dir.join("closures.txt");
+    let squashed = dir.join("closures.txt.sq");
+    fs::write(&file, TEXT).unwrap();
+
+    assert!(squash().arg(&file).status().unwrap().success());
+    assert!(!file.exists());
+    assert!(squashed.exists());
+
+    assert!(squash()
+        .arg("-dk")
+        .arg(&squashed)
+        .status()
+        .unwrap()
+        .success());
+    assert_eq!(fs::read_to_string(&file).unwrap(), TEXT);
+    assert!(squashed.exists());
+
+    // refuses to overwrite without -f
+    assert!(!squash()
+        .arg("-d")
+        .arg(&squashed)
+        .status()
+        .unwrap()
+        .success());
+    assert!(squash()
+        .arg("-df")
+        .arg(&squashed)
+        .status()
+        .unwrap()
+        .success());
+    assert_eq!(fs::read_to_string(&file).unwrap(), TEXT);
+    assert!(!squashed.exists());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn pipes() {
+    let mut compress = squash()
+        .arg("-c")
+        .arg("-")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    compress
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(TEXT.as_bytes())
+        .unwrap();
+    let squashed = compress.wait_with_output().unwrap();
+    assert!(squashed.status.success());
+
+    let mut decompress = squash()
+        .arg("-d")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    decompress
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(&squashed.stdout)
+        .unwrap();
+    let unsquashed = decompress.wait_with_output().unwrap();
+    assert!(unsquashed.status.success());
+    assert_eq!(unsquashed.stdout, TEXT.as_bytes());
+}
+
+#[test]
+fn concatenated_files() {
+    let dir = scratch_dir("cli-concatenated");
+    let first = dir.join("first.log");
+    let second = dir.join("second.log");
+    fs::write(&first, TEXT).unwrap();
+    fs::write(&second, "and then some more\n").unwrap();
+    assert!(squash()
+        .arg(&first)
+        .arg(&second)
+        .status()
+        .unwrap()
+        .success());
+
+    // as log rotation does, appending each compressed segment to one file
+    let log = dir.join("all.log.sq");
+    let mut concatenated = fs::read(dir.join("first.log.sq")).unwrap();
+    concatenated.extend(fs::read(dir.join("second.log.sq")).unwrap());
+    fs::write(&log, &concatenated).unwrap();
+    let unsquashed = squash().arg("-dc").arg(&log).output().unwrap();
+    assert!(unsquashed.status.success());
+    assert_eq!(
+        unsquashed.stdout,
+        format!("{}and then some more\n", TEXT).as_bytes()
+    );
+
+    // trailing garbage is ignored, except with --strict
+    concatenated.extend_from_slice(b"\0\0\0");
+    fs::write(&log, &concatenated).unwrap();
+    assert!(squash()
+        .arg("-dc")
+        .arg(&log)
+        .output()
+        .unwrap()
+        .status
+        .success());
+    let strict = squash()
+        .arg("-dc")
+        .arg("--strict")
+        .arg(&log)
+        .output()
+        .unwrap();
+    assert!(!strict.status.success());
+    assert!(String::from_utf8_lossy(&strict.stderr).contains("trailing data"));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn append() {
+    let dir = scratch_dir("cli-append");
+    let log = dir.join("app.log");
+    let squashed = dir.join("app.log.sq");
+    let restored = dir.join("restored.log");
+    let mut expected = String::new();
+    for day in 0..3 {
+        let segment = format!("day {}: {}", day, TEXT);
+        fs::write(&log, &segment).unwrap();
+        expected.push_str(&segment);
+        // the first append starts the file
+        assert!(squash()
+            .args(["enc", "--append"])
+            .arg(&log)
+            .arg(&squashed)
+            .status()
+            .unwrap()
+            .success());
+    }
+    assert!(squash()
+        .arg("dec")
+        .arg(&squashed)
+        .arg(&restored)
+        .status()
+        .unwrap()
+        .success());
+    assert_eq!(fs::read_to_string(&restored).unwrap(), expected);
+
+    // a file that isn't a whole stream is left alone
+    fs::write(&squashed, "not squashed").unwrap();
+    assert!(!squash()
+        .args(["enc", "--append"])
+        .arg(&log)
+        .arg(&squashed)
+        .status()
+        .unwrap()
+        .success());
+    assert_eq!(fs::read_to_string(&squashed).unwrap(), "not squashed");
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn dec_stored_name() {
+    let dir = scratch_dir("cli-dec-stored-name");
+    let victim = dir.join("victim");
+    let squashed = dir.join("victim.sq");
+    fs::write(&victim, TEXT).unwrap();
+    assert!(squash().arg("-k").arg(&victim).status().unwrap().success());
+    let dec = |input: &PathBuf, args: &[&str]| {
+        squash().arg("dec").args(args).arg(input).output().unwrap()
+    };
+
+    // the name the stream stores is only written over with -f
+    fs::write(&victim, "keep me").unwrap();
+    assert!(!dec(&squashed, &[]).status.success());
+    assert_eq!(fs::read_to_string(&victim).unwrap(), "keep me");
+    assert!(dec(&squashed, &["-f"]).status.success());
+    assert_eq!(fs::read_to_string(&victim).unwrap(), TEXT);
+
+    // and never when it's the input itself
+    fs::rename(&squashed, &victim).unwrap();
+    let stored = fs::read(&victim).unwrap();
+    let decoded = dec(&victim, &["-f"]);
+    assert!(!decoded.status.success());
+    let error = String::from_utf8_lossy(&decoded.stderr);
+    assert!(error.contains("would overwrite the input"), "{}", error);
+    assert!(fs::read(&victim).unwrap() == stored);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn enc_over_the_input() {
+    let dir = scratch_dir("cli-enc-over-input");
+    let file = dir.join("closures.txt");
+    fs::write(&file, TEXT).unwrap();
+    for args in [&["enc"][..], &["enc", "--append"]] {
+        let squashed = squash().args(args).arg(&file).arg(&file).output().unwrap();
+        assert!(!squashed.status.success());
+        let error = String::from_utf8_lossy(&squashed.stderr);
+        assert!(error.contains("would overwrite the input"), "{}", error);
+        assert_eq!(fs::read_to_string(&file).unwrap(), TEXT);
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn stored_name_of_the_input() {
+    let dir = scratch_dir("cli-stored-name-of-input");
+    let input = dir.join("loop.sq");
+    fs::write(&input, TEXT).unwrap();
+    // squashed to standard output, so that it stores the name of the file it replaces
+    let squashed = squash().arg("-c").arg(&input).output().unwrap();
+    assert!(squashed.status.success());
+    fs::write(&input, &squashed.stdout).unwrap();
+
+    let decoded = squash()
+        .args(["-d", "-N", "-f"])
+        .arg(&input)
+        .output()
+        .unwrap();
+    assert!(!decoded.status.success());
+    let error = String::from_utf8_lossy(&decoded.stderr);
+    assert!(error.contains("would overwrite the input"), "{}", error);
+    assert!(fs::read(&input).unwrap() == squashed.stdout);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn encryption() {
+    let dir = scratch_dir("cli-encryption");
+    let input = dir.join("secret.txt");
+    let squashed = dir.join("secret.txt.sq");
+    let restored = dir.join("restored.txt");
+    let right = dir.join("right");
+    let wrong = dir.join("wrong");
+    fs::write(&input, TEXT.repeat(20)).unwrap();
+    fs::write(&right, "correct horse\n").unwrap();
+    fs::write(&wrong, "battery staple\n").unwrap();
+    // the password is read from standard input, as file descriptor 0
+    let with_password = |password: &PathBuf| {
+        let mut command = squash();
+        command.stdin(Stdio::from(fs::File::open(password).unwrap()));
+        command
+    };
+
+    assert!(with_password(&right)
+        .args(["enc", "--encrypt", "--password-fd=0"])
+        .arg(&input)
+        .arg(&squashed)
+        .status()
+        .unwrap()
+        .success());
+    assert!(!fs::read_to_string(&squashed)
+        .unwrap_or_default()
+        .contains("closure"));
+    assert!(with_password(&right)
+        .args(["dec", "--password-fd=0"])
+        .arg(&squashed)
+        .arg(&restored)
+        .status()
+        .unwrap()
+        .success());
+    assert_eq!(fs::read_to_string(&restored).unwrap(), TEXT.repeat(20));
+    let tested = with_password(&right)
+        .args(["test", "--password-fd=0"])
+        .arg(&squashed)
+        .output()
+        .unwrap();
+    assert!(String::from_utf8_lossy(&tested.stdout).ends_with(": ok\n"));
+
+    // a wrong password leaves no output behind
+    fs::remove_file(&restored).unwrap();
+    let decrypted = with_password(&wrong)
+        .args(["dec", "--password-fd=0"])
+        .arg(&squashed)
+        .arg(&restored)
+        .output()
+        .unwrap();
+    assert!(!decrypted.status.success());
+    let error = String::from_utf8_lossy(&decrypted.stderr);
+    assert!(error.contains("wrong password"), "{}", error);
+    assert!(!restored.exists());
+
+    let info = squash().arg("info").arg(&squashed).output().unwrap();
+    let info = String::from_utf8_lossy(&info.stdout);
+    assert!(
+        info.contains("encryption        ChaCha20-Poly1305"),
+        "{}",
+        info
+    );
+    assert!(info.contains("encrypted"), "{}", info);
+
+    // nor is an encrypted file appended to
+    assert!(!with_password(&right)
+        .args(["enc", "--append", "--encrypt", "--password-fd=0"])
+        .arg(&input)
+        .arg(&squashed)
+        .status()
+        .unwrap()
+        .success());
+
+    // only the first line is read, so a writer that keeps the pipe open isn't waited on
+    fs::remove_file(&squashed).unwrap();
+    let mut child = squash()
+        .args(["enc", "--encrypt", "--password-fd=0"])
+        .arg(&input)
+        .arg(&squashed)
+        .stdin(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdin = child.stdin.take().unwrap();
+    stdin.write_all(b"correct horse\n").unwrap();
+    let started = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait().unwrap() {
+            break status;
+        }
+        if started.elapsed() > Duration::from_secs(60) {
+            child.kill().unwrap();
+            panic!("still waiting for the password's writer to close the pipe");
+        }
+        thread::sleep(Duration::from_millis(10));
+    };
+    drop(stdin);
+    assert!(status.success());
+    assert!(with_password(&right)
+        .args(["dec", "--password-fd=0"])
+        .arg(&squashed)
+        .arg(&restored)
+        .status()
+        .unwrap()
+        .success());
+    assert_eq!(fs::read_to_string(&restored).unwrap(), TEXT.repeat(20));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn recover() {
+    const BLOCK_SIZE: usize = 1 << 18;
+    let dir = scratch_dir("cli-recover");
+    let squashed = dir.join("data.sq");
+    let restored = dir.join("data");
+    let input: Vec<u8> = (0..BLOCK_SIZE * 3).map(|i| ((i * i) >> 9) as u8).collect();
+    fs::write(&restored, &input).unwrap();
+    assert!(squash()
+        .arg("enc")
+        .arg(&restored)
+        .arg(&squashed)
+        .status()
+        .unwrap()
+        .success());
+
+    // damage somewhere in the middle block
+    let mut damaged = fs::read(&squashed).unwrap();
+    let middle = damaged.len() / 2;
+    damaged[middle] ^= 0x40;
+    fs::write(&squashed, &damaged).unwrap();
+    fs::remove_file(&restored).unwrap();
+    assert!(!squash()
+        .arg("dec")
+        .arg(&squashed)
+        .arg(&restored)
+        .status()
+        .unwrap()
+        .success());
+
+    let recover = squash()
+        .arg("recover")
+        .arg(&squashed)
+        .arg(&restored)
+        .output()
+        .unwrap();
+    assert!(recover.status.success());
+    let report = String::from_utf8_lossy(&recover.stdout);
+    assert!(report.contains("recovered 2 blocks"), "{}", report);
+    assert!(
+        report.contains("at byte 262144 of the output"),
+        "{}",
+        report
+    );
+    let recovered = fs::read(&restored).unwrap();
+    assert!(recovered == [&input[..BLOCK_SIZE], &input[BLOCK_SIZE * 2..]].concat());
+
+    // the output isn't overwritten without -f, and garbage has nothing to recover
+    assert!(!squash()
+        .arg("recover")
+        .arg(&squashed)
+        .arg(&restored)
+        .status()
+        .unwrap()
+        .success());
+    let garbage = dir.join("garbage.sq");
+    fs::write(&garbage, "not squashed").unwrap();
+    assert!(!squash()
+        .args(["recover", "-f"])
+        .arg(&garbage)
+        .arg(&restored)
+        .status()
+        .unwrap()
+        .success());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_and_repair() {
+    let dir = scratch_dir("cli-repair");
+    let input = dir.join("data");
+    let squashed = dir.join("data.sq");
+    let repaired = dir.join("repaired.sq");
+    let text: String = TEXT.repeat(6000);
+    fs::write(&input, &text).unwrap();
+    assert!(squash()
+        .args(["-k", "--parity=50"])
+        .arg(&input)
+        .status()
+        .unwrap()
+        .success());
+    let test = |file: &PathBuf| squash().arg("test").arg(file).output().unwrap();
+    let intact = test(&squashed);
+    assert!(intact.status.success());
+    assert!(String::from_utf8_lossy(&intact.stdout).ends_with(": ok\n"));
+
+    // damage one of the blocks
+    let original = fs::read(&squashed).unwrap();
+    let mut damaged = original.clone();
+    let middle = damaged.len() / 3;
+    damaged[middle] ^= 0x40;
+    fs::write(&squashed, &damaged).unwrap();
+    let tested = test(&squashed);
+    assert!(!tested.status.success());
+    let report = String::from_utf8_lossy(&tested.stdout);
+    assert!(
+        report.contains("repairable with squash repair"),
+        "{}",
+        report
+    );
+
+    let repair = squash()
+        .arg("repair")
+        .arg(&squashed)
+        .arg(&repaired)
+        .output()
+        .unwrap();
+    assert!(repair.status.success());
+    let report = String::from_utf8_lossy(&repair.stdout);
+    assert!(report.contains("rebuilt 1 block"), "{}", report);
+    assert!(fs::read(&repaired).unwrap() == original);
+
+    // a file beyond repair leaves no output behind
+    let garbage = dir.join("garbage.sq");
+    fs::write(&garbage, "not squashed").unwrap();
+    let missing = dir.join("missing.sq");
+    assert!(!squash()
+        .arg("repair")
+        .arg(&garbage)
+        .arg(&missing)
+        .status()
+        .unwrap()
+        .success());
+    assert!(!missing.exists());
+    assert!(!test(&garbage).status.success());
+
+    // a flipped bit in a file without parity is found with --bitflip, and reported
+    let small = dir.join("small");
+    fs::write(&small, TEXT.repeat(20)).unwrap();
+    assert!(squash().arg("-k").arg(&small).status().unwrap().success());
+    let small_squashed = dir.join("small.sq");
+    let original = fs::read(&small_squashed).unwrap();
+    let mut flipped = original.clone();
+    // a few bytes into the block, after its sync marker, length and checksum
+    const SYNC_MARKER: [u8; 6] = [0x27, 0x18, 0x28, 0x18, 0x28, 0x45];
+    let frame = (0..flipped.len())
+        .find(|&i| flipped[i..].starts_with(&SYNC_MARKER))
+        .unwrap();
+    let byte = frame + 14 + 8;
+    flipped[byte] ^= 1 << 2;
+    fs::write(&small_squashed, &flipped).unwrap();
+    assert!(!test(&small_squashed).status.success());
+    let repair = squash()
+        .args(["repair", "-f", "--bitflip=60"])
+        .arg(&small_squashed)
+        .arg(&repaired)
+        .output()
+        .unwrap();
+    assert!(repair.status.success());
+    let report = String::from_utf8_lossy(&repair.stdout);
+    assert!(report.contains("flipped 1 bit back"), "{}", report);
+    let expected = format!("bit 2 of byte {} was flipped, in block 0", byte);
+    assert!(report.contains(&expected), "{}", report);
+    assert!(fs::read(&repaired).unwrap() == original);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn failures_exit_non_zero() {
+    let dir = scratch_dir("cli-failures");
+    let missing = dir.join("missing");
+    let garbage = dir.join("garbage.sq");
+    fs::write(&garbage, "not squashed").unwrap();
+    let good = dir.join("good.txt");
+    fs::write(&good, TEXT).unwrap();
+
+    assert!(!squash().arg(&missing).status().unwrap().success());
+    assert!(!squash().arg("-d").arg(&garbage).status().unwrap().success());
+    assert!(!squash().arg("--bogus").status().unwrap().success());
+    // one bad file fails the run, but the good one is still done
+    assert!(!squash()
+        .arg(&missing)
+        .arg(&good)
+        .status()
+        .unwrap()
+        .success());
+    assert!(dir.join("good.txt.sq").exists());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn recursive_batch() {
+    let dir = scratch_dir("cli-recursive");
+    fs::create_dir_all(dir.join("nested/deeper")).unwrap();
+    let files = [
+        dir.join("top.txt"),
+        dir.join("nested/middle.txt"),
+        dir.join("nested/deeper/bottom.txt"),
+    ];
+    for (i, file) in files.iter().enumerate() {
+        fs::write(file, TEXT.repeat(i + 1)).unwrap();
+    }
+    #[cfg(unix)]
+    std::os::unix::fs::symlink("top.txt", dir.join("link.txt")).unwrap();
+
+    let compress = squash().args(["-r", "-j", "2"]).arg(&dir).output().unwrap();
+    assert!(compress.status.success());
+    let report = String::from_utf8_lossy(&compress.stderr);
+    assert!(report.contains("3 files:"), "{}", report);
+    for file in &files {
+        assert!(!file.exists());
+        let mut squashed = file.clone().into_os_string();
+        squashed.push(".sq");
+        assert!(PathBuf::from(squashed).exists());
+    }
+    // links are skipped rather than followed
+    #[cfg(unix)]
+    {
+        assert!(report.contains("symbolic link -- skipped"), "{}", report);
+        assert!(fs::symlink_metadata(dir.join("link.txt")).is_ok());
+        assert!(!dir.join("link.txt.sq").exists());
+    }
+
+    // a directory without -r fails, but doesn't stop the rest of the batch
+    fs::write(dir.join("loose.txt"), TEXT).unwrap();
+    assert!(!squash()
+        .arg(dir.join("nested"))
+        .arg(dir.join("loose.txt"))
+        .status()
+        .unwrap()
+        .success());
+    assert!(dir.join("loose.txt.sq").exists());
+
+    assert!(squash()
+        .args(["-dr", "-j4"])
+        .arg(&dir)
+        .status()
+        .unwrap()
+        .success());
+    for (i, file) in files.iter().enumerate() {
+        assert_eq!(fs::read_to_string(file).unwrap(), TEXT.repeat(i + 1));
+    }
+    assert_eq!(fs::read_to_string(dir.join("loose.txt")).unwrap(), TEXT);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn archives() {
+    let dir = scratch_dir("cli-archives");
+    fs::create_dir_all(dir.join("docs/more")).unwrap();
+    fs::write(dir.join("docs/closures.txt"), TEXT).unwrap();
+    fs::write(dir.join("docs/more/again.txt"), TEXT.repeat(3)).unwrap();
+
+    for solid in &[false, true] {
+        let archive = dir.join("docs.sqa");
+        let mut create = squash();
+        create.current_dir(&dir).args(["create", "-f"]);
+        if *solid {
+            create.arg("--solid");
+        }
+        assert!(create.arg(&archive).arg("docs").status().unwrap().success());
+
+        let listing = squash().arg("list").arg(&archive).output().unwrap();
+        assert!(listing.status.success());
+        let listing = String::from_utf8_lossy(&listing.stdout);
+        assert!(listing.contains("docs/more/"), "{}", listing);
+        assert!(listing.contains("docs/more/again.txt"), "{}", listing);
+
+        let out = dir.join(if *solid { "solid" } else { "per-file" });
+        assert!(squash()
+            .arg("extract")
+            .arg("-C")
+            .arg(&out)
+            .arg(&archive)
+            .status()
+            .unwrap()
+            .success());
+        assert_eq!(
+            fs::read_to_string(out.join("docs/more/again.txt")).unwrap(),
+            TEXT.repeat(3)
+        );
+        assert_eq!(
+            fs::read_to_string(out.join("docs/closures.txt")).unwrap(),
+            TEXT
+        );
+    }
+    assert!(!squash()
+        .arg("list")
+        .arg(dir.join("docs/closures.txt"))
+        .status()
+        .unwrap()
+        .success());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn tar_pipes() {
+    let dir = scratch_dir("cli-tar");
+    fs::create_dir_all(dir.join("docs/more")).unwrap();
+    fs::write(dir.join("docs/closures.txt"), TEXT).unwrap();
+    fs::write(dir.join("docs/more/again.txt"), TEXT.repeat(3)).unwrap();
+
+    let create = squash()
+        .current_dir(&dir)
+        .args(["tar", "-c", "docs"])
+        .output()
+        .unwrap();
+    assert!(create.status.success());
+
+    fs::create_dir_all(dir.join("out")).unwrap();
+    let mut extract = squash()
+        .current_dir(&dir)
+        .args(["tar", "-xv", "-C", "out"])
+        .stdin(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    extract
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(&create.stdout)
+        .unwrap();
+    let extract = extract.wait_with_output().unwrap();
+    assert!(extract.status.success());
+    assert!(String::from_utf8_lossy(&extract.stderr).contains("docs/more/again.txt"));
+    assert_eq!(
+        fs::read_to_string(dir.join("out/docs/more/again.txt")).unwrap(),
+        TEXT.repeat(3)
+    );
+    assert_eq!(
+        fs::read_to_string(dir.join("out/docs/closures.txt")).unwrap(),
+        TEXT
+    );
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn info() {
+    let dir = scratch_dir("cli-info");
+    let file = dir.join("closures.txt");
+    fs::write(&file, TEXT.repeat(50)).unwrap();
+    assert!(squash().arg(&file).status().unwrap().success());
+    let squashed = dir.join("closures.txt.sq");
+
+    let text = squash()
+        .arg("info")
+        .arg("--decode")
+        .arg(&squashed)
+        .output()
+        .unwrap();
+    assert!(text.status.success());
+    let text = String::from_utf8_lossy(&text.stdout);
+    assert!(text.contains("blocks            1"), "{}", text);
+    assert!(text.contains("block size        262144 bytes"), "{}", text);
+    assert!(text.contains("original name     closures.txt"), "{}", text);
+
+    let json = squash()
+        .args(["info", "--json", "--decode"])
+        .arg(&squashed)
+        .output()
+        .unwrap();
+    assert!(json.status.success());
+    let json = String::from_utf8_lossy(&json.stdout);
+    assert!(json.starts_with("{\"file\":"), "{}", json);
+    assert!(json.contains("\"type\":\"full\",\"text\":true"), "{}", json);
+    assert!(
+        json.contains(&format!("\"decoded_size\":{}", TEXT.len() * 50)),
+        "{}",
+        json
+    );
+
+    assert!(!squash()
+        .arg("info")
+        .arg(file.with_extension("missing"))
+        .status()
+        .unwrap()
+        .success());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn analyze() {
+    let dir = scratch_dir("cli-analyze");
+    let file = dir.join("closures.txt");
+    fs::write(&file, TEXT.repeat(50)).unwrap();
+
+    let text = squash().arg("analyze").arg(&file).output().unwrap();
+    assert!(text.status.success());
+    let text = String::from_utf8_lossy(&text.stdout);
+    assert!(text.contains("blocks            1"), "{}", text);
+    assert!(text.contains("rle shrink"), "{}", text);
+
+    let json = squash()
+        .args(["analyze", "--json", "--no-text"])
+        .arg(&file)
+        .output()
+        .unwrap();
+    assert!(json.status.success());
+    let json = String::from_utf8_lossy(&json.stdout);
+    assert!(json.starts_with("{\"file\":"), "{}", json);
+    assert!(
+        json.contains(&format!(
+            "\"stages\":[{{\"name\":\"input\",\"symbols\":{},",
+            TEXT.len() * 50
+        )),
+        "{}",
+        json
+    );
+    assert!(json.contains("{\"name\":\"bwt\""), "{}", json);
+    assert!(!json.contains("{\"name\":\"text\""), "{}", json);
+    assert!(json.contains("\"bits_per_symbol\":"), "{}", json);
+
+    assert!(!squash()
+        .args(["analyze", "--bogus"])
+        .arg(&file)
+        .status()
+        .unwrap()
+        .success());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn bench() {
+    let run = |args: &[&str]| {
+        let output = squash().arg("bench").args(args).output().unwrap();
+        assert!(output.status.success());
+        String::from_utf8_lossy(&output.stdout).into_owned()
+    };
+    let json = run(&[
+        "--json",
+        "--size=16K",
+        "--corpus=text,runs",
+        "--options=default,no-text",
+        "--levels=1",
+    ]);
+    let lines: Vec<&str> = json.lines().collect();
+    assert_eq!(lines.len(), 4, "{}", json);
+    assert!(lines[0]
+        .starts_with("{\"input\":\"text\",\"options\":\"default\",\"level\":1,\"size\":16384,"));
+    assert!(lines[3].starts_with("{\"input\":\"runs\",\"options\":\"no-text\","));
+    assert!(lines.iter().all(|l| l.contains("\"compress_peak\":")));
+    // the corpora are the same every time
+    let squashed = |line: &str| {
+        line.split("\"squashed\":")
+            .nth(1)
+            .unwrap()
+            .split(',')
+            .next()
+            .unwrap()
+            .to_string()
+    };
+    let again = run(&["--json", "--size=16K", "--corpus=text", "--levels=1"]);
+    assert_eq!(squashed(&again), squashed(lines[0]));
+
+    let dir = scratch_dir("cli-bench");
+    let file = dir.join("closures.txt");
+    fs::write(&file, TEXT.repeat(20)).unwrap();
+    let text = run(&["--levels=6", file.to_str().unwrap()]);
+    assert!(text.contains("closures.txt"), "{}", text);
+
+    for args in [&["--corpus=nope"][..], &["--options=fast"], &["--levels=0"]] {
+        assert!(!squash().arg("bench").args(args).status().unwrap().success());
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,36 @@
+// helpers shared by the tests, both these integration tests and the library's own,
+// which include this file as `common`. Each test uses only some of them
+#![allow(dead_code)]
+
+use std::fs;
+use std::path::PathBuf;
+
+// xorshift64*, for reproducible test data
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Rng(seed | 1)
+    }
+
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next() >> 32) as usize % n
+    }
+}
+
+// a fresh, empty directory for one test to work in, named uniquely within its test
+// binary
+pub fn scratch_dir(test: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("squash-{}-{}", test, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
@@ -0,0 +1,220 @@
+// Every format version ever written must still decode, bit for bit. The fixtures in
+// tests/fixtures were written from the inputs below: v1 by the original release, the
+// only one of that version, and v2 by the change that brought that version in. They
+// must never be regenerated: if one stops decoding, files of that version out in the
+// world would too. A new format version gets its own set of fixtures, written from
+// the same inputs, before it's released.
+
+mod common;
+
+use common::Rng;
+use squash::squash_algorithm::*;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::time::{Duration, UNIX_EPOCH};
+
+const BLOCK_SIZE: usize = 1 << 18;
+
+const TEXT: &str = "When you create a closure, Rust infers which \
+    trait to use based on how the closure uses the values from the environment. All \
+    closures implement FnOnce because they can all be called at least once. Closures \
+    that don't move the captured variables also implement FnMut, and CLOSURES that \
+    don't need mutable access to the captured variables also implement Fn.\n";
+
+// the plaintext of each fixture, by name. These must stay exactly as they were when
+// the fixtures were written. Writers that had the options squashed "text" with
+// metadata, "skewed" at level 9 with the halving model, and "repeated" with dedup
+fn inputs() -> Vec<(&'static str, Vec<u8>)> {
+    let mut rng = Rng::new(44);
+    let text = TEXT.repeat(8).into_bytes();
+    let noise: Vec<u8> = (0..2000).map(|_| rng.next() as u8).collect();
+    let skewed: Vec<u8> = (0..4000).map(|_| rng.next() as u8 & 0x83).collect();
+    let mut repeated: Vec<u8> = (0..12_000).map(|_| rng.next() as u8).collect();
+    repeated.extend_from_within(..);
+    // runs of a few symbols, over more than one block
+    let mut blocks = vec![];
+    while blocks.len() < BLOCK_SIZE + 1000 {
+        let byte = b"\0\x01ab"[rng.below(4)];
+        let run = 1 + rng.below(4096);
+        blocks.resize(blocks.len() + run, byte);
+    }
+    blocks.truncate(BLOCK_SIZE + 1000);
+    vec![
+        ("text", text),
+        ("noise", noise),
+        ("skewed", skewed),
+        ("repeated", repeated),
+        ("blocks", blocks),
+    ]
+}
+
+// the version this build writes
+fn current_version() -> u8 {
+    let mut squashed = vec![];
+    squash(&mut &b""[..], &mut squashed).unwrap();
+    inspect(&mut &squashed[..], false).unwrap().version
+}
+
+fn fixture(version: u8, name: &str) -> Vec<u8> {
+    let path: PathBuf = [
+        env!("CARGO_MANIFEST_DIR"),
+        "tests",
+        "fixtures",
+        &format!("v{}-{}.sq", version, name),
+    ]
+    .iter()
+    .collect();
+    fs::read(&path).unwrap_or_else(|e| panic!("{}: {}", path.display(), e))
+}
+
+fn unsquashed(stream: &[u8]) -> io::Result<Vec<u8>> {
+    let mut out = vec![];
+    unsquash(&mut &stream[..], &mut out)?;
+    Ok(out)
+}
+
+#[test]
+fn every_version_decodes_bit_exactly() {
+    // which also checks there are fixtures for every version up to this one
+    for version in 1..=current_version() {
+        for (name, input) in inputs() {
+            let decoded = unsquashed(&fixture(version, name))
+                .unwrap_or_else(|e| panic!("v{}-{}: {}", version, name, e));
+            assert!(decoded == input, "v{}-{} decoded wrongly", version, name);
+        }
+    }
+}
+
+#[test]
+fn every_version_inspects() {
+    for version in 1..=current_version() {
+        for (name, input) in inputs() {
+            let stream = fixture(version, name);
+            let info = inspect(&mut &stream[..], true).unwrap();
+            let case = format!("v{}-{}", version, name);
+            assert_eq!(info.version, version, "{}", case);
+            assert_eq!(info.block_size as usize, BLOCK_SIZE, "{}", case);
+            let decoded: usize = info.blocks.iter().map(|b| b.decoded_size.unwrap()).sum();
+            assert_eq!(decoded, input.len(), "{}", case);
+            assert_eq!(
+                info.blocks.len(),
+                input.len().div_ceil(BLOCK_SIZE),
+                "{}",
+                case
+            );
+
+            // the header and blocks, with their framing, and the trailer make up the
+            // whole stream: in version 1, 4 bytes before each block and no trailer,
+            // and since then, a 6-byte sync marker and a checksum around each block
+            // length and a 22-byte trailer
+            let (overhead, trailer) = if version >= 2 { (14, 22) } else { (4, 0) };
+            let blocks: u64 = info
+                .blocks
+                .iter()
+                .map(|b| overhead + u64::from(b.length))
+                .sum();
+            assert_eq!(
+                info.header_length + blocks + trailer,
+                stream.len() as u64,
+                "{}",
+                case
+            );
+            assert_eq!(info.stored_size(), stream.len() as u64, "{}", case);
+            assert_eq!(info.trailer.is_some(), version >= 2, "{}", case);
+            if let Some(trailer) = info.trailer {
+                assert_eq!(trailer.length, input.len() as u64, "{}", case);
+                assert_eq!(trailer.blocks as usize, info.blocks.len(), "{}", case);
+            }
+        }
+    }
+}
+
+#[test]
+fn features_of_each_version() {
+    for version in 1..=current_version() {
+        let info = |name| inspect(&mut &fixture(version, name)[..], false).unwrap();
+        if version == 1 {
+            // no flags of any kind: every block is the full pipeline
+            let text = info("text");
+            assert_eq!(text.header_length, 4 + 1 + 12);
+            assert_eq!(text.dictionary_words, 0);
+            for name in ["text", "noise", "repeated"] {
+                let block = &info(name).blocks[0];
+                assert_eq!(block.block_type, BlockType::Full, "v1-{}", name);
+                assert!(!block.text && block.dedup_ops.is_none());
+            }
+            continue;
+        }
+        let case = |name| format!("v{}-{}", version, name);
+        let text = info("text");
+        assert!(text.dictionary_words > 0, "{}", case("text"));
+        assert!(text.blocks[0].text, "{}", case("text"));
+        assert_eq!(info("noise").blocks[0].block_type, BlockType::Stored);
+        let repeated = info("repeated");
+        assert!(repeated.dedup_window.is_some(), "{}", case("repeated"));
+        assert!(
+            repeated.blocks[0].dedup_ops.is_some(),
+            "{}",
+            case("repeated")
+        );
+        assert_eq!(
+            text.metadata.and_then(|m| m.name),
+            Some(String::from("closures.txt")),
+            "{}",
+            case("text")
+        );
+        assert_eq!(info("skewed").arithmetic_encoder.model, Model::Halving);
+    }
+}
+
+#[test]
+fn metadata_of_old_versions() {
+    let read = |version| read_metadata(&mut &fixture(version, "text")[..]).unwrap();
+    assert_eq!(read(1), None);
+    for version in 2..=current_version() {
+        let metadata = read(version).unwrap();
+        assert_eq!(
+            metadata.mtime,
+            Some(UNIX_EPOCH + Duration::new(1_600_000_000, 5))
+        );
+        assert_eq!(metadata.mode, Some(0o644));
+    }
+}
+
+#[test]
+fn old_versions_cut_mid_block_are_refused() {
+    // streams from before the trailer end wherever their last block does, but one
+    // cut partway through a block is still caught
+    for version in 1..=current_version() {
+        for (name, _) in inputs() {
+            let stream = fixture(version, name);
+            let cut = &stream[..stream.len() - 20];
+            assert!(unsquashed(cut).is_err(), "v{}-{}", version, name);
+        }
+    }
+}
+
+#[test]
+fn unknown_versions_are_refused() {
+    let mut stream = fixture(current_version(), "text");
+    for version in [0, current_version() + 1, 255] {
+        stream[4] = version;
+        let error = unsquashed(&stream).unwrap_err();
+        assert_eq!(error.to_string(), "unsupported format version");
+    }
+}
+
+#[test]
+fn streams_of_every_version_concatenated() {
+    // old streams without a trailer end where the next one's magic number starts
+    let mut concatenated = vec![];
+    let mut expected = vec![];
+    for version in (1..=current_version()).rev().chain(1..=current_version()) {
+        for (name, input) in inputs() {
+            concatenated.extend(fixture(version, name));
+            expected.extend(input);
+        }
+    }
+    assert!(unsquashed(&concatenated).unwrap() == expected);
+}
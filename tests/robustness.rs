@@ -0,0 +1,199 @@
+// Decoding must never panic, whatever it's given: every input either decodes or
+// fails with an error. These tests feed the decoder mutated copies of valid streams
+// (bit flips, truncations and splices of one stream into another), all drawn from
+// fixed seeds so that any failure names a case that can be replayed.
+
+mod common;
+
+use common::Rng;
+use squash::squash_algorithm::*;
+use std::io;
+use std::panic;
+use std::time::{Duration, UNIX_EPOCH};
+
+const TEXT: &str = "When you create a closure, Rust infers which \
+    trait to use based on how the closure uses the values from the environment. All \
+    closures implement FnOnce because they can all be called at least once. Closures \
+    that don't move the captured variables also implement FnMut, and CLOSURES that \
+    don't need mutable access to the captured variables also implement Fn.\n";
+
+fn squashed(input: &[u8], options: &SquashOptions) -> Vec<u8> {
+    let mut out = vec![];
+    squash_with_options(&mut &input[..], &mut out, options).unwrap();
+    out
+}
+
+// valid streams covering each block type and every optional header section
+fn streams() -> Vec<(&'static str, Vec<u8>)> {
+    let mut rng = Rng::new(42);
+    let noise: Vec<u8> = (0..4096).map(|_| rng.next() as u8).collect();
+    let skewed: Vec<u8> = noise.iter().map(|b| b & 0x83).collect();
+    let mut repeated = (0..20_000).map(|_| rng.next() as u8).collect::<Vec<u8>>();
+    repeated.extend_from_within(..);
+    let defaults = SquashOptions::default_options();
+    vec![
+        ("empty", squashed(b"", &defaults)),
+        ("text", squashed(TEXT.repeat(4).as_bytes(), &defaults)),
+        ("skewed", squashed(&skewed, &defaults)),
+        ("noise", squashed(&noise[..512], &defaults)),
+        (
+            "searched",
+            squashed(
+                &skewed,
+                &SquashOptions {
+                    level: 9,
+                    model: Model::Halving,
+                    ..defaults.clone()
+                },
+            ),
+        ),
+        (
+            "dedup",
+            squashed(
+                &repeated,
+                &SquashOptions {
+                    dedup_window: Some(DEFAULT_DEDUP_WINDOW),
+                    ..defaults.clone()
+                },
+            ),
+        ),
+        (
+            "metadata",
+            squashed(
+                TEXT.as_bytes(),
+                &SquashOptions {
+                    metadata: Some(Metadata {
+                        name: Some(String::from("closures.txt")),
+                        size: Some(TEXT.len() as u64),
+                        mtime: Some(UNIX_EPOCH + Duration::new(1_600_000_000, 5)),
+                        mode: Some(0o644),
+                    }),
+                    ..defaults.clone()
+                },
+            ),
+        ),
+        (
+            "parity",
+            squashed(
+                TEXT.repeat(2).as_bytes(),
+                &SquashOptions {
+                    parity: Some(100),
+                    ..defaults.clone()
+                },
+            ),
+        ),
+    ]
+}
+
+// decode a stream every way there is, returning whether any of them panicked
+fn panics(stream: &[u8]) -> bool {
+    panic::catch_unwind(|| {
+        let _ = unsquash(&mut &stream[..], &mut io::sink());
+        let _ = inspect(&mut &stream[..], true);
+        let _ = read_metadata(&mut &stream[..]);
+        let _ = recover(stream, &mut io::sink());
+        let _ = repair(stream, &mut io::sink());
+    })
+    .is_err()
+}
+
+fn check(name: &str, mutation: String, stream: &[u8]) {
+    assert!(
+        !panics(stream),
+        "{}: decoding panicked with {}",
+        name,
+        mutation
+    );
+}
+
+#[test]
+fn valid_streams_decode() {
+    for (name, stream) in streams() {
+        assert!(
+            unsquash(&mut &stream[..], &mut io::sink()).is_ok(),
+            "{}",
+            name
+        );
+    }
+}
+
+#[test]
+fn bit_flips() {
+    let mut rng = Rng::new(1);
+    for (name, stream) in streams() {
+        // every bit of the header and the start of the first block, where the
+        // lengths and indices that decoding trusts are kept
+        for bit in 0..stream.len().min(64) * 8 {
+            let mut mutated = stream.clone();
+            mutated[bit / 8] ^= 1 << (bit % 8);
+            check(name, format!("bit {} flipped", bit), &mutated);
+        }
+        // and a few bits at a time anywhere
+        for round in 0..200 {
+            let mut mutated = stream.clone();
+            let mut flipped = vec![];
+            for _ in 0..1 + rng.below(4) {
+                let bit = rng.below(stream.len() * 8);
+                mutated[bit / 8] ^= 1 << (bit % 8);
+                flipped.push(bit);
+            }
+            check(
+                name,
+                format!("bits {:?} flipped (round {})", flipped, round),
+                &mutated,
+            );
+        }
+    }
+}
+
+#[test]
+fn truncations() {
+    for (name, stream) in streams() {
+        let step = (stream.len() / 300).max(1);
+        for length in (0..stream.len()).step_by(step) {
+            let truncated = &stream[..length];
+            assert!(
+                unsquash(&mut &truncated[..], &mut io::sink()).is_err(),
+                "{}: accepted when cut to {} bytes",
+                name,
+                length
+            );
+            check(name, format!("truncation to {} bytes", length), truncated);
+        }
+    }
+}
+
+#[test]
+fn splices() {
+    let mut rng = Rng::new(2);
+    let streams = streams();
+    for round in 0..500 {
+        let (first, a) = &streams[rng.below(streams.len())];
+        let (second, b) = &streams[rng.below(streams.len())];
+        let cut_a = rng.below(a.len() + 1);
+        let cut_b = rng.below(b.len() + 1);
+        let mut spliced = a[..cut_a].to_vec();
+        spliced.extend_from_slice(&b[cut_b..]);
+        check(
+            first,
+            format!(
+                "the first {} bytes followed by {} from byte {} (round {})",
+                cut_a, second, cut_b, round
+            ),
+            &spliced,
+        );
+    }
+}
+
+#[test]
+fn garbage_after_a_header() {
+    let mut rng = Rng::new(3);
+    let (_, text) = &streams()[1];
+    // the magic number and version, then anything at all
+    for round in 0..500 {
+        let length = rng.below(200);
+        let mut garbage = text[..5].to_vec();
+        garbage.extend((0..length).map(|_| rng.next() as u8));
+        check("garbage", format!("round {}", round), &garbage);
+    }
+}